@@ -1,6 +1,26 @@
 use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{KvsError, Result};
+
+/// 数据文件头部的魔数
+pub const LOG_MAGIC: [u8; 4] = *b"KVSL";
+/// 当前数据文件的格式版本
+pub const LOG_VERSION: u32 = 1;
+/// 数据文件头部的长度：magic(4字节) | version(u32)
+pub const LOG_HEADER_SIZE: u64 = 8;
+// 记录头部的长度：crc(u32) | timestamp(u64) | kind(u8) | key_size(u32) | value_size(u32)
+const RECORD_HEADER_SIZE: usize = 21;
+// hint命令头部的长度：key_size(u64) | value_size(u64) | value_pos(u64)
+const HINT_HEADER_SIZE: u64 = 24;
+
+const KIND_SET: u8 = 0;
+const KIND_RM: u8 = 1;
 
 /// keydir的值结构，记录命令位置
+#[derive(Debug, Clone, Copy)]
 pub struct CommandPos {
     /// 说明文件id
     pub file_id: u64,    
@@ -17,7 +37,7 @@ impl CommandPos {
         self.file_id = file_id;
         self.value_size = value_size;
         self.value_pos = value_pos;
-        return res;
+        res
     }
 }
 
@@ -48,12 +68,214 @@ impl DataCommand {
     pub fn rm(key: String) -> DataCommand {
         Self::Rm { key }
     }
+
+    /// 将命令编码为一条二进制记录
+    ///
+    /// 记录的布局为：crc(u32) | timestamp(u64) | kind(u8) | key_size(u32) | value_size(u32) | key | value，
+    /// 整数均为小端序，crc覆盖crc之后的所有字节
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, key, value) = match self {
+            DataCommand::Set { key, value } => (KIND_SET, key, value.as_str()),
+            DataCommand::Rm { key } => (KIND_RM, key, ""),
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE + key.len() + value.len());
+        buf.extend_from_slice(&[0u8; 4]); // crc最后再回填
+        buf.extend_from_slice(&timestamp.to_le_bytes());
+        buf.push(kind);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key.as_bytes());
+        buf.extend_from_slice(value.as_bytes());
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// 从reader中解码一条二进制记录，同时返回记录的长度，读到文件末尾时返回None
+    ///
+    /// `remaining`是文件中从记录开头起剩余的字节数量，头部中的长度超出它时不会分配内存
+    ///
+    /// # Errors
+    ///
+    /// 记录不完整时返回IO错误，crc校验失败或者长度超出剩余字节时返回`KvsError::ChecksumMismatch`
+    pub fn decode<R: Read>(reader: &mut R, remaining: u64) -> Result<Option<(DataCommand, u64)>> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        // 在记录开头遇到EOF说明文件已经读完
+        let first = reader.read(&mut header)?;
+        if first == 0 {
+            return Ok(None);
+        }
+        reader.read_exact(&mut header[first..])?;
+        let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let kind = header[12];
+        let key_size = u32::from_le_bytes(header[13..17].try_into().unwrap()) as usize;
+        let value_size = u32::from_le_bytes(header[17..21].try_into().unwrap()) as usize;
+        // 损坏的长度字段可能非常大，必须在分配内存之前检查
+        let body_size = key_size
+            .checked_add(value_size)
+            .filter(|&size| (RECORD_HEADER_SIZE + size) as u64 <= remaining)
+            .ok_or(KvsError::ChecksumMismatch)?;
+        let mut body = vec![0u8; body_size];
+        reader.read_exact(&mut body)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&body);
+        if hasher.finalize() != crc {
+            return Err(KvsError::ChecksumMismatch);
+        }
+
+        let value = body.split_off(key_size);
+        let key = into_string(body)?;
+        let cmd = match kind {
+            KIND_SET => DataCommand::Set {
+                key,
+                value: into_string(value)?,
+            },
+            KIND_RM => DataCommand::Rm { key },
+            _ => return Err(KvsError::UnexpectedCommandType),
+        };
+        Ok(Some((cmd, (RECORD_HEADER_SIZE + key_size + value_size) as u64)))
+    }
+}
+
+fn into_string(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+}
+
+/// 数据文件的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// 迁移前的数据文件，命令以json流的形式保存，没有文件头部
+    Json,
+    /// 带有文件头部的二进制格式，每条记录都带有crc校验
+    Binary,
+}
+
+impl LogFormat {
+    /// 读取文件头部判断数据文件的格式，返回后reader位于第一条命令的起始位置
+    pub fn detect<R: Read + Seek>(reader: &mut R) -> Result<LogFormat> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut header = [0u8; LOG_HEADER_SIZE as usize];
+        let mut read = 0;
+        while read < header.len() {
+            match reader.read(&mut header[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        if read == header.len() && header[..4] == LOG_MAGIC {
+            let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+            if version != LOG_VERSION {
+                return Err(KvsError::UnsupportedVersion(version));
+            }
+            return Ok(LogFormat::Binary);
+        }
+        // 没有魔数的文件是旧的json数据文件，从头开始读
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(LogFormat::Json)
+    }
+
+    /// 向新的数据文件写入文件头部
+    pub fn write_header<W: Write>(writer: &mut W) -> Result<()> {
+        writer.write_all(&LOG_MAGIC)?;
+        writer.write_all(&LOG_VERSION.to_le_bytes())?;
+        Ok(())
+    }
 }
 
 /// hint文件中的命令结构
+///
+/// hint文件与合并后的数据文件一一对应，记录该数据文件中每个键的位置。
+/// 启动时直接读取hint文件即可重建keydir，而不需要反序列化数据文件中的值。
+/// 在文件中的布局为：key_size(u64) | value_size(u64) | value_pos(u64) | key，整数均为小端序。
+/// value_size为0的命令是删除标记，说明该键在合并文件中被删除
 pub struct HintCommand {
     key_size: u64,   //键的大小
     value_size: u64, //值的大小
-    value_pos: String, //值在merged_file中的位置
-    key: String,       //键
+    value_pos: u64,  //值在merged_file中的位置
+    key: String,     //键
+}
+
+impl HintCommand {
+    /// 根据键与其命令在合并文件中的位置构造HintCommand
+    pub fn new(key: String, value_size: u64, value_pos: u64) -> HintCommand {
+        Self {
+            key_size: key.len() as u64,
+            value_size,
+            value_pos,
+            key,
+        }
+    }
+
+    /// 构造键的删除标记
+    pub fn tombstone(key: String) -> HintCommand {
+        Self::new(key, 0, 0)
+    }
+
+    /// hint命令在文件中占用的字节数量
+    pub fn size(&self) -> u64 {
+        HINT_HEADER_SIZE + self.key_size
+    }
+
+    /// 判断是否为删除标记，记录的长度不可能为0
+    pub fn is_tombstone(&self) -> bool {
+        self.value_size == 0
+    }
+
+    /// 将HintCommand写入到hint文件中
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.key_size.to_le_bytes())?;
+        writer.write_all(&self.value_size.to_le_bytes())?;
+        writer.write_all(&self.value_pos.to_le_bytes())?;
+        writer.write_all(self.key.as_bytes())?;
+        Ok(())
+    }
+
+    /// 从hint文件中读取一条HintCommand，读到文件末尾时返回None
+    ///
+    /// `remaining`是文件中从这条命令开头起剩余的字节数量，键的长度超出它时返回`KvsError::ChecksumMismatch`
+    pub fn read_from<R: Read>(reader: &mut R, remaining: u64) -> Result<Option<HintCommand>> {
+        let mut buf = [0u8; 8];
+        // 读取第一个字段时遇到EOF说明hint文件已经读完
+        match reader.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let key_size = u64::from_le_bytes(buf);
+        reader.read_exact(&mut buf)?;
+        let value_size = u64::from_le_bytes(buf);
+        reader.read_exact(&mut buf)?;
+        let value_pos = u64::from_le_bytes(buf);
+        if key_size.checked_add(HINT_HEADER_SIZE).is_none_or(|size| size > remaining) {
+            return Err(KvsError::ChecksumMismatch);
+        }
+        let mut key = vec![0u8; key_size as usize];
+        reader.read_exact(&mut key)?;
+        let key = String::from_utf8(key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some(HintCommand {
+            key_size,
+            value_size,
+            value_pos,
+            key,
+        }))
+    }
+
+    /// 将HintCommand转换为键与其在keydir中的位置
+    pub fn into_pos(self, file_id: u64) -> (String, CommandPos) {
+        (
+            self.key,
+            CommandPos {
+                file_id,
+                value_size: self.value_size,
+                value_pos: self.value_pos,
+            },
+        )
+    }
 }
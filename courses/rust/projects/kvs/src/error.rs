@@ -12,6 +12,10 @@ pub enum KvsError {
     UnexpectedCommandType,
     /// Key not found
     KeyNotFound,
+    /// The checksum of a log record doesn't match its content
+    ChecksumMismatch,
+    /// The log file is written in an unknown format version
+    UnsupportedVersion(u32),
 }
 
 impl std::fmt::Display for KvsError {
@@ -21,6 +25,8 @@ impl std::fmt::Display for KvsError {
             KvsError::SerdeJsonError(e) => write!(f, "SerdeJson error: {}", e),
             KvsError::UnexpectedCommandType => write!(f, "Unexpected command type"),
             KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::ChecksumMismatch => write!(f, "Checksum mismatch"),
+            KvsError::UnsupportedVersion(v) => write!(f, "Unsupported log version: {}", v),
         }
     }
 }
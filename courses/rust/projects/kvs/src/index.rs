@@ -0,0 +1,449 @@
+// 磁盘上的键索引
+
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Take, Write};
+use std::path::{Path, PathBuf};
+
+use crate::command::{CommandPos, HintCommand};
+use crate::error::Result;
+
+/// 索引文件尾部的魔数
+const INDEX_MAGIC: [u8; 4] = *b"KVSI";
+// 索引文件尾部的长度：log_size | entry_count | dead_size | sparse_pos | bloom_pos | magic
+const FOOTER_SIZE: u64 = 44;
+// 稀疏索引每隔多少个键记录一个键的位置
+const SPARSE_INTERVAL: u64 = 16;
+// 布隆过滤器中每个键占用的位数与哈希函数的个数，误判率约为1%
+const BLOOM_BITS_PER_KEY: u64 = 10;
+const BLOOM_HASHES: u32 = 7;
+
+/// 数据文件的键索引的存放方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexMode {
+    /// 所有键都保存在内存中的keydir里
+    #[default]
+    Memory,
+    /// 只有活跃文件的键保存在内存中，已封存的数据文件各自带有一个按键排序的索引文件
+    Disk {
+        /// 活跃文件超过这个大小时被封存，之后的写入使用一个新的活跃文件
+        max_file_size: u64,
+    },
+}
+
+pub fn index_path(data_dir: &Path, file_id: u64) -> PathBuf {
+    data_dir.join(format!("{}.index", file_id))
+}
+
+/// 键在一个数据文件中的最终状态
+#[derive(Debug, Clone, Copy)]
+pub enum KeyState {
+    /// 键的值所在的位置
+    Live(CommandPos),
+    /// 键在该数据文件中被删除
+    Removed,
+}
+
+impl KeyState {
+    fn from_hint(hint: HintCommand, file_id: u64) -> (String, KeyState) {
+        let is_tombstone = hint.is_tombstone();
+        let (key, cmd_pos) = hint.into_pos(file_id);
+        if is_tombstone {
+            (key, KeyState::Removed)
+        } else {
+            (key, KeyState::Live(cmd_pos))
+        }
+    }
+}
+
+/// 布隆过滤器，使用一个64位哈希值的高低两半组合出多个哈希函数
+struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    fn new(entries: u64) -> BloomFilter {
+        let bytes = (entries * BLOOM_BITS_PER_KEY).div_ceil(8).max(1);
+        BloomFilter {
+            bits: vec![0; bytes as usize],
+            hashes: BLOOM_HASHES,
+        }
+    }
+
+    // 键在位数组中对应的位置
+    fn positions(&self, key: &str) -> impl Iterator<Item = u64> {
+        let hash = hash64(key.as_bytes());
+        let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+        let nbits = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % nbits)
+    }
+
+    fn insert(&mut self, key: &str) {
+        for bit in self.positions(key).collect::<Vec<_>>() {
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    // 返回false时键一定不存在
+    fn may_contain(&self, key: &str) -> bool {
+        self.positions(key)
+            .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+}
+
+/// 使用FNV-1a和MurmurHash3的64位收尾函数计算键的哈希值，使键的每个字节都影响哈希值的两半
+///
+/// 哈希值随布隆过滤器写入索引文件，因此不能改变
+fn hash64(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// 索引文件的写入器，键必须按升序写入
+///
+/// 索引文件的布局为：按键排序的HintCommand | 稀疏索引 | 布隆过滤器 | 尾部。
+/// 稀疏索引每条的布局为：pos(u64) | key_size(u64) | key，布隆过滤器的布局为：hashes(u32) | bits。
+/// 尾部记录数据文件的大小，打开索引文件时大小不符说明数据文件在索引写入后又被追加过，需要重建索引。
+/// 索引先写入临时文件，写完后再重命名
+pub struct IndexWriter {
+    writer: BufWriter<File>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+    file_id: u64,
+    pos: u64,
+    entry_count: u64,
+    sparse: Vec<(String, u64)>,
+    bloom: BloomFilter,
+}
+
+impl IndexWriter {
+    // expected_entries只用于决定布隆过滤器的大小，可以大于实际写入的键的数量
+    pub fn create(data_dir: &Path, file_id: u64, expected_entries: u64) -> Result<IndexWriter> {
+        let path = index_path(data_dir, file_id);
+        let tmp_path = data_dir.join(format!("{}.index.tmp", file_id));
+        Ok(IndexWriter {
+            writer: BufWriter::new(File::create(&tmp_path)?),
+            tmp_path,
+            path,
+            file_id,
+            pos: 0,
+            entry_count: 0,
+            sparse: Vec::new(),
+            bloom: BloomFilter::new(expected_entries),
+        })
+    }
+
+    pub fn add(&mut self, key: String, state: KeyState) -> Result<()> {
+        if self.entry_count.is_multiple_of(SPARSE_INTERVAL) {
+            self.sparse.push((key.clone(), self.pos));
+        }
+        self.bloom.insert(&key);
+        self.pos += 24 + key.len() as u64;
+        self.entry_count += 1;
+        match state {
+            KeyState::Live(cmd_pos) => HintCommand::new(key, cmd_pos.value_size, cmd_pos.value_pos),
+            KeyState::Removed => HintCommand::tombstone(key),
+        }
+        .write_to(&mut self.writer)
+    }
+
+    pub fn finish(mut self, log_size: u64, dead_size: u64) -> Result<KeyIndex> {
+        let sparse_pos = self.pos;
+        for (key, pos) in &self.sparse {
+            self.writer.write_all(&pos.to_le_bytes())?;
+            self.writer.write_all(&(key.len() as u64).to_le_bytes())?;
+            self.writer.write_all(key.as_bytes())?;
+            self.pos += 16 + key.len() as u64;
+        }
+        let bloom_pos = self.pos;
+        self.writer.write_all(&self.bloom.hashes.to_le_bytes())?;
+        self.writer.write_all(&self.bloom.bits)?;
+        for field in [log_size, self.entry_count, dead_size, sparse_pos, bloom_pos] {
+            self.writer.write_all(&field.to_le_bytes())?;
+        }
+        self.writer.write_all(&INDEX_MAGIC)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        drop(self.writer);
+        fs::rename(&self.tmp_path, &self.path)?;
+        Ok(KeyIndex {
+            reader: BufReader::new(File::open(&self.path)?),
+            path: self.path,
+            file_id: self.file_id,
+            sparse: self.sparse,
+            bloom: self.bloom,
+            entries_end: sparse_pos,
+            entry_count: self.entry_count,
+            dead_size,
+        })
+    }
+}
+
+/// 一个已封存数据文件的键索引
+///
+/// 内存中只保留稀疏索引与布隆过滤器，查找一个键最多读取索引文件中的`SPARSE_INTERVAL`条记录
+pub struct KeyIndex {
+    reader: BufReader<File>,
+    path: PathBuf,
+    file_id: u64,
+    sparse: Vec<(String, u64)>,
+    bloom: BloomFilter,
+    entries_end: u64,
+    /// 索引中键的数量
+    pub entry_count: u64,
+    /// 数据文件中被文件内后续命令覆盖的无用数据的大小
+    pub dead_size: u64,
+}
+
+impl KeyIndex {
+    /// 将键的最终状态写入数据文件的索引文件，entries不需要有序
+    pub fn build(
+        data_dir: &Path,
+        file_id: u64,
+        mut entries: Vec<(String, KeyState)>,
+        log_size: u64,
+        dead_size: u64,
+    ) -> Result<KeyIndex> {
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut writer = IndexWriter::create(data_dir, file_id, entries.len() as u64)?;
+        for (key, state) in entries {
+            writer.add(key, state)?;
+        }
+        writer.finish(log_size, dead_size)
+    }
+
+    /// 打开数据文件的索引文件
+    ///
+    /// 索引文件不存在、不完整或者与数据文件的大小不符时返回None
+    pub fn open(data_dir: &Path, file_id: u64, log_size: u64) -> Result<Option<KeyIndex>> {
+        let path = index_path(data_dir, file_id);
+        let mut reader = match File::open(&path) {
+            Ok(file) => BufReader::new(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let len = reader.seek(SeekFrom::End(0))?;
+        if len < FOOTER_SIZE {
+            return Ok(None);
+        }
+        reader.seek(SeekFrom::Start(len - FOOTER_SIZE))?;
+        let mut footer = [0u8; FOOTER_SIZE as usize];
+        reader.read_exact(&mut footer)?;
+        let field = |i: usize| u64::from_le_bytes(footer[i * 8..i * 8 + 8].try_into().unwrap());
+        let (sparse_pos, bloom_pos) = (field(3), field(4));
+        if footer[40..] != INDEX_MAGIC || field(0) != log_size || bloom_pos > len - FOOTER_SIZE {
+            return Ok(None);
+        }
+
+        reader.seek(SeekFrom::Start(sparse_pos))?;
+        let mut sparse = Vec::new();
+        let mut buf = [0u8; 8];
+        let mut section = (&mut reader).take(bloom_pos - sparse_pos);
+        while section.limit() > 0 {
+            section.read_exact(&mut buf)?;
+            let pos = u64::from_le_bytes(buf);
+            section.read_exact(&mut buf)?;
+            let mut key = vec![0u8; u64::from_le_bytes(buf) as usize];
+            section.read_exact(&mut key)?;
+            let key = String::from_utf8(key)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            sparse.push((key, pos));
+        }
+        let mut hashes = [0u8; 4];
+        reader.read_exact(&mut hashes)?;
+        let mut bits = vec![0u8; (len - FOOTER_SIZE - bloom_pos - 4) as usize];
+        reader.read_exact(&mut bits)?;
+
+        Ok(Some(KeyIndex {
+            reader,
+            path,
+            file_id,
+            sparse,
+            bloom: BloomFilter {
+                bits,
+                hashes: u32::from_le_bytes(hashes),
+            },
+            entries_end: sparse_pos,
+            entry_count: field(1),
+            dead_size: field(2),
+        }))
+    }
+
+    /// 查找键在数据文件中的最终状态，数据文件中没有这个键时返回None
+    pub fn get(&mut self, key: &str) -> Result<Option<KeyState>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        // 找到最后一个不大于key的稀疏索引，键只可能在它之后的SPARSE_INTERVAL条记录中
+        let block = self
+            .sparse
+            .partition_point(|(sparse_key, _)| sparse_key.as_str() <= key);
+        if block == 0 {
+            return Ok(None);
+        }
+        let start = self.sparse[block - 1].1;
+        self.reader.seek(SeekFrom::Start(start))?;
+        let mut block_reader = (&mut self.reader).take(self.entries_end - start);
+        for _ in 0..SPARSE_INTERVAL {
+            let remaining = block_reader.limit();
+            let hint = match HintCommand::read_from(&mut block_reader, remaining)? {
+                Some(hint) => hint,
+                None => break,
+            };
+            let (entry_key, state) = KeyState::from_hint(hint, self.file_id);
+            match entry_key.as_str().cmp(key) {
+                std::cmp::Ordering::Less => continue,
+                std::cmp::Ordering::Equal => return Ok(Some(state)),
+                std::cmp::Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// 按键的升序遍历索引中的所有键
+    pub fn entries(&self) -> Result<IndexEntries> {
+        let reader = BufReader::new(File::open(&self.path)?).take(self.entries_end);
+        Ok(IndexEntries {
+            reader,
+            file_id: self.file_id,
+        })
+    }
+}
+
+/// 按键的升序遍历一个索引文件
+pub struct IndexEntries {
+    reader: Take<BufReader<File>>,
+    file_id: u64,
+}
+
+impl Iterator for IndexEntries {
+    type Item = Result<(String, KeyState)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let remaining = self.reader.limit();
+        HintCommand::read_from(&mut self.reader, remaining)
+            .map(|hint| hint.map(|hint| KeyState::from_hint(hint, self.file_id)))
+            .transpose()
+    }
+}
+
+/// 按键的升序合并多个索引文件，每个键返回它在各个数据文件中的状态，按文件id升序排列
+pub struct MergedEntries {
+    sources: Vec<MergeSource>,
+}
+
+// 参与合并的一个索引文件
+struct MergeSource {
+    file_id: u64,
+    // 当前最小的键，遍历完时为None
+    head: Option<(String, KeyState)>,
+    entries: IndexEntries,
+}
+
+impl Iterator for MergedEntries {
+    type Item = Result<(String, Vec<(u64, KeyState)>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self
+            .sources
+            .iter()
+            .filter_map(|source| source.head.as_ref().map(|(key, _)| key))
+            .min()?
+            .clone();
+        let mut versions = Vec::new();
+        for source in &mut self.sources {
+            if source
+                .head
+                .as_ref()
+                .is_some_and(|(head_key, _)| *head_key == key)
+            {
+                let (_, state) = source.head.take().unwrap();
+                versions.push((source.file_id, state));
+                source.head = match source.entries.next().transpose() {
+                    Ok(head) => head,
+                    Err(e) => return Some(Err(e)),
+                };
+            }
+        }
+        Some(Ok((key, versions)))
+    }
+}
+
+/// 磁盘索引模式下已封存数据文件的键索引
+pub struct DiskIndex {
+    /// 每个已封存数据文件的索引
+    pub indexes: BTreeMap<u64, KeyIndex>,
+    /// 活跃文件中被删除的键，它们的旧值可能还在已封存的数据文件中
+    pub removed: HashSet<String>,
+    /// 活跃文件封存的大小
+    pub max_file_size: u64,
+}
+
+impl DiskIndex {
+    pub fn new(max_file_size: u64) -> DiskIndex {
+        DiskIndex {
+            indexes: BTreeMap::new(),
+            removed: HashSet::new(),
+            max_file_size,
+        }
+    }
+
+    /// 从新到旧查找键在已封存数据文件中的位置，键在活跃文件中被删除时返回None
+    pub fn locate(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        if self.removed.contains(key) {
+            return Ok(None);
+        }
+        for index in self.indexes.values_mut().rev() {
+            match index.get(key)? {
+                Some(KeyState::Live(cmd_pos)) => return Ok(Some(cmd_pos)),
+                Some(KeyState::Removed) => return Ok(None),
+                None => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// 按键的升序合并所有已封存数据文件的索引
+    pub fn merged_entries(&self) -> Result<MergedEntries> {
+        let mut sources = Vec::new();
+        for index in self.indexes.values() {
+            let mut entries = index.entries()?;
+            sources.push(MergeSource {
+                file_id: index.file_id,
+                head: entries.next().transpose()?,
+                entries,
+            });
+        }
+        Ok(MergedEntries { sources })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomFilter;
+
+    // 误判率应接近过滤器设计的1%，相似的键也是如此
+    #[test]
+    fn bloom_false_positive_rate() {
+        let mut filter = BloomFilter::new(10_000);
+        for i in 0..10_000 {
+            filter.insert(&format!("key{}", i));
+        }
+        assert!((0..10_000).all(|i| filter.may_contain(&format!("key{}", i))));
+        let false_positives = (10_000..110_000)
+            .filter(|i| filter.may_contain(&format!("key{}", i)))
+            .count();
+        let rate = false_positives as f64 / 100_000.0;
+        assert!(rate < 0.015, "false positive rate {}", rate);
+    }
+}
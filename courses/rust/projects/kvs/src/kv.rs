@@ -1,19 +1,19 @@
 // kvstore
 
 use serde_json::{Deserializer as JsonDeserializer,self};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::fs::{File,OpenOptions};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::command::CommandPos;
+use crate::command::{CommandPos, HintCommand, LogFormat, LOG_HEADER_SIZE};
 use crate::error::Result;
+use crate::index::{index_path, DiskIndex, IndexMode, IndexWriter, KeyIndex, KeyState};
+use crate::policy::{CompactionPolicy, FileUsage, ThresholdPolicy};
 use crate::DataCommand;
 use crate::KvsError;
 
-const MAX_USELESS_SIZE: u64 = 0x10_000; //最大无用大小
-
 /// 带有当前写入位置终点的BufWriter
 pub struct BufWriterWithPos<W: Write + Seek> {
     writer: BufWriter<W>,
@@ -23,9 +23,16 @@ pub struct BufWriterWithPos<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     pub fn new(mut inner: W,file_id:u64) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::End(0))?; //获取当前文件的尾部位置,因为总是从尾部位置开始Append
+        let mut pos = inner.seek(SeekFrom::End(0))?; //获取当前文件的尾部位置,因为总是从尾部位置开始Append
+        let mut writer = BufWriter::new(inner);
+        if pos == 0 {
+            // 新的数据文件需要先写入文件头部
+            LogFormat::write_header(&mut writer)?;
+            writer.flush()?;
+            pos = LOG_HEADER_SIZE;
+        }
         Ok(Self {
-            writer: BufWriter::new(inner),
+            writer,
             file_id,
             pos,
         })
@@ -33,7 +40,7 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
 
     // 像日志中写入一条命令，同时返回写入的位置与写入的命令长度
     pub fn write_command(&mut self, command: DataCommand) -> Result<(u64, u64)> {
-        let result = serde_json::to_vec(&command)?;
+        let result = command.encode();
         let prev_pos = self.pos;
         self.writer.write_all(result.as_slice())?;
         let length = self
@@ -48,11 +55,111 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
     }
 }
 
+/// 数据文件的读取器，同时记录了数据文件的格式
+struct LogReader {
+    reader: BufReader<File>,
+    format: LogFormat,
+}
+
+impl LogReader {
+    fn open(path: &Path) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let format = LogFormat::detect(&mut reader)?;
+        Ok(Self { reader, format })
+    }
+
+    // 读取指定位置的命令，json与二进制格式的文件都可以读取
+    fn read_command(&mut self, cmd_pos: &CommandPos) -> Result<DataCommand> {
+        self.reader.seek(SeekFrom::Start(cmd_pos.value_pos))?;
+        // reader.take返回一个只能读取指定字节数量的读取器，这里返回的依然是一个可变引用
+        let mut cmd_reader = (&mut self.reader).take(cmd_pos.value_size);
+        match self.format {
+            LogFormat::Json => Ok(serde_json::from_reader(cmd_reader)?),
+            LogFormat::Binary => match DataCommand::decode(&mut cmd_reader, cmd_pos.value_size)? {
+                Some((cmd, _)) => Ok(cmd),
+                None => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            },
+        }
+    }
+
+    // 从头读取数据文件中的所有命令，同时传入每条命令的位置与长度
+    fn replay(&mut self, mut apply: impl FnMut(DataCommand, u64, u64)) -> Result<()> {
+        LogFormat::detect(&mut self.reader)?; // 回到第一条命令的起始位置
+        match self.format {
+            LogFormat::Json => {
+                let mut cur_pos = 0;
+                // 使用serdejson的反序列化器将数据转换为json序列流
+                let mut stream = JsonDeserializer::from_reader(&mut self.reader).into_iter::<DataCommand>();
+                // 使用while let而不是for循环，因为for循环无法获取长度与位置
+                while let Some(cmd) = stream.next() {
+                    let next_pos = stream.byte_offset() as u64; // 获取读取一条命令后的位置
+                    apply(cmd?, cur_pos, next_pos - cur_pos);
+                    cur_pos = next_pos;
+                }
+            }
+            LogFormat::Binary => {
+                let mut cur_pos = LOG_HEADER_SIZE;
+                let log_size = self.reader.get_ref().metadata()?.len();
+                while let Some((cmd, cmd_len)) =
+                    DataCommand::decode(&mut self.reader, log_size.saturating_sub(cur_pos))?
+                {
+                    apply(cmd, cur_pos, cmd_len);
+                    cur_pos += cmd_len;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 读取数据文件中所有Rm命令的键
+    fn removed_keys(&mut self) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        self.replay(|cmd, _, _| {
+            if let DataCommand::Rm { key } = cmd {
+                keys.push(key);
+            }
+        })?;
+        Ok(keys)
+    }
+
+    // 读取数据文件中每个键的最终状态，为数据文件建立索引文件
+    fn build_index(&mut self, data_dir: &Path, file_id: u64, log_size: u64) -> Result<KeyIndex> {
+        let mut states = HashMap::new();
+        let mut dead_size = 0; // 被文件内后续命令覆盖的数据与Rm命令本身都是无用数据
+        self.replay(|cmd, cur_pos, cmd_len| {
+            let (key, state) = match cmd {
+                DataCommand::Set { key, .. } => {
+                    let cmd_pos = CommandPos{file_id,value_size:cmd_len,value_pos:cur_pos};
+                    (key, KeyState::Live(cmd_pos))
+                }
+                DataCommand::Rm { key } => {
+                    dead_size += cmd_len;
+                    (key, KeyState::Removed)
+                }
+            };
+            if let Some(KeyState::Live(old_cmd)) = states.insert(key, state) {
+                dead_size += old_cmd.value_size;
+            }
+        })?;
+        KeyIndex::build(data_dir, file_id, states.into_iter().collect(), log_size, dead_size)
+    }
+}
+
 /// The `KvStore` stores string key/value pairs.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
-/// A `BTreeMap` in memory stores the keys and the value locations for fast query.
+/// A `HashMap` in memory stores the keys and the value locations for fast query.
+///
+/// With `IndexMode::Disk`, only the keys of the active log file stay in memory. A log
+/// file is sealed once it grows over the size limit, and every sealed log file gets an
+/// `index` file holding its keys in sorted order. Only a sparse index and a bloom filter
+/// of each `index` file are kept in memory, and lookups check the sealed log files from
+/// the newest to the oldest.
+///
+/// Each log file starts with a magic number and a format version, and every record
+/// carries a crc32 checksum. Log files written before the binary format was introduced
+/// are still readable and get rewritten into the binary format by the next compaction.
 ///
 /// ```rust
 /// # use kvs::{KvStore, Result};
@@ -66,25 +173,45 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
 /// # }
 /// ```
 pub struct KvStore {
-    key_dir: HashMap<String, CommandPos>,     // 内存中的哈希表
-    readers: HashMap<u64, BufReader<File>>, // 缓存所有已经关闭的文件，适用于频繁小数据读
+    key_dir: HashMap<String, CommandPos>,     // 内存中的哈希表，磁盘索引模式下只有活跃文件的键
+    readers: HashMap<u64, LogReader>,         // 缓存所有已经关闭的文件，适用于频繁小数据读
     writer: BufWriterWithPos<File>,           // 适用于频繁小数据写
     data_dir: PathBuf,                        // 数据目录
-    useless_size: u64,                        // 当前无用的数据总和，超过一定值则进行文件Merge
+    usage: BTreeMap<u64, FileUsage>,          // 每个数据文件的大小与其中无用的数据量，由合并策略决定是否Merge
+    policy: Box<dyn CompactionPolicy>,        // 合并策略
+    disk_index: Option<DiskIndex>,            // 磁盘索引模式下已封存数据文件的键索引
+}
+
+fn log_path(data_dir: &Path, file_id: u64) -> PathBuf {
+    data_dir.join(format!("{}.log", file_id))
+}
+
+fn hint_path(data_dir: &Path, file_id: u64) -> PathBuf {
+    data_dir.join(format!("{}.hint", file_id))
+}
+
+// 返回数据文件的使用情况，不存在时插入一个空的
+fn usage_of(usage: &mut BTreeMap<u64, FileUsage>, file_id: u64) -> &mut FileUsage {
+    usage.entry(file_id).or_insert(FileUsage {
+        file_id,
+        size: 0,
+        useless_size: 0,
+    })
 }
 
 impl KvStore {
+    // 新建一个数据文件作为活跃文件，文件id总是比现有的所有文件id更大
     fn get_writer(
-        mut path_buf: PathBuf,
+        data_dir: &Path,
         log_file_list: &mut Vec<u64>,
     ) -> Result<BufWriterWithPos<File>> {
-        log_file_list.push(log_file_list.len() as u64);
-        path_buf.push(format!("{}.log", log_file_list[log_file_list.len() - 1]));
+        let file_id = log_file_list.last().map_or(0, |id| id + 1);
+        log_file_list.push(file_id);
         let file = OpenOptions::new()
             .append(true)
             .create(true)
-            .open(path_buf)?;
-        return Ok(BufWriterWithPos::new(file,log_file_list[log_file_list.len() - 1])?);
+            .open(log_path(data_dir, file_id))?;
+        BufWriterWithPos::new(file, file_id)
     }
 
     /// 返回数据目录中的数据文件，并返回排序的数据文件名，此处的文件名默认为数字
@@ -108,81 +235,350 @@ impl KvStore {
         return vec![];
     }
 
-    // 读取日志文件，并修改kvstore状态，同时返回其中无用字节数量
+    // 读取日志文件，并修改kvstore状态，同时记录各个数据文件中无用字节数量
     fn read_log_files(
         key_dir: &mut HashMap<String, CommandPos>,
-        readers: &mut HashMap<u64, BufReader<File>>,
-        mut path_buf: PathBuf,
+        readers: &mut HashMap<u64, LogReader>,
+        usage: &mut BTreeMap<u64, FileUsage>,
+        data_dir: &Path,
         file_id: u64,
-    ) -> Result<u64> {
-        path_buf.push(format!("{}.log", file_id));
-        let file = OpenOptions::new().read(true).open(path_buf)?;
-        let mut buf_reader = BufReader::new(file);
-        let mut cur_pos = buf_reader.seek(SeekFrom::Start(0))?;
-        // 使用serdejson的反序列化器将数据转换为json序列流
-        let mut stream =  JsonDeserializer::from_reader(&mut buf_reader).into_iter::<DataCommand>();
-        let mut useless_size: u64 = 0;
-        // 使用while let而不是for循环，因为for循环无法获取长度与位置
-        while let Some(cmd) = stream.next(){
-            let next_pos = stream.byte_offset() as u64; // 获取读取一条命令后的位置
-            let cmd_len = next_pos - cur_pos;
-            match cmd? {
-                DataCommand::Set { key, value:_ } => {
-                    key_dir.entry(key)
-                        .and_modify(|x| useless_size += x.change(file_id, cmd_len,cur_pos))
-                        .or_insert(CommandPos{file_id:file_id,value_size:cmd_len,value_pos:cur_pos});
+    ) -> Result<()> {
+        let mut log_reader = LogReader::open(&log_path(data_dir, file_id))?;
+        usage_of(usage, file_id).size = fs::metadata(log_path(data_dir, file_id))?.len();
+        log_reader.replay(|cmd, cur_pos, cmd_len| match cmd {
+            DataCommand::Set { key, value: _ } => {
+                let cmd_pos = CommandPos{file_id,value_size:cmd_len,value_pos:cur_pos};
+                if let Some(old_cmd) = key_dir.insert(key, cmd_pos) {
+                    // 旧命令所在的数据文件增长无用字节数量
+                    usage_of(usage, old_cmd.file_id).useless_size += old_cmd.value_size;
                 }
-                DataCommand::Rm { key } => {
-                    if let Some(old_cmd) = key_dir.remove(&key) {
-                        // 如果移除成功，那么说明之前的命令的值没有意义，增长无用字节数量
-                        useless_size += old_cmd.value_size
-                    }
+            }
+            DataCommand::Rm { key } => {
+                if let Some(old_cmd) = key_dir.remove(&key) {
+                    // 如果移除成功，那么说明之前的命令的值没有意义，增长无用字节数量
+                    usage_of(usage, old_cmd.file_id).useless_size += old_cmd.value_size;
+                }
+                // Rm命令本身在合并时也可以被清除
+                usage_of(usage, file_id).useless_size += cmd_len;
+            }
+        })?;
+        readers.insert(file_id, log_reader); //将log_reader插入到readers中
+        Ok(())
+    }
+
+    // 读取合并文件对应的hint文件重建keydir，不需要读取数据文件中的值，同时记录各个数据文件中无用字节数量
+    fn read_hint_file(
+        key_dir: &mut HashMap<String, CommandPos>,
+        readers: &mut HashMap<u64, LogReader>,
+        usage: &mut BTreeMap<u64, FileUsage>,
+        data_dir: &Path,
+        file_id: u64,
+    ) -> Result<()> {
+        let mut hint_reader = BufReader::new(File::open(hint_path(data_dir, file_id))?);
+        let mut remaining = hint_reader.get_ref().metadata()?.len();
+        usage_of(usage, file_id).size = fs::metadata(log_path(data_dir, file_id))?.len();
+        while let Some(hint) = HintCommand::read_from(&mut hint_reader, remaining)? {
+            remaining -= hint.size();
+            let is_tombstone = hint.is_tombstone();
+            let (key, cmd_pos) = hint.into_pos(file_id);
+            // 删除标记对应的Rm命令需要保留，所以不计入无用字节数量
+            let old_cmd = if is_tombstone {
+                key_dir.remove(&key)
+            } else {
+                key_dir.insert(key, cmd_pos)
+            };
+            if let Some(old_cmd) = old_cmd {
+                usage_of(usage, old_cmd.file_id).useless_size += old_cmd.value_size;
+            }
+        }
+        // 数据文件依然需要打开，get时从中读取值
+        readers.insert(file_id, LogReader::open(&log_path(data_dir, file_id))?);
+        Ok(())
+    }
+
+    // 打开所有已封存数据文件的索引文件，索引文件不可用时从数据文件重建，
+    // 然后按键合并所有索引，统计被更新的数据文件覆盖的无用字节数量
+    fn read_index_files(
+        disk_index: &mut DiskIndex,
+        readers: &mut HashMap<u64, LogReader>,
+        usage: &mut BTreeMap<u64, FileUsage>,
+        data_dir: &Path,
+        file_ids: &[u64],
+    ) -> Result<()> {
+        for &file_id in file_ids {
+            let mut log_reader = LogReader::open(&log_path(data_dir, file_id))?;
+            let log_size = fs::metadata(log_path(data_dir, file_id))?.len();
+            let index = match KeyIndex::open(data_dir, file_id, log_size)? {
+                Some(index) => index,
+                None => log_reader.build_index(data_dir, file_id, log_size)?,
+            };
+            let file_usage = usage_of(usage, file_id);
+            file_usage.size = log_size;
+            file_usage.useless_size = index.dead_size;
+            disk_index.indexes.insert(file_id, index);
+            readers.insert(file_id, log_reader);
+        }
+        for entry in disk_index.merged_entries()? {
+            let (_, versions) = entry?;
+            // 只有最新的版本有效
+            for (_, state) in &versions[..versions.len() - 1] {
+                if let KeyState::Live(old_cmd) = state {
+                    usage_of(usage, old_cmd.file_id).useless_size += old_cmd.value_size;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 磁盘索引模式下，活跃文件超过大小上限时将其封存，之后的写入使用一个新的活跃文件
+    fn maybe_seal(&mut self) -> Result<()> {
+        match &self.disk_index {
+            Some(disk_index) if self.writer.pos >= disk_index.max_file_size => {}
+            _ => return Ok(()),
+        }
+        self.seal_active()?;
+        let mut log_file_list = Self::sorted_log_list(&self.data_dir);
+        self.writer = Self::get_writer(&self.data_dir, &mut log_file_list)?;
+        let file_id = self.writer.file_id;
+        self.readers.insert(file_id, LogReader::open(&log_path(&self.data_dir, file_id))?);
+        usage_of(&mut self.usage, file_id).size = self.writer.pos;
+        Ok(())
+    }
+
+    // 为活跃文件写入索引文件，并将其中的键移出内存，内存索引模式下什么都不做
+    fn seal_active(&mut self) -> Result<()> {
+        let disk_index = match &mut self.disk_index {
+            Some(disk_index) => disk_index,
+            None => return Ok(()),
+        };
+        let file_id = self.writer.file_id;
+        let entries = self
+            .key_dir
+            .drain()
+            .map(|(key, cmd_pos)| (key, KeyState::Live(cmd_pos)))
+            .chain(disk_index.removed.drain().map(|key| (key, KeyState::Removed)))
+            .collect();
+        // 活跃文件是最新的数据文件，其中的无用数据都是被文件内的命令覆盖的
+        let dead_size = usage_of(&mut self.usage, file_id).useless_size;
+        let index = KeyIndex::build(&self.data_dir, file_id, entries, self.writer.pos, dead_size)?;
+        disk_index.indexes.insert(file_id, index);
+        Ok(())
+    }
+
+    // 返回键的值所在的位置，键不存在时返回None
+    fn locate(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        if let Some(cmd_pos) = self.key_dir.get(key) {
+            return Ok(Some(*cmd_pos));
+        }
+        match &mut self.disk_index {
+            Some(disk_index) => disk_index.locate(key),
+            None => Ok(None),
+        }
+    }
+
+    // 如果合并策略选中了数据文件，则合并这些数据文件
+    fn maybe_compact(&mut self) -> Result<()> {
+        let usage: Vec<FileUsage> = self.usage.values().copied().collect();
+        let file_ids = self.policy.pick(&usage);
+        if file_ids.is_empty() {
+            return Ok(());
+        }
+        self.compact_files(file_ids)
+    }
+
+    /// Merges the live data of all log files into a new log file.
+    ///
+    /// The compaction policy is ignored. Later writes go to another new log file.
+    pub fn compact(&mut self) -> Result<()> {
+        let file_ids = self.readers.keys().copied().collect();
+        self.compact_files(file_ids)
+    }
+
+    // 将选中的数据文件中的有效数据合并到一个新的数据文件中，并为其生成hint文件，之后的写入使用另一个新的活跃文件
+    fn compact_files(&mut self, file_ids: Vec<u64>) -> Result<()> {
+        let picked: BTreeSet<u64> = file_ids
+            .into_iter()
+            .filter(|file_id| self.readers.contains_key(file_id))
+            .collect();
+        if picked.is_empty() {
+            return Ok(());
+        }
+        // 合并后会使用新的活跃文件，所以磁盘索引模式下先封存当前的活跃文件
+        self.seal_active()?;
+        let mut log_file_list = Self::sorted_log_list(&self.data_dir);
+        let mut merged_writer = Self::get_writer(&self.data_dir, &mut log_file_list)?;
+        let merged_file_id = merged_writer.file_id;
+        if self.disk_index.is_some() {
+            self.merge_sealed(&picked, &mut merged_writer)?;
+        } else {
+            self.merge_live(&picked, &mut merged_writer)?;
+        }
+        // 合并文件不再写入，新的写入使用新的活跃文件
+        self.writer = Self::get_writer(&self.data_dir, &mut log_file_list)?;
+        // 从旧到新删除选中的文件，合并文件比它们都新，所以中途崩溃时留下的旧数据会被合并文件覆盖
+        for &old_file_id in &picked {
+            for old_path in [hint_path(&self.data_dir, old_file_id), index_path(&self.data_dir, old_file_id)] {
+                if old_path.exists() {
+                    fs::remove_file(old_path)?;
+                }
+            }
+            fs::remove_file(log_path(&self.data_dir, old_file_id))?;
+            self.readers.remove(&old_file_id);
+            self.usage.remove(&old_file_id);
+            if let Some(disk_index) = &mut self.disk_index {
+                disk_index.indexes.remove(&old_file_id);
+            }
+        }
+        // 选中的文件中没有有效数据时，合并文件是空的，直接删除
+        if merged_writer.pos == LOG_HEADER_SIZE {
+            for merged_path in [hint_path(&self.data_dir, merged_file_id), index_path(&self.data_dir, merged_file_id)] {
+                if merged_path.exists() {
+                    fs::remove_file(merged_path)?;
                 }
             }
-            cur_pos = next_pos;
+            fs::remove_file(log_path(&self.data_dir, merged_file_id))?;
+            if let Some(disk_index) = &mut self.disk_index {
+                disk_index.indexes.remove(&merged_file_id);
+            }
+        } else {
+            self.readers.insert(merged_file_id, LogReader::open(&log_path(&self.data_dir, merged_file_id))?);
+            // 合并文件中没有无用数据
+            usage_of(&mut self.usage, merged_file_id).size = merged_writer.pos;
         }
-        readers.insert(file_id, buf_reader); //将buf_reader插入到readers中
-        return Ok(useless_size)
+        // 为新的活跃文件创建reader
+        let file_id = self.writer.file_id;
+        self.readers.insert(file_id, LogReader::open(&log_path(&self.data_dir, file_id))?);
+        usage_of(&mut self.usage, file_id).size = self.writer.pos;
+        Ok(())
     }
 
-    fn compact(&mut self,mut new_writer:BufWriterWithPos<File>) -> Result<()>{
-        // 将现有数据全部写入到新的日志文件中
-        for (key,cmd_pos) in self.key_dir.iter_mut() {
+    // 将keydir中位于选中文件的数据写入合并文件，并为其生成hint文件
+    fn merge_live(
+        &mut self,
+        picked: &BTreeSet<u64>,
+        merged_writer: &mut BufWriterWithPos<File>,
+    ) -> Result<()> {
+        let merged_file_id = merged_writer.file_id;
+        // hint文件先写入临时文件，写完后再重命名，避免启动时读到不完整的hint文件
+        let hint_tmp_path = self.data_dir.join(format!("{}.hint.tmp", merged_file_id));
+        let mut hint_writer = BufWriter::new(File::create(&hint_tmp_path)?);
+        // 没有选中的旧文件中可能还有被删除的键的值，所以选中文件中这些键的Rm命令也要写入，否则重启后这些键会复活
+        let oldest_unpicked = self
+            .readers
+            .keys()
+            .filter(|file_id| !picked.contains(file_id))
+            .min()
+            .copied();
+        if let Some(oldest_unpicked) = oldest_unpicked {
+            let mut copied = HashSet::new();
+            for file_id in picked.range(oldest_unpicked..) {
+                let reader = self.readers.get_mut(file_id).expect("Cannot find log reader");
+                for key in reader.removed_keys()? {
+                    if !self.key_dir.contains_key(&key) && copied.insert(key.clone()) {
+                        merged_writer.write_command(DataCommand::rm(key.clone()))?;
+                        HintCommand::tombstone(key).write_to(&mut hint_writer)?;
+                    }
+                }
+            }
+        }
+        // 将选中文件中的现有数据全部写入到新的日志文件中
+        for (key,cmd_pos) in self.key_dir.iter_mut().filter(|(_, cmd_pos)| picked.contains(&cmd_pos.file_id)) {
             let reader = self.readers.get_mut(&cmd_pos.file_id).expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.value_pos))?;
-            if let DataCommand::Set {  value,.. } = serde_json::from_reader(reader.take(cmd_pos.value_size))?{
-                let (value_pos,value_size) = new_writer.write_command(DataCommand::Set { key: key.to_owned(), value: value })?;
-                cmd_pos.change(new_writer.file_id,value_size,value_pos); //写完后要更新命令位置
+            // 旧的json数据也会在这里被重写为二进制格式
+            if let DataCommand::Set {  value,.. } = reader.read_command(cmd_pos)?{
+                let (value_pos,value_size) = merged_writer.write_command(DataCommand::Set { key: key.to_owned(), value: value })?;
+                cmd_pos.change(merged_file_id,value_size,value_pos); //写完后要更新命令位置
+                HintCommand::new(key.to_owned(), value_size, value_pos).write_to(&mut hint_writer)?;
             }
         }
-        self.writer = new_writer;
-        // 将旧文件删除
-        for (old_file_id,_) in &self.readers {
-            let mut path_buf = self.data_dir.clone();
-            path_buf.push(format!("{}.log", old_file_id));
-            fs::remove_file(path_buf)?;
+        merged_writer.writer.flush()?;
+        hint_writer.flush()?;
+        drop(hint_writer);
+        fs::rename(&hint_tmp_path, hint_path(&self.data_dir, merged_file_id))?;
+        Ok(())
+    }
+
+    // 按键合并所有已封存数据文件的索引，将最新版本位于选中文件的数据写入合并文件，并为其生成索引文件
+    fn merge_sealed(
+        &mut self,
+        picked: &BTreeSet<u64>,
+        merged_writer: &mut BufWriterWithPos<File>,
+    ) -> Result<()> {
+        let disk_index = self.disk_index.as_mut().expect("disk index mode");
+        let expected_entries = picked
+            .iter()
+            .filter_map(|file_id| disk_index.indexes.get(file_id))
+            .map(|index| index.entry_count)
+            .sum();
+        // 合并的结果按键有序，直接写入索引文件
+        let mut index_writer = IndexWriter::create(&self.data_dir, merged_writer.file_id, expected_entries)?;
+        for entry in disk_index.merged_entries()? {
+            let (key, versions) = entry?;
+            let &(file_id, state) = versions.last().expect("no version of key");
+            if !picked.contains(&file_id) {
+                continue;
+            }
+            match state {
+                KeyState::Live(cmd_pos) => {
+                    let reader = self.readers.get_mut(&cmd_pos.file_id).expect("Cannot find log reader");
+                    // 旧的json数据也会在这里被重写为二进制格式
+                    if let DataCommand::Set { value, .. } = reader.read_command(&cmd_pos)? {
+                        let (value_pos, value_size) = merged_writer.write_command(DataCommand::Set { key: key.clone(), value })?;
+                        let cmd_pos = CommandPos { file_id: merged_writer.file_id, value_size, value_pos };
+                        index_writer.add(key, KeyState::Live(cmd_pos))?;
+                    }
+                }
+                // 没有选中的文件中还有这个键的旧数据时，Rm命令需要保留，否则这个键会复活
+                KeyState::Removed => {
+                    if versions.iter().any(|(file_id, _)| !picked.contains(file_id)) {
+                        merged_writer.write_command(DataCommand::rm(key.clone()))?;
+                        index_writer.add(key, KeyState::Removed)?;
+                    }
+                }
+            }
         }
-        // 重新创建readers
-        let mut new_readers = HashMap::new();
-        let mut path_buf = self.data_dir.clone();
-        path_buf.push(format!("{}.log", self.writer.file_id));
-        let file = OpenOptions::new().read(true).open(path_buf)?;
-        let buf_reader = BufReader::new(file);
-        new_readers.insert(self.writer.file_id, buf_reader);
-        self.readers = new_readers;
-        // useless_size数据现在为0
-        self.useless_size = 0;
+        merged_writer.writer.flush()?;
+        // 合并文件中没有无用数据
+        let index = index_writer.finish(merged_writer.pos, 0)?;
+        disk_index.indexes.insert(merged_writer.file_id, index);
         Ok(())
     }
 
     /// Opens a `KvStore` with the given path.
     ///
     /// This will create a new directory if the given one does not exist.
+    /// Merged log files that have a `hint` file are loaded from the hint file,
+    /// so their values are not deserialized.
     ///
     /// # Errors
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path_buf: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_policy(path_buf, ThresholdPolicy::default())
+    }
+
+    /// Opens a `KvStore` with the given path and compaction policy.
+    ///
+    /// See `open` for details.
+    pub fn open_with_policy(
+        path_buf: impl Into<PathBuf>,
+        policy: impl CompactionPolicy + 'static,
+    ) -> Result<KvStore> {
+        Self::open_with_index_mode(path_buf, policy, IndexMode::Memory)
+    }
+
+    /// Opens a `KvStore` with the given path, compaction policy and index mode.
+    ///
+    /// With `IndexMode::Disk`, the `index` file of a sealed log file is rebuilt from
+    /// the log file if it is missing or out of date. The same data directory can be
+    /// opened in either mode.
+    ///
+    /// See `open` for details.
+    pub fn open_with_index_mode(
+        path_buf: impl Into<PathBuf>,
+        policy: impl CompactionPolicy + 'static,
+        index_mode: IndexMode,
+    ) -> Result<KvStore> {
         let data_dir: PathBuf = path_buf.into();
 
         fs::create_dir_all(&data_dir)?;
@@ -192,41 +588,72 @@ impl KvStore {
         
         let mut log_file_list = Self::sorted_log_list(&data_dir);
 
-        let writer = Self::get_writer(data_dir.to_owned(), &mut log_file_list)?;
+        let writer = Self::get_writer(&data_dir, &mut log_file_list)?;
 
-        let mut useless_size = 0; //无用的数据量大小
-        for &file_id in log_file_list.iter() {
-            useless_size += Self::read_log_files(&mut key_dir, &mut readers, data_dir.to_owned(), file_id)?;
-        }
+        let mut usage = BTreeMap::new(); //各个数据文件的使用情况
+        let disk_index = match index_mode {
+            IndexMode::Memory => {
+                for &file_id in log_file_list.iter() {
+                    // 存在hint文件说明这是一个完整的合并文件，直接从hint文件中重建keydir
+                    if hint_path(&data_dir, file_id).exists() {
+                        Self::read_hint_file(&mut key_dir, &mut readers, &mut usage, &data_dir, file_id)?;
+                    } else {
+                        Self::read_log_files(&mut key_dir, &mut readers, &mut usage, &data_dir, file_id)?;
+                    }
+                }
+                None
+            }
+            IndexMode::Disk { max_file_size } => {
+                // 新建的活跃文件之前的数据文件都已经封存
+                let mut disk_index = DiskIndex::new(max_file_size);
+                let (active, sealed) = log_file_list.split_last().expect("no active log file");
+                Self::read_index_files(&mut disk_index, &mut readers, &mut usage, &data_dir, sealed)?;
+                readers.insert(*active, LogReader::open(&log_path(&data_dir, *active))?);
+                usage_of(&mut usage, *active).size = writer.pos;
+                Some(disk_index)
+            }
+        };
         
         Ok(Self {
             key_dir,
             readers,
             writer,
             data_dir,
-            useless_size: useless_size,
+            usage,
+            policy: Box::new(policy),
+            disk_index,
         })
     }
+
+    /// Returns the space usage of every log file in ascending file id order.
+    ///
+    /// The last one is the active log file.
+    pub fn file_usage(&self) -> Vec<FileUsage> {
+        self.usage.values().copied().collect()
+    }
     
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
     pub fn set(&mut self, key: String, value: String) -> Result<()>{
-        if self.useless_size > MAX_USELESS_SIZE {
-            // 首先获取一个新的writer
-            let mut log_file_list = Self::sorted_log_list(&self.data_dir);
-            let new_writer = Self::get_writer(self.data_dir.clone(), &mut log_file_list)?;
-            // 将现有的数据全部写到新文件中,并且清除旧数据
-            self.compact(new_writer)?;
-        }
+        // 由合并策略决定是否将数据写到新文件中,并且清除旧数据
+        self.maybe_compact()?;
+        // 磁盘索引模式下旧值可能在已封存的数据文件中
+        let sealed_cmd = match &mut self.disk_index {
+            Some(disk_index) if !self.key_dir.contains_key(&key) => {
+                let sealed_cmd = disk_index.locate(&key)?;
+                disk_index.removed.remove(&key);
+                sealed_cmd
+            }
+            _ => None,
+        };
         let (value_pos,value_size) = self.writer.write_command(DataCommand::Set { key: key.clone(), value: value })?;
-        self.key_dir.entry(key)
-            .and_modify(|x| {
-                self.useless_size += x.value_size; // 增长无用字节数量
-                x.change(self.writer.file_id,value_size,value_pos);
-            })
-            .or_insert(CommandPos{file_id:self.writer.file_id,value_size:value_size,value_pos:value_pos});
-        Ok(())
+        let file_id = self.writer.file_id;
+        if let Some(old_cmd) = self.key_dir.insert(key, CommandPos{file_id,value_size,value_pos}).or(sealed_cmd) {
+            usage_of(&mut self.usage, old_cmd.file_id).useless_size += old_cmd.value_size; // 增长无用字节数量
+        }
+        usage_of(&mut self.usage, file_id).size = self.writer.pos;
+        self.maybe_seal()
     }
 
     /// Gets the string value of a given string key.
@@ -234,13 +661,11 @@ impl KvStore {
     /// Returns `None` if the given key does not exist.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
         // 需要可变的self，因为我们会修改其readers的seek指针
-        if let Some(cmd_pos) = self.key_dir.get(&key) {
+        if let Some(cmd_pos) = self.locate(&key)? {
             // 因为理论上这个log reader是必须存在的，所以用expect?
             let reader = self.readers.get_mut(&cmd_pos.file_id).expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.value_pos))?;
-            // reader.take返回一个只能读取指定字节数量的读取器，这里返回的依然是一个可变引用
             // 使用..语法要求必须放在末尾，并且不能跟','
-            if let DataCommand::Set {  value,.. } = serde_json::from_reader(reader.take(cmd_pos.value_size))?{
+            if let DataCommand::Set {  value,.. } = reader.read_command(&cmd_pos)?{
                 return Ok(Some(value))
             }else {
                 return Err(KvsError::UnexpectedCommandType);   
@@ -253,11 +678,19 @@ impl KvStore {
 
     /// Remove a given key.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        if let Some(cmd_pos) = self.key_dir.get(&key) { //存在才需要删除，否则不删除
-            let (_,_) = self.writer.write_command(DataCommand::Rm { key: key.clone()})?;
-            self.useless_size += cmd_pos.value_size;
+        if let Some(old_cmd) = self.locate(&key)? { //存在才需要删除，否则不删除
+            let (_,cmd_len) = self.writer.write_command(DataCommand::Rm { key: key.clone()})?;
             self.key_dir.remove(&key);
-            Ok(())
+            // 磁盘索引模式下需要记住被删除的键，以免读到已封存的数据文件中的旧值
+            if let Some(disk_index) = &mut self.disk_index {
+                disk_index.removed.insert(key);
+            }
+            usage_of(&mut self.usage, old_cmd.file_id).useless_size += old_cmd.value_size;
+            // Rm命令本身在合并时也可以被清除
+            let file_id = self.writer.file_id;
+            usage_of(&mut self.usage, file_id).useless_size += cmd_len;
+            usage_of(&mut self.usage, file_id).size = self.writer.pos;
+            self.maybe_seal()
         }else{
             return Err(KvsError::KeyNotFound); 
        }
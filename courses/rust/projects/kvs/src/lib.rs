@@ -2,9 +2,13 @@
 //! A simple key/value store.
 
 pub use command::{CommandPos,DataCommand};
+pub use index::IndexMode;
 pub use kv::KvStore;
+pub use policy::{CompactionPolicy, CompactionWindow, FileUsage, ThresholdPolicy};
 pub use error::{KvsError, Result};
 
 mod kv;
 mod error;
-mod command;
\ No newline at end of file
+mod command;
+mod policy;
+mod index;
\ No newline at end of file
@@ -0,0 +1,127 @@
+// 合并策略
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 数据文件的空间使用情况
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileUsage {
+    /// 数据文件id
+    pub file_id: u64,
+    /// 数据文件的大小
+    pub size: u64,
+    /// 数据文件中无用数据的大小，合并时可以被清除
+    pub useless_size: u64,
+}
+
+impl FileUsage {
+    /// 返回无用数据在数据文件中所占的比例，范围为0到1
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.size == 0 {
+            0.0
+        } else {
+            self.useless_size as f64 / self.size as f64
+        }
+    }
+}
+
+/// 合并策略，决定何时合并以及合并哪些数据文件
+///
+/// 每次set之前都会询问合并策略，`KvStore::compact`则无视合并策略合并所有数据文件
+pub trait CompactionPolicy: Send {
+    /// 返回需要合并的数据文件id，返回空表示不需要合并
+    ///
+    /// `usage`按照文件id升序排列，最后一个是活跃文件
+    fn pick(&self, usage: &[FileUsage]) -> Vec<u64>;
+}
+
+/// 默认的合并策略，由几个阈值组合而成
+///
+/// 无用数据总量超过`max_useless_size`，或者超过有效数据的`max_useless_ratio`倍时触发合并。
+/// 设置了`min_garbage_ratio`时只合并无用数据比例不低于该值的数据文件，否则合并所有数据文件。
+/// 设置了`window`时只在该时间段内合并
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThresholdPolicy {
+    /// 无用数据总量的上限，默认为64KiB
+    pub max_useless_size: Option<u64>,
+    /// 无用数据与有效数据之比的上限
+    pub max_useless_ratio: Option<f64>,
+    /// 只合并无用数据比例不低于该值的数据文件
+    pub min_garbage_ratio: Option<f64>,
+    /// 只在该时间段内合并
+    pub window: Option<CompactionWindow>,
+}
+
+impl Default for ThresholdPolicy {
+    fn default() -> Self {
+        Self {
+            max_useless_size: Some(0x10_000),
+            max_useless_ratio: None,
+            min_garbage_ratio: None,
+            window: None,
+        }
+    }
+}
+
+impl CompactionPolicy for ThresholdPolicy {
+    fn pick(&self, usage: &[FileUsage]) -> Vec<u64> {
+        let useless_size: u64 = usage.iter().map(|u| u.useless_size).sum();
+        let live_size = usage
+            .iter()
+            .map(|u| u.size)
+            .sum::<u64>()
+            .saturating_sub(useless_size);
+        let over_size = self.max_useless_size.is_some_and(|max| useless_size > max);
+        let over_ratio = self
+            .max_useless_ratio
+            .is_some_and(|max| useless_size as f64 > max * live_size as f64);
+        if !over_size && !over_ratio {
+            return vec![];
+        }
+        if let Some(window) = self.window {
+            if !window.contains(SystemTime::now()) {
+                return vec![];
+            }
+        }
+        match self.min_garbage_ratio {
+            // 只合并最脏的数据文件
+            Some(min) => usage
+                .iter()
+                .filter(|u| u.useless_size > 0 && u.garbage_ratio() >= min)
+                .map(|u| u.file_id)
+                .collect(),
+            None => usage.iter().map(|u| u.file_id).collect(),
+        }
+    }
+}
+
+/// 每天的一个时间段，以UTC的小时表示
+///
+/// 时间段从`start_hour`开始，在`end_hour`之前结束。`start_hour`大于`end_hour`时跨越午夜
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionWindow {
+    /// 开始的小时，0到23
+    pub start_hour: u32,
+    /// 结束的小时，0到23
+    pub end_hour: u32,
+}
+
+impl CompactionWindow {
+    /// 构造从`start_hour`到`end_hour`的时间段
+    pub fn new(start_hour: u32, end_hour: u32) -> Self {
+        Self {
+            start_hour,
+            end_hour,
+        }
+    }
+
+    /// 判断给定的时间是否在时间段内
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let hour = (secs / 3600 % 24) as u32;
+        if self.start_hour <= self.end_hour {
+            self.start_hour <= hour && hour < self.end_hour
+        } else {
+            self.start_hour <= hour || hour < self.end_hour
+        }
+    }
+}
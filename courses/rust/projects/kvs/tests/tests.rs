@@ -1,7 +1,8 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{IndexMode, KvStore, KvsError, Result, ThresholdPolicy};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::fs;
 use std::process::Command;
 use tempfile::TempDir;
 use walkdir::WalkDir;
@@ -301,3 +302,298 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Compaction should leave a hint file next to the merged log file.
+// Test data correctness when the key dir is rebuilt from hint files.
+#[test]
+fn compaction_with_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let has_hint_file = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.path().extension() == Some("hint".as_ref()))
+    };
+
+    let mut iter = 0;
+    while !has_hint_file() {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+    }
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    for key_id in 2..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key)?, Some(format!("{}", iter - 1)));
+    }
+
+    Ok(())
+}
+
+// `compact` should merge all log files whatever the compaction policy is.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = ThresholdPolicy {
+        max_useless_size: None,
+        ..ThresholdPolicy::default()
+    };
+    let mut store = KvStore::open_with_policy(temp_dir.path(), policy)?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.file_usage().len(), 1);
+
+    store.compact()?;
+    // the merged log file and the new active log file
+    let usage = store.file_usage();
+    assert_eq!(usage.len(), 2);
+    assert!(usage.iter().all(|u| u.useless_size == 0));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key)?, Some("9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Only the dirtiest log files should be merged with a garbage ratio threshold.
+// Test that a key removed in a merged file stays removed although its value
+// is still in an older log file.
+#[test]
+fn compact_dirtiest_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    let policy = ThresholdPolicy {
+        max_useless_size: Some(0),
+        min_garbage_ratio: Some(0.5),
+        ..ThresholdPolicy::default()
+    };
+    let mut store = KvStore::open_with_policy(temp_dir.path(), policy)?;
+    let clean_file = store.file_usage()[0].file_id;
+    // the log file with the removal is mostly garbage, unlike the first one
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    let usage = store.file_usage();
+    assert_eq!(usage.len(), 3);
+    assert_eq!(usage[0].file_id, clean_file);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    for key_id in 2..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key)?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Log files written as a json stream before the binary format should still be readable.
+#[test]
+fn read_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("0.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Rm":{"key":"key2"}}"#,
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A flipped bit in a record should be detected by its checksum.
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("0.log");
+    let mut data = fs::read(&log_path)?;
+    let last = data.len() - 1;
+    data[last] ^= 0x01;
+    fs::write(&log_path, data)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::ChecksumMismatch) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption is not detected"),
+    }
+}
+
+// With the on-disk index, sealed log files should get an index file and keys
+// should be found in them, also after reopening in either index mode.
+#[test]
+fn disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mode = IndexMode::Disk {
+        max_file_size: 4096,
+    };
+    let open = || KvStore::open_with_index_mode(temp_dir.path(), ThresholdPolicy::default(), mode);
+    let index_files = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("index".as_ref()))
+            .count()
+    };
+
+    let mut store = open()?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..1000).step_by(3) {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    for key_id in (0..1000).step_by(5) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(index_files() > 1);
+    assert!(matches!(
+        store.remove("key0".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        store.remove("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    let expected = |key_id: usize| {
+        if key_id.is_multiple_of(5) {
+            None
+        } else if key_id.is_multiple_of(3) {
+            Some("new".to_owned())
+        } else {
+            Some(format!("value{}", key_id))
+        }
+    };
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..1000 {
+            assert_eq!(store.get(format!("key{}", key_id))?, expected(key_id));
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+
+    drop(store);
+    let mut store = open()?;
+    check(&mut store)?;
+    store.set("key0".to_owned(), "back".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("back".to_owned()));
+    store.remove("key0".to_owned())?;
+
+    store.compact()?;
+    assert_eq!(index_files(), 1);
+    check(&mut store)?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+    store.set("key1".to_owned(), "memory".to_owned())?;
+    drop(store);
+    let mut store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("memory".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// Compaction with the on-disk index should reclaim stale data and keep the
+// removed keys removed although their values are still in unmerged log files.
+#[test]
+fn disk_index_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mode = IndexMode::Disk {
+        max_file_size: 1024,
+    };
+    let policy = ThresholdPolicy {
+        max_useless_size: Some(0),
+        min_garbage_ratio: Some(0.5),
+        ..ThresholdPolicy::default()
+    };
+    let mut store = KvStore::open_with_index_mode(temp_dir.path(), policy, mode)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for iter in 0..20 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    store.remove("key0".to_owned())?;
+    for iter in 20..40 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    // the overwritten values of "hot" have been merged away
+    let usage = store.file_usage();
+    assert!(usage.len() < 10);
+    assert!(usage.iter().map(|u| u.useless_size).sum::<u64>() < 1024);
+
+    drop(store);
+    let mode = IndexMode::Disk {
+        max_file_size: 1024,
+    };
+    let mut store = KvStore::open_with_index_mode(temp_dir.path(), ThresholdPolicy::default(), mode)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("hot".to_owned())?, Some("39".to_owned()));
+    for key_id in 1..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key)?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A flipped bit in the length of a record should be reported as corruption, without
+// allocating the length.
+#[test]
+fn detect_corrupted_record_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // the highest byte of the key size, after the file header, crc, timestamp and kind
+    let log_path = temp_dir.path().join("0.log");
+    let mut data = fs::read(&log_path)?;
+    data[8 + 4 + 8 + 1 + 3] ^= 0x80;
+    fs::write(&log_path, data)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::ChecksumMismatch) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption is not detected"),
+    }
+}
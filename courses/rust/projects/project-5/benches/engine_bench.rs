@@ -0,0 +1,119 @@
+use criterion::{
+    criterion_group, criterion_main, BatchSize, Benchmark, Criterion, ParameterizedBenchmark,
+};
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, LsmKvsEngine, SledKvsEngine};
+use rand::prelude::*;
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+fn open_kvs(temp_dir: &TempDir, mmap: bool) -> KvStore<RayonThreadPool> {
+    let options = KvStoreOptions {
+        mmap,
+        ..KvStoreOptions::default()
+    };
+    KvStore::open_with_options(temp_dir.path(), 1, options).unwrap()
+}
+
+fn open_sled(temp_dir: &TempDir) -> SledKvsEngine<RayonThreadPool> {
+    SledKvsEngine::new(sled::open(temp_dir.path()).unwrap(), 1).unwrap()
+}
+
+fn open_lsm(temp_dir: &TempDir) -> LsmKvsEngine<RayonThreadPool> {
+    LsmKvsEngine::open(temp_dir.path(), 1).unwrap()
+}
+
+fn set_all<E: KvsEngine>(engine: &E, keys: u32) {
+    for i in 1..keys {
+        engine
+            .set(format!("key{}", i), "value".to_owned())
+            .wait()
+            .unwrap();
+    }
+}
+
+fn set_bench(c: &mut Criterion) {
+    let bench = Benchmark::new("kvs", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (open_kvs(&temp_dir, false), temp_dir)
+            },
+            |(store, _temp_dir)| set_all(&store, 1 << 12),
+            BatchSize::SmallInput,
+        )
+    })
+    .with_function("sled", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (open_sled(&temp_dir), temp_dir)
+            },
+            |(db, _temp_dir)| set_all(&db, 1 << 12),
+            BatchSize::SmallInput,
+        )
+    })
+    .with_function("lsm", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (open_lsm(&temp_dir), temp_dir)
+            },
+            |(engine, _temp_dir)| set_all(&engine, 1 << 12),
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench("set_bench", bench);
+}
+
+/// Reads random keys from an engine holding `1 << i` keys.
+fn get_random<E: KvsEngine>(b: &mut criterion::Bencher, engine: E, i: u32) {
+    let mut rng = SmallRng::from_seed([0; 16]);
+    b.iter(|| {
+        engine
+            .get(format!("key{}", rng.gen_range(1, 1 << i)))
+            .wait()
+            .unwrap();
+    })
+}
+
+// The kvs stores are compacted before reading, so every key is read from a sealed
+// log file, which is memory-mapped in `kvs_mmap`. The lsm engine is compacted too, so
+// every key is read from a table in its deepest level.
+fn get_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, &i| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = open_kvs(&temp_dir, false);
+            set_all(&store, 1 << i);
+            store.compact().wait().unwrap();
+            get_random(b, store, i);
+        },
+        vec![8, 12, 16],
+    )
+    .with_function("kvs_mmap", |b, &i| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open_kvs(&temp_dir, true);
+        set_all(&store, 1 << i);
+        store.compact().wait().unwrap();
+        get_random(b, store, i);
+    })
+    .with_function("sled", |b, &i| {
+        let temp_dir = TempDir::new().unwrap();
+        let db = open_sled(&temp_dir);
+        set_all(&db, 1 << i);
+        get_random(b, db, i);
+    })
+    .with_function("lsm", |b, &i| {
+        let temp_dir = TempDir::new().unwrap();
+        let engine = open_lsm(&temp_dir);
+        set_all(&engine, 1 << i);
+        engine.compact().wait().unwrap();
+        get_random(b, engine, i);
+    });
+    c.bench("get_bench", bench);
+}
+
+criterion_group!(benches, set_bench, get_bench);
+criterion_main!(benches);
@@ -2,6 +2,7 @@ use clap::AppSettings;
 use kvs::{KvsClient, Result};
 use std::net::SocketAddr;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
 use tokio::prelude::*;
 
@@ -38,6 +39,12 @@ enum Command {
         key: String,
         #[structopt(name = "VALUE", help = "The string value of the key")]
         value: String,
+        #[structopt(
+            long,
+            help = "Sets the number of seconds after which the key expires",
+            value_name = "SECONDS"
+        )]
+        ttl: Option<u64>,
         #[structopt(
             long,
             help = "Sets the server address",
@@ -60,6 +67,51 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+    #[structopt(
+        name = "scan",
+        about = "List the key/value pairs in a key range or with a key prefix"
+    )]
+    Scan {
+        #[structopt(
+            name = "START",
+            help = "The first key of the range. Lists from the smallest key if omitted"
+        )]
+        start: Option<String>,
+        #[structopt(name = "END", help = "The key after the last key of the range")]
+        end: Option<String>,
+        #[structopt(
+            long,
+            help = "Lists the keys starting with the prefix instead of a range",
+            value_name = "PREFIX",
+            raw(conflicts_with_all = r#"&["START", "END"]"#)
+        )]
+        prefix: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets the maximum number of key/value pairs to list",
+            value_name = "N"
+        )]
+        limit: Option<usize>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "compact", about = "Reclaim the space taken by stale data")]
+    Compact {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
 }
 
 fn main() {
@@ -74,22 +126,58 @@ fn run(opt: Opt) -> Result<()> {
     match opt.command {
         Command::Get { key, addr } => {
             let client = KvsClient::connect(addr);
-            if let (Some(value), _) = client.and_then(move |client| client.get(key)).wait()? {
+            if let Some(value) = client.and_then(move |client| client.get(key)).wait()? {
                 println!("{}", value);
             } else {
                 println!("Key not found");
             }
         }
-        Command::Set { key, value, addr } => {
+        Command::Set {
+            key,
+            value,
+            ttl,
+            addr,
+        } => {
             let client = KvsClient::connect(addr);
-            client
-                .and_then(move |client| client.set(key, value))
-                .wait()?;
+            match ttl {
+                Some(ttl) => client
+                    .and_then(move |client| {
+                        client.set_with_ttl(key, value, Duration::from_secs(ttl))
+                    })
+                    .wait()?,
+                None => client
+                    .and_then(move |client| client.set(key, value))
+                    .wait()?,
+            };
         }
         Command::Remove { key, addr } => {
             let client = KvsClient::connect(addr);
             client.and_then(move |client| client.remove(key)).wait()?;
         }
+        Command::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            addr,
+        } => {
+            let client = KvsClient::connect(addr);
+            let pairs = match prefix {
+                Some(prefix) => client
+                    .and_then(move |client| client.scan_prefix(prefix))
+                    .wait()?,
+                None => client
+                    .and_then(move |client| client.scan(start.unwrap_or_default(), end, limit))
+                    .wait()?,
+            };
+            for (key, value) in pairs.into_iter().take(limit.unwrap_or(usize::MAX)) {
+                println!("{} {}", key, value);
+            }
+        }
+        Command::Compact { addr } => {
+            let client = KvsClient::connect(addr);
+            client.and_then(move |client| client.compact()).wait()?;
+        }
     }
     Ok(())
 }
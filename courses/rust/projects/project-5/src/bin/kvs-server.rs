@@ -3,30 +3,56 @@ extern crate log;
 #[macro_use]
 extern crate clap;
 
+use clap::AppSettings;
 use kvs::thread_pool::*;
-use kvs::{KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
+use kvs::{
+    Compression, IndexMode, KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsError, KvsServer,
+    LsmKvsEngine, MemoryKvsEngine, Result, SledKvsEngine, SyncPolicy, WriteBatch,
+};
 use log::LevelFilter;
 use std::env;
 use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::{Duration, SystemTime};
 use structopt::StructOpt;
+use tokio::prelude::*;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "kvs-server")]
+#[structopt(
+    name = "kvs-server",
+    raw(global_settings = "&[\
+                           AppSettings::DisableHelpSubcommand,\
+                           AppSettings::VersionlessSubcommands]")
+)]
 struct Opt {
     #[structopt(
         long,
-        help = "Sets the listening address",
+        help = "Sets the listening address, or the address of the running server to back up",
         value_name = "IP:PORT",
-        raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+        raw(default_value = "DEFAULT_LISTENING_ADDRESS", global = "true"),
         parse(try_from_str)
     )]
     addr: SocketAddr,
+    #[structopt(
+        long = "resp-addr",
+        help = "Also serves the Redis RESP2 protocol on this address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    resp_addr: Option<SocketAddr>,
+    #[structopt(
+        long = "http-addr",
+        help = "Also serves an HTTP gateway on this address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    http_addr: Option<SocketAddr>,
     #[structopt(
         long,
         help = "Sets the storage engine",
@@ -34,6 +60,100 @@ struct Opt {
         raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Sets when the kvs engine syncs its log to the disk: \
+                always, never, every:<WRITES> or interval:<MILLISECONDS>",
+        value_name = "POLICY",
+        default_value = "never",
+        parse(try_from_str)
+    )]
+    sync: SyncPolicy,
+    #[structopt(
+        long,
+        help = "Sets how the kvs engine compresses the values it writes: none, lz4 or zstd",
+        value_name = "CODEC",
+        default_value = "none",
+        parse(try_from_str)
+    )]
+    compression: Compression,
+    #[structopt(
+        long = "cache-size",
+        help = "Sets the bytes of hot values the kvs engine keeps in memory, 0 to disable",
+        value_name = "BYTES",
+        default_value = "0"
+    )]
+    cache_size: usize,
+    #[structopt(
+        long,
+        help = "Makes the kvs engine read the sealed log files through memory maps"
+    )]
+    mmap: bool,
+    #[structopt(
+        long,
+        help = "Sets where the kvs engine indexes the keys of its sealed log files: \
+                memory, or disk:<MAX_FILE_BYTES> to seal log files at that size",
+        value_name = "MODE",
+        default_value = "memory",
+        parse(try_from_str)
+    )]
+    index: IndexMode,
+    #[structopt(
+        long = "backup-dir",
+        help = "Lets clients back up the data to directories under this one",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    backup_dir: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(
+        name = "backup",
+        about = "Back up the data of the running kvs server to a directory"
+    )]
+    Backup {
+        #[structopt(
+            name = "DIR",
+            help = "An empty or non-existent directory under the --backup-dir of the server",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+    },
+    #[structopt(
+        name = "restore",
+        about = "Restore a backup to the current directory before starting the kvs server"
+    )]
+    Restore {
+        #[structopt(
+            name = "DIR",
+            help = "A directory written by backup",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+    },
+    #[structopt(
+        name = "migrate",
+        about = "Copy the data in the current directory to a directory of another engine"
+    )]
+    Migrate {
+        #[structopt(
+            long,
+            help = "Sets the engine of the new directory",
+            value_name = "ENGINE-NAME",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        to: Engine,
+        #[structopt(
+            name = "DIR",
+            help = "An empty or non-existent directory",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+    },
 }
 
 arg_enum! {
@@ -41,56 +161,345 @@ arg_enum! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        lsm,
+        memory
     }
 }
 
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
     let mut opt = Opt::from_args();
-    let res = current_engine().and_then(move |curr_engine| {
+    let res = match opt.command.take() {
+        Some(Command::Backup { dir }) => backup(dir, opt.addr),
+        Some(Command::Restore { dir }) => restore(dir),
+        Some(Command::Migrate { to, dir }) => migrate(to, dir),
+        None => serve(opt),
+    };
+    if let Err(e) = res {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+fn serve(mut opt: Opt) -> Result<()> {
+    // the memory engine keeps nothing in the current directory
+    if opt.engine == Some(Engine::memory) {
+        return run(opt);
+    }
+    current_engine().and_then(move |curr_engine| {
         if opt.engine.is_none() {
             opt.engine = curr_engine;
         }
-        if curr_engine.is_some() && opt.engine != curr_engine {
-            error!("Wrong engine!");
+        if let Some(curr_engine) = curr_engine.filter(|&engine| opt.engine != Some(engine)) {
+            error!(
+                "Wrong engine! The data is stored by the {} engine, \
+                 `kvs-server migrate` can copy it to another engine",
+                curr_engine
+            );
             exit(1);
         }
         run(opt)
-    });
-    if let Err(e) = res {
-        error!("{}", e);
-        exit(1);
+    })
+}
+
+/// Asks the server running at `addr` to back up its data to `dir` under its backup
+/// directory.
+fn backup(dir: PathBuf, addr: SocketAddr) -> Result<()> {
+    info!("Backing up the server at {} to {:?}", addr, dir);
+    KvsClient::connect(addr)
+        .and_then(move |client| client.backup_to(dir))
+        .wait()?;
+    Ok(())
+}
+
+/// Restores the backup in `dir` to the current directory.
+fn restore(dir: PathBuf) -> Result<()> {
+    if current_engine()?.is_some_and(|engine| engine != Engine::kvs) {
+        return Err(KvsError::StringError(
+            "Backups can only be restored for the kvs engine".to_owned(),
+        ));
+    }
+    info!("Restoring the backup in {:?}", dir);
+    KvStore::<RayonThreadPool>::restore(dir, current_dir()?)?;
+    fs::write(current_dir()?.join("engine"), format!("{}", Engine::kvs))?;
+    Ok(())
+}
+
+/// Copies every live pair in the current directory to `dir` with the `to` engine.
+///
+/// The copy is read back and compared with the source by count and checksum before the
+/// engine file is written to `dir`, so a server only starts in a complete copy. The
+/// server must not run in the current directory during the migration. Keys set with a
+/// time-to-live expire at the same time in the copy, give or take the time it takes to
+/// copy them.
+fn migrate(to: Engine, dir: PathBuf) -> Result<()> {
+    let from = current_engine()?.ok_or_else(|| {
+        KvsError::StringError("The current directory records no engine".to_owned())
+    })?;
+    if to == from || to == Engine::memory || from == Engine::memory {
+        return Err(KvsError::StringError(format!(
+            "Cannot migrate from the {} engine to the {} engine",
+            from, to
+        )));
+    }
+    fs::create_dir_all(&dir)?;
+    if fs::read_dir(&dir)?.next().is_some() {
+        return Err(KvsError::StringError(format!(
+            "Migration directory {:?} is not empty",
+            dir
+        )));
+    }
+    info!(
+        "Migrating the {} data to {:?} with the {} engine",
+        from, dir, to
+    );
+
+    let concurrency = num_cpus::get() as u32;
+    let path = current_dir()?;
+    let (count, crc32) = match from {
+        Engine::kvs => migrate_from(
+            &KvStore::<RayonThreadPool>::open(path, concurrency)?,
+            to,
+            &dir,
+        )?,
+        Engine::sled => migrate_from(&open_sled(&path, concurrency)?, to, &dir)?,
+        Engine::lsm => migrate_from(
+            &LsmKvsEngine::<RayonThreadPool>::open(path, concurrency)?,
+            to,
+            &dir,
+        )?,
+        Engine::memory => unreachable!("the memory engine keeps no data"),
+    };
+
+    let tmp_path = dir.join("engine.tmp");
+    fs::write(&tmp_path, format!("{}", to))?;
+    fs::rename(&tmp_path, dir.join("engine"))?;
+    info!("Migrated {} pairs with checksum {:08x}", count, crc32);
+    Ok(())
+}
+
+/// Copies the pairs of `src` to `dir` with the `to` engine and verifies the copy.
+///
+/// Returns the number of pairs and their checksum.
+fn migrate_from<E: KvsEngine>(src: &E, to: Engine, dir: &Path) -> Result<(u64, u32)> {
+    let concurrency = num_cpus::get() as u32;
+    match to {
+        Engine::kvs => migrate_into(src, KvStore::<RayonThreadPool>::open(dir, concurrency)?),
+        Engine::sled => migrate_into(src, open_sled(dir, concurrency)?),
+        Engine::lsm => migrate_into(
+            src,
+            LsmKvsEngine::<RayonThreadPool>::open(dir, concurrency)?,
+        ),
+        Engine::memory => unreachable!("the memory engine has no directory"),
     }
 }
 
+fn migrate_into<S: KvsEngine, D: KvsEngine>(src: &S, dest: D) -> Result<(u64, u32)> {
+    for_each_page(src, |page| {
+        let mut batch = WriteBatch::new();
+        let mut expiring = Vec::new();
+        for (key, value) in page {
+            let expires_at = match src.expiry_bytes(key.clone()).wait() {
+                Ok(expires_at) => expires_at,
+                // the key expired after it was read
+                Err(KvsError::KeyNotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            match expires_at.map(|at| at.duration_since(SystemTime::now())) {
+                None => {
+                    batch.set(key, value);
+                }
+                Some(Ok(ttl)) => expiring.push((key, value, ttl)),
+                Some(Err(_)) => {}
+            }
+        }
+        dest.write_batch(batch).wait()?;
+        for (key, value, ttl) in expiring {
+            dest.set_bytes_with_ttl(key, value, ttl).wait()?;
+        }
+        Ok(())
+    })?;
+
+    // The source is read again and compared with the copy. The copy is read through
+    // the same engine, as sled can't open a database again until the first handle's
+    // background flusher has let go of it. Keys expiring soon are left out, as they may
+    // expire between both reads.
+    let cutoff = SystemTime::now() + MIGRATION_EXPIRY_MARGIN;
+    let source = digest(src, cutoff)?;
+    let copy = digest(&dest, cutoff)?;
+    if source != copy {
+        return Err(KvsError::StringError(format!(
+            "The copy doesn't match the source: {} pairs with checksum {:08x} in the \
+             source, {} pairs with checksum {:08x} read back",
+            source.0, source.1, copy.0, copy.1
+        )));
+    }
+    Ok(source)
+}
+
+/// Keys expiring within this time of the verification of a migration are not verified.
+const MIGRATION_EXPIRY_MARGIN: Duration = Duration::from_secs(600);
+
+/// Returns the number and checksum of the pairs of `engine` that don't expire before
+/// `cutoff`, including whether they expire at all.
+fn digest<E: KvsEngine>(engine: &E, cutoff: SystemTime) -> Result<(u64, u32)> {
+    let mut digest = PairDigest::default();
+    for_each_page(engine, |page| {
+        for (key, value) in page {
+            let expires_at = match engine.expiry_bytes(key.clone()).wait() {
+                Ok(expires_at) => expires_at,
+                Err(KvsError::KeyNotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            if expires_at.is_none_or(|at| at > cutoff) {
+                digest.add(&key, &value, expires_at.is_some());
+            }
+        }
+        Ok(())
+    })?;
+    Ok((digest.count, digest.hasher.finalize()))
+}
+
+/// Number of pairs read from an engine at a time during a migration.
+const MIGRATION_PAGE: usize = 1024;
+
+/// Calls `f` with the live pairs of `engine` in ascending key order, a page at a time.
+fn for_each_page<E, F>(engine: &E, mut f: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: FnMut(Vec<(Vec<u8>, Vec<u8>)>) -> Result<()>,
+{
+    let mut start = Vec::new();
+    loop {
+        let page: Vec<_> = engine
+            .scan_bytes(start, None, Some(MIGRATION_PAGE))
+            .collect()
+            .wait()?;
+        let last = match page.last() {
+            Some((key, _)) => key.clone(),
+            None => return Ok(()),
+        };
+        let full = page.len() == MIGRATION_PAGE;
+        f(page)?;
+        if !full {
+            return Ok(());
+        }
+        // the smallest key after the last one
+        start = last;
+        start.push(0);
+    }
+}
+
+/// Count and crc32 checksum of a sequence of pairs.
+#[derive(Default)]
+struct PairDigest {
+    count: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl PairDigest {
+    fn add(&mut self, key: &[u8], value: &[u8], expires: bool) {
+        self.count += 1;
+        for bytes in &[key, value] {
+            self.hasher.update(&(bytes.len() as u64).to_le_bytes());
+            self.hasher.update(bytes);
+        }
+        self.hasher.update(&[expires as u8]);
+    }
+}
+
+fn open_sled(path: &Path, concurrency: u32) -> Result<SledKvsEngine<RayonThreadPool>> {
+    SledKvsEngine::new(sled::open(path)?, concurrency)
+}
+
 fn run(opt: Opt) -> Result<()> {
     let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
     info!("Listening on {}", opt.addr);
+    if let Some(resp_addr) = opt.resp_addr {
+        info!("RESP listening on {}", resp_addr);
+    }
+    if let Some(http_addr) = opt.http_addr {
+        info!("HTTP listening on {}", http_addr);
+    }
+    if let Some(backup_dir) = &opt.backup_dir {
+        info!("Backups under {:?}", backup_dir);
+    }
 
     // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    if engine != Engine::memory {
+        fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    }
 
     let concurrency = num_cpus::get() as u32;
     match engine {
-        Engine::kvs => run_with(
-            KvStore::<RayonThreadPool>::open(env::current_dir()?, concurrency)?,
+        Engine::kvs => {
+            info!("Sync policy: {}", opt.sync);
+            info!("Compression: {}", opt.compression);
+            info!("Index: {}", opt.index);
+            let options = KvStoreOptions {
+                sync: opt.sync,
+                compression: opt.compression,
+                cache_capacity: opt.cache_size,
+                mmap: opt.mmap,
+                index: opt.index,
+                ..KvStoreOptions::default()
+            };
+            run_with(
+                KvStore::<RayonThreadPool>::open_with_options(
+                    env::current_dir()?,
+                    concurrency,
+                    options,
+                )?,
+                opt.addr,
+                opt.resp_addr,
+                opt.http_addr,
+                opt.backup_dir,
+            )
+        }
+        Engine::sled => run_with(
+            open_sled(&env::current_dir()?, concurrency)?,
             opt.addr,
+            opt.resp_addr,
+            opt.http_addr,
+            opt.backup_dir,
         ),
-        Engine::sled => run_with(
-            SledKvsEngine::<RayonThreadPool>::new(
-                sled::Db::start_default(env::current_dir()?)?,
-                concurrency,
-            )?,
+        Engine::lsm => run_with(
+            LsmKvsEngine::<RayonThreadPool>::open(env::current_dir()?, concurrency)?,
             opt.addr,
+            opt.resp_addr,
+            opt.http_addr,
+            opt.backup_dir,
+        ),
+        Engine::memory => run_with(
+            MemoryKvsEngine::new(),
+            opt.addr,
+            opt.resp_addr,
+            opt.http_addr,
+            opt.backup_dir,
         ),
     }
 }
 
-pub fn run_with<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
+pub fn run_with<E: KvsEngine>(
+    engine: E,
+    addr: SocketAddr,
+    resp_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    backup_dir: Option<PathBuf>,
+) -> Result<()> {
+    let mut server = KvsServer::new(engine);
+    if let Some(resp_addr) = resp_addr {
+        server = server.with_resp(resp_addr);
+    }
+    if let Some(http_addr) = http_addr {
+        server = server.with_http(http_addr);
+    }
+    if let Some(backup_dir) = backup_dir {
+        server = server.with_backup_root(backup_dir);
+    }
     server.run(addr)
 }
 
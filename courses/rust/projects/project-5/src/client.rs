@@ -1,88 +1,470 @@
-use crate::common::{Request, Response};
-use crate::KvsError;
+use crate::common::{Request, Response, Tagged, FEATURES, PROTOCOL_VERSION};
+use crate::{CasResult, KvsError, Result, WriteBatch};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::prelude::task::{self, Task};
 use tokio::prelude::*;
 use tokio_serde_json::{ReadJson, WriteJson};
 
+// key/value pairs returned by a scan
+type BytePairs = Vec<(Vec<u8>, Vec<u8>)>;
+
 /// Key value store client
+///
+/// A client is a handle to one connection, and its clones share the connection. A
+/// request is sent when its future is first polled, without waiting for the responses
+/// of the requests sent before it, and every response is routed back to its future by
+/// the id of the request. The server runs the requests in flight on a connection at the
+/// same time, so a request that must see the effects of another one should only be
+/// sent once the future of the other one has resolved.
+///
+/// The client agrees on the protocol version and features with the server when it
+/// connects. A request of a feature the server doesn't support fails with
+/// `KvsError::UnsupportedRequest` without being sent.
+#[derive(Clone)]
 pub struct KvsClient {
-    read_json: ReadJson<FramedRead<ReadHalf<TcpStream>, LengthDelimitedCodec>, Response>,
-    write_json: WriteJson<FramedWrite<WriteHalf<TcpStream>, LengthDelimitedCodec>, Request>,
+    conn: Arc<Mutex<Connection>>,
+    protocol: Arc<Protocol>,
+}
+
+/// The protocol agreed on in the handshake.
+struct Protocol {
+    version: u32,
+    features: Vec<String>,
 }
 
 impl KvsClient {
     /// Connect to `addr` to access `KvsServer`.
+    ///
+    /// The future resolves once the server has answered the handshake. Servers from
+    /// before ids were added to requests can't serve the client, and fail it here.
     pub fn connect(addr: SocketAddr) -> impl Future<Item = Self, Error = KvsError> {
         TcpStream::connect(&addr)
-            .map(|tcp| {
+            .map_err(KvsError::from)
+            .and_then(|tcp| {
                 let (read_half, write_half) = tcp.split();
                 let read_json =
                     ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
                 let write_json =
                     WriteJson::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()));
-                KvsClient {
+                let conn = Connection {
                     read_json,
                     write_json,
+                    next_id: 0,
+                    queue: VecDeque::new(),
+                    waiting: HashMap::new(),
+                    received: HashMap::new(),
+                    error: None,
+                };
+                let conn = Arc::new(Mutex::new(conn));
+                let hello = Request::Hello {
+                    version: PROTOCOL_VERSION,
+                    features: FEATURES.iter().map(|&feature| feature.to_owned()).collect(),
+                };
+                // the ids of the other requests start at 1
+                ResponseFuture {
+                    conn: Arc::clone(&conn),
+                    id: 0,
+                    req: Some(hello),
                 }
+                // those servers close the connection on a request with an id
+                .map_err(|e| {
+                    KvsError::StringError(format!(
+                        "Handshake failed, the server may predate request ids: {}",
+                        e
+                    ))
+                })
+                .and_then(|resp| match resp {
+                    Response::Hello { version, features } => Ok(KvsClient {
+                        conn,
+                        protocol: Arc::new(Protocol { version, features }),
+                    }),
+                    Response::Err(msg) => Err(KvsError::StringError(msg)),
+                    _ => Err(KvsError::StringError("Invalid response".to_owned())),
+                })
             })
-            .map_err(|e| e.into())
+    }
+
+    /// Returns the protocol version agreed on with the server.
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol.version
+    }
+
+    /// Returns whether the server supports an optional feature of the protocol.
+    ///
+    /// The features are `ttl`, `cas`, `scan`, `batch`, `compact` and `backup`, named
+    /// after the methods they enable.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.protocol.features.iter().any(|f| f == feature)
     }
 
     /// Get the value of a given key from the server.
-    pub fn get(self, key: String) -> impl Future<Item = (Option<String>, Self), Error = KvsError> {
+    pub fn get_bytes(&self, key: Vec<u8>) -> impl Future<Item = Option<Vec<u8>>, Error = KvsError> {
         self.send_request(Request::Get { key })
-            .and_then(move |(resp, client)| match resp {
-                Some(Response::Get(value)) => Ok((value, client)),
-                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
-                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
+            .and_then(|resp| match resp {
+                Response::Get(value) => Ok(value),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
             })
     }
 
-    /// Set the value of a string key in the server.
-    pub fn set(self, key: String, value: String) -> impl Future<Item = Self, Error = KvsError> {
+    /// Get the string value of a given string key from the server.
+    pub fn get(&self, key: String) -> impl Future<Item = Option<String>, Error = KvsError> {
+        self.get_bytes(key.into_bytes())
+            .and_then(|value| Ok(value.map(String::from_utf8).transpose()?))
+    }
+
+    /// Set the value of a key in the server.
+    pub fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> impl Future<Item = (), Error = KvsError> {
         self.send_request(Request::Set { key, value })
-            .and_then(move |(resp, client)| match resp {
-                Some(Response::Set) => Ok(client),
-                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
-                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
+            .and_then(|resp| match resp {
+                Response::Set => Ok(()),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
             })
     }
 
-    /// Remove a string key in the server.
-    pub fn remove(self, key: String) -> impl Future<Item = Self, Error = KvsError> {
+    /// Set the value of a string key in the server.
+    pub fn set(&self, key: String, value: String) -> impl Future<Item = (), Error = KvsError> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Set the value of a key in the server, which expires after `ttl`.
+    pub fn set_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Item = (), Error = KvsError> {
+        self.send_request(Request::SetWithTtl { key, value, ttl })
+            .and_then(|resp| match resp {
+                Response::Set => Ok(()),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            })
+    }
+
+    /// Set the value of a string key in the server, which expires after `ttl`.
+    pub fn set_with_ttl(
+        &self,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> impl Future<Item = (), Error = KvsError> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Remove a key in the server.
+    pub fn remove_bytes(&self, key: Vec<u8>) -> impl Future<Item = (), Error = KvsError> {
         self.send_request(Request::Remove { key })
-            .and_then(move |(resp, client)| match resp {
-                Some(Response::Remove) => Ok(client),
-                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
-                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
+            .and_then(|resp| match resp {
+                Response::Remove => Ok(()),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            })
+    }
+
+    /// Remove a string key in the server.
+    pub fn remove(&self, key: String) -> impl Future<Item = (), Error = KvsError> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Replace the value of a key in the server if it currently equals `expected`.
+    ///
+    /// `None` as `expected` means the key must not exist, and `None` as `new` removes
+    /// the key. Resolves to `Err` holding the current value if it doesn't match.
+    pub fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> impl Future<Item = CasResult<Vec<u8>>, Error = KvsError> {
+        self.send_request(Request::Cas { key, expected, new })
+            .and_then(|resp| match resp {
+                Response::Cas => Ok(Ok(())),
+                Response::CasMismatch(current) => Ok(Err(current)),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            })
+    }
+
+    /// Replace the string value of a string key in the server if it currently equals
+    /// `expected`.
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> impl Future<Item = CasResult<String>, Error = KvsError> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+        .and_then(|res| match res {
+            Ok(()) => Ok(Ok(())),
+            Err(current) => Ok(Err(current.map(String::from_utf8).transpose()?)),
+        })
+    }
+
+    /// Apply all writes in a batch atomically in the server.
+    pub fn write_batch(&self, batch: WriteBatch) -> impl Future<Item = (), Error = KvsError> {
+        self.send_request(Request::Batch(batch))
+            .and_then(|resp| match resp {
+                Response::Batch => Ok(()),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            })
+    }
+
+    /// Compact the storage of the server.
+    ///
+    /// The future resolves once the server has finished the compaction. A server
+    /// running the sled engine only drops the expired keys.
+    pub fn compact(&self) -> impl Future<Item = (), Error = KvsError> {
+        self.send_request(Request::Compact)
+            .and_then(|resp| match resp {
+                Response::Compact => Ok(()),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            })
+    }
+
+    /// Back up the data of the server to the directory `dir` on the server's host.
+    ///
+    /// `dir` is resolved under the backup root of the server, so it must be relative
+    /// and not contain `..`. Servers without a backup root refuse backups.
+    pub fn backup_to(&self, dir: PathBuf) -> impl Future<Item = (), Error = KvsError> {
+        self.send_request(Request::Backup { dir })
+            .and_then(|resp| match resp {
+                Response::Backup => Ok(()),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
             })
     }
 
-    fn send_request(
-        self,
-        req: Request,
-    ) -> impl Future<Item = (Option<Response>, Self), Error = KvsError> {
-        let read_json = self.read_json;
-        self.write_json
-            .send(req)
-            .and_then(move |write_json| {
-                read_json
-                    .into_future()
-                    .map(move |(resp, read_json)| {
-                        let client = KvsClient {
-                            read_json,
-                            write_json,
-                        };
-                        (resp, client)
-                    })
-                    .map_err(|(err, _)| err)
+    /// Check that the server answers requests.
+    pub fn ping(&self) -> impl Future<Item = (), Error = KvsError> {
+        self.send_request(Request::Ping)
+            .and_then(|resp| match resp {
+                Response::Pong => Ok(()),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
             })
-            .map_err(|e| e.into())
+    }
+
+    /// Returns whether the connection has failed, so that every request would fail.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.conn.lock().unwrap().error.is_some()
+    }
+
+    /// Returns whether `other` is a clone of this client.
+    pub(crate) fn same_connection(&self, other: &KvsClient) -> bool {
+        Arc::ptr_eq(&self.conn, &other.conn)
+    }
+
+    /// Scan the key/value pairs with keys in the range `[start, end)` in the server.
+    ///
+    /// The range has no upper bound if `end` is `None`. At most `limit` pairs are
+    /// returned if it is given.
+    pub fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> impl Future<Item = BytePairs, Error = KvsError> {
+        self.send_request(Request::Scan { start, end, limit })
+            .and_then(Self::scan_response)
+    }
+
+    /// Scan the string key/value pairs with keys in the range `[start, end)` in the server.
+    pub fn scan(
+        &self,
+        start: String,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> impl Future<Item = Vec<(String, String)>, Error = KvsError> {
+        self.scan_bytes(start.into_bytes(), end.map(String::into_bytes), limit)
+            .and_then(into_string_pairs)
+    }
+
+    /// Scan the key/value pairs whose keys start with `prefix` in the server.
+    pub fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+    ) -> impl Future<Item = BytePairs, Error = KvsError> {
+        self.send_request(Request::ScanPrefix { prefix })
+            .and_then(Self::scan_response)
+    }
+
+    /// Scan the string key/value pairs whose keys start with `prefix` in the server.
+    pub fn scan_prefix(
+        &self,
+        prefix: String,
+    ) -> impl Future<Item = Vec<(String, String)>, Error = KvsError> {
+        self.scan_prefix_bytes(prefix.into_bytes())
+            .and_then(into_string_pairs)
+    }
+
+    fn scan_response(resp: Response) -> Result<BytePairs> {
+        match resp {
+            Response::Scan(pairs) => Ok(pairs),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    fn send_request(&self, req: Request) -> impl Future<Item = Response, Error = KvsError> {
+        if let Some(feature) = req.feature().filter(|feature| !self.supports(feature)) {
+            return future::Either::A(future::err(KvsError::UnsupportedRequest(format!(
+                "the server doesn't support {}",
+                feature
+            ))));
+        }
+        let id = {
+            let mut conn = self.conn.lock().unwrap();
+            conn.next_id += 1;
+            conn.next_id
+        };
+        let resp = ResponseFuture {
+            conn: Arc::clone(&self.conn),
+            id,
+            req: Some(req),
+        };
+        future::Either::B(resp.and_then(|resp| match resp {
+            Response::Unsupported(msg) => Err(KvsError::UnsupportedRequest(msg)),
+            resp => Ok(resp),
+        }))
+    }
+}
+
+fn into_string_pairs(pairs: BytePairs) -> Result<Vec<(String, String)>> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+        .collect()
+}
+
+/// The state of a connection shared by the clones of a client.
+///
+/// There is no task driving the connection. Instead, every poll of a `ResponseFuture`
+/// writes the queued requests and reads the available responses, waking the futures
+/// whose responses arrived. Only the task that polled last is woken by the socket, so a
+/// future that resolves or is dropped wakes another waiting future to take over.
+struct Connection {
+    // responses are decoded once they are routed, so that an unknown one only fails its
+    // own request
+    read_json: ReadJson<FramedRead<ReadHalf<TcpStream>, LengthDelimitedCodec>, Tagged<Value>>,
+    write_json: WriteJson<FramedWrite<WriteHalf<TcpStream>, LengthDelimitedCodec>, Tagged<Request>>,
+    next_id: u64,
+    // requests not yet accepted by the socket
+    queue: VecDeque<Tagged<Request>>,
+    // tasks of the futures waiting for their responses
+    waiting: HashMap<u64, Task>,
+    // responses not yet taken by their futures
+    received: HashMap<u64, Result<Response>>,
+    // set once the connection fails, failing every request in flight
+    error: Option<String>,
+}
+
+impl Connection {
+    /// Writes the queued requests and dispatches the available responses.
+    fn drive(&mut self) -> Result<()> {
+        if let Some(msg) = &self.error {
+            return Err(KvsError::StringError(msg.clone()));
+        }
+        let res = self.poll_io();
+        if let Err(e) = &res {
+            self.error = Some(format!("{}", e));
+            for task in self.waiting.values() {
+                task.notify();
+            }
+        }
+        res
+    }
+
+    fn poll_io(&mut self) -> Result<()> {
+        while let Some(req) = self.queue.pop_front() {
+            if let AsyncSink::NotReady(req) = self.write_json.start_send(req)? {
+                self.queue.push_front(req);
+                break;
+            }
+        }
+        self.write_json.poll_complete()?;
+        while let Async::Ready(resp) = self.read_json.poll()? {
+            let Tagged { id, body } = resp.ok_or_else(|| {
+                KvsError::StringError("Connection closed by the server".to_owned())
+            })?;
+            // the response of a dropped future is discarded
+            if let Some(task) = self.waiting.get(&id) {
+                task.notify();
+                let resp = Response::deserialize(&body).map_err(KvsError::from);
+                self.received.insert(id, resp);
+            }
+        }
+        Ok(())
+    }
+
+    /// Wakes a waiting future to keep driving the connection.
+    fn hand_off(&self) {
+        if let Some(task) = self.waiting.values().next() {
+            task.notify();
+        }
+    }
+}
+
+/// Future of the response to a request sent on a shared connection.
+struct ResponseFuture {
+    conn: Arc<Mutex<Connection>>,
+    id: u64,
+    // the request until it is first polled
+    req: Option<Request>,
+}
+
+impl Future for ResponseFuture {
+    type Item = Response;
+    type Error = KvsError;
+
+    fn poll(&mut self) -> Poll<Response, KvsError> {
+        let mut conn = self.conn.lock().unwrap();
+        if let Some(body) = self.req.take() {
+            conn.queue.push_back(Tagged { id: self.id, body });
+        }
+        // registered before driving, so that the response is kept if it is read now
+        conn.waiting.insert(self.id, task::current());
+        let res = conn.drive();
+        if let Some(resp) = conn.received.remove(&self.id) {
+            conn.waiting.remove(&self.id);
+            conn.hand_off();
+            return resp.map(Async::Ready);
+        }
+        if let Err(e) = res {
+            conn.waiting.remove(&self.id);
+            return Err(e);
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for ResponseFuture {
+    fn drop(&mut self) {
+        if let Ok(mut conn) = self.conn.lock() {
+            if conn.waiting.remove(&self.id).is_some() {
+                conn.received.remove(&self.id);
+                conn.hand_off();
+            }
+        }
     }
 }
@@ -1,16 +1,227 @@
+use crate::WriteBatch;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
 
+/// The version of the protocol spoken over `KvsClient` connections.
+///
+/// Version 1 had no handshake. Its first releases sent requests and responses alone,
+/// one request at a time, and later ones tagged them with ids (see `Tagged`). The
+/// server speaks version 1 on connections that don't start with a handshake, and
+/// answers frames without an id without one. Version 2 added the handshake and the
+/// `Unsupported` response.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The optional features of the protocol this crate supports.
+///
+/// The client and the server agree in the handshake on the features both of them
+/// support, so a request of a feature the server lacks fails in the client instead of
+/// on the server.
+pub(crate) const FEATURES: &[&str] = &["ttl", "cas", "scan", "batch", "compact", "backup"];
+
+/// A request or a response with the id the client gave to the request.
+///
+/// A connection carries many requests at the same time, and the server answers them
+/// in the order they finish, so every response carries the id of its request.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Tagged<T> {
+    pub id: u64,
+    pub body: T,
+}
+
+// Keys and values are sent as strings when they are valid UTF-8, as version 1 sent
+// them, and in base64 otherwise (see the `bytes` module).
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+    },
+    Set {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+    },
+    SetWithTtl {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Remove {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+    },
+    Cas {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        #[serde(with = "bytes::option")]
+        expected: Option<Vec<u8>>,
+        #[serde(with = "bytes::option")]
+        new: Option<Vec<u8>>,
+    },
+    Scan {
+        #[serde(with = "bytes")]
+        start: Vec<u8>,
+        #[serde(with = "bytes::option")]
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    ScanPrefix {
+        #[serde(with = "bytes")]
+        prefix: Vec<u8>,
+    },
+    Batch(WriteBatch),
+    Compact,
+    // the directory is resolved under the backup root of the server
+    Backup {
+        dir: PathBuf,
+    },
+    // checks that the server answers, without touching the engine
+    Ping,
+    // the handshake, sent as the first request of a connection
+    Hello {
+        version: u32,
+        features: Vec<String>,
+    },
+}
+
+impl Request {
+    /// Returns the optional feature the server must support to serve the request.
+    pub fn feature(&self) -> Option<&'static str> {
+        match self {
+            Request::SetWithTtl { .. } => Some("ttl"),
+            Request::Cas { .. } => Some("cas"),
+            Request::Scan { .. } | Request::ScanPrefix { .. } => Some("scan"),
+            Request::Batch(_) => Some("batch"),
+            Request::Compact => Some("compact"),
+            Request::Backup { .. } => Some("backup"),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    Get(Option<String>),
+    Get(#[serde(with = "bytes::option")] Option<Vec<u8>>),
     Set,
     Remove,
+    Cas,
+    // the current value didn't match the expected one
+    CasMismatch(#[serde(with = "bytes::option")] Option<Vec<u8>>),
+    Scan(#[serde(with = "bytes::pairs")] Vec<(Vec<u8>, Vec<u8>)>),
+    Batch,
+    Compact,
+    Backup,
+    Pong,
+    // the negotiated version and the features both sides support
+    Hello { version: u32, features: Vec<String> },
+    // the request couldn't be decoded, as it is newer than the server
+    Unsupported(String),
     Err(String),
 }
+
+/// (De)serializes keys and values.
+///
+/// Bytes that are valid UTF-8 are written as a string, so that clients of version 1,
+/// which sent strings, can still talk to the server. Other bytes are written as a map
+/// with a base64 string, `{"base64": "..."}`, instead of an array of numbers, which is
+/// about four times as large. Arrays of numbers, which earlier versions of the crate
+/// sent, are still read.
+pub(crate) mod bytes {
+    use serde::de::{self, Deserializer};
+    use serde::ser::Serializer;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum Encoded<'a> {
+        Str(&'a str),
+        Base64 { base64: String },
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Decoded {
+        Str(String),
+        Base64 { base64: String },
+        Array(Vec<u8>),
+    }
+
+    /// Bytes in a container (de)serialized with the encoding of this module.
+    #[derive(Deserialize)]
+    struct Bytes(#[serde(with = "self")] Vec<u8>);
+
+    struct BytesRef<'a>(&'a [u8]);
+
+    impl Serialize for BytesRef<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize(self.0, serializer)
+        }
+    }
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => Encoded::Str(s),
+            Err(_) => Encoded::Base64 {
+                base64: base64::encode(bytes),
+            },
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        match Decoded::deserialize(deserializer)? {
+            Decoded::Str(s) => Ok(s.into_bytes()),
+            Decoded::Base64 { base64 } => base64::decode(&base64).map_err(de::Error::custom),
+            Decoded::Array(bytes) => Ok(bytes),
+        }
+    }
+
+    pub mod option {
+        use super::{Bytes, BytesRef};
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            bytes: &Option<Vec<u8>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            bytes.as_deref().map(BytesRef).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Vec<u8>>, D::Error> {
+            Ok(Option::<Bytes>::deserialize(deserializer)?.map(|bytes| bytes.0))
+        }
+    }
+
+    pub mod pairs {
+        use super::{Bytes, BytesRef};
+        use serde::ser::SerializeSeq;
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+        pub fn serialize<S: Serializer>(
+            pairs: &[(Vec<u8>, Vec<u8>)],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(pairs.len()))?;
+            for (key, value) in pairs {
+                seq.serialize_element(&(BytesRef(key), BytesRef(value)))?;
+            }
+            seq.end()
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pairs, D::Error> {
+            let pairs = Vec::<(Bytes, Bytes)>::deserialize(deserializer)?;
+            Ok(pairs
+                .into_iter()
+                .map(|(key, value)| (key.0, value.0))
+                .collect())
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::{slice, vec};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// A group of writes applied atomically by `KvsEngine::write_batch`.
+///
+/// The writes are applied in the order they are added. Either all of them or none
+/// of them are visible after a crash.
+///
+/// ```rust
+/// # use kvs::WriteBatch;
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set("key1".to_owned(), "value1".to_owned())
+///     .remove("key2".to_owned());
+/// assert_eq!(batch.len(), 2);
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single write in a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// Sets the value of a key.
+    Set {
+        /// The key to set.
+        #[serde(with = "crate::common::bytes")]
+        key: Vec<u8>,
+        /// The new value of the key.
+        #[serde(with = "crate::common::bytes")]
+        value: Vec<u8>,
+    },
+    /// Removes a key.
+    Remove {
+        /// The key to remove.
+        #[serde(with = "crate::common::bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    /// Creates an empty `WriteBatch`.
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Adds a write setting the value of a key.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Adds a write removing a given key.
+    ///
+    /// The whole batch fails with `KvsError::KeyNotFound` if the key doesn't exist
+    /// when it is removed.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch contains no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Checks that every removed key exists at the time it is removed.
+    ///
+    /// `contains_key` tells whether a key exists before the batch is applied.
+    pub(crate) fn check_removes<F>(&self, mut contains_key: F) -> Result<()>
+    where
+        F: FnMut(&[u8]) -> Result<bool>,
+    {
+        // whether the keys written by the batch exist after the previous writes
+        let mut written: HashMap<&[u8], bool> = HashMap::new();
+        for op in &self.ops {
+            match op {
+                BatchOp::Set { key, .. } => {
+                    written.insert(key, true);
+                }
+                BatchOp::Remove { key } => {
+                    let exists = match written.get(key.as_slice()) {
+                        Some(&exists) => exists,
+                        None => contains_key(key)?,
+                    };
+                    if !exists {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    written.insert(key, false);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a WriteBatch {
+    type Item = &'a BatchOp;
+    type IntoIter = slice::Iter<'a, BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.iter()
+    }
+}
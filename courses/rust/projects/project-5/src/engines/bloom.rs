@@ -0,0 +1,106 @@
+//! Bloom filters of the keys in the sorted files written by the engines.
+//!
+//! A filter is encoded as the number of hashes (`u32`, little-endian) followed by the
+//! bits.
+
+use std::convert::TryInto;
+use std::io::{self, Write};
+
+/// Bits per key and number of hashes, for about 1% false positives.
+const BITS_PER_KEY: u64 = 10;
+const HASHES: u32 = 7;
+
+/// Bloom filter deriving its `hashes` hashes from the two halves of a 64-bit hash of
+/// the key.
+pub(super) struct BloomFilter {
+    hashes: u32,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized for `expected_keys` keys.
+    pub(super) fn new(expected_keys: u64) -> BloomFilter {
+        let len = (expected_keys * BITS_PER_KEY).div_ceil(8).max(1);
+        BloomFilter {
+            hashes: HASHES,
+            bits: vec![0; len as usize],
+        }
+    }
+
+    /// Decodes a filter written by `write_to`.
+    ///
+    /// Returns `None` if `buf` doesn't hold a valid filter.
+    pub(super) fn decode(buf: &[u8]) -> Option<BloomFilter> {
+        let hashes = u32::from_le_bytes(buf.get(..4)?.try_into().ok()?);
+        let bits = buf[4..].to_vec();
+        if bits.is_empty() {
+            return None;
+        }
+        Some(BloomFilter { hashes, bits })
+    }
+
+    /// Writes the encoded filter, returning the number of bytes written.
+    pub(super) fn write_to(&self, mut writer: impl Write) -> io::Result<u64> {
+        writer.write_all(&self.hashes.to_le_bytes())?;
+        writer.write_all(&self.bits)?;
+        Ok(4 + self.bits.len() as u64)
+    }
+
+    fn bits_of(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let hash = hash64(key);
+        let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+        let bits = self.bits.len() as u64 * 8;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    pub(super) fn insert(&mut self, key: &[u8]) {
+        for bit in self.bits_of(key).collect::<Vec<_>>() {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Returns `false` if the key is certainly not in the filter.
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        self.bits_of(key)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+}
+
+/// Hashes a key with FNV-1a and the 64-bit finalizer of MurmurHash3, so that every
+/// byte of the key affects both halves of the hash.
+///
+/// The hash is stored in the files through the filters, so it must never change.
+fn hash64(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomFilter;
+
+    // The false positive rate should be close to the 1% the filter is sized for, also
+    // for similar keys.
+    #[test]
+    fn false_positive_rate() {
+        let mut filter = BloomFilter::new(10_000);
+        for i in 0..10_000 {
+            filter.insert(format!("key{}", i).as_bytes());
+        }
+        assert!((0..10_000).all(|i| filter.may_contain(format!("key{}", i).as_bytes())));
+        let false_positives = (10_000..110_000)
+            .filter(|i| filter.may_contain(format!("key{}", i).as_bytes()))
+            .count();
+        let rate = false_positives as f64 / 100_000.0;
+        assert!(rate < 0.015, "false positive rate {}", rate);
+    }
+}
@@ -0,0 +1,177 @@
+//! Online backups of the log files.
+//!
+//! A backup holds the log files of a consistent state of the store and a manifest
+//! listing their generations, lengths and crc32 checksums. The log files are opened
+//! with the writer lock held, which a compaction also needs to swap the index
+//! entries, so the opened files replay to the state at that moment even if a
+//! compaction deletes some of them afterwards. The sealed log files never change, so
+//! they are hard linked into the backup if possible. Only the part of the active log
+//! file written before the backup started is copied.
+//!
+//! The manifest is written last, so a directory without a manifest holds an
+//! unfinished backup.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use super::{log_path, snapshot, sorted_gen_list, KvStoreWriter};
+use crate::{KvsError, Result};
+
+/// Name of the manifest file in a backup directory.
+const MANIFEST: &str = "MANIFEST";
+
+/// Version of the manifest format.
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    logs: Vec<LogEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LogEntry {
+    gen: u64,
+    len: u64,
+    crc32: u32,
+}
+
+/// Backs up the log files in `path` to the empty directory `dir`.
+pub(super) fn backup(path: &Path, writer: &Mutex<KvStoreWriter>, dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(KvsError::StringError(format!(
+            "Backup directory {:?} is not empty",
+            dir
+        )));
+    }
+
+    let (logs, active_gen, active_len) = {
+        let mut writer = writer.lock().unwrap();
+        writer.writer.flush()?;
+        let mut logs = Vec::new();
+        for gen in sorted_gen_list(path)? {
+            match snapshot::open_log(path, gen) {
+                Ok(file) => logs.push((gen, file)),
+                // The compaction that merged the file has swapped the index entries,
+                // so the compaction file listed too holds its live commands.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        (logs, writer.current_gen, writer.writer.pos)
+    };
+
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        logs: Vec::with_capacity(logs.len()),
+    };
+    for (gen, mut file) in logs {
+        let dest = log_path(dir, gen);
+        let entry = if gen == active_gen {
+            copy_log(gen, file.take(active_len), &dest)?
+        } else if fs::hard_link(log_path(path, gen), &dest).is_ok() {
+            let (len, crc32) = checksum(&mut file)?;
+            LogEntry { gen, len, crc32 }
+        } else {
+            copy_log(gen, file, &dest)?
+        };
+        manifest.logs.push(entry);
+    }
+
+    let tmp_path = dir.join(format!("{}.tmp", MANIFEST));
+    let mut tmp = File::create(&tmp_path)?;
+    serde_json::to_writer_pretty(&mut tmp, &manifest)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, dir.join(MANIFEST))?;
+    Ok(())
+}
+
+/// Restores the backup in `dir` to `path`, which must not contain log files.
+///
+/// Every log file is verified against the manifest before any is copied.
+pub(super) fn restore(dir: &Path, path: &Path) -> Result<()> {
+    let manifest: Manifest = match File::open(dir.join(MANIFEST)) {
+        Ok(file) => serde_json::from_reader(file)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(KvsError::InvalidBackup(format!(
+                "{:?} has no manifest",
+                dir
+            )));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if manifest.version != MANIFEST_VERSION {
+        return Err(KvsError::InvalidBackup(format!(
+            "unsupported manifest version {}",
+            manifest.version
+        )));
+    }
+    for entry in &manifest.logs {
+        let (len, crc32) = match File::open(log_path(dir, entry.gen)) {
+            Ok(mut file) => checksum(&mut file)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(KvsError::InvalidBackup(format!(
+                    "log {} is missing",
+                    entry.gen
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if len != entry.len || crc32 != entry.crc32 {
+            return Err(KvsError::InvalidBackup(format!(
+                "log {} doesn't match the manifest",
+                entry.gen
+            )));
+        }
+    }
+
+    fs::create_dir_all(path)?;
+    if !sorted_gen_list(path)?.is_empty() {
+        return Err(KvsError::StringError(format!(
+            "{:?} already contains log files",
+            path
+        )));
+    }
+    for entry in &manifest.logs {
+        let mut dest = File::create(log_path(path, entry.gen))?;
+        io::copy(&mut File::open(log_path(dir, entry.gen))?, &mut dest)?;
+        dest.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Copies a log file to `dest`, returning its manifest entry.
+fn copy_log(gen: u64, src: impl Read, dest: &Path) -> Result<LogEntry> {
+    let mut dest = File::create(dest)?;
+    let (len, crc32) = copy_checksummed(src, &mut dest)?;
+    dest.sync_all()?;
+    Ok(LogEntry { gen, len, crc32 })
+}
+
+/// Returns the length and the crc32 checksum of the rest of a file.
+fn checksum(file: &mut File) -> Result<(u64, u32)> {
+    copy_checksummed(file, io::sink())
+}
+
+/// Copies `src` to `dest`, returning the number of bytes copied and their crc32
+/// checksum.
+fn copy_checksummed(mut src: impl Read, mut dest: impl Write) -> Result<(u64, u32)> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut len = 0;
+    let mut buf = [0; 8192];
+    loop {
+        let n = src.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        dest.write_all(&buf[..n])?;
+        len += n as u64;
+    }
+    Ok((len, hasher.finalize()))
+}
@@ -0,0 +1,277 @@
+//! A sharded LRU cache of the values read by `KvStore::get`.
+//!
+//! Every entry remembers the position of the command its value was read from, and a
+//! lookup only hits if it equals the position in the index. So a value read while a
+//! write to the same key is in progress can be cached, but it is never served after
+//! the write. Writers still invalidate the keys they write to free the memory early,
+//! and a compaction moves the entries of the commands it copies to their new
+//! positions, so the values stay cached.
+//!
+//! The byte budget is split evenly among the shards. Each shard keeps its entries in a
+//! doubly linked list stored in a vector, ordered from the most to the least recently
+//! used, and evicts from the tail.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::CommandPos;
+
+/// Number of shards of a cache.
+const SHARDS: usize = 16;
+/// Bytes charged for every entry on top of its key and value, roughly the size of
+/// its bookkeeping.
+const ENTRY_OVERHEAD: usize = 64;
+/// Index of no node.
+const NIL: usize = usize::MAX;
+
+/// Statistics of the value cache of a `KvStore`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    /// Number of lookups that found the value in the cache.
+    pub hits: u64,
+    /// Number of lookups that had to read the value from the log.
+    pub misses: u64,
+    /// Number of cached values.
+    pub entries: usize,
+    /// Bytes charged to the cached values, including their keys.
+    pub bytes: usize,
+}
+
+impl CacheStats {
+    /// Returns the fraction of lookups that found the value in the cache, from 0 to 1.
+    ///
+    /// Returns `None` if nothing has been looked up.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            None
+        } else {
+            Some(self.hits as f64 / lookups as f64)
+        }
+    }
+}
+
+pub(super) struct ValueCache {
+    // empty if the cache is disabled
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ValueCache {
+    /// Creates a cache holding at most `capacity` bytes. A zero capacity disables it.
+    pub(super) fn new(capacity: usize) -> ValueCache {
+        let shards = if capacity == 0 {
+            Vec::new()
+        } else {
+            (0..SHARDS)
+                .map(|_| Mutex::new(Shard::new(capacity / SHARDS)))
+                .collect()
+        };
+        ValueCache {
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> Option<&Mutex<Shard>> {
+        if self.shards.is_empty() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        Some(&self.shards[hasher.finish() as usize % self.shards.len()])
+    }
+
+    /// Returns the cached value of `key` if it was read from the command at `pos`.
+    pub(super) fn get(&self, key: &[u8], pos: CommandPos) -> Option<Vec<u8>> {
+        let value = self.shard(key)?.lock().unwrap().get(key, pos);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Caches the value of `key` read from the command at `pos`.
+    pub(super) fn insert(&self, key: &[u8], pos: CommandPos, value: &[u8]) {
+        if let Some(shard) = self.shard(key) {
+            shard.lock().unwrap().insert(key, pos, value);
+        }
+    }
+
+    /// Drops the cached value of `key`.
+    pub(super) fn invalidate(&self, key: &[u8]) {
+        if let Some(shard) = self.shard(key) {
+            shard.lock().unwrap().invalidate(key);
+        }
+    }
+
+    /// Returns whether the value of `key` read from the command at `pos` is cached,
+    /// without counting a hit or a miss.
+    pub(super) fn contains(&self, key: &[u8], pos: CommandPos) -> bool {
+        self.shard(key).is_some_and(|shard| {
+            let shard = shard.lock().unwrap();
+            shard
+                .map
+                .get(key)
+                .is_some_and(|&idx| shard.nodes[idx].pos == pos)
+        })
+    }
+
+    /// Moves the cached value of `key` read from the command at `old_pos` to `new_pos`.
+    pub(super) fn relocate(&self, key: &[u8], old_pos: CommandPos, new_pos: CommandPos) {
+        if let Some(shard) = self.shard(key) {
+            shard.lock().unwrap().relocate(key, old_pos, new_pos);
+        }
+    }
+
+    pub(super) fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        };
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            stats.entries += shard.map.len();
+            stats.bytes += shard.bytes;
+        }
+        stats
+    }
+}
+
+struct Node {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    pos: CommandPos,
+    prev: usize,
+    next: usize,
+}
+
+impl Node {
+    fn charge(&self) -> usize {
+        self.key.len() + self.value.len() + ENTRY_OVERHEAD
+    }
+}
+
+struct Shard {
+    map: HashMap<Vec<u8>, usize>,
+    nodes: Vec<Node>,
+    // slots of evicted nodes to reuse
+    free: Vec<usize>,
+    // the most and the least recently used nodes
+    head: usize,
+    tail: usize,
+    bytes: usize,
+    capacity: usize,
+}
+
+impl Shard {
+    fn new(capacity: usize) -> Shard {
+        Shard {
+            map: HashMap::new(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+            bytes: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: &[u8], pos: CommandPos) -> Option<Vec<u8>> {
+        let idx = *self.map.get(key)?;
+        if self.nodes[idx].pos != pos {
+            // the cached value is stale
+            self.remove(idx);
+            return None;
+        }
+        self.unlink(idx);
+        self.push_front(idx);
+        Some(self.nodes[idx].value.clone())
+    }
+
+    fn insert(&mut self, key: &[u8], pos: CommandPos, value: &[u8]) {
+        self.invalidate(key);
+        if key.len() + value.len() + ENTRY_OVERHEAD > self.capacity {
+            return;
+        }
+        let node = Node {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            pos,
+            prev: NIL,
+            next: NIL,
+        };
+        let charge = node.charge();
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = node;
+                idx
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        self.map.insert(key.to_vec(), idx);
+        self.push_front(idx);
+        self.bytes += charge;
+        while self.bytes > self.capacity {
+            self.remove(self.tail);
+        }
+    }
+
+    fn invalidate(&mut self, key: &[u8]) {
+        if let Some(&idx) = self.map.get(key) {
+            self.remove(idx);
+        }
+    }
+
+    fn relocate(&mut self, key: &[u8], old_pos: CommandPos, new_pos: CommandPos) {
+        if let Some(&idx) = self.map.get(key) {
+            if self.nodes[idx].pos == old_pos {
+                self.nodes[idx].pos = new_pos;
+            }
+        }
+    }
+
+    fn remove(&mut self, idx: usize) {
+        self.unlink(idx);
+        self.bytes -= self.nodes[idx].charge();
+        let key = mem::take(&mut self.nodes[idx].key);
+        self.nodes[idx].value = Vec::new();
+        self.map.remove(&key);
+        self.free.push(idx);
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let (prev, next) = (self.nodes[idx].prev, self.nodes[idx].next);
+        match prev {
+            NIL => self.head = next,
+            prev => self.nodes[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.nodes[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, idx: usize) {
+        self.nodes[idx].prev = NIL;
+        self.nodes[idx].next = self.head;
+        match self.head {
+            NIL => self.tail = idx,
+            head => self.nodes[head].prev = idx,
+        }
+        self.head = idx;
+    }
+}
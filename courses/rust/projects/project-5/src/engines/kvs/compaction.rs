@@ -0,0 +1,641 @@
+//! Background compaction of sealed log files.
+//!
+//! When the compaction policy picks some log files, or `KvStore::compact` is called,
+//! the writer seals the active log file and hands the picked generations and the
+//! generation number reserved for the compaction file to the compaction thread. New
+//! writes go to a fresh active log file in the meantime.
+//!
+//! The compaction thread copies the live entries of the picked generations to a
+//! temporary file, which is renamed into place once it is synced. The index entries
+//! are then swapped to the compaction file with a compare-and-swap under the writer
+//! lock: an entry is only swapped if it still points to the copied command, so
+//! entries written while the compaction runs are kept.
+//!
+//! If an older generation is not picked, it may still hold a value of a key removed in
+//! a picked generation. The "remove" commands of such keys are copied to the
+//! compaction file too, so that the key stays removed when the log is replayed.
+//!
+//! Expired values are not copied. They are replaced by "remove" commands in the same
+//! way if an older generation is not picked.
+//!
+//! With `IndexMode::Disk`, the keys of the picked generations are in their index files
+//! rather than in memory. The index files are merged instead, which also tells exactly
+//! which removals an unpicked generation depends on, and the index file of the
+//! compaction file is written alongside. It replaces the merged ones under the writer
+//! lock. Lookups check the newer generations first, so the keys written while the
+//! compaction runs are not shadowed by the copies.
+//!
+//! While a snapshot is pinned, the merged log files are retired rather than deleted
+//! (see the `snapshot` module).
+
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, Receiver, Sender, TryRecvError};
+use crossbeam::select;
+use serde_json::Deserializer;
+use tokio::sync::oneshot;
+
+use super::cache::ValueCache;
+use super::keydir::{index_path, IndexWriter, KeyDir, KeyState, Merge};
+use super::options::Compression;
+use super::record::{
+    needs_recompression, read_command, read_record, write_file_header, write_record, Command,
+    JsonCommand, LogFormat,
+};
+use super::snapshot::Snapshots;
+use super::{
+    gen_usage, log_path, now_millis, sorted_gen_list, BufReaderWithPos, BufWriterWithPos,
+    CommandPos, KvStoreReader, KvStoreWriter,
+};
+use crate::{KvsError, Result};
+
+/// Number of index entries swapped each time the writer lock is taken.
+const SWAP_BATCH_SIZE: usize = 1024;
+
+/// Statistics and progress of the background compaction.
+#[derive(Debug, Clone, Default)]
+pub struct CompactionStats {
+    /// Whether a compaction is running.
+    pub running: bool,
+    /// Number of compactions finished since the store was opened.
+    pub finished: u64,
+    /// Number of live entries the running or last compaction copies.
+    pub entries_total: u64,
+    /// Number of entries the running or last compaction has copied so far.
+    pub entries_copied: u64,
+    /// Bytes of log files reclaimed by the finished compactions.
+    pub bytes_reclaimed: u64,
+    /// How long the last finished compaction took.
+    pub last_duration: Option<Duration>,
+}
+
+impl CompactionStats {
+    /// Returns the fraction of entries the running compaction has copied, from 0 to 1.
+    ///
+    /// Returns `None` if no compaction is running.
+    pub fn progress(&self) -> Option<f64> {
+        if !self.running {
+            None
+        } else if self.entries_total == 0 {
+            Some(1.0)
+        } else {
+            Some(self.entries_copied as f64 / self.entries_total as f64)
+        }
+    }
+}
+
+/// Counters shared by the writer, the compaction thread and `KvStore::compaction_stats`.
+#[derive(Debug, Default)]
+pub(super) struct CompactionProgress {
+    running: AtomicBool,
+    finished: AtomicU64,
+    entries_total: AtomicU64,
+    entries_copied: AtomicU64,
+    bytes_reclaimed: AtomicU64,
+    last_duration: Mutex<Option<Duration>>,
+    // notified once the running compaction finishes
+    waiters: Mutex<Vec<oneshot::Sender<()>>>,
+}
+
+impl CompactionProgress {
+    /// Marks a compaction as started.
+    pub(super) fn start(&self) {
+        self.running.store(true, Ordering::SeqCst);
+    }
+
+    /// Marks the running compaction as finished and notifies the waiters.
+    fn finish(&self) {
+        let mut waiters = self.waiters.lock().unwrap();
+        self.running.store(false, Ordering::SeqCst);
+        for waiter in waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+    }
+
+    pub(super) fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Returns a receiver notified once the running compaction finishes, right away
+    /// if none is running.
+    pub(super) fn wait(&self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let mut waiters = self.waiters.lock().unwrap();
+        if self.is_running() {
+            waiters.push(tx);
+        } else {
+            let _ = tx.send(());
+        }
+        rx
+    }
+
+    pub(super) fn stats(&self) -> CompactionStats {
+        CompactionStats {
+            running: self.running.load(Ordering::SeqCst),
+            finished: self.finished.load(Ordering::SeqCst),
+            entries_total: self.entries_total.load(Ordering::SeqCst),
+            entries_copied: self.entries_copied.load(Ordering::SeqCst),
+            bytes_reclaimed: self.bytes_reclaimed.load(Ordering::SeqCst),
+            last_duration: *self.last_duration.lock().unwrap(),
+        }
+    }
+}
+
+/// A compaction handed to the compaction thread.
+pub(super) struct CompactionTask {
+    /// Generation of the compaction file.
+    pub(super) gen: u64,
+    /// Generations to compact, or `None` to compact every generation before `gen`.
+    pub(super) gens: Option<Vec<u64>>,
+    /// Receives the result once the compaction finishes.
+    pub(super) done: oneshot::Sender<Result<()>>,
+}
+
+/// Handle of the compaction thread.
+///
+/// Dropping the handle stops a running compaction and waits for the thread to exit.
+pub(super) struct CompactionHandle {
+    progress: Arc<CompactionProgress>,
+    // dropping the sender tells the thread to stop
+    stop_tx: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CompactionHandle {
+    /// Spawns the compaction thread.
+    ///
+    /// The thread runs the compactions received from `rx` one by one.
+    pub(super) fn spawn(
+        compactor: Compactor,
+        rx: Receiver<CompactionTask>,
+        progress: Arc<CompactionProgress>,
+    ) -> Result<CompactionHandle> {
+        let (stop_tx, stop_rx) = channel::bounded(0);
+        let thread = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || compactor.run(rx, stop_rx))?;
+        Ok(CompactionHandle {
+            progress,
+            stop_tx: Some(stop_tx),
+            thread: Some(thread),
+        })
+    }
+
+    pub(super) fn stats(&self) -> CompactionStats {
+        self.progress.stats()
+    }
+
+    pub(super) fn wait(&self) -> oneshot::Receiver<()> {
+        self.progress.wait()
+    }
+}
+
+impl Drop for CompactionHandle {
+    fn drop(&mut self) {
+        drop(self.stop_tx.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Compaction thread panicked");
+            }
+        }
+    }
+}
+
+/// State of the compaction thread.
+pub(super) struct Compactor {
+    pub(super) path: Arc<PathBuf>,
+    pub(super) index: Arc<KeyDir>,
+    pub(super) reader: KvStoreReader,
+    pub(super) writer: Weak<Mutex<KvStoreWriter>>,
+    pub(super) progress: Arc<CompactionProgress>,
+    pub(super) snapshots: Arc<Snapshots>,
+    pub(super) cache: Arc<ValueCache>,
+    // the copied values are rewritten with this codec
+    pub(super) compression: Compression,
+}
+
+impl Compactor {
+    fn run(self, rx: Receiver<CompactionTask>, stop_rx: Receiver<()>) {
+        loop {
+            let task = select! {
+                recv(rx) -> msg => match msg {
+                    Ok(task) => task,
+                    Err(_) => return,
+                },
+                recv(stop_rx) -> _ => return,
+            };
+            let compaction_gen = task.gen;
+            self.progress.start();
+            let start = Instant::now();
+            let res = match self.compact(compaction_gen, task.gens, &stop_rx) {
+                Ok(true) => {
+                    self.progress.finished.fetch_add(1, Ordering::SeqCst);
+                    *self.progress.last_duration.lock().unwrap() = Some(start.elapsed());
+                    Ok(())
+                }
+                Ok(false) => Err(KvsError::StringError("Compaction stopped".to_owned())),
+                Err(e) => {
+                    error!("Compaction to generation {} failed: {}", compaction_gen, e);
+                    Err(e)
+                }
+            };
+            if let Err(e) = fs::remove_file(tmp_path(&self.path, compaction_gen)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    error!("Temporary compaction file cannot be deleted: {}", e);
+                }
+            }
+            self.progress.finish();
+            // nobody waits for an automatic compaction
+            let _ = task.done.send(res);
+        }
+    }
+
+    /// Merges the live entries in the given generations into the log file of
+    /// `compaction_gen`. `None` merges every generation before `compaction_gen`.
+    ///
+    /// Returns `false` if the compaction is stopped before it finishes.
+    fn compact(
+        &self,
+        compaction_gen: u64,
+        gens: Option<Vec<u64>>,
+        stop_rx: &Receiver<()>,
+    ) -> Result<bool> {
+        let all_gens: Vec<u64> = sorted_gen_list(&self.path)?
+            .into_iter()
+            .filter(|&gen| gen < compaction_gen)
+            .collect();
+        let picked: BTreeSet<u64> = match gens {
+            Some(gens) => gens
+                .into_iter()
+                .filter(|&gen| gen < compaction_gen)
+                .collect(),
+            None => all_gens.iter().copied().collect(),
+        };
+        let oldest_unpicked = all_gens.iter().find(|gen| !picked.contains(gen)).copied();
+
+        let mut compaction_writer = BufWriterWithPos::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(tmp_path(&self.path, compaction_gen))?,
+        )?;
+        write_file_header(&mut compaction_writer)?;
+
+        let compaction_len = if self.index.is_disk() {
+            self.merge_indexes(compaction_gen, &picked, compaction_writer, stop_rx)?
+        } else {
+            self.merge_live(
+                compaction_gen,
+                &picked,
+                oldest_unpicked,
+                compaction_writer,
+                stop_rx,
+            )?
+        };
+        let compaction_len = match compaction_len {
+            Some(len) => len,
+            None => return Ok(false),
+        };
+
+        self.reader.epoch.fetch_add(1, Ordering::SeqCst);
+        self.reader.close_stale_handles();
+
+        // remove stale log files
+        // Note that actually these files are not deleted immediately because `KvStoreReader`s
+        // still keep open file handles. When `KvStoreReader` is used next time, it will clear
+        // its stale file handles. On Unix, the files will be deleted after all the handles
+        // are closed. On Windows, the deletions below will fail and stale files are expected
+        // to be deleted in the next compaction.
+        let mut stale_len = 0;
+        for &stale_gen in &picked {
+            let file_path = log_path(&self.path, stale_gen);
+            let len = fs::metadata(&file_path).map(|m| m.len()).unwrap_or(0);
+            match self.snapshots.remove_log(stale_gen) {
+                Ok(()) => stale_len += len,
+                Err(e) => error!("{:?} cannot be deleted: {}", file_path, e),
+            }
+            let index_path = index_path(&self.path, stale_gen);
+            if let Err(e) = fs::remove_file(&index_path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    error!("{:?} cannot be deleted: {}", index_path, e);
+                }
+            }
+        }
+        self.progress
+            .bytes_reclaimed
+            .fetch_add(stale_len.saturating_sub(compaction_len), Ordering::SeqCst);
+        Ok(true)
+    }
+
+    /// Copies the live entries of the picked generations from the index in memory,
+    /// and swaps their index entries to the compaction file.
+    ///
+    /// Returns the length of the compaction file, or `None` if the compaction is
+    /// stopped before it finishes.
+    fn merge_live(
+        &self,
+        compaction_gen: u64,
+        picked: &BTreeSet<u64>,
+        oldest_unpicked: Option<u64>,
+        mut compaction_writer: BufWriterWithPos<File>,
+        stop_rx: &Receiver<()>,
+    ) -> Result<Option<u64>> {
+        let now = now_millis();
+        // keep the removals that an older unpicked generation depends on
+        if let Some(oldest_unpicked) = oldest_unpicked {
+            let mut copied = HashSet::new();
+            for &gen in picked.range(oldest_unpicked..) {
+                for key in removed_keys(&self.path, gen, now)? {
+                    if !self.index.entries.contains_key(&key) && copied.insert(key.clone()) {
+                        let cmd = Command::remove(key);
+                        write_record(&mut compaction_writer, &cmd, self.compression)?;
+                    }
+                }
+            }
+        }
+
+        let entries: Vec<(Vec<u8>, CommandPos)> = self
+            .index
+            .entries
+            .iter()
+            .filter(|entry| picked.contains(&entry.value().gen))
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        self.progress
+            .entries_total
+            .store(entries.len() as u64, Ordering::SeqCst);
+        self.progress.entries_copied.store(0, Ordering::SeqCst);
+
+        let mut swaps = Vec::with_capacity(entries.len());
+        for (key, old_pos) in entries {
+            if stop_rx.try_recv() != Err(TryRecvError::Empty) {
+                return Ok(None);
+            }
+            if old_pos.is_expired(now) {
+                if oldest_unpicked.is_some_and(|gen| gen < old_pos.gen) {
+                    let cmd = Command::remove(key.clone());
+                    write_record(&mut compaction_writer, &cmd, self.compression)?;
+                }
+                swaps.push((key, old_pos, None));
+                self.progress.entries_copied.fetch_add(1, Ordering::SeqCst);
+                continue;
+            }
+            let new_pos = self.copy_command(old_pos, compaction_gen, &mut compaction_writer)?;
+            swaps.push((key, old_pos, Some(new_pos)));
+            self.progress.entries_copied.fetch_add(1, Ordering::SeqCst);
+        }
+        let compaction_len = self.finish_log(compaction_gen, compaction_writer)?;
+
+        let writer = match self.writer.upgrade() {
+            Some(writer) => writer,
+            // the store is closed, and the compaction file is loaded on the next open
+            None => return Ok(None),
+        };
+        // The copied removals are counted as live, otherwise the compaction file
+        // would be picked again only to copy them once more.
+        gen_usage(&mut writer.lock().unwrap().usage, compaction_gen).len = compaction_len;
+        for batch in swaps.chunks(SWAP_BATCH_SIZE) {
+            // Writers update the index with the writer lock held, so an entry
+            // cannot change between the comparison and the swap.
+            let mut writer = writer.lock().unwrap();
+            for (key, old_pos, new_pos) in batch {
+                match (self.index.entries.get(key), new_pos) {
+                    (Some(ref entry), Some(new_pos)) if *entry.value() == *old_pos => {
+                        // the value is the same, so it stays cached
+                        self.cache.relocate(key, *old_pos, *new_pos);
+                        self.index.entries.insert(key.clone(), *new_pos);
+                    }
+                    // the expired value is dropped
+                    (Some(ref entry), None) if *entry.value() == *old_pos => {
+                        self.cache.invalidate(key);
+                        self.index.entries.remove(key);
+                    }
+                    // the key is overwritten or removed during the compaction, so
+                    // the copied command is stale
+                    (_, Some(new_pos)) => {
+                        gen_usage(&mut writer.usage, compaction_gen).stale += new_pos.len
+                    }
+                    (_, None) => {}
+                }
+            }
+        }
+        writer
+            .lock()
+            .unwrap()
+            .usage
+            .retain(|gen, _| !picked.contains(gen));
+        Ok(Some(compaction_len))
+    }
+
+    /// Merges the index files of the generations before the compaction file, copying
+    /// the newest values in the picked generations, and writes the index file of the
+    /// compaction file.
+    ///
+    /// Returns the length of the compaction file, or `None` if the compaction is
+    /// stopped before it finishes.
+    fn merge_indexes(
+        &self,
+        compaction_gen: u64,
+        picked: &BTreeSet<u64>,
+        mut compaction_writer: BufWriterWithPos<File>,
+        stop_rx: &Receiver<()>,
+    ) -> Result<Option<u64>> {
+        let indexes = self.index.sealed_before(compaction_gen);
+        let total = indexes
+            .iter()
+            .filter(|index| picked.contains(&index.gen()))
+            .map(|index| index.count())
+            .sum();
+        self.progress.entries_total.store(total, Ordering::SeqCst);
+        self.progress.entries_copied.store(0, Ordering::SeqCst);
+
+        let mut index_writer = IndexWriter::create(&self.path, compaction_gen, total)?;
+        // the cached values to move to the compaction file once its index is used
+        let mut relocations = Vec::new();
+        let now = now_millis();
+        for entry in Merge::of_indexes(&indexes)? {
+            if stop_rx.try_recv() != Err(TryRecvError::Empty) {
+                return Ok(None);
+            }
+            let (key, versions) = entry?;
+            let gens: Vec<u64> = versions.iter().map(|&(i, _)| indexes[i].gen()).collect();
+            let (newest_gen, newest) = (gens[gens.len() - 1], versions[versions.len() - 1].1);
+            if picked.contains(&newest_gen) {
+                match newest {
+                    KeyState::Live(old_pos) if !old_pos.is_expired(now) => {
+                        let new_pos =
+                            self.copy_command(old_pos, compaction_gen, &mut compaction_writer)?;
+                        index_writer.add(&key, KeyState::Live(new_pos))?;
+                        if self.cache.contains(&key, old_pos) {
+                            relocations.push((key, old_pos, new_pos));
+                        }
+                    }
+                    // the removal is kept if an unpicked generation holds the key
+                    _ if gens.iter().any(|gen| !picked.contains(gen)) => {
+                        let cmd = Command::remove(key.clone());
+                        write_record(&mut compaction_writer, &cmd, self.compression)?;
+                        index_writer.add(&key, KeyState::Removed)?;
+                    }
+                    _ => {}
+                }
+            }
+            let merged = gens.iter().filter(|gen| picked.contains(gen)).count();
+            self.progress
+                .entries_copied
+                .fetch_add(merged as u64, Ordering::SeqCst);
+        }
+        let compaction_len = self.finish_log(compaction_gen, compaction_writer)?;
+        let compaction_index = Arc::new(index_writer.finish(compaction_len, 0)?);
+        drop(indexes);
+
+        let writer = match self.writer.upgrade() {
+            Some(writer) => writer,
+            // the store is closed, and the compaction file is loaded on the next open
+            None => return Ok(None),
+        };
+        gen_usage(&mut writer.lock().unwrap().usage, compaction_gen).len = compaction_len;
+        // A copied value written again since it was copied is stale in the compaction
+        // file. Writers update the index with the writer lock held, so the newest
+        // location of a key can't change during its check. A key written between the
+        // check of its batch and the swap is only counted when the store is opened again.
+        let mut entries = compaction_index.iter()?.peekable();
+        loop {
+            let mut writer = writer.lock().unwrap();
+            for entry in entries.by_ref().take(SWAP_BATCH_SIZE) {
+                if let (key, KeyState::Live(new_pos)) = entry? {
+                    let shadowed = self
+                        .index
+                        .get(&key)?
+                        .is_none_or(|cmd_pos| cmd_pos.gen > compaction_gen);
+                    if shadowed {
+                        gen_usage(&mut writer.usage, compaction_gen).stale += new_pos.len;
+                    }
+                }
+            }
+            if entries.peek().is_none() {
+                self.index
+                    .replace_sealed(picked, Arc::clone(&compaction_index));
+                writer.usage.retain(|gen, _| !picked.contains(gen));
+                // The values are the same, so they stay cached. They are only moved
+                // now, as lookups find them at the old locations until the swap.
+                for (key, old_pos, new_pos) in relocations {
+                    self.cache.relocate(&key, old_pos, new_pos);
+                }
+                break;
+            }
+        }
+        Ok(Some(compaction_len))
+    }
+
+    /// Copies the command at `old_pos` to the compaction file, returning its location
+    /// there.
+    fn copy_command(
+        &self,
+        old_pos: CommandPos,
+        compaction_gen: u64,
+        compaction_writer: &mut BufWriterWithPos<File>,
+    ) -> Result<CommandPos> {
+        let new_pos = compaction_writer.pos; // pos in the new log file
+        self.reader
+            .read_and(old_pos, |format, mut entry_reader| match format {
+                LogFormat::Binary => {
+                    let mut record = Vec::new();
+                    entry_reader.read_to_end(&mut record)?;
+                    if needs_recompression(&record, self.compression) {
+                        let cmd = read_command(&mut &record[..], record.len() as u64, format)?;
+                        write_record(compaction_writer, &cmd, self.compression)
+                    } else {
+                        compaction_writer.write_all(&record)?;
+                        Ok(())
+                    }
+                }
+                // rewrite legacy json commands in the binary format
+                LogFormat::Json => {
+                    let cmd = read_command(&mut entry_reader, old_pos.len, format)?;
+                    write_record(compaction_writer, &cmd, self.compression)
+                }
+            })?;
+        let new_pos = CommandPos::from((compaction_gen, new_pos..compaction_writer.pos));
+        Ok(new_pos.expiring(old_pos.expires_at))
+    }
+
+    /// Syncs the compaction file and renames it into place, returning its length.
+    fn finish_log(
+        &self,
+        compaction_gen: u64,
+        mut compaction_writer: BufWriterWithPos<File>,
+    ) -> Result<u64> {
+        // The stale log files are deleted afterwards, so the compaction file must
+        // reach the disk first whatever the sync policy is.
+        compaction_writer.sync()?;
+        let compaction_len = compaction_writer.pos;
+        drop(compaction_writer);
+        fs::rename(
+            tmp_path(&self.path, compaction_gen),
+            log_path(&self.path, compaction_gen),
+        )?;
+        Ok(compaction_len)
+    }
+}
+
+/// Returns the keys removed by the "remove" commands in a log file, and the keys
+/// whose values in the file are expired at `now`.
+fn removed_keys(dir: &Path, gen: u64, now: u64) -> Result<Vec<Vec<u8>>> {
+    let file = File::open(log_path(dir, gen))?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReaderWithPos::new(file)?;
+    let mut keys = Vec::new();
+    match LogFormat::detect(&mut reader)? {
+        LogFormat::Json => {
+            for cmd in Deserializer::from_reader(reader).into_iter::<JsonCommand>() {
+                if let Command::Remove { key } = cmd?.into() {
+                    keys.push(key);
+                }
+            }
+        }
+        LogFormat::Binary => {
+            let mut remaining = file_len.saturating_sub(reader.pos);
+            while let Some(cmd) = read_record(&mut reader, remaining)? {
+                remaining = file_len.saturating_sub(reader.pos);
+                match cmd {
+                    Command::Remove { key } => keys.push(key),
+                    Command::Set {
+                        key,
+                        expires_at: Some(expires_at),
+                        ..
+                    } if expires_at <= now => keys.push(key),
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// Path of the compaction file while it is being written.
+///
+/// It doesn't have the `log` extension, so an unfinished compaction file is never
+/// loaded.
+fn tmp_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.compacting", gen))
+}
+
+/// Removes the compaction files left by a compaction interrupted by a crash.
+pub(super) fn remove_unfinished(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("compacting".as_ref()) {
+            warn!("Removing unfinished compaction file {:?}", path);
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,676 @@
+//! The index from keys to the locations of their values.
+//!
+//! By default every key is kept in a skip list in memory. With `IndexMode::Disk`, the
+//! skip list only holds the keys written to the active log file. When the active log
+//! file is sealed, because it has grown over the size limit or a compaction starts,
+//! its keys are written to an index file (`<gen>.index`) sorted by key and dropped
+//! from memory. The keys removed in the active log file are remembered too, so that
+//! the older values in the sealed log files are not found.
+//!
+//! An index file holds the entries sorted by key, a sparse index with the offset of
+//! every `SPARSE_INTERVAL`th entry, a bloom filter of the keys and a footer. Only the
+//! sparse index and the bloom filter are kept in memory, and the entries are read
+//! through a memory map. A lookup checks the active keys first, then the index files
+//! from the newest generation to the oldest, skipping the files whose bloom filter
+//! rules the key out. A scan merges the active keys with every index file.
+//!
+//! The footer records the length of the log file the index was written for. If the
+//! index file is missing, or the log file has another length because the process
+//! crashed before the log was synced, the index file is rebuilt from the log file on
+//! open. A compaction merges the index files of the picked generations instead of
+//! walking the skip list (see the `compaction` module).
+//!
+//! Layouts, with integers in little endian:
+//!
+//! - entry: key length (u32) | state (u8) | pos (u64) | len (u64) | expiry (u64) | key
+//! - sparse index entry: entry offset (u64) | key length (u32) | key
+//! - bloom filter: number of hashes (u32) | bits
+//! - footer: log length | entry count | stale bytes | sparse index offset |
+//!   bloom filter offset (u64 each) | magic
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crossbeam_skiplist::SkipMap;
+use memmap::Mmap;
+
+use super::options::IndexMode;
+use super::{gen_usage, now_millis, CommandPos, GenUsage};
+use crate::engines::bloom::BloomFilter;
+use crate::Result;
+
+/// Magic number at the end of an index file.
+const INDEX_MAGIC: [u8; 4] = *b"KVSI";
+const ENTRY_HEADER_LEN: usize = 29;
+const FOOTER_LEN: usize = 44;
+/// Number of entries between two keys of the sparse index.
+const SPARSE_INTERVAL: u64 = 16;
+
+const STATE_LIVE: u8 = 0;
+const STATE_REMOVED: u8 = 1;
+
+/// State of a key after the commands in a log file.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum KeyState {
+    /// The key was last set by the command at the location.
+    Live(CommandPos),
+    /// The key was last removed.
+    Removed,
+}
+
+/// Index of the keys of a store, with the keys of the sealed log files on disk in
+/// disk mode.
+pub(super) struct KeyDir {
+    // every key in memory mode, and the keys written to the active log file in disk mode
+    pub(super) entries: SkipMap<Vec<u8>, CommandPos>,
+    disk: Option<DiskKeys>,
+    // held for reading by lookups and scans, and for writing while a batch is applied
+    batch: RwLock<()>,
+}
+
+struct DiskKeys {
+    path: Arc<PathBuf>,
+    max_file_size: u64,
+    // keys removed in the active log file
+    removed: SkipMap<Vec<u8>, ()>,
+    // index files of the sealed log files
+    indexes: RwLock<BTreeMap<u64, Arc<KeyIndex>>>,
+}
+
+impl KeyDir {
+    pub(super) fn new(path: Arc<PathBuf>, mode: IndexMode) -> KeyDir {
+        let disk = match mode {
+            IndexMode::Memory => None,
+            IndexMode::Disk { max_file_size } => Some(DiskKeys {
+                path,
+                max_file_size,
+                removed: SkipMap::new(),
+                indexes: RwLock::new(BTreeMap::new()),
+            }),
+        };
+        KeyDir {
+            entries: SkipMap::new(),
+            disk,
+            batch: RwLock::new(()),
+        }
+    }
+
+    /// Returns whether the keys of the sealed log files are kept on disk.
+    pub(super) fn is_disk(&self) -> bool {
+        self.disk.is_some()
+    }
+
+    /// Returns the location of the value of a key, which may be expired.
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        let _batch = self.batch.read().unwrap();
+        if let Some(entry) = self.entries.get(key) {
+            return Ok(Some(*entry.value()));
+        }
+        let disk = match &self.disk {
+            Some(disk) => disk,
+            None => return Ok(None),
+        };
+        if disk.removed.contains_key(key) {
+            return Ok(None);
+        }
+        for index in disk.indexes.read().unwrap().values().rev() {
+            match index.get(key)? {
+                Some(KeyState::Live(cmd_pos)) => return Ok(Some(cmd_pos)),
+                Some(KeyState::Removed) => return Ok(None),
+                None => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the location of the value of a key, unless the key is missing or expired.
+    pub(super) fn live(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        let now = now_millis();
+        Ok(self.get(key)?.filter(|cmd_pos| !cmd_pos.is_expired(now)))
+    }
+
+    /// Points a key to the value written to the active log file.
+    ///
+    /// It must be called with the writer lock held.
+    pub(super) fn insert(&self, key: Vec<u8>, cmd_pos: CommandPos) {
+        match &self.disk {
+            // The new location is inserted first, so a concurrent lookup never falls
+            // through to the value before the removal.
+            Some(disk) => {
+                self.entries.insert(key.clone(), cmd_pos);
+                disk.removed.remove(&key);
+            }
+            None => {
+                self.entries.insert(key, cmd_pos);
+            }
+        }
+    }
+
+    /// Removes a key in the active log file.
+    ///
+    /// It must be called with the writer lock held.
+    pub(super) fn remove(&self, key: &[u8]) {
+        if let Some(disk) = &self.disk {
+            disk.removed.insert(key.to_vec(), ());
+        }
+        self.entries.remove(key);
+    }
+
+    /// Points the keys written by a batch to their new locations, or removes them if
+    /// the location is `None`, in the order they are given.
+    ///
+    /// A lookup or a scan sees every write of the batch or none of them. It must be
+    /// called with the writer lock held.
+    pub(super) fn apply_batch(&self, writes: Vec<(Vec<u8>, Option<CommandPos>)>) {
+        let _batch = self.batch.write().unwrap();
+        for (key, cmd_pos) in writes {
+            match cmd_pos {
+                Some(cmd_pos) => self.insert(key, cmd_pos),
+                None => self.remove(&key),
+            }
+        }
+    }
+
+    /// Calls `f` with the keys from `start` on and the locations of their values, which
+    /// may be expired, in ascending key order until it returns `false`.
+    pub(super) fn scan<F>(&self, start: &[u8], mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], CommandPos) -> bool,
+    {
+        let _batch = self.batch.read().unwrap();
+        let disk = match &self.disk {
+            Some(disk) => disk,
+            None => {
+                for entry in self.entries.range(start.to_vec()..) {
+                    if !f(entry.key(), *entry.value()) {
+                        break;
+                    }
+                }
+                return Ok(());
+            }
+        };
+        // The read lock is held during the scan, so no log file is sealed, which would
+        // move its keys from memory to an index file the scan has already passed.
+        let indexes = disk.indexes.read().unwrap();
+        let mut sources: Vec<Source> = vec![
+            Box::new(
+                self.entries
+                    .range(start.to_vec()..)
+                    .map(|entry| Ok((entry.key().clone(), KeyState::Live(*entry.value())))),
+            ),
+            Box::new(
+                disk.removed
+                    .range(start.to_vec()..)
+                    .map(|entry| Ok((entry.key().clone(), KeyState::Removed))),
+            ),
+        ];
+        for index in indexes.values().rev() {
+            sources.push(Box::new(index.iter_from(start)?));
+        }
+        for entry in Merge::new(sources)? {
+            let (key, versions) = entry?;
+            // the first source holding the key is the newest
+            if let KeyState::Live(cmd_pos) = versions[0].1 {
+                if !f(&key, cmd_pos) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the active log file has grown enough to be sealed.
+    pub(super) fn should_seal(&self, len: u64) -> bool {
+        self.disk
+            .as_ref()
+            .is_some_and(|disk| len >= disk.max_file_size)
+    }
+
+    /// Writes the index file of the active log file of `gen`, which is `log_len` bytes
+    /// long and has `stale` bytes of stale commands, and drops its keys from memory.
+    ///
+    /// Nothing is done in memory mode. It must be called with the writer lock held.
+    pub(super) fn seal(&self, gen: u64, log_len: u64, stale: u64) -> Result<()> {
+        let disk = match &self.disk {
+            Some(disk) => disk,
+            None => return Ok(()),
+        };
+        let count = self.entries.iter().count() + disk.removed.iter().count();
+        let mut writer = IndexWriter::create(&disk.path, gen, count as u64)?;
+        let sources: Vec<Source> = vec![
+            Box::new(
+                self.entries
+                    .iter()
+                    .map(|entry| Ok((entry.key().clone(), KeyState::Live(*entry.value())))),
+            ),
+            Box::new(
+                disk.removed
+                    .iter()
+                    .map(|entry| Ok((entry.key().clone(), KeyState::Removed))),
+            ),
+        ];
+        for entry in Merge::new(sources)? {
+            let (key, versions) = entry?;
+            writer.add(&key, versions[0].1)?;
+        }
+        let index = writer.finish(log_len, stale)?;
+        // The index file is visible before the keys leave memory, so lookups always
+        // find them in one of both.
+        disk.indexes.write().unwrap().insert(gen, Arc::new(index));
+        for entry in self.entries.iter() {
+            self.entries.remove(entry.key());
+        }
+        for entry in disk.removed.iter() {
+            disk.removed.remove(entry.key());
+        }
+        Ok(())
+    }
+
+    /// Adds the index file of a sealed log file loaded on open.
+    pub(super) fn add_sealed(&self, index: KeyIndex) {
+        if let Some(disk) = &self.disk {
+            disk.indexes
+                .write()
+                .unwrap()
+                .insert(index.gen, Arc::new(index));
+        }
+    }
+
+    /// Returns the index files of the sealed generations before `gen` in ascending
+    /// generation order.
+    pub(super) fn sealed_before(&self, gen: u64) -> Vec<Arc<KeyIndex>> {
+        match &self.disk {
+            Some(disk) => disk
+                .indexes
+                .read()
+                .unwrap()
+                .range(..gen)
+                .map(|(_, index)| Arc::clone(index))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Replaces the index files of the compacted generations with the one of the
+    /// compaction file.
+    ///
+    /// It must be called with the writer lock held.
+    pub(super) fn replace_sealed(&self, compacted: &BTreeSet<u64>, index: Arc<KeyIndex>) {
+        if let Some(disk) = &self.disk {
+            let mut indexes = disk.indexes.write().unwrap();
+            indexes.retain(|gen, _| !compacted.contains(gen));
+            indexes.insert(index.gen, index);
+        }
+    }
+
+    /// Counts the values shadowed by a newer generation or expired at `now` as stale in
+    /// `usage`.
+    ///
+    /// The stale commands within a log file are already counted in its index file.
+    pub(super) fn count_stale(&self, usage: &mut BTreeMap<u64, GenUsage>, now: u64) -> Result<()> {
+        let indexes = self.sealed_before(u64::MAX);
+        for entry in Merge::of_indexes(&indexes)? {
+            let (_, versions) = entry?;
+            let (newest, older) = versions.split_last().unwrap();
+            let expired = match newest.1 {
+                KeyState::Live(cmd_pos) if cmd_pos.is_expired(now) => Some(&newest.1),
+                _ => None,
+            };
+            for state in older.iter().map(|(_, state)| state).chain(expired) {
+                if let KeyState::Live(cmd_pos) = state {
+                    gen_usage(usage, cmd_pos.gen).stale += cmd_pos.len;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes an index file. The keys must be added in ascending order.
+///
+/// The file is written to a temporary path and renamed into place once it is synced.
+pub(super) struct IndexWriter {
+    writer: BufWriter<File>,
+    dir: PathBuf,
+    gen: u64,
+    len: u64,
+    count: u64,
+    sparse: Vec<(Vec<u8>, u64)>,
+    bloom: BloomFilter,
+}
+
+impl IndexWriter {
+    /// Creates the index file of `gen`, sizing the bloom filter for `expected_entries`.
+    pub(super) fn create(dir: &Path, gen: u64, expected_entries: u64) -> Result<IndexWriter> {
+        Ok(IndexWriter {
+            writer: BufWriter::new(File::create(tmp_path(dir, gen))?),
+            dir: dir.to_owned(),
+            gen,
+            len: 0,
+            count: 0,
+            sparse: Vec::new(),
+            bloom: BloomFilter::new(expected_entries),
+        })
+    }
+
+    pub(super) fn add(&mut self, key: &[u8], state: KeyState) -> Result<()> {
+        if self.count.is_multiple_of(SPARSE_INTERVAL) {
+            self.sparse.push((key.to_vec(), self.len));
+        }
+        self.bloom.insert(key);
+        let (kind, cmd_pos) = match state {
+            KeyState::Live(cmd_pos) => (STATE_LIVE, cmd_pos),
+            KeyState::Removed => (STATE_REMOVED, CommandPos::from((self.gen, 0..0))),
+        };
+        let mut header = [0u8; ENTRY_HEADER_LEN];
+        header[..4].copy_from_slice(&(key.len() as u32).to_le_bytes());
+        header[4] = kind;
+        header[5..13].copy_from_slice(&cmd_pos.pos.to_le_bytes());
+        header[13..21].copy_from_slice(&cmd_pos.len.to_le_bytes());
+        header[21..].copy_from_slice(&cmd_pos.expires_at.unwrap_or(0).to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(key)?;
+        self.len += (ENTRY_HEADER_LEN + key.len()) as u64;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Finishes the index file of a log file of `log_len` bytes with `stale` bytes of
+    /// stale commands, and opens it.
+    pub(super) fn finish(mut self, log_len: u64, stale: u64) -> Result<KeyIndex> {
+        let sparse_pos = self.len;
+        for (key, offset) in &self.sparse {
+            self.writer.write_all(&offset.to_le_bytes())?;
+            self.writer.write_all(&(key.len() as u32).to_le_bytes())?;
+            self.writer.write_all(key)?;
+            self.len += 12 + key.len() as u64;
+        }
+        let bloom_pos = self.len;
+        self.bloom.write_to(&mut self.writer)?;
+        for field in &[log_len, self.count, stale, sparse_pos, bloom_pos] {
+            self.writer.write_all(&field.to_le_bytes())?;
+        }
+        self.writer.write_all(&INDEX_MAGIC)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        drop(self.writer);
+        fs::rename(
+            tmp_path(&self.dir, self.gen),
+            index_path(&self.dir, self.gen),
+        )?;
+        let gen = self.gen;
+        KeyIndex::open(&self.dir, gen, log_len)?.ok_or_else(|| invalid_index(gen).into())
+    }
+}
+
+/// The index file of a sealed log file.
+pub(super) struct KeyIndex {
+    gen: u64,
+    map: Mmap,
+    // the entries take the beginning of the file up to here
+    entries_end: usize,
+    sparse: Vec<(Vec<u8>, usize)>,
+    bloom: BloomFilter,
+    count: u64,
+    stale: u64,
+}
+
+impl KeyIndex {
+    /// Opens the index file of `gen` written for a log file of `log_len` bytes.
+    ///
+    /// Returns `None` if the file is missing, incomplete or written for another length.
+    pub(super) fn open(dir: &Path, gen: u64, log_len: u64) -> Result<Option<KeyIndex>> {
+        let file = match File::open(index_path(dir, gen)) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if file.metadata()?.len() < FOOTER_LEN as u64 {
+            return Ok(None);
+        }
+        // Index files are never written again once renamed into place. They are only
+        // deleted, which leaves the mapped pages intact.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(KeyIndex::parse(gen, map).filter(|index| index.log_len() == log_len))
+    }
+
+    fn parse(gen: u64, map: Mmap) -> Option<KeyIndex> {
+        let footer_pos = map.len() - FOOTER_LEN;
+        let footer = &map[footer_pos..];
+        if footer[40..] != INDEX_MAGIC {
+            return None;
+        }
+        let field = |i: usize| read_u64(footer, i * 8);
+        let (count, stale) = (field(1)?, field(2)?);
+        let (sparse_pos, bloom_pos) = (field(3)? as usize, field(4)? as usize);
+        if sparse_pos > bloom_pos || bloom_pos + 4 > footer_pos {
+            return None;
+        }
+
+        let mut sparse = Vec::new();
+        let mut pos = sparse_pos;
+        while pos < bloom_pos {
+            let offset = read_u64(&map, pos)? as usize;
+            let key_len = read_u32(&map, pos + 8)? as usize;
+            let key = map.get(pos + 12..pos + 12 + key_len)?;
+            sparse.push((key.to_vec(), offset));
+            pos += 12 + key_len;
+        }
+        let bloom = BloomFilter::decode(&map[bloom_pos..footer_pos])?;
+        Some(KeyIndex {
+            gen,
+            map,
+            entries_end: sparse_pos,
+            sparse,
+            bloom,
+            count,
+            stale,
+        })
+    }
+
+    fn log_len(&self) -> u64 {
+        read_u64(&self.map, self.map.len() - FOOTER_LEN).unwrap_or(0)
+    }
+
+    pub(super) fn gen(&self) -> u64 {
+        self.gen
+    }
+
+    /// Number of keys in the index.
+    pub(super) fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Bytes of the commands in the log file overwritten within the file, and of the
+    /// "remove" and batch commands.
+    pub(super) fn stale(&self) -> u64 {
+        self.stale
+    }
+
+    /// Returns the state of a key in the log file, or `None` if the file doesn't hold it.
+    fn get(&self, key: &[u8]) -> Result<Option<KeyState>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        for entry in self.iter_from(key)?.take(1) {
+            let (entry_key, state) = entry?;
+            if entry_key == key {
+                return Ok(Some(state));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns an iterator over the entries.
+    pub(super) fn iter(&self) -> Result<IndexIter<'_>> {
+        self.iter_from(&[])
+    }
+
+    /// Returns an iterator over the entries with keys from `start` on.
+    fn iter_from(&self, start: &[u8]) -> Result<IndexIter<'_>> {
+        // only the entries after the last sparse key not greater than `start` can match
+        let block = self
+            .sparse
+            .partition_point(|(key, _)| key.as_slice() <= start);
+        let mut pos = match block {
+            0 => 0,
+            block => self.sparse[block - 1].1,
+        };
+        while pos < self.entries_end {
+            let (key, _, next) = self.entry_at(pos)?;
+            if key >= start {
+                break;
+            }
+            pos = next;
+        }
+        Ok(IndexIter { index: self, pos })
+    }
+
+    /// Decodes the entry at `pos`, returning the key, its state and the next offset.
+    fn entry_at(&self, pos: usize) -> Result<(&[u8], KeyState, usize)> {
+        let decode = || {
+            let header = self.map.get(pos..pos + ENTRY_HEADER_LEN)?;
+            let key_len = read_u32(header, 0)? as usize;
+            let key = self
+                .map
+                .get(pos + ENTRY_HEADER_LEN..pos + ENTRY_HEADER_LEN + key_len)?;
+            let state = match header[4] {
+                STATE_LIVE => {
+                    let range = read_u64(header, 5)?..read_u64(header, 5)? + read_u64(header, 13)?;
+                    let expires_at = Some(read_u64(header, 21)?).filter(|&at| at != 0);
+                    KeyState::Live(CommandPos::from((self.gen, range)).expiring(expires_at))
+                }
+                STATE_REMOVED => KeyState::Removed,
+                _ => return None,
+            };
+            Some((key, state, pos + ENTRY_HEADER_LEN + key_len))
+        };
+        decode().ok_or_else(|| invalid_index(self.gen).into())
+    }
+}
+
+/// Iterator over the entries of an index file in ascending key order.
+pub(super) struct IndexIter<'a> {
+    index: &'a KeyIndex,
+    pos: usize,
+}
+
+impl<'a> Iterator for IndexIter<'a> {
+    type Item = Result<(Vec<u8>, KeyState)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.index.entries_end {
+            return None;
+        }
+        match self.index.entry_at(self.pos) {
+            Ok((key, state, next)) => {
+                self.pos = next;
+                Some(Ok((key.to_vec(), state)))
+            }
+            Err(e) => {
+                self.pos = self.index.entries_end;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// A sorted sequence of keys and their states.
+pub(super) type Source<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, KeyState)>> + 'a>;
+
+/// Merges sorted sources of keys.
+///
+/// Every key is yielded once, with its states in the sources holding it, in the order
+/// of the sources.
+pub(super) struct Merge<'a> {
+    sources: Vec<Source<'a>>,
+    heads: Vec<Option<(Vec<u8>, KeyState)>>,
+}
+
+impl<'a> Merge<'a> {
+    pub(super) fn new(mut sources: Vec<Source<'a>>) -> Result<Merge<'a>> {
+        let heads = sources
+            .iter_mut()
+            .map(|source| source.next().transpose())
+            .collect::<Result<_>>()?;
+        Ok(Merge { sources, heads })
+    }
+
+    /// Creates a merge of the index files in ascending generation order.
+    pub(super) fn of_indexes(indexes: &'a [Arc<KeyIndex>]) -> Result<Merge<'a>> {
+        let sources = indexes
+            .iter()
+            .map(|index| Ok(Box::new(index.iter_from(&[])?) as Source))
+            .collect::<Result<Vec<_>>>()?;
+        Merge::new(sources)
+    }
+}
+
+impl<'a> Iterator for Merge<'a> {
+    type Item = Result<(Vec<u8>, Vec<(usize, KeyState)>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self
+            .heads
+            .iter()
+            .filter_map(|head| head.as_ref().map(|(key, _)| key))
+            .min()?
+            .clone();
+        let mut states = Vec::new();
+        for (i, head) in self.heads.iter_mut().enumerate() {
+            if head.as_ref().is_some_and(|(head_key, _)| *head_key == key) {
+                states.push((i, head.take().unwrap().1));
+                *head = match self.sources[i].next().transpose() {
+                    Ok(next) => next,
+                    Err(e) => return Some(Err(e)),
+                };
+            }
+        }
+        Some(Ok((key, states)))
+    }
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(buf.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+fn invalid_index(gen: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("index file of generation {} is corrupted", gen),
+    )
+}
+
+pub(super) fn index_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.index", gen))
+}
+
+/// Path of an index file while it is being written.
+fn tmp_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.indexing", gen))
+}
+
+/// Removes the index files being written when the process crashed, and the index
+/// files of log files that no longer exist.
+pub(super) fn remove_stale(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let stale = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("indexing") => true,
+            Some("index") => !path.with_extension("log").exists(),
+            _ => false,
+        };
+        if stale && path.is_file() {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+//! Memory maps of the sealed log files.
+//!
+//! If `KvStoreOptions::mmap` is set, the readers share a read-only memory map of every
+//! sealed log file instead of opening their own file handles, so reading a command is
+//! a slice copy. The active log file still grows and is read through file handles.
+//!
+//! Like the file handles, the maps are dropped once a compaction finishes (see
+//! `KvStoreReader::close_stale_handles`) and created again on demand. A read in
+//! progress keeps its map alive, so the deleted files are unmapped when the last
+//! read of them finishes.
+
+use std::collections::BTreeMap;
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use memmap::Mmap;
+
+use super::record::LogFormat;
+use super::{snapshot, CommandPos};
+use crate::Result;
+
+/// A memory-mapped sealed log file.
+pub(super) struct LogMap {
+    pub(super) format: LogFormat,
+    map: Mmap,
+}
+
+impl LogMap {
+    /// Returns the bytes of the command at `cmd_pos`.
+    pub(super) fn command(&self, cmd_pos: CommandPos) -> Result<&[u8]> {
+        let start = cmd_pos.pos as usize;
+        let end = start + cmd_pos.len as usize;
+        self.map
+            .get(start..end)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+}
+
+/// The memory maps shared by the readers of a store.
+pub(super) struct LogMaps {
+    path: Arc<PathBuf>,
+    // the active log file and newer ones are not mapped
+    active_gen: AtomicU64,
+    // the compaction epoch the maps were created in, and the maps
+    maps: RwLock<(u64, BTreeMap<u64, Arc<LogMap>>)>,
+}
+
+impl LogMaps {
+    pub(super) fn new(path: Arc<PathBuf>, active_gen: u64) -> LogMaps {
+        LogMaps {
+            path,
+            active_gen: AtomicU64::new(active_gen),
+            maps: RwLock::new((0, BTreeMap::new())),
+        }
+    }
+
+    /// Marks the log files before `gen` as sealed.
+    pub(super) fn seal_before(&self, gen: u64) {
+        self.active_gen.store(gen, Ordering::SeqCst);
+    }
+
+    /// Returns the map of the log file of `gen`, mapping it if needed.
+    ///
+    /// Returns `None` if the log file is not sealed yet.
+    pub(super) fn get(&self, gen: u64) -> Result<Option<Arc<LogMap>>> {
+        if gen >= self.active_gen.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        if let Some(map) = self.maps.read().unwrap().1.get(&gen) {
+            return Ok(Some(Arc::clone(map)));
+        }
+
+        let file = snapshot::open_log(&self.path, gen)?;
+        // Sealed log files are never written again. They are only deleted or renamed,
+        // which leaves the mapped pages intact.
+        let map = unsafe { Mmap::map(&file)? };
+        let format = LogFormat::detect(&mut Cursor::new(&map[..]))?;
+        let map = Arc::new(LogMap { format, map });
+        let mut maps = self.maps.write().unwrap();
+        Ok(Some(Arc::clone(maps.1.entry(gen).or_insert(map))))
+    }
+
+    /// Drops the maps created before the compaction `epoch` finished.
+    pub(super) fn unmap_before(&self, epoch: u64) {
+        let mut maps = self.maps.write().unwrap();
+        if maps.0 < epoch {
+            *maps = (epoch, BTreeMap::new());
+        }
+    }
+}
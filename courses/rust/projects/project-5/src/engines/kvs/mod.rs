@@ -0,0 +1,1528 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use crossbeam::queue::ArrayQueue;
+use crossbeam_skiplist::SkipMap;
+use serde_json::Deserializer;
+use tokio::prelude::*;
+use tokio::sync::oneshot;
+
+pub use self::cache::CacheStats;
+use self::cache::ValueCache;
+pub use self::compaction::CompactionStats;
+use self::compaction::{CompactionHandle, CompactionProgress, CompactionTask, Compactor};
+use self::keydir::{IndexWriter, KeyDir, KeyIndex, KeyState};
+use self::mmap::LogMaps;
+pub use self::options::{
+    Compression, IndexMode, KvStoreOptions, SyncPolicy, GROUP_COMMIT_MAX_DELAY,
+};
+pub use self::policy::{CompactionPolicy, CompactionWindow, GenUsage, ThresholdPolicy};
+use self::record::{
+    read_command, read_record, write_file_header, write_record, Command, JsonCommand, LogFormat,
+    FILE_HEADER_LEN,
+};
+pub use self::snapshot::Snapshot;
+use self::snapshot::Snapshots;
+use super::{
+    expires_after, expiry_time, now_millis, scan_pages, BatchOp, CasResult, KvsEngine, WriteBatch,
+};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result};
+
+mod backup;
+mod cache;
+mod compaction;
+mod keydir;
+mod mmap;
+mod options;
+mod policy;
+mod record;
+mod snapshot;
+
+/// The `KvStore` stores key/value pairs of arbitrary bytes.
+///
+/// Key/value pairs are persisted to disk in log files. Log files are named after
+/// monotonically increasing generation numbers with a `log` extension name.
+/// A skip list in memory stores the keys and the value locations for fast query.
+/// With `IndexMode::Disk`, it only stores the keys of the active log file, and the
+/// keys of the sealed log files are looked up in sorted index files on disk (see the
+/// `keydir` module).
+///
+/// Commands are stored as checksummed binary records (see the `record` module for
+/// the layout). Log files written as json by older versions are still readable and
+/// are rewritten in the binary format during compaction.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # use kvs::thread_pool::{ThreadPool, RayonThreadPool};
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let mut store: KvStore<RayonThreadPool> = KvStore::open(current_dir()?, 2)?;
+/// store.set("key".to_owned(), "value".to_owned()).wait()?;
+/// let val = store.get("key".to_owned()).wait()?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvStore<P: ThreadPool> {
+    // directory for the log and other data
+    path: Arc<PathBuf>,
+    // map generation number to the file reader
+    index: Arc<KeyDir>,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    thread_pool: P,
+    reader_pool: Arc<ArrayQueue<KvStoreReader>>,
+    compaction: Arc<CompactionHandle>,
+    snapshots: Arc<Snapshots>,
+    cache: Arc<ValueCache>,
+}
+
+impl<P: ThreadPool> KvStore<P> {
+    /// Opens a `KvStore` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// `concurrency` specifies how many threads at most can read the database at the same time.
+    ///
+    /// If the process crashed in the middle of writing a command, the newest log file
+    /// ends with a torn command. The torn command is dropped and the file is truncated
+    /// back to the end of the last complete command.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Corrupted` if a command that is not at the tail of the log
+    /// cannot be decoded or doesn't match its checksum.
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        Self::open_with_options(path, concurrency, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// See `open` for details.
+    ///
+    /// A background thread is started to compact the log, and if the sync policy groups
+    /// writes, another one is started to sync the log. They stop after every clone of
+    /// the `KvStore` is dropped.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
+        let path = Arc::new(path.into());
+        fs::create_dir_all(&*path)?;
+        compaction::remove_unfinished(&path)?;
+        snapshot::remove_retired(&path)?;
+        keydir::remove_stale(&path)?;
+
+        let mut readers = BTreeMap::new();
+        let index = Arc::new(KeyDir::new(Arc::clone(&path), options.index));
+
+        let gen_list = sorted_gen_list(&path)?;
+        let mut usage = BTreeMap::new();
+        // the values expired by now are counted as stale while loading
+        let now = now_millis();
+
+        for (i, &gen) in gen_list.iter().enumerate() {
+            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
+            let torn_at = if index.is_disk() {
+                load_sealed(&path, gen, &mut reader, &index, &mut usage)?
+            } else {
+                load(gen, &mut reader, &index.entries, &mut usage, now)?
+            };
+            if let Some(valid_len) = torn_at {
+                // Only the log file being written when the process crashed can end with
+                // a torn command. The files after it (the active log file created by an
+                // interrupted compaction) contain no commands yet.
+                if !only_headers(&path, &gen_list[i + 1..])? {
+                    return Err(KvsError::Corrupted {
+                        gen,
+                        pos: valid_len,
+                    });
+                }
+                truncate_log(&path, gen, valid_len)?;
+                gen_usage(&mut usage, gen).len = valid_len;
+            }
+            readers.insert(gen, reader);
+        }
+        index.count_stale(&mut usage, now)?;
+        // the values expiring later are counted once they expire
+        let mut expiring = BTreeSet::new();
+        index.scan(&[], |key, cmd_pos| {
+            if let Some(expires_at) = cmd_pos.expires_at.filter(|&at| at > now) {
+                expiring.insert((expires_at, key.to_vec()));
+            }
+            true
+        })?;
+
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let writer = new_log_file(&path, current_gen)?;
+        gen_usage(&mut usage, current_gen).len = writer.pos;
+
+        let maps = if options.mmap {
+            Some(Arc::new(LogMaps::new(Arc::clone(&path), current_gen)))
+        } else {
+            None
+        };
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            epoch: Arc::new(AtomicU64::new(0)),
+            seen_epoch: Cell::new(0),
+            readers: RefCell::new(BTreeMap::new()),
+            maps: maps.clone(),
+        };
+
+        let (group_commit_tx, group_commit_rx) = channel::bounded(1);
+        let (compaction_tx, compaction_rx) = channel::unbounded();
+        let progress = Arc::new(CompactionProgress::default());
+        let snapshots = Arc::new(Snapshots::new(Arc::clone(&path)));
+        let cache = Arc::new(ValueCache::new(options.cache_capacity));
+        let writer = KvStoreWriter {
+            writer,
+            current_gen,
+            usage,
+            expiring,
+            policy: options.compaction,
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            sync: options.sync,
+            compression: options.compression,
+            pending_syncs: Vec::new(),
+            group_commit_tx,
+            compaction_tx,
+            progress: Arc::clone(&progress),
+            snapshots: Arc::clone(&snapshots),
+            cache: Arc::clone(&cache),
+            maps,
+        };
+        let writer = Arc::new(Mutex::new(writer));
+        let compactor = Compactor {
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            reader: reader.clone(),
+            writer: Arc::downgrade(&writer),
+            progress: Arc::clone(&progress),
+            snapshots: Arc::clone(&snapshots),
+            cache: Arc::clone(&cache),
+            compression: options.compression,
+        };
+        let compaction = Arc::new(CompactionHandle::spawn(compactor, compaction_rx, progress)?);
+        match options.sync {
+            SyncPolicy::EveryN(_) | SyncPolicy::Interval(_) => {
+                let writer = Arc::downgrade(&writer);
+                let sync = options.sync;
+                thread::Builder::new()
+                    .name("kvs-group-commit".to_owned())
+                    .spawn(move || run_group_commit(writer, group_commit_rx, sync))?;
+            }
+            SyncPolicy::Always | SyncPolicy::Never => {}
+        }
+
+        let thread_pool = P::new(concurrency)?;
+        let reader_pool = Arc::new(ArrayQueue::new(concurrency as usize));
+        for _ in 1..concurrency {
+            reader_pool.push(reader.clone()).unwrap();
+        }
+        reader_pool.push(reader).unwrap();
+
+        Ok(KvStore {
+            path,
+            index,
+            writer,
+            thread_pool,
+            reader_pool,
+            compaction,
+            snapshots,
+            cache,
+        })
+    }
+
+    /// Takes a snapshot of the store.
+    ///
+    /// The snapshot sees every write finished before this call and none after.
+    pub fn snapshot(&self) -> Snapshot<P> {
+        // writes update the index with the writer lock held
+        let _writer = self.writer.lock().unwrap();
+        let lsn = self.snapshots.pin();
+        Snapshot::new(self.clone(), lsn)
+    }
+
+    /// Restores the backup in `backup_dir`, written by `backup_to`, to `path`.
+    ///
+    /// This will create a new directory if the given one does not exist. It must not
+    /// contain log files already.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidBackup` if the manifest is missing or a log file in
+    /// the backup is missing or doesn't match its checksum. Nothing is written in that
+    /// case.
+    pub fn restore(backup_dir: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<()> {
+        backup::restore(backup_dir.as_ref(), path.as_ref())
+    }
+
+    /// Returns the statistics and progress of the background compaction.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.compaction.stats()
+    }
+
+    /// Returns a future that resolves once the running background compaction, if any,
+    /// finishes.
+    ///
+    /// A compaction the policy picks after a write is already running when the write
+    /// resolves.
+    pub fn wait_for_compaction(&self) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        Box::new(
+            self.compaction
+                .wait()
+                .map_err(|_| KvsError::StringError("Compaction thread exited".to_owned())),
+        )
+    }
+
+    /// Returns the hit and miss counters and the size of the value cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Returns the space usage of every log file in ascending generation order.
+    ///
+    /// The last one is the active log file.
+    pub fn gen_usage(&self) -> Vec<GenUsage> {
+        let writer = self.writer.lock().unwrap();
+        writer.usage.values().copied().collect()
+    }
+
+    /// Streams the values of the index entries picked by `pick`, reading them a page at
+    /// a time on the thread pool.
+    ///
+    /// `pick` is called with the key a page starts from and the maximum number of
+    /// entries in it. It returns the keys and value locations in the order they are
+    /// yielded, and the key the next page starts from.
+    fn read_entries<F>(
+        &self,
+        start: Vec<u8>,
+        limit: Option<usize>,
+        pick: F,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>
+    where
+        F: Fn(&[u8], usize) -> Result<EntryPage> + Send + Sync + 'static,
+    {
+        let reader_pool = self.reader_pool.clone();
+        scan_pages(&self.thread_pool, start, limit, move |start, max| {
+            let (entries, next) = pick(start, max)?;
+            let reader = reader_pool.pop().unwrap();
+            let pairs = entries
+                .into_iter()
+                .map(|(key, cmd_pos)| Ok((key, reader.read_value(cmd_pos)?)))
+                .collect::<Result<Vec<_>>>();
+            reader_pool.push(reader).unwrap();
+            Ok((pairs?, next))
+        })
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for KvStore<P> {
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let mut writer = writer.lock().unwrap();
+            match writer.set(key, value, None) {
+                Ok(()) => writer.ack(tx),
+                Err(e) => {
+                    if tx.send(Err(e)).is_err() {
+                        error!("Receiving end is dropped");
+                    }
+                }
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Sets the value of a key that expires after `ttl`.
+    ///
+    /// The expiry time is stored in the log. Expired values are dropped by the next
+    /// compaction of their log file.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let expires_at = expires_after(ttl);
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let mut writer = writer.lock().unwrap();
+            match writer.set(key, value, Some(expires_at)) {
+                Ok(()) => writer.ack(tx),
+                Err(e) => {
+                    if tx.send(Err(e)).is_err() {
+                        error!("Receiving end is dropped");
+                    }
+                }
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist or is expired.
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        let cache = self.cache.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = (|| {
+                if let Some(cmd_pos) = index.live(&key)? {
+                    if let Some(value) = cache.get(&key, cmd_pos) {
+                        return Ok(Some(value));
+                    }
+                    let reader = reader_pool.pop().unwrap();
+                    let res = reader.read_value(cmd_pos);
+                    reader_pool.push(reader).unwrap();
+                    let value = res?;
+                    cache.insert(&key, cmd_pos, &value);
+                    Ok(Some(value))
+                } else {
+                    Ok(None)
+                }
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Gets the expiry time of a given key, read from the index.
+    fn expiry_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<SystemTime>, Error = KvsError> + Send> {
+        let index = self.index.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = index.live(&key).and_then(|cmd_pos| {
+                let cmd_pos = cmd_pos.ok_or(KvsError::KeyNotFound)?;
+                Ok(cmd_pos.expires_at.map(expiry_time))
+            });
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Error
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let mut writer = writer.lock().unwrap();
+            match writer.remove(key) {
+                Ok(()) => writer.ack(tx),
+                Err(e) => {
+                    if tx.send(Err(e)).is_err() {
+                        error!("Receiving end is dropped");
+                    }
+                }
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Atomically replaces the value of a key if it currently equals `expected`.
+    ///
+    /// The current value is read and the new one is written while holding the writer
+    /// lock, so no other write can come in between.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = CasResult<Vec<u8>>, Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let index = self.index.clone();
+        let reader_pool = self.reader_pool.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let mut writer = writer.lock().unwrap();
+            let res = (|| {
+                let current = match index.live(&key)? {
+                    Some(cmd_pos) => {
+                        let reader = reader_pool.pop().unwrap();
+                        let res = reader.read_value(cmd_pos);
+                        reader_pool.push(reader).unwrap();
+                        Some(res?)
+                    }
+                    None => None,
+                };
+                if current != expected {
+                    return Ok(Err(current));
+                }
+                let (ack_tx, ack_rx) = oneshot::channel();
+                match new {
+                    Some(value) => writer.set(key, value, None)?,
+                    None if current.is_some() => writer.remove(key)?,
+                    // removing a key that doesn't exist writes nothing
+                    None => {
+                        let _ = ack_tx.send(Ok(()));
+                        return Ok(Ok(ack_rx));
+                    }
+                }
+                writer.ack(ack_tx);
+                Ok(Ok(ack_rx))
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten()
+                .and_then(|res| match res {
+                    Ok(ack_rx) => future::Either::A(
+                        ack_rx
+                            .map_err(|e| KvsError::StringError(format!("{}", e)))
+                            .flatten()
+                            .map(Ok),
+                    ),
+                    Err(current) => future::Either::B(future::ok(Err(current))),
+                }),
+        )
+    }
+
+    /// Applies all writes in a batch atomically.
+    ///
+    /// The batch is written to the log as a batch record followed by its commands,
+    /// and the log is flushed once for the whole batch.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key that is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let mut writer = writer.lock().unwrap();
+            match writer.write_batch(batch) {
+                Ok(()) => writer.ack(tx),
+                Err(e) => {
+                    if tx.send(Err(e)).is_err() {
+                        error!("Receiving end is dropped");
+                    }
+                }
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Scans the key/value pairs with keys in the range `[start, end)`.
+    ///
+    /// Expired keys are skipped.
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        self.read_entries(start, limit, pick_range(self.index.clone(), end))
+    }
+
+    /// Scans the keys in the range `[start, end)` from the index, without reading
+    /// the values.
+    fn scan_keys_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = KvsError> + Send> {
+        let pick = pick_range(self.index.clone(), end);
+        scan_pages(&self.thread_pool, start, limit, move |start, max| {
+            let (entries, next) = pick(start, max)?;
+            Ok((entries.into_iter().map(|(key, _)| key).collect(), next))
+        })
+    }
+
+    /// Scans the key/value pairs whose keys start with `prefix`.
+    ///
+    /// Expired keys are skipped.
+    fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        let index = self.index.clone();
+        self.read_entries(prefix.clone(), None, move |start, max| {
+            let now = now_millis();
+            let mut entries = Vec::new();
+            let mut next = None;
+            index.scan(start, |key, cmd_pos| {
+                if !key.starts_with(&prefix) {
+                    return false;
+                }
+                if entries.len() >= max {
+                    next = Some(key.to_vec());
+                    return false;
+                }
+                if !cmd_pos.is_expired(now) {
+                    entries.push((key.to_vec(), cmd_pos));
+                }
+                true
+            })?;
+            Ok((entries, next))
+        })
+    }
+
+    /// Compacts every log file, ignoring the compaction policy.
+    ///
+    /// The active log file is sealed and compacted too. The returned future resolves
+    /// once the compaction finishes, after any compaction already running.
+    fn compact(&self) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = writer.lock().unwrap().compact(None);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten()
+                .and_then(|done| {
+                    done.map_err(|e| KvsError::StringError(format!("{}", e)))
+                        .flatten()
+                }),
+        )
+    }
+
+    /// Backs up the log files to the directory `dir` while the store keeps serving.
+    ///
+    /// The backup holds every write finished before this call. The sealed log files are
+    /// hard linked if `dir` is on the same file system, and a manifest listing the
+    /// checksums of the log files is written last. Use `KvStore::restore` to restore it.
+    fn backup_to(&self, dir: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let path = self.path.clone();
+        let writer = self.writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = backup::backup(&path, &writer, &dir);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+}
+
+/// The keys and value locations of a page of a scan, and the key the next page starts
+/// from.
+type EntryPage = (Vec<(Vec<u8>, CommandPos)>, Option<Vec<u8>>);
+
+/// A single thread reader.
+///
+/// Each `KvStore` instance has its own `KvStoreReader` and
+/// `KvStoreReader`s open the same files separately. So the user
+/// can read concurrently through multiple `KvStore`s in different
+/// threads.
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    // number of compactions finished, shared by all readers
+    epoch: Arc<AtomicU64>,
+    // `epoch` when the handles of this reader were last cleared
+    seen_epoch: Cell<u64>,
+    readers: RefCell<BTreeMap<u64, (LogFormat, BufReaderWithPos<File>)>>,
+    // memory maps of the sealed log files shared by all readers, if enabled
+    maps: Option<Arc<LogMaps>>,
+}
+
+impl KvStoreReader {
+    /// Close all file handles if a compaction has finished since they were opened.
+    ///
+    /// `epoch` is increased after a compaction has swapped the index entries of the
+    /// compacted generations to the compaction file, so the in-memory index contains no
+    /// entries pointing to the compacted files anymore. Not every older generation is
+    /// compacted, so the handles still in use are simply reopened on demand, and the
+    /// stale files can be deleted. The shared memory maps are dropped in the same way.
+    fn close_stale_handles(&self) {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        if self.seen_epoch.get() != epoch {
+            self.readers.borrow_mut().clear();
+            if let Some(maps) = &self.maps {
+                maps.unmap_before(epoch);
+            }
+            self.seen_epoch.set(epoch);
+        }
+    }
+
+    /// Read the log file at the given `CommandPos`.
+    ///
+    /// `f` also receives the format of the log file the command is written in.
+    /// Sealed log files are read from their memory maps if they are enabled.
+    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(LogFormat, &mut dyn Read) -> Result<R>,
+    {
+        self.close_stale_handles();
+
+        if let Some(maps) = &self.maps {
+            if let Some(map) = maps.get(cmd_pos.gen)? {
+                return f(map.format, &mut map.command(cmd_pos)?);
+            }
+        }
+
+        let mut readers = self.readers.borrow_mut();
+        // Open the file if we haven't opened it in this `KvStoreReader`.
+        if let btree_map::Entry::Vacant(entry) = readers.entry(cmd_pos.gen) {
+            let mut reader = BufReaderWithPos::new(snapshot::open_log(&self.path, cmd_pos.gen)?)?;
+            let format = LogFormat::detect(&mut reader)?;
+            entry.insert((format, reader));
+        }
+        let (format, reader) = readers.get_mut(&cmd_pos.gen).unwrap();
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let mut cmd_reader = reader.take(cmd_pos.len);
+        f(*format, &mut cmd_reader)
+    }
+
+    // Read the log file at the given `CommandPos` and deserialize it to `Command`.
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
+        self.read_and(cmd_pos, |format, mut cmd_reader| {
+            read_command(&mut cmd_reader, cmd_pos.len, format)
+        })
+    }
+
+    // Read the value of the "set" command at the given `CommandPos`.
+    fn read_value(&self, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        match self.read_command(cmd_pos)? {
+            Command::Set { value, .. } => Ok(value),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> KvStoreReader {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            epoch: Arc::clone(&self.epoch),
+            seen_epoch: Cell::new(self.epoch.load(Ordering::SeqCst)),
+            // don't use other KvStoreReader's readers
+            readers: RefCell::new(BTreeMap::new()),
+            maps: self.maps.clone(),
+        }
+    }
+}
+
+struct KvStoreWriter {
+    writer: BufWriterWithPos<File>,
+    current_gen: u64,
+    // length and stale bytes of each log file, which the compaction policy decides on
+    usage: BTreeMap<u64, GenUsage>,
+    // expiry times and keys of the values set with a time-to-live, which are counted
+    // as stale once they expire
+    expiring: BTreeSet<(u64, Vec<u8>)>,
+    policy: Arc<dyn CompactionPolicy>,
+    path: Arc<PathBuf>,
+    index: Arc<KeyDir>,
+    sync: SyncPolicy,
+    compression: Compression,
+    // writes waiting for the next group commit to be acknowledged
+    pending_syncs: Vec<oneshot::Sender<Result<()>>>,
+    // wakes up the group commit thread when a group is full
+    group_commit_tx: Sender<()>,
+    // hands compactions to the compaction thread
+    compaction_tx: Sender<CompactionTask>,
+    progress: Arc<CompactionProgress>,
+    snapshots: Arc<Snapshots>,
+    cache: Arc<ValueCache>,
+    maps: Option<Arc<LogMaps>>,
+}
+
+impl KvStoreWriter {
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let old_cmd = self.index.get(&key)?;
+        let cmd = Command::Set {
+            key,
+            value,
+            expires_at,
+        };
+        let pos = self.writer.pos;
+        write_record(&mut self.writer, &cmd, self.compression)?;
+        self.flush()?;
+        if let Command::Set { key, .. } = cmd {
+            let lsn = self.snapshots.next_lsn();
+            self.snapshots.record(&key, lsn, old_cmd);
+            if let Some(old_cmd) = old_cmd {
+                gen_usage(&mut self.usage, old_cmd.gen).stale += old_cmd.len;
+            }
+            let cmd_pos = CommandPos::from((self.current_gen, pos..self.writer.pos));
+            self.cache.invalidate(&key);
+            if let Some(expires_at) = expires_at {
+                self.expiring.insert((expires_at, key.clone()));
+            }
+            self.index.insert(key, cmd_pos.expiring(expires_at));
+        }
+        self.maybe_seal()?;
+        self.maybe_compact()
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        if let Some(old_cmd) = self.index.live(&key)? {
+            let cmd = Command::remove(key);
+            let pos = self.writer.pos;
+            write_record(&mut self.writer, &cmd, self.compression)?;
+            self.flush()?;
+            if let Command::Remove { key } = cmd {
+                let lsn = self.snapshots.next_lsn();
+                self.snapshots.record(&key, lsn, Some(old_cmd));
+                self.cache.invalidate(&key);
+                self.index.remove(&key);
+                gen_usage(&mut self.usage, old_cmd.gen).stale += old_cmd.len;
+                // the "remove" command itself can be deleted in the next compaction
+                gen_usage(&mut self.usage, self.current_gen).stale += self.writer.pos - pos;
+            }
+            self.maybe_seal()?;
+            self.maybe_compact()
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let index = &self.index;
+        batch.check_removes(|key| Ok(index.live(key)?.is_some()))?;
+
+        // The batch is encoded up front so that it reaches the log with a single write.
+        let mut buf = Vec::new();
+        write_record(
+            &mut buf,
+            &Command::batch(batch.len() as u64),
+            self.compression,
+        )?;
+        let mut cmds = Vec::with_capacity(batch.len());
+        for op in batch {
+            let cmd = match op {
+                BatchOp::Set { key, value } => Command::set(key, value),
+                BatchOp::Remove { key } => Command::remove(key),
+            };
+            let start = buf.len() as u64;
+            write_record(&mut buf, &cmd, self.compression)?;
+            cmds.push((cmd, start..buf.len() as u64));
+        }
+        let base = self.writer.pos;
+        self.writer.write_all(&buf)?;
+        self.flush()?;
+
+        // the batch record can be deleted in the next compaction
+        gen_usage(&mut self.usage, self.current_gen).stale += cmds[0].1.start;
+        // snapshots see all writes of the batch or none
+        let lsn = self.snapshots.next_lsn();
+        // the locations the keys had before each write, which may be earlier writes of
+        // the batch that aren't in the index yet
+        let mut written = BTreeMap::new();
+        let mut writes = Vec::with_capacity(cmds.len());
+        for (cmd, range) in cmds {
+            let len = range.end - range.start;
+            let range = base + range.start..base + range.end;
+            let (key, new_cmd) = match cmd {
+                Command::Set { key, .. } => (key, Some((self.current_gen, range).into())),
+                Command::Remove { key } => {
+                    gen_usage(&mut self.usage, self.current_gen).stale += len;
+                    (key, None)
+                }
+                Command::Batch { .. } => unreachable!(),
+            };
+            let old_cmd = match written.get(&key) {
+                Some(&old_cmd) => old_cmd,
+                None => self.index.get(&key)?,
+            };
+            self.snapshots.record(&key, lsn, old_cmd);
+            self.cache.invalidate(&key);
+            if let Some(old_cmd) = old_cmd {
+                gen_usage(&mut self.usage, old_cmd.gen).stale += old_cmd.len;
+            }
+            written.insert(key.clone(), new_cmd);
+            writes.push((key, new_cmd));
+        }
+        self.index.apply_batch(writes);
+        self.maybe_seal()?;
+        self.maybe_compact()
+    }
+
+    /// Flushes the written commands to the log file, and syncs the file if the
+    /// sync policy is `Always`.
+    fn flush(&mut self) -> Result<()> {
+        if self.sync == SyncPolicy::Always {
+            self.writer.sync()?;
+        } else {
+            self.writer.flush()?;
+        }
+        gen_usage(&mut self.usage, self.current_gen).len = self.writer.pos;
+        Ok(())
+    }
+
+    /// Acknowledges a successful write.
+    ///
+    /// With a grouping sync policy, the acknowledgement is delayed until the group
+    /// commit thread has synced the log.
+    fn ack(&mut self, tx: oneshot::Sender<Result<()>>) {
+        match self.sync {
+            SyncPolicy::Always | SyncPolicy::Never => {
+                if tx.send(Ok(())).is_err() {
+                    error!("Receiving end is dropped");
+                }
+            }
+            SyncPolicy::EveryN(n) => {
+                self.pending_syncs.push(tx);
+                if self.pending_syncs.len() >= n {
+                    // the thread is already woken up if the channel is full
+                    let _ = self.group_commit_tx.try_send(());
+                }
+            }
+            SyncPolicy::Interval(_) => self.pending_syncs.push(tx),
+        }
+    }
+
+    /// Seals the active log file once it has grown over the size limit of
+    /// `IndexMode::Disk`, moving its keys to an index file.
+    fn maybe_seal(&mut self) -> Result<()> {
+        if self.index.should_seal(self.writer.pos) {
+            self.seal_active(self.current_gen + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Seals the active log file and makes the log file of `gen` the active one.
+    fn seal_active(&mut self, gen: u64) -> Result<()> {
+        // Writes waiting for a group commit are in the sealed file, which the group
+        // commit thread won't sync anymore.
+        if !self.pending_syncs.is_empty() {
+            self.writer.sync()?;
+        }
+        let stale = gen_usage(&mut self.usage, self.current_gen).stale;
+        self.index.seal(self.current_gen, self.writer.pos, stale)?;
+        self.current_gen = gen;
+        self.writer = new_log_file(&self.path, gen)?;
+        gen_usage(&mut self.usage, gen).len = self.writer.pos;
+        if let Some(maps) = &self.maps {
+            maps.seal_before(gen);
+        }
+        Ok(())
+    }
+
+    /// Starts a background compaction if the compaction policy asks for one.
+    ///
+    /// Nothing is done if a compaction is already running.
+    fn maybe_compact(&mut self) -> Result<()> {
+        self.count_expired()?;
+        if self.progress.is_running() {
+            return Ok(());
+        }
+        let usage: Vec<GenUsage> = self.usage.values().copied().collect();
+        let gens = self.policy.pick(&usage);
+        if gens.is_empty() {
+            return Ok(());
+        }
+        // the receiver is dropped because nobody waits for an automatic compaction
+        self.compact(Some(gens)).map(drop)
+    }
+
+    /// Counts the values that have expired since the last call as stale, and drops
+    /// them from the index.
+    ///
+    /// A value is only counted if the key still points to it. The location is looked
+    /// up again, as a compaction may have moved the value since it was set.
+    fn count_expired(&mut self) -> Result<()> {
+        let now = now_millis();
+        while self
+            .expiring
+            .first()
+            .is_some_and(|&(expires_at, _)| expires_at <= now)
+        {
+            let (expires_at, key) = self.expiring.pop_first().unwrap();
+            match self.index.get(&key)? {
+                Some(cmd_pos) if cmd_pos.expires_at == Some(expires_at) => {
+                    gen_usage(&mut self.usage, cmd_pos.gen).stale += cmd_pos.len;
+                    self.cache.invalidate(&key);
+                    self.index.remove(&key);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Seals the active log file and hands a compaction of the given generations to
+    /// the compaction thread. `None` compacts every sealed log file.
+    ///
+    /// Returns a receiver of the compaction result.
+    fn compact(&mut self, gens: Option<Vec<u64>>) -> Result<oneshot::Receiver<Result<()>>> {
+        self.progress.start();
+        // increase current gen by 2. current_gen + 1 is for the compaction file
+        let compaction_gen = self.current_gen + 1;
+        self.seal_active(self.current_gen + 2)?;
+        let (done, rx) = oneshot::channel();
+        let task = CompactionTask {
+            gen: compaction_gen,
+            gens,
+            done,
+        };
+        self.compaction_tx
+            .send(task)
+            .map_err(|_| KvsError::StringError("Compaction thread exited".to_owned()))?;
+        Ok(rx)
+    }
+}
+
+impl Drop for KvStoreWriter {
+    // Writes waiting for a group commit are synced and acknowledged when the store is closed.
+    fn drop(&mut self) {
+        if self.pending_syncs.is_empty() {
+            return;
+        }
+        let res = self.writer.sync();
+        for tx in self.pending_syncs.drain(..) {
+            let res = res
+                .as_ref()
+                .map_err(|e| io::Error::new(e.kind(), e.to_string()).into())
+                .map(|_| ());
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        }
+    }
+}
+
+/// Body of the group commit thread.
+///
+/// It syncs the log for the waiting writes whenever a group is full or the interval
+/// elapses, and exits once the `KvStoreWriter` is dropped.
+fn run_group_commit(writer: Weak<Mutex<KvStoreWriter>>, rx: Receiver<()>, sync: SyncPolicy) {
+    let timeout = match sync {
+        SyncPolicy::Interval(interval) => interval,
+        _ => GROUP_COMMIT_MAX_DELAY,
+    };
+    loop {
+        match rx.recv_timeout(timeout) {
+            Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        match writer.upgrade() {
+            Some(writer) => group_commit(&writer),
+            None => return,
+        }
+    }
+}
+
+/// Syncs the active log file and acknowledges all writes waiting for it.
+///
+/// The writer lock is only held to take the waiting writes, so new writes are not
+/// blocked by the sync.
+fn group_commit(writer: &Mutex<KvStoreWriter>) {
+    let (pending_syncs, file) = {
+        let mut writer = writer.lock().unwrap();
+        if writer.pending_syncs.is_empty() {
+            return;
+        }
+        // Commands written to an older log file have been copied to a compaction
+        // file, which is synced before the older file is replaced. So syncing the
+        // current log file makes every waiting write durable.
+        let file = writer.writer.try_clone_file();
+        (mem::take(&mut writer.pending_syncs), file)
+    };
+    let res = file.and_then(|file| file.sync_data());
+    if let Err(ref e) = res {
+        error!("Failed to sync the log: {}", e);
+    }
+    for tx in pending_syncs {
+        let res = res
+            .as_ref()
+            .map_err(|e| io::Error::new(e.kind(), e.to_string()).into())
+            .map(|_| ());
+        if tx.send(res).is_err() {
+            error!("Receiving end is dropped");
+        }
+    }
+}
+
+/// Create a new log file with given generation number and add the reader to the readers map.
+///
+/// The file header is written if the file is empty.
+///
+/// Returns the writer to the log.
+fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
+    let path = log_path(path, gen);
+    let mut writer =
+        BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(&path)?)?;
+    if writer.pos == 0 {
+        write_file_header(&mut writer)?;
+        writer.flush()?;
+    }
+    Ok(writer)
+}
+
+/// Returns sorted generation numbers in the given directory
+fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// Returns a `pick` function of `KvStore::read_entries` picking the live entries with
+/// keys before `end`, or all of them if it is `None`.
+fn pick_range(
+    index: Arc<KeyDir>,
+    end: Option<Vec<u8>>,
+) -> impl Fn(&[u8], usize) -> Result<EntryPage> + Send + Sync + 'static {
+    move |start, max| {
+        let now = now_millis();
+        let mut entries = Vec::new();
+        let mut next = None;
+        index.scan(start, |key, cmd_pos| {
+            if end.as_deref().is_some_and(|end| key >= end) {
+                return false;
+            }
+            if entries.len() >= max {
+                next = Some(key.to_vec());
+                return false;
+            }
+            if !cmd_pos.is_expired(now) {
+                entries.push((key.to_vec(), cmd_pos));
+            }
+            true
+        })?;
+        Ok((entries, next))
+    }
+}
+
+/// Load the whole log file and store value locations in the index map.
+///
+/// The length of the file and the stale bytes a compaction can save, including the
+/// values expired at `now`, are recorded in `usage`. If the file ends with a torn
+/// command, the offset of the torn command is returned. Commands before it are loaded.
+///
+/// # Errors
+///
+/// It returns `KvsError::Corrupted` if a command in the middle of the file is invalid.
+fn load(
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    usage: &mut BTreeMap<u64, GenUsage>,
+    now: u64,
+) -> Result<Option<u64>> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    gen_usage(usage, gen).len = file_len;
+    replay(gen, reader, file_len, |cmd, pos, new_pos| {
+        let old_cmd = match cmd {
+            Command::Set {
+                key, expires_at, ..
+            } => {
+                let cmd_pos = CommandPos::from((gen, pos..new_pos)).expiring(expires_at);
+                if cmd_pos.is_expired(now) {
+                    // an expired value acts as a removal and can be deleted in the
+                    // next compaction
+                    gen_usage(usage, gen).stale += cmd_pos.len;
+                    index.remove(&key).map(|entry| *entry.value())
+                } else {
+                    let old_cmd = index.get(&key).map(|entry| *entry.value());
+                    index.insert(key, cmd_pos);
+                    old_cmd
+                }
+            }
+            Command::Remove { key } => {
+                // the "remove" command itself can be deleted in the next compaction
+                gen_usage(usage, gen).stale += new_pos - pos;
+                index.remove(&key).map(|entry| *entry.value())
+            }
+            // so can the batch record
+            Command::Batch { .. } => {
+                gen_usage(usage, gen).stale += new_pos - pos;
+                None
+            }
+        };
+        if let Some(old_cmd) = old_cmd {
+            gen_usage(usage, old_cmd.gen).stale += old_cmd.len;
+        }
+    })
+}
+
+/// Loads a sealed log file with `IndexMode::Disk`, opening its index file, or writing
+/// it if it is missing or stale.
+///
+/// The length and the stale bytes within the file are recorded in `usage`. The values
+/// shadowed by newer log files are counted by `KeyDir::count_stale` once every file
+/// is loaded. The offset of a torn command is returned like with `load`.
+fn load_sealed(
+    path: &Path,
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    index: &KeyDir,
+    usage: &mut BTreeMap<u64, GenUsage>,
+) -> Result<Option<u64>> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    gen_usage(usage, gen).len = file_len;
+    if let Some(key_index) = KeyIndex::open(path, gen, file_len)? {
+        gen_usage(usage, gen).stale += key_index.stale();
+        index.add_sealed(key_index);
+        return Ok(None);
+    }
+
+    let mut states = BTreeMap::new();
+    let mut stale = 0;
+    let torn_at = replay(gen, reader, file_len, |cmd, pos, new_pos| {
+        let (key, state) = match cmd {
+            Command::Set {
+                key, expires_at, ..
+            } => {
+                let cmd_pos = CommandPos::from((gen, pos..new_pos)).expiring(expires_at);
+                (key, KeyState::Live(cmd_pos))
+            }
+            Command::Remove { key } => {
+                stale += new_pos - pos;
+                (key, KeyState::Removed)
+            }
+            Command::Batch { .. } => {
+                stale += new_pos - pos;
+                return;
+            }
+        };
+        if let Some(KeyState::Live(old_cmd)) = states.insert(key, state) {
+            stale += old_cmd.len;
+        }
+    })?;
+    let mut writer = IndexWriter::create(path, gen, states.len() as u64)?;
+    for (key, state) in states {
+        writer.add(&key, state)?;
+    }
+    gen_usage(usage, gen).stale += stale;
+    index.add_sealed(writer.finish(torn_at.unwrap_or(file_len), stale)?);
+    Ok(torn_at)
+}
+
+/// Replays the commands in a log file of `file_len` bytes, calling `apply` with each
+/// command and its start and end offsets.
+///
+/// The commands of a batch are only applied once the whole batch is read. If the file
+/// ends with a torn command, the offset of the torn command is returned.
+///
+/// # Errors
+///
+/// It returns `KvsError::Corrupted` if a command in the middle of the file is invalid.
+fn replay<F>(
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    file_len: u64,
+    mut apply: F,
+) -> Result<Option<u64>>
+where
+    F: FnMut(Command, u64, u64),
+{
+    // `detect` reads from the beginning of the file and stops at the first command
+    let torn_at = match LogFormat::detect(reader)? {
+        LogFormat::Json => {
+            let mut pos = 0;
+            let mut stream = Deserializer::from_reader(reader).into_iter::<JsonCommand>();
+            loop {
+                match stream.next() {
+                    Some(Ok(cmd)) => {
+                        let new_pos = stream.byte_offset() as u64;
+                        apply(cmd.into(), pos, new_pos);
+                        pos = new_pos;
+                    }
+                    None => break None,
+                    // the file ends in the middle of a command
+                    Some(Err(ref e)) if e.is_eof() => break Some(pos),
+                    Some(Err(e)) if e.is_io() => return Err(e.into()),
+                    Some(Err(_)) => return Err(KvsError::Corrupted { gen, pos }),
+                }
+            }
+        }
+        LogFormat::Binary => {
+            // end of the last applied command or batch
+            let mut pos = reader.pos;
+            // start of the next record
+            let mut record_pos = pos;
+            // records of the batch being read and the number of its commands not read yet
+            let mut batch = Vec::new();
+            let mut remaining = 0;
+            loop {
+                match read_record(reader, file_len.saturating_sub(reader.pos)) {
+                    Ok(Some(cmd)) => {
+                        let new_pos = reader.pos;
+                        match cmd {
+                            Command::Batch { .. } if remaining > 0 => {
+                                return Err(KvsError::Corrupted {
+                                    gen,
+                                    pos: record_pos,
+                                })
+                            }
+                            Command::Batch { len } => remaining = len,
+                            _ if remaining > 0 => remaining -= 1,
+                            _ => {}
+                        }
+                        batch.push((cmd, record_pos, new_pos));
+                        record_pos = new_pos;
+                        if remaining == 0 {
+                            for (cmd, pos, new_pos) in batch.drain(..) {
+                                apply(cmd, pos, new_pos);
+                            }
+                            pos = new_pos;
+                        }
+                    }
+                    // the file ends in the middle of a batch
+                    Ok(None) if remaining > 0 => break Some(pos),
+                    Ok(None) => break None,
+                    // the file ends in the middle of a record
+                    Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        break Some(pos)
+                    }
+                    // the last record is complete but only partially persisted
+                    Err(KvsError::ChecksumMismatch) if reader.pos == file_len => break Some(pos),
+                    Err(KvsError::Io(e)) => return Err(KvsError::Io(e)),
+                    Err(_) => {
+                        return Err(KvsError::Corrupted {
+                            gen,
+                            pos: record_pos,
+                        })
+                    }
+                }
+            }
+        }
+    };
+    Ok(torn_at)
+}
+
+/// Returns the usage of the given generation, adding an empty one if it is missing.
+fn gen_usage(usage: &mut BTreeMap<u64, GenUsage>, gen: u64) -> &mut GenUsage {
+    usage.entry(gen).or_insert(GenUsage {
+        gen,
+        len: 0,
+        stale: 0,
+    })
+}
+
+/// Returns whether the given log files contain no commands.
+fn only_headers(path: &Path, gens: &[u64]) -> Result<bool> {
+    for &gen in gens {
+        if fs::metadata(log_path(path, gen))?.len() > FILE_HEADER_LEN {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Drops the torn command at the end of a log file by truncating the file to `len` bytes.
+fn truncate_log(path: &Path, gen: u64, len: u64) -> Result<()> {
+    let file_path = log_path(path, gen);
+    let file = OpenOptions::new().write(true).open(&file_path)?;
+    let file_len = file.metadata()?.len();
+    warn!(
+        "{:?} ends with a torn command, dropping {} bytes after offset {}",
+        file_path,
+        file_len - len,
+        len
+    );
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// Represents the position and length of a serialized command in the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CommandPos {
+    gen: u64,
+    pos: u64,
+    len: u64,
+    // expiry time of the value set by the command
+    expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    /// Sets the expiry time of the value set by the command.
+    fn expiring(self, expires_at: Option<u64>) -> Self {
+        CommandPos { expires_at, ..self }
+    }
+
+    /// Returns whether the value set by the command is expired at `now`.
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl From<(u64, Range<u64>)> for CommandPos {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
+        CommandPos {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+            expires_at: None,
+        }
+    }
+}
+
+struct BufReaderWithPos<R: Read + Seek> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    fn new(mut inner: R) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufReaderWithPos {
+            reader: BufReader::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for BufReaderWithPos<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+struct BufWriterWithPos<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    fn new(mut inner: W) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+}
+
+impl BufWriterWithPos<File> {
+    /// Flushes the buffer and syncs the file data to the disk.
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+
+    /// Returns another handle to the underlying file.
+    fn try_clone_file(&self) -> io::Result<File> {
+        self.writer.get_ref().try_clone()
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
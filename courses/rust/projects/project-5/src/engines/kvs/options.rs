@@ -0,0 +1,201 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::policy::{CompactionPolicy, ThresholdPolicy};
+
+/// Options for opening a `KvStore`.
+///
+/// ```rust
+/// # use kvs::{KvStoreOptions, SyncPolicy};
+/// let options = KvStoreOptions {
+///     sync: SyncPolicy::Always,
+///     ..KvStoreOptions::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// When writes are flushed to the disk with `fsync`.
+    pub sync: SyncPolicy,
+    /// When the log is compacted and which log files are merged.
+    pub compaction: Arc<dyn CompactionPolicy>,
+    /// How the values written to the log are compressed.
+    pub compression: Compression,
+    /// Bytes of values `get` keeps in memory for the hot keys. Zero disables the cache.
+    pub cache_capacity: usize,
+    /// Whether sealed log files are read through shared memory maps rather than a
+    /// file handle per reader.
+    pub mmap: bool,
+    /// Where the keys of the sealed log files are indexed.
+    pub index: IndexMode,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            sync: SyncPolicy::default(),
+            compaction: Arc::new(ThresholdPolicy::default()),
+            compression: Compression::default(),
+            cache_capacity: 0,
+            mmap: false,
+            index: IndexMode::default(),
+        }
+    }
+}
+
+/// Policy deciding when the log is synchronized to the disk.
+///
+/// A write is acknowledged only after it is as durable as the policy promises.
+/// With `EveryN` and `Interval`, a background thread syncs the log for a group of
+/// writes at once, and all writes in the group are acknowledged together when the
+/// sync finishes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Sync the log before acknowledging every write.
+    Always,
+    /// Sync the log once the given number of writes are waiting.
+    ///
+    /// A smaller group is synced after `GROUP_COMMIT_MAX_DELAY` so that no write
+    /// waits forever.
+    EveryN(usize),
+    /// Sync the log for the waiting writes at the given interval.
+    Interval(Duration),
+    /// Never sync the log explicitly. Writes are only flushed to the operating
+    /// system and can be lost if the machine crashes.
+    #[default]
+    Never,
+}
+
+/// The longest time a write waits for its group to fill up with `SyncPolicy::EveryN`.
+pub const GROUP_COMMIT_MAX_DELAY: Duration = Duration::from_millis(10);
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::EveryN(n) => write!(f, "every:{}", n),
+            SyncPolicy::Interval(interval) => write!(f, "interval:{}", interval.as_millis()),
+            SyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// Parses `always`, `never`, `every:<WRITES>` or `interval:<MILLISECONDS>`.
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid sync policy `{}`, expected always, never, every:<WRITES> or interval:<MILLISECONDS>",
+                s
+            )
+        };
+        match s {
+            "always" => return Ok(SyncPolicy::Always),
+            "never" => return Ok(SyncPolicy::Never),
+            _ => {}
+        }
+        let mut parts = s.splitn(2, ':');
+        let (name, arg) = (parts.next().unwrap(), parts.next().ok_or_else(invalid)?);
+        let arg: u64 = arg.parse().map_err(|_| invalid())?;
+        match name {
+            "every" if arg > 0 => Ok(SyncPolicy::EveryN(arg as usize)),
+            "interval" if arg > 0 => Ok(SyncPolicy::Interval(Duration::from_millis(arg))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Codec compressing the values written to the log.
+///
+/// The codec is recorded in every record, so a store can be reopened with another
+/// codec and still read the values written before. A compaction rewrites the values it
+/// copies with the current codec. Values that don't get smaller are stored verbatim,
+/// and every compaction tries to compress them again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Store values verbatim.
+    #[default]
+    None,
+    /// Compress values with LZ4, which is fast but compresses less.
+    Lz4,
+    /// Compress values with zstd at its default level.
+    Zstd,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// Parses `none`, `lz4` or `zstd`.
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!(
+                "invalid compression `{}`, expected none, lz4 or zstd",
+                s
+            )),
+        }
+    }
+}
+
+/// Where the index from keys to value locations is kept.
+///
+/// With `Disk`, only the keys written to the active log file are kept in memory. The
+/// active log file is sealed once it grows over `max_file_size` bytes, and its keys are
+/// written to a sorted index file next to it. Lookups of older keys read the index
+/// files, which is slower but lets the store hold more keys than fit in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexMode {
+    /// Keep every key in memory.
+    #[default]
+    Memory,
+    /// Keep the keys of the sealed log files in index files.
+    Disk {
+        /// Size in bytes after which the active log file is sealed.
+        max_file_size: u64,
+    },
+}
+
+impl fmt::Display for IndexMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexMode::Memory => write!(f, "memory"),
+            IndexMode::Disk { max_file_size } => write!(f, "disk:{}", max_file_size),
+        }
+    }
+}
+
+/// Parses `memory` or `disk:<MAX_FILE_BYTES>`.
+impl FromStr for IndexMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid index mode `{}`, expected memory or disk:<MAX_FILE_BYTES>",
+                s
+            )
+        };
+        if s == "memory" {
+            return Ok(IndexMode::Memory);
+        }
+        match s.strip_prefix("disk:").map(str::parse) {
+            Some(Ok(max_file_size)) if max_file_size > 0 => Ok(IndexMode::Disk { max_file_size }),
+            _ => Err(invalid()),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Space usage of a log file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenUsage {
+    /// Generation number of the log file.
+    pub gen: u64,
+    /// Length of the log file in bytes.
+    pub len: u64,
+    /// Bytes of stale commands in the log file, which a compaction can drop.
+    pub stale: u64,
+}
+
+impl GenUsage {
+    /// Returns the fraction of the log file taken by stale commands, from 0 to 1.
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.len == 0 {
+            0.0
+        } else {
+            self.stale as f64 / self.len as f64
+        }
+    }
+}
+
+/// Decides when the log is compacted and which log files are merged.
+///
+/// The policy is consulted after every write that is not covered by a running
+/// compaction. `KvStore::compact` compacts every log file regardless of the policy.
+pub trait CompactionPolicy: fmt::Debug + Send + Sync {
+    /// Picks the generations of the log files to compact.
+    ///
+    /// `usage` lists every log file in ascending generation order. The last one is
+    /// the active log file, which is sealed when a compaction starts whether it is
+    /// picked or not.
+    ///
+    /// Returns an empty list if no compaction is needed.
+    fn pick(&self, usage: &[GenUsage]) -> Vec<u64>;
+}
+
+/// The default `CompactionPolicy`, combining a few thresholds.
+///
+/// A compaction is triggered once the stale bytes in the log exceed either
+/// `max_stale_bytes` or `max_stale_ratio` of the live bytes. If `min_garbage_ratio`
+/// is set, only the log files at least that dirty are compacted, otherwise the whole
+/// log is. If `window` is set, compactions only start inside it.
+///
+/// Compactions run in the background while the store keeps serving writes, and copy
+/// the live commands of the log files they merge. `max_compaction_bytes` bounds that
+/// copying: the dirtiest log files, which reclaim the most space for the bytes they
+/// copy, are picked first, and the rest are left to later compactions.
+///
+/// ```rust
+/// # use std::sync::Arc;
+/// # use kvs::{CompactionWindow, KvStoreOptions, ThresholdPolicy};
+/// // compact the files that are mostly garbage at night, 64 MiB at a time
+/// let policy = ThresholdPolicy {
+///     max_stale_ratio: Some(0.5),
+///     min_garbage_ratio: Some(0.7),
+///     max_compaction_bytes: Some(64 * 1024 * 1024),
+///     window: Some(CompactionWindow::new(22, 6)),
+///     ..ThresholdPolicy::default()
+/// };
+/// let options = KvStoreOptions {
+///     compaction: Arc::new(policy),
+///     ..KvStoreOptions::default()
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThresholdPolicy {
+    /// Compact once the stale bytes exceed this many bytes. Defaults to 1 MiB.
+    pub max_stale_bytes: Option<u64>,
+    /// Compact once the stale bytes exceed this ratio of the live bytes.
+    pub max_stale_ratio: Option<f64>,
+    /// Only compact the log files whose garbage ratio is at least this high.
+    pub min_garbage_ratio: Option<f64>,
+    /// Only compact log files with at most this many live bytes in total, unless a
+    /// single log file has more.
+    pub max_compaction_bytes: Option<u64>,
+    /// Only start compactions inside this time window.
+    pub window: Option<CompactionWindow>,
+}
+
+impl Default for ThresholdPolicy {
+    fn default() -> Self {
+        ThresholdPolicy {
+            max_stale_bytes: Some(1024 * 1024),
+            max_stale_ratio: None,
+            min_garbage_ratio: None,
+            max_compaction_bytes: None,
+            window: None,
+        }
+    }
+}
+
+impl CompactionPolicy for ThresholdPolicy {
+    fn pick(&self, usage: &[GenUsage]) -> Vec<u64> {
+        let stale: u64 = usage.iter().map(|u| u.stale).sum();
+        let live = usage
+            .iter()
+            .map(|u| u.len)
+            .sum::<u64>()
+            .saturating_sub(stale);
+        let over_bytes = self.max_stale_bytes.is_some_and(|max| stale > max);
+        let over_ratio = self
+            .max_stale_ratio
+            .is_some_and(|max| stale as f64 > max * live as f64);
+        if !over_bytes && !over_ratio {
+            return Vec::new();
+        }
+        if let Some(window) = self.window {
+            if !window.contains(SystemTime::now()) {
+                return Vec::new();
+            }
+        }
+        let mut candidates: Vec<&GenUsage> = match self.min_garbage_ratio {
+            Some(min) => usage
+                .iter()
+                .filter(|u| u.stale > 0 && u.garbage_ratio() >= min)
+                .collect(),
+            None => usage.iter().collect(),
+        };
+        if let Some(max) = self.max_compaction_bytes {
+            candidates.sort_by(|a, b| b.garbage_ratio().total_cmp(&a.garbage_ratio()));
+            let mut copied = 0;
+            let mut picked = 0;
+            for u in &candidates {
+                let live = u.len.saturating_sub(u.stale);
+                if picked > 0 && copied + live > max {
+                    break;
+                }
+                copied += live;
+                picked += 1;
+            }
+            candidates.truncate(picked);
+        }
+        let mut gens: Vec<u64> = candidates.iter().map(|u| u.gen).collect();
+        gens.sort_unstable();
+        gens
+    }
+}
+
+/// A daily time window in UTC hours.
+///
+/// The window starts at `start_hour` and ends before `end_hour`. It wraps around
+/// midnight if `start_hour` is greater than `end_hour`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionWindow {
+    /// First hour of the window, from 0 to 23.
+    pub start_hour: u32,
+    /// Hour the window ends at, from 0 to 23.
+    pub end_hour: u32,
+}
+
+impl CompactionWindow {
+    /// Creates a window from `start_hour` to `end_hour` in UTC.
+    pub fn new(start_hour: u32, end_hour: u32) -> Self {
+        CompactionWindow {
+            start_hour,
+            end_hour,
+        }
+    }
+
+    /// Returns whether the given time is inside the window.
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let hour = (secs / 3600 % 24) as u32;
+        if self.start_hour <= self.end_hour {
+            self.start_hour <= hour && hour < self.end_hour
+        } else {
+            self.start_hour <= hour || hour < self.end_hour
+        }
+    }
+}
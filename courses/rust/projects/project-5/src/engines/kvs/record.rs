@@ -0,0 +1,387 @@
+//! On-disk format of the log files.
+//!
+//! A log file starts with a file header made of a magic number and a format version:
+//!
+//! ```text
+//! +-------------+---------------+
+//! | magic (4 B) | version (u32) |
+//! +-------------+---------------+
+//! ```
+//!
+//! The header is followed by records. Each record is laid out as:
+//!
+//! ```text
+//! +-----------+-----------------+-----------+---------------+-----------------+-----+-------+
+//! | crc (u32) | timestamp (u64) | kind (u8) | key len (u32) | value len (u32) | key | value |
+//! +-----------+-----------------+-----------+---------------+-----------------+-----+-------+
+//! ```
+//!
+//! All integers are little-endian. The crc32 checksum covers every byte of the record
+//! after the checksum itself, so torn writes and bit rot are detected when the record
+//! is read back.
+//!
+//! The low four bits of the kind byte hold the kind of the record and the high four
+//! bits the codec the value is compressed with (see `Compression`). Only the values of
+//! set records are compressed, and a value is stored verbatim if compressing doesn't
+//! make it smaller. Version 1 files have no compressed records.
+//!
+//! A "set" command with a time-to-live is written as an expiring set record, whose
+//! value starts with the expiry time in milliseconds since the Unix epoch as a `u64`,
+//! followed by the actual value. The expiry time is compressed with the value.
+//!
+//! The commands of a write batch are preceded by a batch record, whose value is the
+//! number of commands in the batch as a `u64`. The batch is only applied if all of its
+//! commands are read back, so a crash in the middle of writing a batch loses the
+//! whole batch.
+//!
+//! Log files written before the binary format have no file header and contain a stream
+//! of json-serialized commands. They are still readable so that existing data
+//! directories can be migrated: the next compaction rewrites them in the binary format.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use super::options::Compression;
+use crate::{KvsError, Result};
+
+/// Magic number at the beginning of every binary log file.
+const MAGIC: [u8; 4] = *b"KVSL";
+/// Version of the binary log format written by this crate.
+const VERSION: u32 = 2;
+/// Oldest version of the binary log format this crate can read.
+const MIN_VERSION: u32 = 1;
+/// Length of the file header: magic and version.
+pub(super) const FILE_HEADER_LEN: u64 = 8;
+/// Length of the record header: crc, timestamp, kind, key length and value length.
+const RECORD_HEADER_LEN: usize = 21;
+
+const KIND_SET: u8 = 0;
+const KIND_REMOVE: u8 = 1;
+const KIND_BATCH: u8 = 2;
+const KIND_SET_EXPIRING: u8 = 3;
+
+/// Mask of the record kind in the kind byte. The rest holds the codec.
+const KIND_MASK: u8 = 0x0f;
+/// Offset of the kind byte in a record.
+const KIND_OFFSET: usize = 12;
+
+const CODEC_NONE: u8 = 0;
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+
+impl Compression {
+    fn codec(self) -> u8 {
+        match self {
+            Compression::None => CODEC_NONE,
+            Compression::Lz4 => CODEC_LZ4,
+            Compression::Zstd => CODEC_ZSTD,
+        }
+    }
+
+    /// Compresses a value, returning `None` if it doesn't get smaller.
+    fn compress(self, value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let compressed = match self {
+            Compression::None => return Ok(None),
+            Compression::Lz4 => lz4::block::compress(value, None, true)?,
+            // level 0 is the default level of zstd
+            Compression::Zstd => zstd::encode_all(value, 0)?,
+        };
+        Ok(Some(compressed).filter(|compressed| compressed.len() < value.len()))
+    }
+}
+
+/// Decompresses a value compressed with the given codec.
+///
+/// # Errors
+///
+/// The record has passed its checksum, so it returns `KvsError::InvalidRecord` if the
+/// codec is unknown or the value cannot be decompressed.
+fn decompress(codec: u8, value: Vec<u8>) -> Result<Vec<u8>> {
+    let decompressed = match codec {
+        CODEC_NONE => return Ok(value),
+        CODEC_LZ4 => lz4::block::decompress(&value, None),
+        CODEC_ZSTD => zstd::decode_all(&value[..]),
+        _ => return Err(unknown_codec(codec)),
+    };
+    decompressed.map_err(|e| KvsError::InvalidRecord(format!("Cannot decompress value: {}", e)))
+}
+
+fn unknown_codec(codec: u8) -> KvsError {
+    KvsError::InvalidRecord(format!("Unknown codec {}", codec))
+}
+
+/// Struct representing a command
+#[derive(Debug)]
+pub(super) enum Command {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        /// Milliseconds since the Unix epoch after which the value is expired.
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    /// Marks the start of a batch of `len` commands.
+    Batch {
+        len: u64,
+    },
+}
+
+impl Command {
+    pub(super) fn set(key: Vec<u8>, value: Vec<u8>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at: None,
+        }
+    }
+
+    pub(super) fn set_expiring(key: Vec<u8>, value: Vec<u8>, expires_at: u64) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+        }
+    }
+
+    pub(super) fn remove(key: Vec<u8>) -> Command {
+        Command::Remove { key }
+    }
+
+    pub(super) fn batch(len: u64) -> Command {
+        Command::Batch { len }
+    }
+}
+
+/// A command in a legacy json log file.
+///
+/// Json logs only store string keys and values.
+#[derive(Deserialize, Debug)]
+pub(super) enum JsonCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl From<JsonCommand> for Command {
+    fn from(cmd: JsonCommand) -> Command {
+        match cmd {
+            JsonCommand::Set { key, value } => Command::set(key.into_bytes(), value.into_bytes()),
+            JsonCommand::Remove { key } => Command::remove(key.into_bytes()),
+        }
+    }
+}
+
+/// The format a log file is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum LogFormat {
+    /// Legacy log file containing a stream of json-serialized commands.
+    Json,
+    /// Log file with a file header and checksummed binary records.
+    Binary,
+}
+
+impl LogFormat {
+    /// Detects the format of a log file from its header.
+    ///
+    /// The reader is left at the position of the first command.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnsupportedVersion` if the file is written in a newer
+    /// format version.
+    pub(super) fn detect<R: Read + Seek>(reader: &mut R) -> Result<LogFormat> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut header = [0; FILE_HEADER_LEN as usize];
+        let mut filled = 0;
+        while filled < header.len() {
+            match reader.read(&mut header[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled == header.len() && header[..4] == MAGIC {
+            let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+            if !(MIN_VERSION..=VERSION).contains(&version) {
+                return Err(KvsError::UnsupportedVersion(version));
+            }
+            return Ok(LogFormat::Binary);
+        }
+        // files without the magic number are json logs written before the binary format
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(LogFormat::Json)
+    }
+}
+
+/// Writes the file header to a new log file.
+pub(super) fn write_file_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+/// Writes a command as a binary record, compressing the value of a set command with
+/// `compression`.
+pub(super) fn write_record<W: Write>(
+    writer: &mut W,
+    cmd: &Command,
+    compression: Compression,
+) -> Result<()> {
+    let len_bytes;
+    let expiring_value;
+    let compressed;
+    let (kind, key, value) = match cmd {
+        Command::Set {
+            key,
+            value,
+            expires_at: None,
+        } => (KIND_SET, &key[..], &value[..]),
+        Command::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+        } => {
+            expiring_value = [&expires_at.to_le_bytes()[..], &value[..]].concat();
+            (KIND_SET_EXPIRING, &key[..], &expiring_value[..])
+        }
+        Command::Remove { key } => (KIND_REMOVE, &key[..], &[][..]),
+        Command::Batch { len } => {
+            len_bytes = len.to_le_bytes();
+            (KIND_BATCH, &[][..], &len_bytes[..])
+        }
+    };
+    let (kind, value) = match cmd {
+        Command::Set { .. } => match compression.compress(value)? {
+            Some(value) => {
+                compressed = value;
+                (kind | compression.codec() << 4, &compressed[..])
+            }
+            None => (kind, value),
+        },
+        _ => (kind, value),
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + key.len() + value.len());
+    // the checksum is filled in after the rest of the record is encoded
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(&timestamp.to_le_bytes());
+    buf.push(kind);
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_le_bytes());
+
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+/// Reads a binary record from a reader with `remaining` bytes left.
+///
+/// Returns `None` if the reader is at the end of the file.
+///
+/// # Errors
+///
+/// It returns an `UnexpectedEof` I/O error if the record is incomplete and
+/// `KvsError::ChecksumMismatch` if the record doesn't match its checksum. A record
+/// whose lengths run past the `remaining` bytes is not allocated: the rest of the
+/// reader is skipped and it is reported as a checksum mismatch too. It returns
+/// `KvsError::InvalidRecord` if a record matching its checksum cannot be decoded.
+pub(super) fn read_record<R: Read>(reader: &mut R, remaining: u64) -> Result<Option<Command>> {
+    let mut header = [0; RECORD_HEADER_LEN];
+    let first = loop {
+        match reader.read(&mut header) {
+            Ok(n) => break n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    if first == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[first..])?;
+
+    let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let kind = header[KIND_OFFSET] & KIND_MASK;
+    let codec = header[KIND_OFFSET] >> 4;
+    let key_len = u32::from_le_bytes(header[13..17].try_into().unwrap()) as usize;
+    let value_len = u32::from_le_bytes(header[17..21].try_into().unwrap()) as usize;
+    let body_len = remaining
+        .checked_sub(RECORD_HEADER_LEN as u64)
+        .and_then(|left| {
+            key_len
+                .checked_add(value_len)
+                .filter(|&len| len as u64 <= left)
+        });
+    let mut body = match body_len {
+        Some(len) => vec![0; len],
+        None => {
+            io::copy(reader, &mut io::sink())?;
+            return Err(KvsError::ChecksumMismatch);
+        }
+    };
+    reader.read_exact(&mut body)?;
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[4..]);
+    hasher.update(&body);
+    if hasher.finalize() != crc {
+        return Err(KvsError::ChecksumMismatch);
+    }
+
+    let value = body.split_off(key_len);
+    let mut value = match kind {
+        KIND_SET | KIND_SET_EXPIRING => decompress(codec, value)?,
+        _ if codec != CODEC_NONE => return Err(unknown_codec(codec)),
+        _ => value,
+    };
+    match kind {
+        KIND_SET => Ok(Some(Command::set(body, value))),
+        KIND_SET_EXPIRING if value.len() >= 8 => {
+            let expires_at = u64::from_le_bytes(value[..8].try_into().unwrap());
+            value.drain(..8);
+            Ok(Some(Command::set_expiring(body, value, expires_at)))
+        }
+        KIND_REMOVE => Ok(Some(Command::remove(body))),
+        KIND_BATCH => {
+            let len = value.as_slice().try_into().map_err(|_| {
+                KvsError::InvalidRecord(format!("Batch header of {} bytes", value.len()))
+            })?;
+            Ok(Some(Command::batch(u64::from_le_bytes(len))))
+        }
+        _ => Err(KvsError::UnexpectedCommandType),
+    }
+}
+
+/// Returns whether a binary record holds a value compressed with a codec other than
+/// `compression`'s, or an uncompressed value that `compression` may shrink.
+pub(super) fn needs_recompression(record: &[u8], compression: Compression) -> bool {
+    match record.get(KIND_OFFSET) {
+        Some(&kind) => {
+            matches!(kind & KIND_MASK, KIND_SET | KIND_SET_EXPIRING)
+                && kind >> 4 != compression.codec()
+        }
+        None => false,
+    }
+}
+
+/// Reads a single command of `len` bytes written in the given format.
+pub(super) fn read_command<R: Read>(
+    reader: &mut R,
+    len: u64,
+    format: LogFormat,
+) -> Result<Command> {
+    match format {
+        LogFormat::Json => Ok(serde_json::from_reader::<_, JsonCommand>(reader)?.into()),
+        LogFormat::Binary => read_record(reader, len)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+    }
+}
@@ -0,0 +1,431 @@
+//! Point-in-time snapshots of a `KvStore`.
+//!
+//! Every write is given a log sequence number (LSN) with the writer lock held, and
+//! all writes of a batch share the same one. A snapshot is pinned to the LSN of the
+//! last write before it is taken.
+//!
+//! The index only holds the latest location of each key. While a snapshot is pinned,
+//! the writer records the location each written key had before the write, keyed by
+//! the key and the LSN of the write. A snapshot reads a key from the first version
+//! recorded after its LSN, or from the index if the key hasn't been written since.
+//! The writer records the version before it updates the index, and a snapshot reads
+//! the index before the versions, so a write racing with a read is never missed.
+//!
+//! The recorded versions may point to log files that a compaction has merged. Such
+//! files are renamed with a `retired` extension instead of being deleted, so that
+//! they are not loaded as log files, and are deleted once every snapshot taken
+//! before the compaction is released.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crossbeam_skiplist::SkipMap;
+use tokio::prelude::*;
+use tokio::sync::oneshot;
+
+use super::keydir::KeyDir;
+use super::{log_path, now_millis, CommandPos, EntryPage, KvStore};
+use crate::engines::into_string_pair;
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result};
+
+/// Snapshot bookkeeping shared by the `KvStore`, the writer and the compaction thread.
+pub(super) struct Snapshots {
+    path: Arc<PathBuf>,
+    // LSN of the last write, only increased with the writer lock held
+    last_lsn: AtomicU64,
+    // number of live snapshots pinned to each LSN
+    pinned: Mutex<BTreeMap<u64, usize>>,
+    // location of a key before the write with the LSN, or `None` if it didn't exist
+    versions: SkipMap<(Vec<u8>, u64), Option<CommandPos>>,
+    // keys of the recorded versions by LSN, so released versions are found without
+    // walking all of them
+    recorded: Mutex<BTreeMap<u64, Vec<Vec<u8>>>>,
+    // generations of the retired log files with the LSN of the last write before
+    // they were retired
+    retired: Mutex<Vec<(u64, u64)>>,
+}
+
+impl Snapshots {
+    pub(super) fn new(path: Arc<PathBuf>) -> Snapshots {
+        Snapshots {
+            path,
+            last_lsn: AtomicU64::new(0),
+            pinned: Mutex::new(BTreeMap::new()),
+            versions: SkipMap::new(),
+            recorded: Mutex::new(BTreeMap::new()),
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Assigns the LSN of a new write.
+    ///
+    /// It must be called with the writer lock held.
+    pub(super) fn next_lsn(&self) -> u64 {
+        self.last_lsn.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Records the location a key had before the write with the given LSN, if a
+    /// snapshot may read it.
+    ///
+    /// It must be called with the writer lock held, before the index is updated.
+    pub(super) fn record(&self, key: &[u8], lsn: u64, old_pos: Option<CommandPos>) {
+        if self.pinned.lock().unwrap().is_empty() {
+            return;
+        }
+        // a batch may write a key twice, and the location before the batch is kept
+        let version = (key.to_vec(), lsn);
+        if !self.versions.contains_key(&version) {
+            let mut recorded = self.recorded.lock().unwrap();
+            self.versions.insert(version, old_pos);
+            recorded.entry(lsn).or_default().push(key.to_vec());
+        }
+    }
+
+    /// Pins a snapshot to the LSN of the last write.
+    ///
+    /// It must be called with the writer lock held.
+    pub(super) fn pin(&self) -> u64 {
+        let lsn = self.last_lsn.load(Ordering::SeqCst);
+        *self.pinned.lock().unwrap().entry(lsn).or_insert(0) += 1;
+        lsn
+    }
+
+    /// Releases a snapshot, dropping the versions and the retired log files that no
+    /// pinned snapshot reads anymore.
+    fn unpin(&self, lsn: u64) {
+        // The last LSN is loaded before the pinned ones. A snapshot pinned after that
+        // has a higher LSN, so the versions it reads are kept.
+        let last_lsn = self.last_lsn.load(Ordering::SeqCst);
+        let mut pinned = self.pinned.lock().unwrap();
+        if let Some(count) = pinned.get_mut(&lsn) {
+            *count -= 1;
+            if *count == 0 {
+                pinned.remove(&lsn);
+            }
+        }
+        let oldest = pinned.keys().next().copied();
+
+        let mut retired = self.retired.lock().unwrap();
+        retired.retain(|&(gen, retired_lsn)| {
+            if oldest.is_some_and(|oldest| oldest <= retired_lsn) {
+                return true;
+            }
+            if let Err(e) = fs::remove_file(retired_path(&self.path, gen)) {
+                error!("Retired log file {} cannot be deleted: {}", gen, e);
+            }
+            false
+        });
+        drop(retired);
+        drop(pinned);
+
+        // a snapshot only reads the versions written after its LSN
+        let threshold = oldest.map_or(last_lsn, |oldest| oldest.min(last_lsn));
+        let mut recorded = self.recorded.lock().unwrap();
+        let kept = recorded.split_off(&threshold.saturating_add(1));
+        for (lsn, keys) in mem::replace(&mut *recorded, kept) {
+            for key in keys {
+                self.versions.remove(&(key, lsn));
+            }
+        }
+    }
+
+    /// Removes a log file merged by a compaction.
+    ///
+    /// The file is retired instead if a snapshot is pinned, because its versions may
+    /// point to the file.
+    pub(super) fn remove_log(&self, gen: u64) -> io::Result<()> {
+        let pinned = self.pinned.lock().unwrap();
+        if pinned.is_empty() {
+            return fs::remove_file(log_path(&self.path, gen));
+        }
+        fs::rename(log_path(&self.path, gen), retired_path(&self.path, gen))?;
+        let lsn = self.last_lsn.load(Ordering::SeqCst);
+        self.retired.lock().unwrap().push((gen, lsn));
+        Ok(())
+    }
+
+    /// Returns the location of the value of a key at the given LSN.
+    fn version_at(&self, index: &KeyDir, key: &[u8], lsn: u64) -> Result<Option<CommandPos>> {
+        let current = index.get(key)?;
+        let first_after = (key.to_vec(), lsn + 1)..=(key.to_vec(), u64::MAX);
+        match self.versions.range(first_after).next() {
+            Some(version) => Ok(*version.value()),
+            None => Ok(current),
+        }
+    }
+
+    /// Returns at most `max` keys from `start` on that exist in the index or have
+    /// recorded versions, in ascending order, until `in_range` returns `false`.
+    fn keys_from<F>(
+        &self,
+        index: &KeyDir,
+        start: &[u8],
+        in_range: F,
+        max: usize,
+    ) -> Result<Vec<Vec<u8>>>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let mut keys = Vec::new();
+        index.scan(start, |key, _| {
+            if !in_range(key) || keys.len() >= max {
+                return false;
+            }
+            keys.push(key.to_vec());
+            true
+        })?;
+        let mut versioned: Vec<Vec<u8>> = Vec::new();
+        for entry in self
+            .versions
+            .range((Bound::Included((start.to_vec(), 0)), Bound::Unbounded))
+        {
+            let key = &entry.key().0;
+            if !in_range(key) || versioned.len() >= max {
+                break;
+            }
+            if versioned.last() != Some(key) {
+                versioned.push(key.clone());
+            }
+        }
+        keys.extend(versioned);
+        keys.sort();
+        keys.dedup();
+        keys.truncate(max);
+        Ok(keys)
+    }
+}
+
+/// A read-only view of a `KvStore` at the point in time it was taken.
+///
+/// Reads through a snapshot see every write finished before `KvStore::snapshot` was
+/// called and none after, even if they read several keys. The writes of a batch
+/// are either all or none seen.
+///
+/// Log files the snapshot may read are kept until every clone of it is dropped, so
+/// a snapshot should not be held for longer than needed. Values that expire are
+/// still hidden once they expire.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # use kvs::thread_pool::{ThreadPool, RayonThreadPool};
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// # let store: KvStore<RayonThreadPool> = KvStore::open(std::env::current_dir()?, 2)?;
+/// store.set("key".to_owned(), "old".to_owned()).wait()?;
+/// let snapshot = store.snapshot();
+/// store.set("key".to_owned(), "new".to_owned()).wait()?;
+/// assert_eq!(snapshot.get("key".to_owned()).wait()?, Some("old".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Snapshot<P: ThreadPool> {
+    store: KvStore<P>,
+    pin: Arc<Pin>,
+}
+
+// Releases the snapshot when the last clone is dropped.
+struct Pin {
+    snapshots: Arc<Snapshots>,
+    lsn: u64,
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        self.snapshots.unpin(self.lsn);
+    }
+}
+
+impl<P: ThreadPool> Snapshot<P> {
+    pub(super) fn new(store: KvStore<P>, lsn: u64) -> Snapshot<P> {
+        let snapshots = Arc::clone(&store.snapshots);
+        Snapshot {
+            store,
+            pin: Arc::new(Pin { snapshots, lsn }),
+        }
+    }
+
+    /// Returns the log sequence number the snapshot is pinned to.
+    ///
+    /// The numbers are only meaningful until the store is closed.
+    pub fn lsn(&self) -> u64 {
+        self.pin.lsn
+    }
+
+    /// Gets the value of a given key when the snapshot was taken.
+    ///
+    /// Returns `None` if the given key did not exist or is expired.
+    pub fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let reader_pool = self.store.reader_pool.clone();
+        let index = self.store.index.clone();
+        let pin = self.pin.clone();
+        let (tx, rx) = oneshot::channel();
+        self.store.thread_pool.spawn(move || {
+            let version = pin.snapshots.version_at(&index, &key, pin.lsn);
+            let res = version.and_then(|cmd_pos| {
+                match cmd_pos.filter(|cmd_pos| !cmd_pos.is_expired(now_millis())) {
+                    Some(cmd_pos) => {
+                        let reader = reader_pool.pop().unwrap();
+                        let res = reader.read_value(cmd_pos).map(Some);
+                        reader_pool.push(reader).unwrap();
+                        res
+                    }
+                    None => Ok(None),
+                }
+            });
+            // the snapshot may be released once the value is read
+            drop(pin);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Gets the string value of a given string key when the snapshot was taken.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    pub fn get(
+        &self,
+        key: String,
+    ) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        Box::new(
+            self.get_bytes(key.into_bytes())
+                .and_then(|value| Ok(value.map(String::from_utf8).transpose()?)),
+        )
+    }
+
+    /// Scans the key/value pairs with keys in the range `[start, end)` when the
+    /// snapshot was taken, in ascending key order.
+    ///
+    /// The range has no upper bound if `end` is `None`. At most `limit` pairs are
+    /// returned if it is given.
+    pub fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        // the pages are read through the pin, so the snapshot stays pinned until the
+        // stream is dropped, even if every clone of it is dropped before
+        let index = self.store.index.clone();
+        let pin = self.pin.clone();
+        self.store.read_entries(start, limit, move |start, max| {
+            let in_range = |key: &[u8]| end.as_deref().is_none_or(|end| key < end);
+            pin.entries_from(&index, start, in_range, max)
+        })
+    }
+
+    /// Scans the key/value pairs whose keys started with `prefix` when the snapshot
+    /// was taken, in ascending key order.
+    pub fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        let index = self.store.index.clone();
+        let pin = self.pin.clone();
+        self.store
+            .read_entries(prefix.clone(), None, move |start, max| {
+                pin.entries_from(&index, start, |key| key.starts_with(&prefix), max)
+            })
+    }
+
+    /// Scans the string key/value pairs with keys in the range `[start, end)` when
+    /// the snapshot was taken, in ascending key order.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if a key or value is not valid UTF-8.
+    pub fn scan(
+        &self,
+        start: String,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (String, String), Error = KvsError> + Send> {
+        let pairs = self.scan_bytes(start.into_bytes(), end.map(String::into_bytes), limit);
+        Box::new(pairs.and_then(into_string_pair))
+    }
+
+    /// Scans the string key/value pairs whose keys started with `prefix` when the
+    /// snapshot was taken, in ascending key order.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if a key or value is not valid UTF-8.
+    pub fn scan_prefix(
+        &self,
+        prefix: String,
+    ) -> Box<dyn Stream<Item = (String, String), Error = KvsError> + Send> {
+        Box::new(
+            self.scan_prefix_bytes(prefix.into_bytes())
+                .and_then(into_string_pair),
+        )
+    }
+}
+
+impl Pin {
+    /// Returns the value locations of at most `max` keys from `start` on that existed
+    /// at the pinned LSN and aren't expired, until `in_range` returns `false`, and the
+    /// key the next page starts from.
+    fn entries_from<F>(
+        &self,
+        index: &KeyDir,
+        start: &[u8],
+        in_range: F,
+        max: usize,
+    ) -> Result<EntryPage>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let mut keys = self.snapshots.keys_from(index, start, in_range, max + 1)?;
+        let next = if keys.len() > max { keys.pop() } else { None };
+        let now = now_millis();
+        let mut entries = Vec::new();
+        for key in keys {
+            match self.snapshots.version_at(index, &key, self.lsn)? {
+                Some(cmd_pos) if !cmd_pos.is_expired(now) => entries.push((key, cmd_pos)),
+                _ => {}
+            }
+        }
+        Ok((entries, next))
+    }
+}
+
+/// Path of a log file retired by a compaction while a snapshot is pinned.
+fn retired_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.retired", gen))
+}
+
+/// Opens the log file of a generation, which may have been retired by a compaction.
+pub(super) fn open_log(dir: &Path, gen: u64) -> io::Result<File> {
+    match File::open(log_path(dir, gen)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => File::open(retired_path(dir, gen)),
+        res => res,
+    }
+}
+
+/// Removes the retired log files left by a store that wasn't closed cleanly.
+pub(super) fn remove_retired(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("retired".as_ref()) {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,205 @@
+//! Leveled compaction of the tables.
+//!
+//! Level 0 holds the flushed memtables, whose key ranges may overlap. Once it has
+//! `level0_tables` tables, all of them are merged with the overlapping tables of
+//! level 1. The tables of every deeper level are sorted and don't overlap, and the
+//! total size of level `n` is limited to `level1_size * 10^(n-1)`. When a level grows
+//! over its limit, one of its tables is merged with the overlapping tables of the next
+//! level. The tables picked in a level go round-robin through the key space, so that
+//! every key range is compacted in turn.
+//!
+//! The output of a compaction is split into tables of about `table_size` bytes. The
+//! merge keeps the newest entry of every key. Tombstones and expired values are only
+//! dropped when no deeper level holds tables, because they may shadow older values of
+//! their keys there.
+
+use std::fs;
+use std::sync::Arc;
+
+use super::merge::{Merge, Source};
+use super::sstable::{table_path, Table, TableBuilder};
+use super::{Inner, LsmOptions, State};
+use crate::engines::now_millis;
+use crate::Result;
+
+/// Growth factor of the size limits from a level to the next.
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+/// Tables of a level to merge into the next level.
+pub(super) struct Compaction {
+    level: usize,
+    // newest first
+    inputs: Vec<Arc<Table>>,
+    // tables of the next level overlapping the inputs
+    overlapping: Vec<Arc<Table>>,
+}
+
+impl Compaction {
+    fn new(levels: &[Vec<Arc<Table>>], level: usize, inputs: Vec<Arc<Table>>) -> Compaction {
+        let first = inputs.iter().map(|table| table.first_key()).min();
+        let last = inputs.iter().map(|table| table.last_key()).max();
+        let overlapping = match (first, last, levels.get(level + 1)) {
+            (Some(first), Some(last), Some(next)) => next
+                .iter()
+                .filter(|table| table.overlaps(first, last))
+                .cloned()
+                .collect(),
+            _ => Vec::new(),
+        };
+        Compaction {
+            level,
+            inputs,
+            overlapping,
+        }
+    }
+}
+
+/// Picks the next compaction the levels need, or returns `None` if every level is
+/// within its limit.
+///
+/// `cursors` holds the last key compacted in every level.
+pub(super) fn pick(
+    state: &State,
+    options: &LsmOptions,
+    cursors: &mut Vec<Vec<u8>>,
+) -> Option<Compaction> {
+    let levels = &state.levels;
+    if levels[0].len() >= options.level0_tables {
+        return Some(Compaction::new(levels, 0, levels[0].clone()));
+    }
+    cursors.resize(levels.len(), Vec::new());
+    for level in 1..levels.len() {
+        let size: u64 = levels[level].iter().map(|table| table.size()).sum();
+        if size <= level_limit(options, level) {
+            continue;
+        }
+        let tables = &levels[level];
+        let table = tables
+            .iter()
+            .find(|table| table.first_key() > cursors[level].as_slice())
+            .unwrap_or(&tables[0]);
+        cursors[level] = table.last_key().to_vec();
+        return Some(Compaction::new(levels, level, vec![Arc::clone(table)]));
+    }
+    None
+}
+
+/// Returns the size limit of a level deeper than level 0.
+fn level_limit(options: &LsmOptions, level: usize) -> u64 {
+    let multiplier = LEVEL_SIZE_MULTIPLIER.saturating_pow(level as u32 - 1);
+    options.level1_size.saturating_mul(multiplier)
+}
+
+/// Merges the tables of a compaction into the next level.
+pub(super) fn run(inner: &Inner, compaction: Compaction) -> Result<()> {
+    let state = inner.state();
+    let output = compaction.level + 1;
+    let bottom = state.levels.iter().skip(output + 1).all(Vec::is_empty);
+    let tables: Vec<_> = compaction
+        .inputs
+        .iter()
+        .chain(&compaction.overlapping)
+        .cloned()
+        .collect();
+    let outputs = merge_tables(inner, &tables, bottom)?;
+
+    let mut levels = state.levels.clone();
+    if levels.len() == output {
+        levels.push(Vec::new());
+    }
+    for level in &mut levels[compaction.level..=output] {
+        level.retain(|table| !tables.iter().any(|t| t.id() == table.id()));
+    }
+    debug!(
+        "Compacted {} tables of level {} and {} tables of level {} into {} tables",
+        compaction.inputs.len(),
+        compaction.level,
+        compaction.overlapping.len(),
+        output,
+        outputs.len()
+    );
+    levels[output].extend(outputs);
+    levels[output].sort_by(|a, b| a.first_key().cmp(b.first_key()));
+    inner.install(levels, None)?;
+    remove_tables(inner, &tables)
+}
+
+/// Merges every table into the deepest level holding tables, dropping every tombstone
+/// and expired value.
+///
+/// It must be called with the background lock held and no frozen memtable.
+pub(super) fn run_full(inner: &Inner) -> Result<()> {
+    let state = inner.state();
+    let tables: Vec<_> = state.levels.iter().flatten().cloned().collect();
+    if tables.is_empty() {
+        return Ok(());
+    }
+    let output = state
+        .levels
+        .iter()
+        .rposition(|level| !level.is_empty())
+        .unwrap_or(0)
+        .max(1);
+    let outputs = merge_tables(inner, &tables, true)?;
+
+    let mut levels = vec![Vec::new(); state.levels.len().max(output + 1)];
+    levels[output] = outputs;
+    inner.install(levels, None)?;
+    remove_tables(inner, &tables)
+}
+
+/// Merges tables ordered from the newest to the oldest into new tables of about
+/// `table_size` bytes.
+///
+/// Tombstones and expired values are dropped if `drop_dead` is set.
+fn merge_tables(inner: &Inner, tables: &[Arc<Table>], drop_dead: bool) -> Result<Vec<Arc<Table>>> {
+    let count: u64 = tables.iter().map(|table| table.count()).sum();
+    let size: u64 = tables.iter().map(|table| table.size()).sum();
+    // the bloom filter of every output table is sized for its share of the entries
+    let table_size = inner.options.table_size as u64;
+    let expected = if size > table_size {
+        count * table_size / size + 1
+    } else {
+        count
+    };
+
+    let sources = tables
+        .iter()
+        .map(|table| Box::new(table.iter_from(&[])) as Source)
+        .collect();
+    let now = now_millis();
+    let mut outputs = Vec::new();
+    let mut builder: Option<TableBuilder> = None;
+    for entry in Merge::new(sources)? {
+        let (key, entry) = entry?;
+        if drop_dead && !entry.is_live(now) {
+            continue;
+        }
+        if builder.is_none() {
+            builder = Some(TableBuilder::create(
+                &inner.path,
+                inner.new_file_id(),
+                expected,
+            )?);
+        }
+        let table = builder.as_mut().unwrap();
+        table.add(&key, &entry)?;
+        if table.size() >= inner.options.table_size {
+            outputs.push(Arc::new(builder.take().unwrap().finish()?));
+        }
+    }
+    if let Some(builder) = builder {
+        outputs.push(Arc::new(builder.finish()?));
+    }
+    Ok(outputs)
+}
+
+/// Deletes the files of the tables replaced by a compaction.
+///
+/// Readers holding the tables keep reading them through their memory maps.
+fn remove_tables(inner: &Inner, tables: &[Arc<Table>]) -> Result<()> {
+    for table in tables {
+        fs::remove_file(table_path(&inner.path, table.id()))?;
+    }
+    Ok(())
+}
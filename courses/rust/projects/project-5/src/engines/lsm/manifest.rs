@@ -0,0 +1,68 @@
+//! The manifest listing the tables of every level.
+//!
+//! The manifest (`MANIFEST`) is rewritten after every flush and compaction, before the
+//! replaced tables and write-ahead logs are deleted. It is written to a temporary file
+//! that is renamed over the previous one, so a crash leaves either the old or the new
+//! list of tables. Tables not listed in the manifest are leftovers of an interrupted
+//! flush or compaction and are deleted on open.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// Name of the manifest file in the data directory.
+const MANIFEST: &str = "MANIFEST";
+
+/// Version of the manifest format.
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct Manifest {
+    pub(super) version: u32,
+    /// Lower bound of the ids of the files created afterwards.
+    pub(super) next_file: u64,
+    /// Smallest id of the write-ahead logs whose memtables are not flushed. The older
+    /// logs are already in the tables and must not be replayed.
+    pub(super) min_wal: u64,
+    /// Ids of the tables of every level, newest first in level 0 and in key order in
+    /// the deeper levels.
+    pub(super) levels: Vec<Vec<u64>>,
+}
+
+impl Manifest {
+    pub(super) fn new(next_file: u64, min_wal: u64, levels: Vec<Vec<u64>>) -> Manifest {
+        Manifest {
+            version: MANIFEST_VERSION,
+            next_file,
+            min_wal,
+            levels,
+        }
+    }
+}
+
+/// Loads the manifest in `dir`, or returns `None` if there is none.
+pub(super) fn load(dir: &Path) -> Result<Option<Manifest>> {
+    let manifest: Manifest = match File::open(dir.join(MANIFEST)) {
+        Ok(file) => serde_json::from_reader(file)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if manifest.version != MANIFEST_VERSION {
+        return Err(KvsError::UnsupportedVersion(manifest.version));
+    }
+    Ok(Some(manifest))
+}
+
+/// Replaces the manifest in `dir`.
+pub(super) fn store(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", MANIFEST));
+    let mut tmp = File::create(&tmp_path)?;
+    serde_json::to_writer(&mut tmp, manifest)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, dir.join(MANIFEST))?;
+    Ok(())
+}
@@ -0,0 +1,160 @@
+//! The memtables holding the latest writes, and the encoding of entries shared by the
+//! write-ahead logs and the SSTables.
+//!
+//! An entry is encoded as:
+//!
+//! ```text
+//! +---------------+-----------+--------------+-----------------+-----+-------+
+//! | key len (u32) | kind (u8) | expiry (u64) | value len (u32) | key | value |
+//! +---------------+-----------+--------------+-----------------+-----+-------+
+//! ```
+//!
+//! All integers are little-endian. The expiry time is in milliseconds since the Unix
+//! epoch, or 0 if the value never expires. A removal has no value.
+
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam_skiplist::SkipMap;
+
+/// Length of the fixed part of an encoded entry.
+const ENTRY_HEADER_LEN: usize = 17;
+/// Bytes charged for every write to a memtable on top of its key and value, roughly
+/// the size of its bookkeeping.
+const ENTRY_OVERHEAD: usize = 32;
+
+const KIND_VALUE: u8 = 0;
+const KIND_REMOVED: u8 = 1;
+
+/// The latest write of a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Entry {
+    /// The key is set to the value, which expires at the given time if any.
+    Value {
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    /// The key is removed. The tombstone shadows the older values of the key until a
+    /// compaction into the deepest level drops it.
+    Removed,
+}
+
+impl Entry {
+    /// Returns the value unless the key is removed or the value is expired at `now`.
+    pub(super) fn into_live(self, now: u64) -> Option<Vec<u8>> {
+        match self {
+            Entry::Value { value, expires_at } if expires_at.is_none_or(|at| at > now) => {
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns whether the key exists at `now`.
+    pub(super) fn is_live(&self, now: u64) -> bool {
+        match self {
+            Entry::Value { expires_at, .. } => expires_at.is_none_or(|at| at > now),
+            Entry::Removed => false,
+        }
+    }
+}
+
+/// A sorted table of the writes since the previous memtable was frozen.
+///
+/// Its writes are also in the write-ahead logs listed in `wals`, which are deleted once
+/// the memtable is flushed to an SSTable.
+pub(super) struct Memtable {
+    map: SkipMap<Vec<u8>, Entry>,
+    // bytes charged to the writes, which decides when the memtable is frozen
+    size: AtomicUsize,
+    wals: Vec<u64>,
+}
+
+impl Memtable {
+    pub(super) fn new(wals: Vec<u64>) -> Memtable {
+        Memtable {
+            map: SkipMap::new(),
+            size: AtomicUsize::new(0),
+            wals,
+        }
+    }
+
+    /// Returns the ids of the write-ahead logs holding the writes.
+    pub(super) fn wals(&self) -> &[u64] {
+        &self.wals
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of keys in the memtable.
+    pub(super) fn len(&self) -> usize {
+        self.map.iter().count()
+    }
+
+    pub(super) fn get(&self, key: &[u8]) -> Option<Entry> {
+        self.map.get(key).map(|entry| entry.value().clone())
+    }
+
+    /// Applies a write.
+    ///
+    /// It must be called with the writer lock held.
+    pub(super) fn apply(&self, key: Vec<u8>, entry: Entry) {
+        let value_len = match &entry {
+            Entry::Value { value, .. } => value.len(),
+            Entry::Removed => 0,
+        };
+        self.size
+            .fetch_add(key.len() + value_len + ENTRY_OVERHEAD, Ordering::SeqCst);
+        self.map.insert(key, entry);
+    }
+
+    /// Returns an iterator over the writes to the keys from `start` on.
+    pub(super) fn iter_from<'a>(
+        &'a self,
+        start: &[u8],
+    ) -> impl Iterator<Item = (Vec<u8>, Entry)> + 'a {
+        self.map
+            .range(start.to_vec()..)
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+    }
+}
+
+/// Appends an encoded entry to `buf`.
+pub(super) fn encode_entry(buf: &mut Vec<u8>, key: &[u8], entry: &Entry) {
+    let (kind, value, expires_at) = match entry {
+        Entry::Value { value, expires_at } => (KIND_VALUE, value.as_slice(), *expires_at),
+        Entry::Removed => (KIND_REMOVED, &[][..], None),
+    };
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.push(kind);
+    buf.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+}
+
+/// Decodes the entry at the beginning of `buf`, returning its key, the entry and the
+/// number of bytes it takes.
+///
+/// Returns `None` if `buf` doesn't start with a valid entry.
+pub(super) fn decode_entry(buf: &[u8]) -> Option<(&[u8], Entry, usize)> {
+    let header = buf.get(..ENTRY_HEADER_LEN)?;
+    let key_len = u32::from_le_bytes(header[..4].try_into().ok()?) as usize;
+    let expires_at = u64::from_le_bytes(header[5..13].try_into().ok()?);
+    let value_len = u32::from_le_bytes(header[13..].try_into().ok()?) as usize;
+    let key_end = ENTRY_HEADER_LEN + key_len;
+    let end = key_end + value_len;
+    let key = buf.get(ENTRY_HEADER_LEN..key_end)?;
+    let value = buf.get(key_end..end)?;
+    let entry = match header[4] {
+        KIND_VALUE => Entry::Value {
+            value: value.to_vec(),
+            expires_at: Some(expires_at).filter(|&at| at != 0),
+        },
+        KIND_REMOVED if value_len == 0 => Entry::Removed,
+        _ => return None,
+    };
+    Some((key, entry, end))
+}
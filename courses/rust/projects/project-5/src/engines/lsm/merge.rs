@@ -0,0 +1,64 @@
+//! Merging of the sorted sources of entries: memtables, tables and levels.
+
+use std::sync::Arc;
+
+use super::memtable::Entry;
+use super::sstable::Table;
+use crate::Result;
+
+/// A sequence of entries in ascending key order.
+pub(super) type Source<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Entry)>> + 'a>;
+
+/// Merges sorted sources of entries ordered from the newest to the oldest.
+///
+/// Every key is yielded once, with its entry in the first source holding it.
+pub(super) struct Merge<'a> {
+    sources: Vec<Source<'a>>,
+    heads: Vec<Option<(Vec<u8>, Entry)>>,
+}
+
+impl<'a> Merge<'a> {
+    pub(super) fn new(mut sources: Vec<Source<'a>>) -> Result<Merge<'a>> {
+        let heads = sources
+            .iter_mut()
+            .map(|source| source.next().transpose())
+            .collect::<Result<_>>()?;
+        Ok(Merge { sources, heads })
+    }
+}
+
+impl<'a> Iterator for Merge<'a> {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self
+            .heads
+            .iter()
+            .filter_map(|head| head.as_ref().map(|(key, _)| key))
+            .min()?
+            .clone();
+        let mut newest = None;
+        for (i, head) in self.heads.iter_mut().enumerate() {
+            if head.as_ref().is_some_and(|(head_key, _)| *head_key == key) {
+                let (_, entry) = head.take().unwrap();
+                newest.get_or_insert(entry);
+                *head = match self.sources[i].next().transpose() {
+                    Ok(next) => next,
+                    Err(e) => return Some(Err(e)),
+                };
+            }
+        }
+        newest.map(|entry| Ok((key, entry)))
+    }
+}
+
+/// Returns the entries with keys from `start` on of a level whose tables are sorted
+/// and don't overlap.
+pub(super) fn level_source<'a>(tables: &'a [Arc<Table>], start: &'a [u8]) -> Source<'a> {
+    let first = tables.partition_point(|table| table.last_key() < start);
+    Box::new(
+        tables[first..]
+            .iter()
+            .flat_map(move |table| table.iter_from(start)),
+    )
+}
@@ -0,0 +1,686 @@
+//! A storage engine based on a log-structured merge-tree.
+//!
+//! Writes are appended to a write-ahead log and applied to the memtable, a skip list
+//! in memory. Once the memtable holds `memtable_size` bytes of writes, it is frozen and
+//! a new one takes the writes while a background job on the thread pool flushes the
+//! frozen one to an immutable SSTable in level 0 (see the `sstable` module). The same
+//! job then compacts the levels that grow over their limits (see the `compaction`
+//! module).
+//!
+//! A lookup checks the memtable, the frozen memtables from the newest, the tables of
+//! level 0 from the newest and one table in every deeper level, and stops at the first
+//! entry of the key. A scan merges all of them. The memtables and the tables of every
+//! level form a state that is replaced as a whole by writers, flushes and compactions,
+//! so a reader works on the state it started with.
+//!
+//! The manifest lists the tables of every level (see the `manifest` module). On open,
+//! the tables listed are opened and the write-ahead logs of the memtables not flushed
+//! yet are replayed into the memtable.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, TryLockError};
+use std::time::{Duration, SystemTime};
+
+use tokio::prelude::*;
+use tokio::sync::oneshot;
+
+use self::manifest::Manifest;
+use self::memtable::{Entry, Memtable};
+use self::merge::{level_source, Merge, Source};
+use self::sstable::Table;
+use self::wal::{wal_path, WalWriter};
+use super::{
+    expires_after, expiry_time, now_millis, scan_pages, BatchOp, CasResult, KvsEngine, ScanPage,
+    WriteBatch,
+};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result};
+
+mod compaction;
+mod manifest;
+mod memtable;
+mod merge;
+mod sstable;
+mod wal;
+
+/// Options for opening an `LsmKvsEngine`.
+///
+/// ```rust
+/// # use kvs::LsmOptions;
+/// let options = LsmOptions {
+///     memtable_size: 1 << 20,
+///     ..LsmOptions::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// Bytes of writes after which the memtable is frozen and flushed to a table.
+    pub memtable_size: usize,
+    /// Number of tables in level 0 that triggers their compaction into level 1.
+    pub level0_tables: usize,
+    /// Total bytes of the tables in level 1. Every deeper level holds ten times more.
+    pub level1_size: u64,
+    /// Bytes of entries after which a compaction starts a new table.
+    pub table_size: usize,
+    /// Whether every write is synced to the disk before it is acknowledged.
+    pub sync: bool,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 << 20,
+            level0_tables: 4,
+            level1_size: 10 << 20,
+            table_size: 2 << 20,
+            sync: false,
+        }
+    }
+}
+
+/// A key value store based on a log-structured merge-tree.
+///
+/// Reads, writes, flushes and compactions run in the given thread pool. Writes are
+/// serialized by a lock, and at most one flush or compaction runs at a time.
+#[derive(Clone)]
+pub struct LsmKvsEngine<P: ThreadPool> {
+    pool: P,
+    inner: Arc<Inner>,
+    // stops the background jobs once the last clone is dropped
+    _guard: Arc<CloseGuard>,
+}
+
+struct Inner {
+    // directory for the write-ahead logs, the tables and the manifest
+    path: PathBuf,
+    options: LsmOptions,
+    state: RwLock<Arc<State>>,
+    writer: Mutex<Writer>,
+    // held by the running flush or compaction, with the last key compacted in every level
+    background: Mutex<Vec<Vec<u8>>>,
+    // set when the memtables or the levels may need a flush or compaction
+    pending: AtomicBool,
+    closed: AtomicBool,
+    next_file: AtomicU64,
+}
+
+/// The memtables and the tables of every level.
+#[derive(Clone)]
+struct State {
+    mem: Arc<Memtable>,
+    // frozen memtables waiting to be flushed, newest first
+    frozen: Vec<Arc<Memtable>>,
+    // newest first in level 0, in key order in the deeper levels
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+struct Writer {
+    wal: WalWriter,
+    // set when a memtable is frozen, until a flush is scheduled
+    frozen: bool,
+}
+
+/// Waits for the running flush or compaction when the last clone of an engine is
+/// dropped, so that no file is written after the engine is reopened.
+struct CloseGuard {
+    inner: Arc<Inner>,
+}
+
+impl Drop for CloseGuard {
+    fn drop(&mut self) {
+        self.inner.closed.store(true, Ordering::SeqCst);
+        let _background = self.inner.background.lock();
+    }
+}
+
+impl<P: ThreadPool> LsmKvsEngine<P> {
+    /// Opens an `LsmKvsEngine` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// `concurrency` specifies the number of threads in the thread pool.
+    ///
+    /// If the process crashed in the middle of writing to a write-ahead log, the log
+    /// ends with a torn record, which is dropped.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Corrupted` if a record that is not at the tail of a
+    /// write-ahead log is invalid.
+    pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        Self::open_with_options(path, concurrency, LsmOptions::default())
+    }
+
+    /// Opens an `LsmKvsEngine` with the given path and options.
+    ///
+    /// See `open` for details.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: LsmOptions,
+    ) -> Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let manifest = manifest::load(&path)?.unwrap_or_default();
+        let mut levels = manifest
+            .levels
+            .iter()
+            .map(|ids| {
+                ids.iter()
+                    .map(|&id| Ok(Arc::new(Table::open(&path, id)?)))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if levels.is_empty() {
+            levels.push(Vec::new());
+        }
+
+        let (mut wals, max_id) = remove_stale(&path, &manifest)?;
+        let wal_id = manifest.next_file.max(max_id + 1);
+        wals.push(wal_id);
+        let mem = Memtable::new(wals);
+        for &id in &mem.wals()[..mem.wals().len() - 1] {
+            wal::replay(&path, id, &mem)?;
+        }
+        let wal = WalWriter::create(&path, wal_id, options.sync)?;
+        let frozen = mem.size() >= options.memtable_size;
+
+        let inner = Arc::new(Inner {
+            path,
+            options,
+            state: RwLock::new(Arc::new(State {
+                mem: Arc::new(mem),
+                frozen: Vec::new(),
+                levels,
+            })),
+            writer: Mutex::new(Writer { wal, frozen: false }),
+            background: Mutex::new(Vec::new()),
+            pending: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            next_file: AtomicU64::new(wal_id + 1),
+        });
+        if frozen {
+            inner.freeze(&mut inner.writer.lock().unwrap())?;
+        }
+        let engine = LsmKvsEngine {
+            pool: P::new(concurrency)?,
+            _guard: Arc::new(CloseGuard {
+                inner: Arc::clone(&inner),
+            }),
+            inner,
+        };
+        // level 0 may be full if the process stopped before a compaction
+        engine.schedule_background();
+        Ok(engine)
+    }
+
+    /// Runs `f` on the thread pool.
+    fn spawn<T, F>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self) -> Result<T> + Send + 'static,
+    {
+        let engine = self.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = f(&engine);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Streams the live pairs with keys from `start` on in ascending key order until
+    /// `in_range` returns `false`, merging them a page at a time on the thread pool.
+    ///
+    /// At most `limit` pairs are streamed if it is given.
+    fn read_pairs<F>(
+        &self,
+        start: Vec<u8>,
+        in_range: F,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        // keeps the tables from being rewritten by a reopened engine mid-scan
+        let guard = Arc::clone(&self._guard);
+        scan_pages(&self.pool, start, limit, move |start, max| {
+            let _guard = &guard;
+            inner.scan_page(start, &in_range, max)
+        })
+    }
+
+    /// Runs `f` with the writer lock held, and schedules a flush if it froze the
+    /// memtable.
+    fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Inner, &mut Writer) -> Result<T>,
+    {
+        let mut writer = self.inner.writer.lock().unwrap();
+        let res = f(&self.inner, &mut writer);
+        if mem::replace(&mut writer.frozen, false) {
+            self.schedule_background();
+        }
+        res
+    }
+
+    /// Flushes the frozen memtables and compacts the levels on the thread pool.
+    fn schedule_background(&self) {
+        let inner = Arc::clone(&self.inner);
+        self.pool.spawn(move || {
+            if let Err(e) = inner.run_background() {
+                error!("Flush or compaction failed: {}", e);
+            }
+        });
+    }
+}
+
+impl Inner {
+    fn state(&self) -> Arc<State> {
+        Arc::clone(&self.state.read().unwrap())
+    }
+
+    fn new_file_id(&self) -> u64 {
+        self.next_file.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Returns the value of a key, unless the key is missing or expired.
+    fn live(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let entry = self.state().get(key)?;
+        Ok(entry.and_then(|entry| entry.into_live(now_millis())))
+    }
+
+    /// Returns the expiry time of a key, or `KvsError::KeyNotFound` if the key is
+    /// missing or expired.
+    fn expiry(&self, key: &[u8]) -> Result<Option<u64>> {
+        let now = now_millis();
+        match self.state().get(key)? {
+            Some(Entry::Value { expires_at, .. }) if expires_at.is_none_or(|at| at > now) => {
+                Ok(expires_at)
+            }
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    /// Logs the writes as one record and applies them to the memtable, which is frozen
+    /// once it is full.
+    fn apply(&self, writer: &mut Writer, entries: Vec<(Vec<u8>, Entry)>) -> Result<()> {
+        writer.wal.append(&entries)?;
+        // only writers replace the memtable
+        let mem = Arc::clone(&self.state().mem);
+        for (key, entry) in entries {
+            mem.apply(key, entry);
+        }
+        if mem.size() >= self.options.memtable_size {
+            self.freeze(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the memtable with an empty one logged to a new write-ahead log.
+    fn freeze(&self, writer: &mut Writer) -> Result<()> {
+        let id = self.new_file_id();
+        writer.wal = WalWriter::create(&self.path, id, self.options.sync)?;
+        writer.frozen = true;
+        let mut state = self.state.write().unwrap();
+        let mut new_state = State::clone(&state);
+        new_state.frozen.insert(0, Arc::clone(&state.mem));
+        new_state.mem = Arc::new(Memtable::new(vec![id]));
+        *state = Arc::new(new_state);
+        Ok(())
+    }
+
+    /// Flushes the frozen memtables and runs the compactions the levels need, unless
+    /// the job running on another thread does it.
+    fn run_background(&self) -> Result<()> {
+        self.pending.store(true, Ordering::SeqCst);
+        loop {
+            let mut cursors = match self.background.try_lock() {
+                Ok(cursors) => cursors,
+                // the running job sees `pending` and runs again
+                Err(TryLockError::WouldBlock) => return Ok(()),
+                Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+            };
+            while self.pending.swap(false, Ordering::SeqCst) {
+                if self.closed.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                self.flush_frozen()?;
+                while let Some(task) = compaction::pick(&self.state(), &self.options, &mut cursors)
+                {
+                    compaction::run(self, task)?;
+                    if self.closed.load(Ordering::SeqCst) {
+                        return Ok(());
+                    }
+                }
+            }
+            drop(cursors);
+            // a job scheduled after the last check may have failed to take the lock
+            if !self.pending.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Flushes the frozen memtables, from the oldest, to tables in level 0.
+    ///
+    /// It must be called with the background lock held.
+    fn flush_frozen(&self) -> Result<()> {
+        while let Some(mem) = self.state().frozen.last().cloned() {
+            let mut levels = self.state().levels.clone();
+            let len = mem.len();
+            if len > 0 {
+                let mut builder =
+                    sstable::TableBuilder::create(&self.path, self.new_file_id(), len as u64)?;
+                for (key, entry) in mem.iter_from(&[]) {
+                    builder.add(&key, &entry)?;
+                }
+                levels[0].insert(0, Arc::new(builder.finish()?));
+            }
+            self.install(levels, Some(&mem))?;
+            for &id in mem.wals() {
+                fs::remove_file(wal_path(&self.path, id))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the new levels in the manifest and makes them visible, dropping the
+    /// flushed memtable if any.
+    ///
+    /// It must be called with the background lock held.
+    fn install(&self, levels: Vec<Vec<Arc<Table>>>, flushed: Option<&Arc<Memtable>>) -> Result<()> {
+        // Memtables frozen concurrently have newer logs, so the smallest log id can
+        // only grow afterwards.
+        let min_wal = self
+            .state()
+            .memtables()
+            .filter(|mem| flushed.is_none_or(|flushed| !Arc::ptr_eq(mem, flushed)))
+            .flat_map(|mem| mem.wals().iter().copied())
+            .min()
+            .unwrap_or(0);
+        let ids = levels
+            .iter()
+            .map(|level| level.iter().map(|table| table.id()).collect())
+            .collect();
+        let next_file = self.next_file.load(Ordering::SeqCst);
+        manifest::store(&self.path, &Manifest::new(next_file, min_wal, ids))?;
+
+        let mut state = self.state.write().unwrap();
+        let mut new_state = State::clone(&state);
+        if let Some(flushed) = flushed {
+            new_state.frozen.retain(|mem| !Arc::ptr_eq(mem, flushed));
+        }
+        new_state.levels = levels;
+        *state = Arc::new(new_state);
+        Ok(())
+    }
+
+    /// Flushes every memtable and merges every table into the deepest level.
+    fn compact(&self) -> Result<()> {
+        {
+            let mut writer = self.writer.lock().unwrap();
+            if self.state().mem.size() > 0 {
+                self.freeze(&mut writer)?;
+                // flushed below
+                writer.frozen = false;
+            }
+        }
+        let _background = self.background.lock().unwrap();
+        self.flush_frozen()?;
+        compaction::run_full(self)
+    }
+
+    /// Returns at most `max` live pairs with keys from `start` on, in ascending key
+    /// order, until `in_range` returns `false`.
+    ///
+    /// Each page is merged from the tables current when it is read, so a scan sees
+    /// the writes made between its pages to the keys after its cursor.
+    fn scan_page<F>(&self, start: &[u8], in_range: F, max: usize) -> Result<ScanPage>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let state = self.state();
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        for entry in Merge::new(state.sources(start))? {
+            let (key, entry) = entry?;
+            if !in_range(&key) {
+                break;
+            }
+            if pairs.len() >= max {
+                return Ok((pairs, Some(key)));
+            }
+            if let Some(value) = entry.into_live(now) {
+                pairs.push((key, value));
+            }
+        }
+        Ok((pairs, None))
+    }
+}
+
+impl State {
+    fn memtables(&self) -> impl Iterator<Item = &Arc<Memtable>> {
+        iter::once(&self.mem).chain(&self.frozen)
+    }
+
+    /// Returns the newest entry of a key.
+    fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        for mem in self.memtables() {
+            if let Some(entry) = mem.get(key) {
+                return Ok(Some(entry));
+            }
+        }
+        for table in &self.levels[0] {
+            if let Some(entry) = table.get(key)? {
+                return Ok(Some(entry));
+            }
+        }
+        for level in &self.levels[1..] {
+            let i = level.partition_point(|table| table.last_key() < key);
+            if let Some(table) = level.get(i) {
+                if let Some(entry) = table.get(key)? {
+                    return Ok(Some(entry));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the sources of the entries with keys from `start` on, newest first.
+    fn sources<'a>(&'a self, start: &'a [u8]) -> Vec<Source<'a>> {
+        let mut sources: Vec<Source> = self
+            .memtables()
+            .map(|mem| Box::new(mem.iter_from(start).map(Ok)) as Source)
+            .collect();
+        for table in &self.levels[0] {
+            sources.push(Box::new(table.iter_from(start)));
+        }
+        for level in &self.levels[1..] {
+            sources.push(level_source(level, start));
+        }
+        sources
+    }
+}
+
+/// Deletes the files left by interrupted flushes and compactions and the write-ahead
+/// logs of the flushed memtables.
+///
+/// Returns the ids of the write-ahead logs to replay in ascending order, and the
+/// largest id of the files in the directory.
+fn remove_stale(path: &Path, manifest: &Manifest) -> Result<(Vec<u64>, u64)> {
+    let mut wals = Vec::new();
+    let mut max_id = 0;
+    for entry in fs::read_dir(path)? {
+        let file_path = entry?.path();
+        let id = match file_path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        max_id = max_id.max(id);
+        let stale = match file_path.extension().and_then(OsStr::to_str) {
+            Some("building") => true,
+            Some("sst") => !manifest.levels.iter().flatten().any(|&table| table == id),
+            Some("wal") if id < manifest.min_wal => true,
+            Some("wal") => {
+                wals.push(id);
+                false
+            }
+            _ => false,
+        };
+        if stale && file_path.is_file() {
+            warn!("Removing stale file {:?}", file_path);
+            fs::remove_file(&file_path)?;
+        }
+    }
+    wals.sort_unstable();
+    Ok((wals, max_id))
+}
+
+impl<P: ThreadPool> KvsEngine for LsmKvsEngine<P> {
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let entry = Entry::Value {
+            value,
+            expires_at: None,
+        };
+        self.spawn(move |engine| {
+            engine.write(|inner, writer| inner.apply(writer, vec![(key, entry)]))
+        })
+    }
+
+    fn set_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let entry = Entry::Value {
+            value,
+            expires_at: Some(expires_after(ttl)),
+        };
+        self.spawn(move |engine| {
+            engine.write(|inner, writer| inner.apply(writer, vec![(key, entry)]))
+        })
+    }
+
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        self.spawn(move |engine| engine.inner.live(&key))
+    }
+
+    fn expiry_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<SystemTime>, Error = KvsError> + Send> {
+        self.spawn(move |engine| Ok(engine.inner.expiry(&key)?.map(expiry_time)))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.spawn(move |engine| {
+            engine.write(|inner, writer| {
+                if inner.live(&key)?.is_none() {
+                    return Err(KvsError::KeyNotFound);
+                }
+                inner.apply(writer, vec![(key, Entry::Removed)])
+            })
+        })
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = CasResult<Vec<u8>>, Error = KvsError> + Send> {
+        self.spawn(move |engine| {
+            engine.write(|inner, writer| {
+                let current = inner.live(&key)?;
+                if current != expected {
+                    return Ok(Err(current));
+                }
+                let entry = match new {
+                    Some(value) => Entry::Value {
+                        value,
+                        expires_at: None,
+                    },
+                    None if current.is_none() => return Ok(Ok(())),
+                    None => Entry::Removed,
+                };
+                inner.apply(writer, vec![(key, entry)])?;
+                Ok(Ok(()))
+            })
+        })
+    }
+
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.spawn(move |engine| {
+            engine.write(|inner, writer| {
+                batch.check_removes(|key| Ok(inner.live(key)?.is_some()))?;
+                let entries = batch
+                    .into_iter()
+                    .map(|op| match op {
+                        BatchOp::Set { key, value } => (
+                            key,
+                            Entry::Value {
+                                value,
+                                expires_at: None,
+                            },
+                        ),
+                        BatchOp::Remove { key } => (key, Entry::Removed),
+                    })
+                    .collect::<Vec<_>>();
+                if entries.is_empty() {
+                    return Ok(());
+                }
+                inner.apply(writer, entries)
+            })
+        })
+    }
+
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        let in_range = move |key: &[u8]| end.as_ref().is_none_or(|end| key < end.as_slice());
+        self.read_pairs(start, in_range, limit)
+    }
+
+    fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        self.read_pairs(prefix.clone(), move |key| key.starts_with(&prefix), None)
+    }
+
+    /// Flushes the memtable and merges every table into one sorted run in the deepest
+    /// level, dropping the overwritten values, the tombstones and the expired values.
+    fn compact(&self) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.spawn(|engine| engine.inner.compact())
+    }
+}
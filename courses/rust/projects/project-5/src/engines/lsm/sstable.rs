@@ -0,0 +1,340 @@
+//! Sorted string tables, the immutable files the memtables are flushed to.
+//!
+//! A table (`<id>.sst`) holds the entries of its keys in ascending key order, encoded
+//! as in the `memtable` module and grouped into blocks of about `BLOCK_SIZE` bytes.
+//! The block index records the offset, the length and the last key of every block, so
+//! a lookup only decodes one block. A bloom filter of the keys lets lookups skip the
+//! tables that don't hold a key without touching the blocks.
+//!
+//! Layouts, with integers in little endian:
+//!
+//! - block index entry: block offset (u64) | block length (u32) | key length (u32) | last key
+//! - bloom filter: number of hashes (u32) | bits
+//! - footer: block index offset | bloom filter offset | entry count (u64 each) | magic
+//!
+//! A table is written to `<id>.building` and renamed once complete, and it is read
+//! through a memory map.
+
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use memmap::Mmap;
+
+use super::memtable::{decode_entry, encode_entry, Entry};
+use crate::engines::bloom::BloomFilter;
+use crate::Result;
+
+/// Magic number at the end of a table.
+const TABLE_MAGIC: [u8; 4] = *b"KVST";
+const FOOTER_LEN: usize = 28;
+/// Bytes of entries after which a block is closed.
+const BLOCK_SIZE: usize = 4096;
+
+/// Location and last key of a block.
+struct BlockHandle {
+    offset: usize,
+    len: usize,
+    last_key: Vec<u8>,
+}
+
+/// Writes a table from entries added in ascending key order.
+pub(super) struct TableBuilder {
+    writer: BufWriter<File>,
+    dir: PathBuf,
+    id: u64,
+    // bytes written so far
+    len: usize,
+    count: u64,
+    block: Vec<u8>,
+    last_key: Vec<u8>,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+}
+
+impl TableBuilder {
+    /// Creates the table `id` in `dir`, sizing the bloom filter for `expected_entries`.
+    pub(super) fn create(dir: &Path, id: u64, expected_entries: u64) -> Result<TableBuilder> {
+        Ok(TableBuilder {
+            writer: BufWriter::new(File::create(tmp_path(dir, id))?),
+            dir: dir.to_owned(),
+            id,
+            len: 0,
+            count: 0,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            last_key: Vec::new(),
+            index: Vec::new(),
+            bloom: BloomFilter::new(expected_entries),
+        })
+    }
+
+    pub(super) fn add(&mut self, key: &[u8], entry: &Entry) -> Result<()> {
+        encode_entry(&mut self.block, key, entry);
+        self.bloom.insert(key);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.count += 1;
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the bytes of entries added so far.
+    pub(super) fn size(&self) -> usize {
+        self.len + self.block.len()
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        self.writer.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            offset: self.len,
+            len: self.block.len(),
+            last_key: self.last_key.clone(),
+        });
+        self.len += self.block.len();
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes the block index, the bloom filter and the footer, and opens the table.
+    pub(super) fn finish(mut self) -> Result<Table> {
+        if !self.block.is_empty() {
+            self.finish_block()?;
+        }
+        let index_pos = self.len as u64;
+        for handle in &self.index {
+            self.writer
+                .write_all(&(handle.offset as u64).to_le_bytes())?;
+            self.writer.write_all(&(handle.len as u32).to_le_bytes())?;
+            self.writer
+                .write_all(&(handle.last_key.len() as u32).to_le_bytes())?;
+            self.writer.write_all(&handle.last_key)?;
+            self.len += 16 + handle.last_key.len();
+        }
+        let bloom_pos = self.len as u64;
+        self.bloom.write_to(&mut self.writer)?;
+        for field in &[index_pos, bloom_pos, self.count] {
+            self.writer.write_all(&field.to_le_bytes())?;
+        }
+        self.writer.write_all(&TABLE_MAGIC)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        drop(self.writer);
+        fs::rename(tmp_path(&self.dir, self.id), table_path(&self.dir, self.id))?;
+        Table::open(&self.dir, self.id)
+    }
+}
+
+/// An immutable table of entries sorted by key.
+pub(super) struct Table {
+    id: u64,
+    map: Mmap,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    count: u64,
+    first_key: Vec<u8>,
+}
+
+impl Table {
+    /// Opens the table `id` in `dir`.
+    pub(super) fn open(dir: &Path, id: u64) -> Result<Table> {
+        let file = File::open(table_path(dir, id))?;
+        if file.metadata()?.len() < FOOTER_LEN as u64 {
+            return Err(invalid_table(id).into());
+        }
+        // Tables are never written again once renamed into place. They are only deleted,
+        // which leaves the mapped pages intact.
+        let map = unsafe { Mmap::map(&file)? };
+        Table::parse(id, map).ok_or_else(|| invalid_table(id).into())
+    }
+
+    fn parse(id: u64, map: Mmap) -> Option<Table> {
+        let footer_pos = map.len() - FOOTER_LEN;
+        let footer = &map[footer_pos..];
+        if footer[24..] != TABLE_MAGIC {
+            return None;
+        }
+        let index_pos = read_u64(footer, 0)? as usize;
+        let bloom_pos = read_u64(footer, 8)? as usize;
+        let count = read_u64(footer, 16)?;
+        if index_pos > bloom_pos || bloom_pos + 4 > footer_pos {
+            return None;
+        }
+
+        let mut index = Vec::new();
+        let mut pos = index_pos;
+        while pos < bloom_pos {
+            let offset = read_u64(&map, pos)? as usize;
+            let len = read_u32(&map, pos + 8)? as usize;
+            let key_len = read_u32(&map, pos + 12)? as usize;
+            let last_key = map.get(pos + 16..pos + 16 + key_len)?;
+            if offset + len > index_pos {
+                return None;
+            }
+            index.push(BlockHandle {
+                offset,
+                len,
+                last_key: last_key.to_vec(),
+            });
+            pos += 16 + key_len;
+        }
+        let bloom = BloomFilter::decode(&map[bloom_pos..footer_pos])?;
+        let first_key = match index.first() {
+            Some(handle) => decode_entry(&map[handle.offset..handle.offset + handle.len])?
+                .0
+                .to_vec(),
+            None => Vec::new(),
+        };
+        Some(Table {
+            id,
+            map,
+            index,
+            bloom,
+            count,
+            first_key,
+        })
+    }
+
+    pub(super) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Number of entries in the table.
+    pub(super) fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Size of the table file in bytes.
+    pub(super) fn size(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    /// Returns the smallest key in the table.
+    pub(super) fn first_key(&self) -> &[u8] {
+        &self.first_key
+    }
+
+    /// Returns the largest key in the table.
+    pub(super) fn last_key(&self) -> &[u8] {
+        self.index
+            .last()
+            .map_or(&[][..], |handle| handle.last_key.as_slice())
+    }
+
+    /// Returns whether the table may hold keys in `first..=last`.
+    pub(super) fn overlaps(&self, first: &[u8], last: &[u8]) -> bool {
+        self.first_key() <= last && self.last_key() >= first
+    }
+
+    /// Returns the entry of a key, or `None` if the table doesn't hold it.
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        // the first block whose last key is not less than `key` is the only one to hold it
+        let block = self
+            .index
+            .partition_point(|handle| handle.last_key.as_slice() < key);
+        let handle = match self.index.get(block) {
+            Some(handle) => handle,
+            None => return Ok(None),
+        };
+        let end = handle.offset + handle.len;
+        let mut pos = handle.offset;
+        while pos < end {
+            let (entry_key, entry, next) = self.entry_at(pos, end)?;
+            if entry_key == key {
+                return Ok(Some(entry));
+            }
+            if entry_key > key {
+                break;
+            }
+            pos = next;
+        }
+        Ok(None)
+    }
+
+    /// Returns an iterator over the entries with keys from `start` on.
+    pub(super) fn iter_from<'a>(&'a self, start: &'a [u8]) -> TableIter<'a> {
+        let block = self
+            .index
+            .partition_point(|handle| handle.last_key.as_slice() < start);
+        TableIter {
+            table: self,
+            block,
+            pos: self.index.get(block).map_or(0, |handle| handle.offset),
+            start,
+        }
+    }
+
+    /// Decodes the entry at `pos` in the block ending at `end`, returning the key, the
+    /// entry and the next offset.
+    fn entry_at(&self, pos: usize, end: usize) -> Result<(&[u8], Entry, usize)> {
+        match decode_entry(&self.map[pos..end]) {
+            Some((key, entry, len)) => Ok((key, entry, pos + len)),
+            None => Err(invalid_table(self.id).into()),
+        }
+    }
+}
+
+/// Iterator over the entries of a table in ascending key order.
+pub(super) struct TableIter<'a> {
+    table: &'a Table,
+    block: usize,
+    pos: usize,
+    // entries with smaller keys at the beginning of the first block are skipped
+    start: &'a [u8],
+}
+
+impl<'a> Iterator for TableIter<'a> {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let handle = self.table.index.get(self.block)?;
+            let end = handle.offset + handle.len;
+            if self.pos >= end {
+                self.block += 1;
+                self.pos = end;
+                continue;
+            }
+            match self.table.entry_at(self.pos, end) {
+                Ok((key, _, next)) if key < self.start => self.pos = next,
+                Ok((key, entry, next)) => {
+                    self.pos = next;
+                    return Some(Ok((key.to_vec(), entry)));
+                }
+                Err(e) => {
+                    self.block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(buf.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+fn invalid_table(id: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("table {} is corrupted", id),
+    )
+}
+
+pub(super) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+/// Path of a table while it is being written.
+pub(super) fn tmp_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.building", id))
+}
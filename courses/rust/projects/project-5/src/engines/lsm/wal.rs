@@ -0,0 +1,137 @@
+//! The write-ahead logs of the memtables.
+//!
+//! Every write is appended to the log of the active memtable (`<id>.wal`) before it is
+//! applied, so that the memtables can be rebuilt after a restart. A log file is a
+//! sequence of records:
+//!
+//! ```text
+//! +-------------+------------------+--------------------+
+//! | crc32 (u32) | payload len (u32) | entries (payload) |
+//! +-------------+------------------+--------------------+
+//! ```
+//!
+//! The checksum covers the payload, which holds one or more entries encoded as in the
+//! `memtable` module. The entries of a batch share one record, so a batch is replayed
+//! entirely or not at all.
+
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::memtable::{decode_entry, encode_entry, Entry, Memtable};
+use crate::{KvsError, Result};
+
+const RECORD_HEADER_LEN: usize = 8;
+
+/// Appends records to a write-ahead log.
+pub(super) struct WalWriter {
+    writer: BufWriter<File>,
+    sync: bool,
+}
+
+impl WalWriter {
+    /// Creates the log `<id>.wal` in `dir`, syncing every record if `sync` is set.
+    pub(super) fn create(dir: &Path, id: u64, sync: bool) -> Result<WalWriter> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(wal_path(dir, id))?;
+        Ok(WalWriter {
+            writer: BufWriter::new(file),
+            sync,
+        })
+    }
+
+    /// Appends the entries as one record.
+    pub(super) fn append(&mut self, entries: &[(Vec<u8>, Entry)]) -> Result<()> {
+        let mut payload = Vec::new();
+        for (key, entry) in entries {
+            encode_entry(&mut payload, key, entry);
+        }
+        self.writer
+            .write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        self.writer
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&payload)?;
+        self.writer.flush()?;
+        if self.sync {
+            self.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Applies the records of the log `<id>.wal` to `mem`.
+///
+/// If the log ends with a torn record, the record is dropped and the file is truncated.
+///
+/// # Errors
+///
+/// It returns `KvsError::Corrupted` if a record in the middle of the log is invalid.
+pub(super) fn replay(dir: &Path, id: u64, mem: &Memtable) -> Result<()> {
+    let path = wal_path(dir, id);
+    let buf = fs::read(&path)?;
+    let mut pos = 0;
+    while pos < buf.len() {
+        let header = match buf.get(pos..pos + RECORD_HEADER_LEN) {
+            Some(header) => header,
+            None => return truncate_wal(&path, pos),
+        };
+        let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let end = pos + RECORD_HEADER_LEN + len;
+        let payload = match buf.get(pos + RECORD_HEADER_LEN..end) {
+            Some(payload) => payload,
+            None => return truncate_wal(&path, pos),
+        };
+        if crc32fast::hash(payload) != crc {
+            // the last record is complete but only partially persisted
+            if end == buf.len() {
+                return truncate_wal(&path, pos);
+            }
+            return Err(KvsError::Corrupted {
+                gen: id,
+                pos: pos as u64,
+            });
+        }
+        let entries = decode_payload(payload).ok_or(KvsError::Corrupted {
+            gen: id,
+            pos: pos as u64,
+        })?;
+        for (key, entry) in entries {
+            mem.apply(key, entry);
+        }
+        pos = end;
+    }
+    Ok(())
+}
+
+fn decode_payload(mut payload: &[u8]) -> Option<Vec<(Vec<u8>, Entry)>> {
+    let mut entries = Vec::new();
+    while !payload.is_empty() {
+        let (key, entry, len) = decode_entry(payload)?;
+        entries.push((key.to_vec(), entry));
+        payload = &payload[len..];
+    }
+    Some(entries)
+}
+
+/// Drops the torn record at the end of a log by truncating the file to `len` bytes.
+fn truncate_wal(path: &Path, len: usize) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    let file_len = file.metadata()?.len();
+    warn!(
+        "{:?} ends with a torn record, dropping {} bytes after offset {}",
+        path,
+        file_len - len as u64,
+        len
+    );
+    file.set_len(len as u64)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+pub(super) fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
@@ -0,0 +1,266 @@
+use super::{expires_after, expiry_time, now_millis, BatchOp, CasResult, KvsEngine, WriteBatch};
+use crate::{KvsError, Result};
+use crossbeam_skiplist::SkipMap;
+use std::iter;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::prelude::stream;
+use tokio::prelude::*;
+
+/// A value and its expiry time in milliseconds since the Unix epoch, if any.
+#[derive(Clone)]
+struct Value {
+    value: Vec<u8>,
+    expires_at: Option<u64>,
+}
+
+impl Value {
+    fn new(value: Vec<u8>) -> Value {
+        Value {
+            value,
+            expires_at: None,
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// A key value store keeping everything in memory, for tests and throwaway caches.
+///
+/// The pairs are kept in a concurrent skip list and are lost when the last clone of
+/// the engine is dropped. Operations never block on I/O, so they run on the calling
+/// thread and the returned futures are already resolved.
+///
+/// Writes hold a lock exclusively, so a compare-and-swap or a batch never interleaves
+/// with another write, and lookups share it, so a read sees a batch entirely or not at
+/// all. A scan takes the lock for each pair it streams, so a scan running while a
+/// batch is applied may only see the writes of the batch to the keys after its cursor.
+#[derive(Clone)]
+pub struct MemoryKvsEngine {
+    map: Arc<SkipMap<Vec<u8>, Value>>,
+    lock: Arc<RwLock<()>>,
+}
+
+impl MemoryKvsEngine {
+    /// Creates an empty `MemoryKvsEngine`.
+    pub fn new() -> Self {
+        MemoryKvsEngine {
+            map: Arc::new(SkipMap::new()),
+            lock: Arc::new(RwLock::new(())),
+        }
+    }
+
+    /// Returns the value of a key, unless the key is missing or expired.
+    ///
+    /// The caller must hold the lock.
+    fn live(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let entry = self.map.get(key)?;
+        if entry.value().is_expired(now_millis()) {
+            return None;
+        }
+        Some(entry.value().value.clone())
+    }
+
+    /// Runs `f` with the lock held shared.
+    fn read<T>(
+        &self,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        T: Send + 'static,
+    {
+        let _reader = self.lock.read().unwrap();
+        Box::new(future::result(f()))
+    }
+
+    /// Runs `f` with the lock held exclusively.
+    fn write<T>(
+        &self,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        T: Send + 'static,
+    {
+        let _writer = self.lock.write().unwrap();
+        Box::new(future::result(f()))
+    }
+
+    /// Streams the live pairs with keys from `start` on, in ascending key order, until
+    /// `in_range` returns `false` or `limit` pairs are streamed.
+    ///
+    /// Each pair is looked up in the skip list when it is polled, so a scan that is
+    /// dropped early doesn't copy the rest of the range.
+    fn stream_pairs<F>(
+        &self,
+        start: Vec<u8>,
+        in_range: F,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>
+    where
+        F: Fn(&[u8]) -> bool + Send + 'static,
+    {
+        let map = Arc::clone(&self.map);
+        let lock = Arc::clone(&self.lock);
+        let now = now_millis();
+        let mut from = Bound::Included(start);
+        let pairs = iter::from_fn(move || loop {
+            let _reader = lock.read().unwrap();
+            let entry = map
+                .range::<Vec<u8>, _>((from.as_ref(), Bound::Unbounded))
+                .next()?;
+            if !in_range(entry.key()) {
+                return None;
+            }
+            from = Bound::Excluded(entry.key().clone());
+            if !entry.value().is_expired(now) {
+                return Some((entry.key().clone(), entry.value().value.clone()));
+            }
+        });
+        Box::new(stream::iter_ok(pairs.take(limit.unwrap_or(usize::MAX))))
+    }
+}
+
+impl Default for MemoryKvsEngine {
+    fn default() -> Self {
+        MemoryKvsEngine::new()
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(|| {
+            self.map.insert(key, Value::new(value));
+            Ok(())
+        })
+    }
+
+    fn set_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let value = Value {
+            value,
+            expires_at: Some(expires_after(ttl)),
+        };
+        self.write(|| {
+            self.map.insert(key, value);
+            Ok(())
+        })
+    }
+
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        self.read(|| Ok(self.live(&key)))
+    }
+
+    fn expiry_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<SystemTime>, Error = KvsError> + Send> {
+        self.read(|| match self.map.get(&key) {
+            Some(entry) if !entry.value().is_expired(now_millis()) => {
+                Ok(entry.value().expires_at.map(expiry_time))
+            }
+            _ => Err(KvsError::KeyNotFound),
+        })
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(|| {
+            let live = self.live(&key).is_some();
+            self.map.remove(&key);
+            if !live {
+                return Err(KvsError::KeyNotFound);
+            }
+            Ok(())
+        })
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = CasResult<Vec<u8>>, Error = KvsError> + Send> {
+        self.write(|| {
+            let current = self.live(&key);
+            if current != expected {
+                return Ok(Err(current));
+            }
+            match new {
+                Some(value) => {
+                    self.map.insert(key, Value::new(value));
+                }
+                None => {
+                    self.map.remove(&key);
+                }
+            }
+            Ok(Ok(()))
+        })
+    }
+
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(|| {
+            batch.check_removes(|key| Ok(self.live(key).is_some()))?;
+            for op in batch {
+                match op {
+                    BatchOp::Set { key, value } => {
+                        self.map.insert(key, Value::new(value));
+                    }
+                    BatchOp::Remove { key } => {
+                        self.map.remove(&key);
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        let in_range = move |key: &[u8]| end.as_ref().is_none_or(|end| key < end.as_slice());
+        self.stream_pairs(start, in_range, limit)
+    }
+
+    fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        self.stream_pairs(prefix.clone(), move |key| key.starts_with(&prefix), None)
+    }
+
+    /// Removes the expired keys.
+    fn compact(&self) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(|| {
+            let now = now_millis();
+            let expired: Vec<_> = self
+                .map
+                .iter()
+                .filter(|entry| entry.value().is_expired(now))
+                .map(|entry| entry.key().clone())
+                .collect();
+            for key in expired {
+                self.map.remove(&key);
+            }
+            Ok(())
+        })
+    }
+}
@@ -1,28 +1,336 @@
-pub use self::kvs::KvStore;
+pub use self::batch::{BatchOp, WriteBatch};
+pub use self::kvs::{
+    CacheStats, CompactionPolicy, CompactionStats, CompactionWindow, Compression, GenUsage,
+    IndexMode, KvStore, KvStoreOptions, Snapshot, SyncPolicy, ThresholdPolicy,
+    GROUP_COMMIT_MAX_DELAY,
+};
+pub use self::lsm::{LsmKvsEngine, LsmOptions};
+pub use self::memory::MemoryKvsEngine;
 pub use self::sled::SledKvsEngine;
-use crate::KvsError;
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result};
 
-use tokio::prelude::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::prelude::{future, stream, Future, Stream};
+use tokio::sync::oneshot;
 
+mod batch;
+mod bloom;
 mod kvs;
+mod lsm;
+mod memory;
 mod sled;
 
+/// Maximum number of pairs a scan reads at a time.
+const SCAN_PAGE_LEN: usize = 256;
+
+/// Result of a compare-and-swap.
+///
+/// `Err` holds the current value of the key, or `None` if the key doesn't exist, when
+/// it didn't equal the expected value.
+pub type CasResult<V> = std::result::Result<(), Option<V>>;
+
 /// Trait for a key value storage engine.
+///
+/// Keys and values are arbitrary byte strings. The methods taking and returning
+/// `String`s are thin wrappers of the byte-oriented ones.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Sets the value of a key that expires after `ttl`.
+    ///
+    /// Once expired, the key reads as if it doesn't exist. If the key already exists,
+    /// the previous value and its expiry will be overwritten. `set_bytes` sets a value
+    /// that never expires.
+    fn set_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send>;
+
+    /// Gets the time a given key expires.
+    ///
+    /// Returns `None` if the key never expires.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found or has expired.
+    fn expiry_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<SystemTime>, Error = KvsError> + Send>;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Atomically replaces the value of a key if it currently equals `expected`.
+    ///
+    /// `None` as `expected` means the key must not exist, and `None` as `new` removes
+    /// the key. An expired key counts as not existing. The new value never expires.
+    ///
+    /// Resolves to `Err` holding the current value if it doesn't equal `expected`, in
+    /// which case nothing is written.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = CasResult<Vec<u8>>, Error = KvsError> + Send>;
+
+    /// Applies all writes in a batch atomically.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key that is not found.
+    /// No write in the batch is applied in that case.
+    fn write_batch(&self, batch: WriteBatch)
+        -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Scans the key/value pairs with keys in the range `[start, end)` in ascending
+    /// key order.
+    ///
+    /// The range has no upper bound if `end` is `None`. At most `limit` pairs are
+    /// returned if it is given.
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>;
+
+    /// Scans the keys in the range `[start, end)` in ascending order.
+    ///
+    /// It is `scan_bytes` without the values, which engines may then skip reading.
+    fn scan_keys_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = KvsError> + Send> {
+        Box::new(self.scan_bytes(start, end, limit).map(|(key, _)| key))
+    }
+
+    /// Scans the key/value pairs whose keys start with `prefix` in ascending key order.
+    fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>;
+
+    /// Reclaims the space taken by stale data right away.
+    ///
+    /// The returned future resolves once the space is reclaimed. `SledKvsEngine`
+    /// can't be told to reclaim space, so it only drops the expired keys.
+    fn compact(&self) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Backs up the data to the directory `dir` while the engine keeps serving.
+    ///
+    /// The directory is created if it doesn't exist and must be empty. Engines that
+    /// don't support online backups return an error.
+    fn backup_to(&self, _dir: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        Box::new(future::err(KvsError::StringError(
+            "Backups are not supported by this engine".to_owned(),
+        )))
+    }
+
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+    fn set(
+        &self,
+        key: String,
+        value: String,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets the value of a string key to a string that expires after `ttl`.
+    ///
+    /// See `set_bytes_with_ttl` for details.
+    fn set_with_ttl(
+        &self,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send>;
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        Box::new(
+            self.get_bytes(key.into_bytes())
+                .and_then(|value| Ok(value.map(String::from_utf8).transpose()?)),
+        )
+    }
 
-    /// Removes a given key.
+    /// Removes a given string key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Atomically replaces the string value of a string key if it currently equals
+    /// `expected`.
+    ///
+    /// See `compare_and_swap_bytes` for details.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the current value doesn't match and is not valid
+    /// UTF-8.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Box<dyn Future<Item = CasResult<String>, Error = KvsError> + Send> {
+        Box::new(
+            self.compare_and_swap_bytes(
+                key.into_bytes(),
+                expected.map(String::into_bytes),
+                new.map(String::into_bytes),
+            )
+            .and_then(|res| match res {
+                Ok(()) => Ok(Ok(())),
+                Err(current) => Ok(Err(current.map(String::from_utf8).transpose()?)),
+            }),
+        )
+    }
+
+    /// Scans the string key/value pairs with keys in the range `[start, end)` in
+    /// ascending key order.
+    ///
+    /// See `scan_bytes` for details.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if a key or value is not valid UTF-8.
+    fn scan(
+        &self,
+        start: String,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (String, String), Error = KvsError> + Send> {
+        let pairs = self.scan_bytes(start.into_bytes(), end.map(String::into_bytes), limit);
+        Box::new(pairs.and_then(into_string_pair))
+    }
+
+    /// Scans the string key/value pairs whose keys start with `prefix` in ascending
+    /// key order.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if a key or value is not valid UTF-8.
+    fn scan_prefix(
+        &self,
+        prefix: String,
+    ) -> Box<dyn Stream<Item = (String, String), Error = KvsError> + Send> {
+        Box::new(
+            self.scan_prefix_bytes(prefix.into_bytes())
+                .and_then(into_string_pair),
+        )
+    }
+}
+
+fn into_string_pair((key, value): (Vec<u8>, Vec<u8>)) -> Result<(String, String)> {
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+}
+
+/// The pairs, or other items, of a page of a scan and the key the next page starts
+/// from, or `None` if the scan is done.
+type ScanPage<T = (Vec<u8>, Vec<u8>)> = (Vec<T>, Option<Vec<u8>>);
+
+/// Streams the pairs, or other items, of a scan from `start` on, reading them a page
+/// at a time on the thread pool.
+///
+/// `read_page` is called with the key a page starts from and the maximum number of
+/// items in it. A page is only read once the previous one is streamed, so a scan
+/// that is dropped early doesn't read the rest of the range. At most `limit` items
+/// are streamed if it is given.
+fn scan_pages<P, T, F>(
+    pool: &P,
+    start: Vec<u8>,
+    limit: Option<usize>,
+    read_page: F,
+) -> Box<dyn Stream<Item = T, Error = KvsError> + Send>
+where
+    P: ThreadPool,
+    T: Send + 'static,
+    F: Fn(&[u8], usize) -> Result<ScanPage<T>> + Send + Sync + 'static,
+{
+    let pool = pool.clone();
+    let read_page = Arc::new(read_page);
+    let first = Some((start, limit.unwrap_or(usize::MAX)));
+    let pages = stream::unfold(first, move |page| {
+        let (start, left) = page?;
+        if left == 0 {
+            return None;
+        }
+        let read_page = read_page.clone();
+        let (tx, rx) = oneshot::channel();
+        pool.spawn(move || {
+            let res = read_page(&start, left.min(SCAN_PAGE_LEN)).map(|(pairs, next)| {
+                let left = left - pairs.len();
+                (pairs, next.map(|next| (next, left)))
+            });
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Some(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    });
+    Box::new(pages.map(stream::iter_ok).flatten())
+}
+
+/// Returns the current time in milliseconds since the Unix epoch, the unit expiry
+/// times are stored in.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Converts an expiry time in milliseconds since the Unix epoch to a `SystemTime`.
+fn expiry_time(expires_at: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(expires_at)
+}
+
+/// Returns the expiry time of a value set now with the given time-to-live.
+fn expires_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
 }
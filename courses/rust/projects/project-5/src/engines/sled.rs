@@ -1,14 +1,26 @@
+use super::{expires_after, expiry_time, now_millis, scan_pages};
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, KvsError, Result};
-use sled::Db;
+use crate::{BatchOp, CasResult, KvsEngine, KvsError, Result, WriteBatch};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Db, IVec, Transactional, Tree};
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime};
 use tokio::prelude::*;
 use tokio::sync::oneshot;
 
+/// Name of the tree mapping the keys set with a time-to-live to their expiry times.
+const EXPIRY_TREE: &[u8] = b"kvs_expiry";
+
 /// Wrapper of `sled::Db`
+///
+/// The expiry times of keys set with a time-to-live are kept in a separate tree. The
+/// two trees are only updated atomically by batches, so if the process crashes in the
+/// middle of another write, the value can at worst expire too early.
 #[derive(Clone)]
 pub struct SledKvsEngine<P: ThreadPool> {
     pool: P,
     db: Db,
+    expiry: Tree,
 }
 
 impl<P: ThreadPool> SledKvsEngine<P> {
@@ -18,20 +30,132 @@ impl<P: ThreadPool> SledKvsEngine<P> {
     /// threads in the thread pool.
     pub fn new(db: Db, concurrency: u32) -> Result<Self> {
         let pool = P::new(concurrency)?;
-        Ok(SledKvsEngine { pool, db })
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        Ok(SledKvsEngine { pool, db, expiry })
+    }
+
+    /// Streams the key/value pairs with keys from `start` on in ascending key order
+    /// until `in_range` returns `false`, reading them a page at a time on the thread
+    /// pool.
+    ///
+    /// Expired keys are skipped. At most `limit` pairs are streamed if it is given.
+    fn read_pairs<F>(
+        &self,
+        start: Vec<u8>,
+        in_range: F,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        let db = self.db.clone();
+        let expiry = self.expiry.clone();
+        scan_pages(&self.pool, start, limit, move |start, max| {
+            let now = now_millis();
+            let mut pairs = Vec::new();
+            for res in db.range(start..) {
+                let (key, value) = res?;
+                if !in_range(&key) {
+                    break;
+                }
+                if pairs.len() >= max {
+                    return Ok((pairs, Some(key.to_vec())));
+                }
+                if !is_expired(&expiry, &key, now)? {
+                    pairs.push((key.to_vec(), value.to_vec()));
+                }
+            }
+            Ok((pairs, None))
+        })
     }
 }
 
+/// Returns the expiry time of a key if it is set with a time-to-live.
+fn expiry_of(expiry: &Tree, key: &[u8]) -> Result<Option<u64>> {
+    expiry.get(key)?.as_deref().map(decode_expiry).transpose()
+}
+
+/// Decodes an expiry time stored in the expiry tree.
+fn decode_expiry(expires_at: &[u8]) -> Result<u64> {
+    let expires_at = expires_at
+        .try_into()
+        .map_err(|_| KvsError::StringError("Invalid expiry time".to_owned()))?;
+    Ok(u64::from_be_bytes(expires_at))
+}
+
+/// Returns whether a key is set with a time-to-live and expired at `now`.
+fn is_expired(expiry: &Tree, key: &[u8], now: u64) -> Result<bool> {
+    Ok(expiry_of(expiry, key)?.is_some_and(|expires_at| expires_at <= now))
+}
+
 impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let db = self.db.clone();
+        let expiry = self.expiry.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                db.insert(key.as_slice(), value)?;
+                expiry.remove(key)?;
+                db.flush()?;
+                Ok(())
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    fn set_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let db = self.db.clone();
+        let expiry = self.expiry.clone();
+        let expires_at = expires_after(ttl);
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                expiry.insert(key.as_slice(), &expires_at.to_be_bytes())?;
+                db.insert(key, value)?;
+                db.flush()?;
+                Ok(())
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
         let db = self.db.clone();
+        let expiry = self.expiry.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
-            let res = db
-                .set(key, value.into_bytes())
-                .and_then(|_| db.flush())
-                .map(|_| ())
-                .map_err(KvsError::from);
+            let res = (|| {
+                if is_expired(&expiry, &key, now_millis())? {
+                    return Ok(None);
+                }
+                let value = db.get(&key)?;
+                Ok(value.map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec()))
+            })();
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
             }
@@ -42,16 +166,20 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
         )
     }
 
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+    fn expiry_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<SystemTime>, Error = KvsError> + Send> {
         let db = self.db.clone();
+        let expiry = self.expiry.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
-            let res = (move || {
-                Ok(db
-                    .get(key)?
-                    .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-                    .map(String::from_utf8)
-                    .transpose()?)
+            let res = (|| {
+                let expires_at = expiry_of(&expiry, &key)?;
+                if expires_at.is_some_and(|at| at <= now_millis()) || db.get(&key)?.is_none() {
+                    return Err(KvsError::KeyNotFound);
+                }
+                Ok(expires_at.map(expiry_time))
             })();
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
@@ -63,12 +191,191 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
         )
     }
 
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let db = self.db.clone();
+        let expiry = self.expiry.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                let expired = is_expired(&expiry, &key, now_millis())?;
+                let removed = db.remove(&key)?;
+                expiry.remove(&key)?;
+                db.flush()?;
+                if removed.is_none() || expired {
+                    return Err(KvsError::KeyNotFound);
+                }
+                Ok(())
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Atomically replaces the value of a key if it currently equals `expected`.
+    ///
+    /// The swap is done with `sled::Tree::compare_and_swap` against the stored value,
+    /// which may be an expired one. It is retried if the stored value changes in the meantime.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = CasResult<Vec<u8>>, Error = KvsError> + Send> {
+        let db = self.db.clone();
+        let expiry = self.expiry.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| loop {
+                let stored = db.get(&key)?;
+                let current = match stored {
+                    Some(_) if is_expired(&expiry, &key, now_millis())? => None,
+                    Some(ref value) => Some(AsRef::<[u8]>::as_ref(value).to_vec()),
+                    None => None,
+                };
+                if current != expected {
+                    return Ok(Err(current));
+                }
+                if stored.is_none() && new.is_none() {
+                    return Ok(Ok(()));
+                }
+                let old = stored.as_ref().map(AsRef::as_ref);
+                if db
+                    .compare_and_swap(&key, old, new.clone().map(IVec::from))?
+                    .is_ok()
+                {
+                    // the new value never expires
+                    expiry.remove(&key)?;
+                    db.flush()?;
+                    return Ok(Ok(()));
+                }
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let db = self.db.clone();
+        let expiry = self.expiry.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                let now = now_millis();
+                // The removed keys are checked in the transaction that applies the batch,
+                // so no other write can remove them in between.
+                let applied = (&*db, &expiry).transaction(|(tx_db, tx_expiry)| {
+                    let mut tx_error = None;
+                    let checked = batch.check_removes(|key| {
+                        let stored = tx_db
+                            .get(key)
+                            .and_then(|value| Ok((value, tx_expiry.get(key)?)));
+                        match stored {
+                            Ok((value, expires_at)) => {
+                                let expires_at = expires_at.as_deref().map(decode_expiry);
+                                let expired = expires_at.transpose()?.is_some_and(|at| at <= now);
+                                Ok(value.is_some() && !expired)
+                            }
+                            Err(e) => {
+                                tx_error = Some(e);
+                                Ok(true)
+                            }
+                        }
+                    });
+                    if let Some(e) = tx_error {
+                        return Err(e.into());
+                    }
+                    checked.map_err(ConflictableTransactionError::Abort)?;
+                    for op in &batch {
+                        let key = match op {
+                            BatchOp::Set { key, value } => {
+                                tx_db.insert(key.as_slice(), value.as_slice())?;
+                                key
+                            }
+                            BatchOp::Remove { key } => {
+                                tx_db.remove(key.as_slice())?;
+                                key
+                            }
+                        };
+                        // the values written by the batch never expire
+                        tx_expiry.remove(key.as_slice())?;
+                    }
+                    Ok(())
+                });
+                match applied {
+                    Ok(()) => {}
+                    Err(TransactionError::Abort(e)) => return Err(e),
+                    Err(TransactionError::Storage(e)) => return Err(e.into()),
+                }
+                db.flush()?;
+                Ok(())
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    fn scan_bytes(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        let in_range = move |key: &[u8]| end.as_deref().is_none_or(|end| key < end);
+        self.read_pairs(start, in_range, limit)
+    }
+
+    fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        self.read_pairs(prefix.clone(), move |key| key.starts_with(&prefix), None)
+    }
+
+    /// Removes the expired keys and flushes the database.
+    ///
+    /// Otherwise it is a no-op: sled has no way to trigger its compaction, and it
+    /// reclaims the space of stale data on its own schedule.
+    fn compact(&self) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
         let db = self.db.clone();
+        let expiry = self.expiry.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
             let res = (|| {
-                db.del(key)?.ok_or(KvsError::KeyNotFound)?;
+                let now = now_millis();
+                for res in expiry.iter() {
+                    let (key, expires_at) = res?;
+                    if !is_expired(&expiry, key.as_ref(), now)? {
+                        continue;
+                    }
+                    // The key may be written concurrently, so each tree is only
+                    // updated if it hasn't changed since it was read.
+                    let value = db.get(&key)?;
+                    let removed = expiry.compare_and_swap(&key, Some(expires_at), None::<IVec>)?;
+                    if removed.is_ok() {
+                        if let Some(value) = value {
+                            // a failed swap means the key has been set again
+                            let _ = db.compare_and_swap(&key, Some(value), None::<IVec>)?;
+                        }
+                    }
+                }
                 db.flush()?;
                 Ok(())
             })();
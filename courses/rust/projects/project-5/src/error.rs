@@ -1,3 +1,6 @@
+// the impls `failure` derives are nested in constants
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 use std::io;
 use std::string::FromUtf8Error;
@@ -21,6 +24,36 @@ pub enum KvsError {
     /// Key or value is invalid UTF-8 sequence
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[cause] FromUtf8Error),
+    /// A log record doesn't match its checksum.
+    /// It indicates a torn write or a corrupted log.
+    #[fail(display = "Checksum mismatch")]
+    ChecksumMismatch,
+    /// A command in the middle of a log file cannot be decoded.
+    /// `pos` is the offset of the invalid command in the log file of generation `gen`.
+    #[fail(display = "Corrupted command in log {} at offset {}", gen, pos)]
+    Corrupted {
+        /// Generation number of the log file
+        gen: u64,
+        /// Offset of the invalid command
+        pos: u64,
+    },
+    /// A log record matches its checksum but cannot be decoded, such as a value that
+    /// doesn't decompress or is compressed with an unknown codec.
+    /// It indicates a log written by a newer version or a program bug.
+    #[fail(display = "Invalid log record: {}", _0)]
+    InvalidRecord(String),
+    /// The log file is written in a format version this crate doesn't know
+    #[fail(display = "Unsupported log format version: {}", _0)]
+    UnsupportedVersion(u32),
+    /// A backup is incomplete or doesn't match its manifest
+    #[fail(display = "Invalid backup: {}", _0)]
+    InvalidBackup(String),
+    /// The server doesn't support a request
+    #[fail(display = "Unsupported request: {}", _0)]
+    UnsupportedRequest(String),
+    /// A request didn't complete in time
+    #[fail(display = "Request timed out")]
+    Timeout,
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
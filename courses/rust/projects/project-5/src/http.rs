@@ -0,0 +1,446 @@
+//! An HTTP/1.1 gateway exposing the store as a REST API, for consumers that can't use
+//! `KvsClient`.
+//!
+//! - `GET /keys/{key}` returns the value as the body, or 404 if the key doesn't exist.
+//! - `PUT /keys/{key}` stores the body as the value of the key. With `?ttl={seconds}`
+//!   the key expires after that time.
+//! - `DELETE /keys/{key}` removes the key, or returns 404 if it doesn't exist.
+//! - `GET /keys?prefix={prefix}` returns a page of the pairs whose keys start with the
+//!   prefix, in key order, as `{"pairs": [{"key": ..., "value": ...}, ...], "next": ...}`.
+//!   A page holds up to `limit` pairs, 1000 by default. `next` is the key the next page
+//!   starts from, to pass as `start`, or `null` after the last page. Keys and values are
+//!   strings if they are valid UTF-8, and `{"base64": ...}` objects otherwise.
+//! - `GET /health` returns 200 if the engine answers, and 503 otherwise.
+//!
+//! Keys in paths and queries are percent-decoded, and errors are JSON objects with an
+//! `error` message. Request bodies must have a `Content-Length`, requests with a
+//! `Transfer-Encoding` are answered with 501. The requests of a connection are answered
+//! in order.
+
+use crate::{KvsEngine, KvsError, Result};
+use bytes::BytesMut;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::str;
+use std::time::Duration;
+use tokio::codec::{Decoder, Encoder, Framed};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::future::Loop;
+use tokio::prelude::*;
+
+/// Maximum length of the request line and the headers of a request.
+const MAX_HEAD_LEN: usize = 64 << 10;
+/// Maximum length of the body of a request.
+const MAX_BODY_LEN: usize = 64 << 20;
+/// Number of pairs in a page of `GET /keys` without a `limit`.
+const DEFAULT_LIST_LIMIT: usize = 1000;
+/// Maximum number of pairs in a page of `GET /keys`.
+const MAX_LIST_LIMIT: usize = 10_000;
+
+/// A request with its body.
+struct Request {
+    method: String,
+    target: String,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+/// A response, which is sent with its body.
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            content_type,
+            body,
+            keep_alive: true,
+        }
+    }
+
+    fn no_content() -> Response {
+        Response::new(204, "", Vec::new())
+    }
+
+    fn json<T: Serialize>(status: u16, value: &T) -> Result<Response> {
+        Ok(Response::new(
+            status,
+            "application/json",
+            serde_json::to_vec(value)?,
+        ))
+    }
+
+    fn error(status: u16, msg: &str) -> Response {
+        let body = serde_json::json!({ "error": msg }).to_string().into_bytes();
+        Response::new(status, "application/json", body)
+    }
+}
+
+/// Returns the reason phrase of the status codes of the gateway.
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Internal Server Error",
+    }
+}
+
+enum CodecError {
+    Io(io::Error),
+    // a malformed request, answered with this status before closing the connection
+    Invalid(u16, &'static str),
+}
+
+impl From<io::Error> for CodecError {
+    fn from(err: io::Error) -> CodecError {
+        CodecError::Io(err)
+    }
+}
+
+impl From<CodecError> for KvsError {
+    fn from(err: CodecError) -> KvsError {
+        match err {
+            CodecError::Io(e) => KvsError::Io(e),
+            CodecError::Invalid(_, msg) => KvsError::StringError(msg.to_owned()),
+        }
+    }
+}
+
+/// Codec decoding requests and encoding responses.
+struct HttpCodec;
+
+impl Decoder for HttpCodec {
+    type Item = Request;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> std::result::Result<Option<Request>, CodecError> {
+        let head_len = match src.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None if src.len() > MAX_HEAD_LEN => {
+                return Err(CodecError::Invalid(431, "Request headers are too large"))
+            }
+            None => return Ok(None),
+        };
+        let head = str::from_utf8(&src[..head_len])
+            .map_err(|_| CodecError::Invalid(400, "Invalid request headers"))?;
+        let mut lines = head.split("\r\n");
+        let request_line: Vec<_> = lines.next().unwrap_or_default().split(' ').collect();
+        let (method, target, version) = match request_line.as_slice() {
+            [method, target, version] => (*method, *target, *version),
+            _ => return Err(CodecError::Invalid(400, "Invalid request line")),
+        };
+        let mut keep_alive = match version {
+            "HTTP/1.1" => true,
+            "HTTP/1.0" => false,
+            _ => return Err(CodecError::Invalid(505, "Unsupported HTTP version")),
+        };
+        let mut body_len = 0;
+        for line in lines.filter(|line| !line.is_empty()) {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(CodecError::Invalid(400, "Invalid header"))?;
+            let value = value.trim();
+            let has_token = |token| {
+                value
+                    .split(',')
+                    .any(|t| t.trim().eq_ignore_ascii_case(token))
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => {
+                    body_len = value
+                        .parse()
+                        .map_err(|_| CodecError::Invalid(400, "Invalid Content-Length"))?
+                }
+                "transfer-encoding" => {
+                    return Err(CodecError::Invalid(501, "Unsupported Transfer-Encoding"))
+                }
+                "connection" if has_token("close") => keep_alive = false,
+                "connection" if has_token("keep-alive") => keep_alive = true,
+                _ => {}
+            }
+        }
+        if body_len > MAX_BODY_LEN {
+            return Err(CodecError::Invalid(413, "Request body is too large"));
+        }
+        if src.len() < head_len + body_len {
+            src.reserve(head_len + body_len - src.len());
+            return Ok(None);
+        }
+
+        let method = method.to_owned();
+        let target = target.to_owned();
+        src.split_to(head_len);
+        let body = src.split_to(body_len).to_vec();
+        Ok(Some(Request {
+            method,
+            target,
+            body,
+            keep_alive,
+        }))
+    }
+}
+
+impl Encoder for HttpCodec {
+    type Item = Response;
+    type Error = CodecError;
+
+    fn encode(
+        &mut self,
+        resp: Response,
+        dst: &mut BytesMut,
+    ) -> std::result::Result<(), CodecError> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", resp.status, reason(resp.status));
+        // a 204 response has no body and must not give its length
+        if resp.status != 204 {
+            head.push_str(&format!("Content-Length: {}\r\n", resp.body.len()));
+        }
+        if !resp.content_type.is_empty() {
+            head.push_str(&format!("Content-Type: {}\r\n", resp.content_type));
+        }
+        if !resp.keep_alive {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        dst.extend_from_slice(head.as_bytes());
+        dst.extend_from_slice(&resp.body);
+        Ok(())
+    }
+}
+
+/// Serves the HTTP gateway to the connections accepted by `listener`.
+pub(crate) fn listen<E: KvsEngine>(
+    engine: E,
+    listener: TcpListener,
+) -> impl Future<Item = (), Error = ()> {
+    listener
+        .incoming()
+        .map_err(|e| error!("IO error: {}", e))
+        .for_each(move |tcp| {
+            let serving = serve(engine.clone(), tcp)
+                .map_err(|e| error!("Error on serving HTTP client: {}", e));
+            tokio::spawn(serving);
+            Ok(())
+        })
+}
+
+type Connection = Framed<TcpStream, HttpCodec>;
+
+fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> impl Future<Item = (), Error = KvsError> {
+    future::loop_fn(HttpCodec.framed(tcp), move |conn| {
+        let engine = engine.clone();
+        conn.into_future().then(
+            move |res| -> Box<dyn Future<Item = Loop<(), Connection>, Error = KvsError> + Send> {
+                match res {
+                    Ok((Some(req), conn)) => {
+                        let keep_alive = req.keep_alive;
+                        let sent = handle(&engine, req).and_then(move |mut resp| {
+                            resp.keep_alive = keep_alive;
+                            conn.send(resp).map_err(KvsError::from)
+                        });
+                        Box::new(sent.map(move |conn| {
+                            if keep_alive {
+                                Loop::Continue(conn)
+                            } else {
+                                Loop::Break(())
+                            }
+                        }))
+                    }
+                    Ok((None, _)) => Box::new(future::ok(Loop::Break(()))),
+                    Err((CodecError::Invalid(status, msg), conn)) => {
+                        let mut resp = Response::error(status, msg);
+                        resp.keep_alive = false;
+                        let sent = conn.send(resp).map_err(KvsError::from);
+                        Box::new(sent.map(|_| Loop::Break(())))
+                    }
+                    Err((CodecError::Io(e), _)) => Box::new(future::err(e.into())),
+                }
+            },
+        )
+    })
+}
+
+/// Routes a request to the engine.
+fn handle<E: KvsEngine>(
+    engine: &E,
+    req: Request,
+) -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
+    let respond = |resp| -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
+        Box::new(future::ok(resp))
+    };
+    let (path, query) = match req.target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (req.target.as_str(), ""),
+    };
+    let query = match parse_query(query) {
+        Some(query) => query,
+        None => return respond(Response::error(400, "Invalid query")),
+    };
+
+    let resp: Box<dyn Future<Item = Response, Error = KvsError> + Send> =
+        match (req.method.as_str(), path) {
+            ("GET", "/health") => {
+                let checked = engine.get_bytes(Vec::new()).then(|res| match res {
+                    Ok(_) => Response::json(200, &serde_json::json!({ "status": "ok" })),
+                    Err(e) => Response::json(
+                        503,
+                        &serde_json::json!({ "status": "unavailable", "error": e.to_string() }),
+                    ),
+                });
+                Box::new(checked)
+            }
+            ("GET", "/keys") => {
+                let limit = match query.get("limit").map(|limit| parse_limit(limit)) {
+                    Some(Some(limit)) => limit,
+                    Some(None) => return respond(Response::error(400, "Invalid limit")),
+                    None => DEFAULT_LIST_LIMIT,
+                };
+                let prefix = query.get("prefix").cloned().unwrap_or_default();
+                let start = match query.get("start") {
+                    Some(start) if *start > prefix => start.clone(),
+                    _ => prefix.clone(),
+                };
+                // one more pair is read to find where the next page starts
+                let page = engine
+                    .scan_bytes(start, prefix_end(&prefix), Some(limit + 1))
+                    .collect()
+                    .and_then(move |mut pairs| {
+                        let next = pairs.get(limit).map(|(key, _)| key.clone());
+                        pairs.truncate(limit);
+                        let pairs = pairs
+                            .into_iter()
+                            .map(|(key, value)| Pair { key, value })
+                            .collect();
+                        Response::json(200, &Page { pairs, next })
+                    });
+                Box::new(page)
+            }
+            (_, "/health") | (_, "/keys") => respond(Response::error(405, "Method not allowed")),
+            (method, path) if path.starts_with("/keys/") => {
+                let key = match percent_decode(&path["/keys/".len()..]) {
+                    Some(key) if !key.is_empty() => key,
+                    _ => return respond(Response::error(400, "Invalid key")),
+                };
+                match method {
+                    "GET" => Box::new(engine.get_bytes(key).map(|value| match value {
+                        Some(value) => Response::new(200, "application/octet-stream", value),
+                        None => Response::error(404, "Key not found"),
+                    })),
+                    "PUT" => {
+                        let ttl = match query.get("ttl").map(|ttl| parse_seconds(ttl)) {
+                            Some(Some(ttl)) => Some(ttl),
+                            Some(None) => return respond(Response::error(400, "Invalid ttl")),
+                            None => None,
+                        };
+                        let written = match ttl {
+                            Some(ttl) => engine.set_bytes_with_ttl(key, req.body, ttl),
+                            None => engine.set_bytes(key, req.body),
+                        };
+                        Box::new(written.map(|_| Response::no_content()))
+                    }
+                    "DELETE" => Box::new(engine.remove_bytes(key).map(|_| Response::no_content())),
+                    _ => respond(Response::error(405, "Method not allowed")),
+                }
+            }
+            _ => respond(Response::error(404, "Not found")),
+        };
+    Box::new(resp.then(|res| match res {
+        Ok(resp) => Ok(resp),
+        Err(KvsError::KeyNotFound) => Ok(Response::error(404, "Key not found")),
+        Err(e) => Ok(Response::error(500, &e.to_string())),
+    }))
+}
+
+/// A page of pairs listed by `GET /keys`.
+#[derive(Serialize)]
+struct Page {
+    pairs: Vec<Pair>,
+    #[serde(with = "crate::common::bytes::option")]
+    next: Option<Vec<u8>>,
+}
+
+/// A pair listed by `GET /keys`.
+#[derive(Serialize)]
+struct Pair {
+    #[serde(with = "crate::common::bytes")]
+    key: Vec<u8>,
+    #[serde(with = "crate::common::bytes")]
+    value: Vec<u8>,
+}
+
+/// Returns the first key after all the keys starting with `prefix`, or `None` if there
+/// is none.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Parses the percent-encoded parameters of a query, or returns `None` if it is
+/// malformed.
+fn parse_query(query: &str) -> Option<HashMap<&str, Vec<u8>>> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            Some((name, percent_decode(&value.replace('+', " "))?))
+        })
+        .collect()
+}
+
+/// Decodes `%XX` escapes, or returns `None` if an escape is invalid.
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// Parses the `limit` of `GET /keys`, capped at `MAX_LIST_LIMIT`.
+fn parse_limit(s: &[u8]) -> Option<usize> {
+    let limit: usize = str::from_utf8(s).ok()?.parse().ok()?;
+    if limit == 0 {
+        return None;
+    }
+    Some(limit.min(MAX_LIST_LIMIT))
+}
+
+fn parse_seconds(s: &[u8]) -> Option<Duration> {
+    let secs: u64 = str::from_utf8(s).ok()?.parse().ok()?;
+    if secs == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(secs))
+}
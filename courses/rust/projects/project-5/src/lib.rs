@@ -5,13 +5,23 @@
 extern crate log;
 
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use common::PROTOCOL_VERSION;
+pub use engines::{
+    BatchOp, CacheStats, CasResult, CompactionPolicy, CompactionStats, CompactionWindow,
+    Compression, GenUsage, IndexMode, KvStore, KvStoreOptions, KvsEngine, LsmKvsEngine, LsmOptions,
+    MemoryKvsEngine, SledKvsEngine, Snapshot, SyncPolicy, ThresholdPolicy, WriteBatch,
+    GROUP_COMMIT_MAX_DELAY,
+};
 pub use error::{KvsError, Result};
+pub use pool::{KvsClientPool, KvsClientPoolOptions};
 pub use server::KvsServer;
 
 mod client;
 mod common;
 mod engines;
 mod error;
+mod http;
+mod pool;
+mod resp;
 mod server;
 pub mod thread_pool;
@@ -0,0 +1,297 @@
+use crate::{KvsClient, KvsError, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::prelude::future::Loop;
+use tokio::prelude::task::{self, Task};
+use tokio::prelude::*;
+use tokio::runtime::{Builder, Runtime, TaskExecutor};
+use tokio::sync::oneshot;
+use tokio::timer::{timeout, Delay, Interval};
+
+/// Options of a `KvsClientPool`.
+///
+/// ```
+/// # use kvs::KvsClientPoolOptions;
+/// # use std::time::Duration;
+/// let options = KvsClientPoolOptions {
+///     timeout: Duration::from_millis(500),
+///     ..KvsClientPoolOptions::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvsClientPoolOptions {
+    /// Number of connections to the server.
+    pub size: usize,
+    /// Time after which a request fails with `KvsError::Timeout`, including the time it
+    /// waits for a connection.
+    pub timeout: Duration,
+    /// Interval between two pings of every connection.
+    pub health_check_interval: Duration,
+    /// Delay before reconnecting after a failed attempt. It doubles on every failure.
+    pub min_backoff: Duration,
+    /// Maximum delay between two reconnect attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for KvsClientPoolOptions {
+    fn default() -> Self {
+        KvsClientPoolOptions {
+            size: 4,
+            timeout: Duration::from_secs(5),
+            health_check_interval: Duration::from_secs(10),
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A pool of connections to a `KvsServer`, which outlives restarts of the server.
+///
+/// Requests are spread round-robin over the connections, and every connection carries
+/// many requests at the same time like a `KvsClient`. A connection that fails a request
+/// or a health check is dropped and reconnected in the background, with exponential
+/// backoff between the attempts. Requests wait for a connection while none is up, so a
+/// request fails with `KvsError::Timeout` if the server stays unreachable. Requests in
+/// flight on a failing connection fail and are not retried, since they may have been
+/// applied.
+///
+/// The pool runs its connections in its own runtime, so its futures can be waited on
+/// from any thread.
+#[derive(Clone)]
+pub struct KvsClientPool {
+    shared: Arc<Shared>,
+    // shuts the connections and the background jobs down once the last clone is dropped
+    _runtime: Arc<Runtime>,
+}
+
+impl KvsClientPool {
+    /// Creates a pool of connections to `addr` with the default options.
+    pub fn connect(addr: SocketAddr) -> Result<Self> {
+        Self::connect_with_options(addr, KvsClientPoolOptions::default())
+    }
+
+    /// Creates a pool of connections to `addr` with the given options.
+    ///
+    /// The connections are opened in the background, so the server doesn't need to be
+    /// up yet.
+    pub fn connect_with_options(addr: SocketAddr, options: KvsClientPoolOptions) -> Result<Self> {
+        if options.size == 0 {
+            return Err(KvsError::StringError(
+                "The pool size must be positive".to_owned(),
+            ));
+        }
+        let runtime = Builder::new()
+            .core_threads(1)
+            .name_prefix("kvs-client-pool-")
+            .build()?;
+        let slots = (0..options.size)
+            .map(|_| {
+                Mutex::new(Slot {
+                    client: None,
+                    reconnecting: false,
+                })
+            })
+            .collect();
+        let shared = Arc::new(Shared {
+            addr,
+            options,
+            slots,
+            next: AtomicUsize::new(0),
+            waiters: Mutex::new(Vec::new()),
+            executor: runtime.executor(),
+        });
+        for index in 0..shared.slots.len() {
+            let mut slot = shared.slots[index].lock().unwrap();
+            start_reconnect(&shared, index, &mut slot);
+        }
+        shared.executor.spawn(health_check(Arc::clone(&shared)));
+        Ok(KvsClientPool {
+            shared,
+            _runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Runs `f` with a connected client of the pool and resolves to the result of the
+    /// future it returns.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClientPool, Result};
+    /// # use tokio::prelude::*;
+    /// # fn main() -> Result<()> {
+    /// let pool = KvsClientPool::connect("127.0.0.1:4000".parse().unwrap())?;
+    /// let value = pool.execute(|client| client.get("key".to_owned())).wait()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn execute<F, R>(&self, f: F) -> impl Future<Item = R::Item, Error = KvsError>
+    where
+        F: FnOnce(&KvsClient) -> R + Send + 'static,
+        R: Future<Error = KvsError> + Send + 'static,
+        R::Item: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let request = Checkout {
+            shared: Arc::clone(&self.shared),
+        }
+        .and_then(move |client| f(&client))
+        .timeout(self.shared.options.timeout)
+        .map_err(timeout_error)
+        .then(move |res| {
+            if tx.send(res).is_err() {
+                debug!("Receiving end is dropped");
+            }
+            Ok(())
+        });
+        self.shared.executor.spawn(request);
+        rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+            .flatten()
+    }
+}
+
+/// The state of a pool shared with its background jobs.
+struct Shared {
+    addr: SocketAddr,
+    options: KvsClientPoolOptions,
+    slots: Vec<Mutex<Slot>>,
+    // round-robin position of the next request
+    next: AtomicUsize,
+    // tasks of the requests waiting for a connection
+    waiters: Mutex<Vec<Task>>,
+    executor: TaskExecutor,
+}
+
+struct Slot {
+    // `None` while the connection is down
+    client: Option<KvsClient>,
+    reconnecting: bool,
+}
+
+/// Future of a connected client of the pool.
+struct Checkout {
+    shared: Arc<Shared>,
+}
+
+impl Future for Checkout {
+    type Item = KvsClient;
+    type Error = KvsError;
+
+    fn poll(&mut self) -> Poll<KvsClient, KvsError> {
+        let shared = &self.shared;
+        // held while looking for a client, so that a reconnect can't finish unnoticed
+        let mut waiters = shared.waiters.lock().unwrap();
+        let start = shared.next.fetch_add(1, Ordering::Relaxed);
+        for i in 0..shared.slots.len() {
+            let index = (start + i) % shared.slots.len();
+            let mut slot = shared.slots[index].lock().unwrap();
+            match &slot.client {
+                Some(client) if !client.is_closed() => return Ok(Async::Ready(client.clone())),
+                _ => start_reconnect(shared, index, &mut slot),
+            }
+        }
+        waiters.push(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+/// Drops the connection of a slot and reconnects it in the background, unless a
+/// reconnect is already running.
+fn start_reconnect(shared: &Arc<Shared>, index: usize, slot: &mut Slot) {
+    slot.client = None;
+    if !slot.reconnecting {
+        slot.reconnecting = true;
+        shared.executor.spawn(reconnect(Arc::clone(shared), index));
+    }
+}
+
+/// Connects a slot, retrying with exponential backoff until it succeeds.
+fn reconnect(shared: Arc<Shared>, index: usize) -> impl Future<Item = (), Error = ()> {
+    future::loop_fn(Duration::from_secs(0), move |backoff| {
+        let shared = Arc::clone(&shared);
+        let addr = shared.addr;
+        let timeout = shared.options.timeout;
+        Delay::new(Instant::now() + backoff)
+            .map_err(|e| KvsError::StringError(format!("{}", e)))
+            .and_then(move |_| {
+                KvsClient::connect(addr)
+                    .timeout(timeout)
+                    .map_err(timeout_error)
+            })
+            .then(move |res| {
+                let options = &shared.options;
+                match res {
+                    Ok(client) => {
+                        debug!("Connected to {}", addr);
+                        let mut slot = shared.slots[index].lock().unwrap();
+                        slot.client = Some(client);
+                        slot.reconnecting = false;
+                        drop(slot);
+                        for task in shared.waiters.lock().unwrap().drain(..) {
+                            task.notify();
+                        }
+                        Ok(Loop::Break(()))
+                    }
+                    Err(e) => {
+                        let backoff = (backoff * 2)
+                            .max(options.min_backoff)
+                            .min(options.max_backoff);
+                        warn!(
+                            "Failed to connect to {}: {}, retrying in {:?}",
+                            addr, e, backoff
+                        );
+                        Ok(Loop::Continue(backoff))
+                    }
+                }
+            })
+    })
+}
+
+/// Pings every connection periodically, and reconnects the ones that don't answer in
+/// time.
+fn health_check(shared: Arc<Shared>) -> impl Future<Item = (), Error = ()> {
+    let interval = shared.options.health_check_interval;
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(|e| error!("Health check timer error: {}", e))
+        .for_each(move |_| {
+            for index in 0..shared.slots.len() {
+                let client = match &shared.slots[index].lock().unwrap().client {
+                    Some(client) => client.clone(),
+                    None => continue,
+                };
+                let shared = Arc::clone(&shared);
+                let ping = client
+                    .ping()
+                    .timeout(shared.options.timeout)
+                    .map_err(timeout_error)
+                    .or_else(move |e| {
+                        warn!(
+                            "Health check of a connection to {} failed: {}",
+                            shared.addr, e
+                        );
+                        let mut slot = shared.slots[index].lock().unwrap();
+                        // the slot may have been reconnected in the meantime
+                        if slot
+                            .client
+                            .as_ref()
+                            .is_some_and(|current| current.same_connection(&client))
+                        {
+                            start_reconnect(&shared, index, &mut slot);
+                        }
+                        Ok(())
+                    });
+                tokio::spawn(ping);
+            }
+            Ok(())
+        })
+}
+
+fn timeout_error(e: timeout::Error<KvsError>) -> KvsError {
+    if e.is_elapsed() {
+        KvsError::Timeout
+    } else if e.is_inner() {
+        e.into_inner().unwrap()
+    } else {
+        KvsError::StringError(format!("Timer error: {}", e))
+    }
+}
@@ -0,0 +1,470 @@
+//! A front-end speaking the Redis RESP2 protocol, so that Redis tools and client
+//! libraries can use the store.
+//!
+//! Commands are arrays of bulk strings, or inline commands as typed in telnet. The
+//! commands of a connection run one after another and are answered in order, so
+//! pipelined commands see the effects of the ones before them. The supported commands
+//! are `PING`, `GET`, `SET` with the `EX` and `PX` options, `DEL`, `EXISTS`, `SCAN` with
+//! the `MATCH` and `COUNT` options, and `INFO`.
+//!
+//! The cursor of `SCAN` is an opaque string encoding the key the next page starts from,
+//! so a page only reads its own keys. A full iteration returns every key that exists
+//! during all of it, and no key twice.
+
+use crate::{KvsEngine, KvsError, Result};
+use bytes::BytesMut;
+use std::io;
+use std::process;
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::codec::{Decoder, Encoder};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+
+/// Maximum length of a bulk string in a command, as in Redis.
+const MAX_BULK_LEN: i64 = 512 << 20;
+/// Maximum number of arguments of a command.
+const MAX_ARGS: i64 = 1 << 20;
+/// Maximum length of an inline command or of the header of an array or a bulk string.
+const MAX_LINE_LEN: usize = 64 << 10;
+/// Number of keys a `SCAN` examines when no `COUNT` is given, as in Redis.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A reply of the RESP2 protocol.
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => {
+                buf.push(b'+');
+                buf.extend_from_slice(s.as_bytes());
+            }
+            Reply::Error(msg) => {
+                buf.push(b'-');
+                // a line break would end the error early
+                buf.extend(
+                    msg.bytes()
+                        .map(|b| if b == b'\r' || b == b'\n' { b' ' } else { b }),
+                );
+            }
+            Reply::Integer(n) => write!(buf, ":{}", n).unwrap(),
+            Reply::Bulk(None) => buf.extend_from_slice(b"$-1"),
+            Reply::Bulk(Some(data)) => {
+                write!(buf, "${}\r\n", data.len()).unwrap();
+                buf.extend_from_slice(data);
+            }
+            Reply::Array(items) => {
+                write!(buf, "*{}\r\n", items.len()).unwrap();
+                for item in items {
+                    item.write_to(buf);
+                }
+                return;
+            }
+        }
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Codec decoding the arguments of commands and encoding replies.
+struct RespCodec;
+
+impl Decoder for RespCodec {
+    type Item = Vec<Vec<u8>>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Vec<Vec<u8>>>> {
+        while let Some((args, len)) = parse_command(src)? {
+            src.split_to(len);
+            // empty commands are skipped, like blank lines in telnet
+            if !args.is_empty() {
+                return Ok(Some(args));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder for RespCodec {
+    type Item = Reply;
+    type Error = io::Error;
+
+    fn encode(&mut self, reply: Reply, dst: &mut BytesMut) -> io::Result<()> {
+        let mut buf = Vec::new();
+        reply.write_to(&mut buf);
+        dst.extend_from_slice(&buf);
+        Ok(())
+    }
+}
+
+/// Parses the command at the start of `buf`, and returns its arguments and its length,
+/// or `None` if it is incomplete.
+fn parse_command(buf: &[u8]) -> io::Result<Option<(Vec<Vec<u8>>, usize)>> {
+    let (line, mut pos) = match read_line(buf)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some((args, pos)));
+    }
+
+    let count = parse_length(&line[1..], MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.max(0) as usize);
+    for _ in 0..count {
+        let (line, header_len) = match read_line(&buf[pos..])? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error("expected '$'"));
+        }
+        let len = parse_length(&line[1..], MAX_BULK_LEN)?;
+        if len < 0 {
+            return Err(protocol_error("invalid bulk length"));
+        }
+        let start = pos + header_len;
+        let end = start + len as usize;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(protocol_error("expected CRLF after bulk string"));
+        }
+        args.push(buf[start..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+/// Returns the line at the start of `buf` without its line break, and its length with
+/// the line break, or `None` if the line is incomplete.
+fn read_line(buf: &[u8]) -> io::Result<Option<(&[u8], usize)>> {
+    match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => {
+            let line = &buf[..end];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            Ok(Some((line, end + 1)))
+        }
+        None if buf.len() > MAX_LINE_LEN => Err(protocol_error("too big inline request")),
+        None => Ok(None),
+    }
+}
+
+/// Parses the length of an array or a bulk string, which may be negative.
+fn parse_length(digits: &[u8], max: i64) -> io::Result<i64> {
+    str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<i64>().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Protocol error: {}", msg),
+    )
+}
+
+/// What `INFO` reports about the server.
+struct Info {
+    port: u16,
+    started: Instant,
+}
+
+/// Serves the RESP2 protocol to the connections accepted by `listener`.
+pub(crate) fn listen<E: KvsEngine>(
+    engine: E,
+    listener: TcpListener,
+) -> Result<impl Future<Item = (), Error = ()>> {
+    let info = Arc::new(Info {
+        port: listener.local_addr()?.port(),
+        started: Instant::now(),
+    });
+    Ok(listener
+        .incoming()
+        .map_err(|e| error!("IO error: {}", e))
+        .for_each(move |tcp| {
+            let serving = serve(engine.clone(), Arc::clone(&info), tcp)
+                .map_err(|e| error!("Error on serving RESP client: {}", e));
+            tokio::spawn(serving);
+            Ok(())
+        }))
+}
+
+fn serve<E: KvsEngine>(
+    engine: E,
+    info: Arc<Info>,
+    tcp: TcpStream,
+) -> impl Future<Item = (), Error = KvsError> {
+    let (sink, stream) = RespCodec.framed(tcp).split();
+    let replies = stream.map_err(KvsError::from).and_then(move |args| {
+        execute(&engine, &info, args).then(|res| -> Result<Reply> {
+            match res {
+                Ok(reply) => Ok(reply),
+                Err(e) => Ok(Reply::Error(format!("ERR {}", e))),
+            }
+        })
+    });
+    sink.sink_map_err(KvsError::from)
+        .send_all(replies)
+        .map(|_| ())
+}
+
+/// Runs a command on the engine.
+fn execute<E: KvsEngine>(
+    engine: &E,
+    info: &Info,
+    mut args: Vec<Vec<u8>>,
+) -> Box<dyn Future<Item = Reply, Error = KvsError> + Send> {
+    let name = String::from_utf8_lossy(&args.remove(0)).to_ascii_lowercase();
+    let reply = match (name.as_str(), args.len()) {
+        ("ping", 0) => Reply::Simple("PONG"),
+        ("ping", 1) => Reply::Bulk(args.pop()),
+        ("get", 1) => return Box::new(engine.get_bytes(args.remove(0)).map(Reply::Bulk)),
+        ("set", n) if n >= 2 => return set(engine, args),
+        ("del", n) if n >= 1 => {
+            let removed = stream::iter_ok(args)
+                .and_then({
+                    let engine = engine.clone();
+                    move |key| engine.remove_bytes(key).then(removed_count)
+                })
+                .fold(0, |sum, n| -> Result<i64> { Ok(sum + n) });
+            return Box::new(removed.map(Reply::Integer));
+        }
+        ("exists", n) if n >= 1 => {
+            let gets: Vec<_> = args.into_iter().map(|key| engine.get_bytes(key)).collect();
+            let count = future::join_all(gets)
+                .map(|values| values.iter().filter(|value| value.is_some()).count() as i64);
+            return Box::new(count.map(Reply::Integer));
+        }
+        ("scan", n) if n >= 1 => return scan(engine, args),
+        ("info", 0) => info_reply(info, "default"),
+        ("info", 1) => info_reply(
+            info,
+            &String::from_utf8_lossy(&args[0]).to_ascii_lowercase(),
+        ),
+        ("ping", _)
+        | ("get", _)
+        | ("set", _)
+        | ("del", _)
+        | ("exists", _)
+        | ("scan", _)
+        | ("info", _) => Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        )),
+        _ => Reply::Error(format!("ERR unknown command '{}'", name)),
+    };
+    Box::new(future::ok(reply))
+}
+
+/// Maps the result of removing a key to the number of keys removed.
+fn removed_count(res: Result<()>) -> Result<i64> {
+    match res {
+        Ok(()) => Ok(1),
+        Err(KvsError::KeyNotFound) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs `SET key value [EX seconds | PX milliseconds]`.
+fn set<E: KvsEngine>(
+    engine: &E,
+    args: Vec<Vec<u8>>,
+) -> Box<dyn Future<Item = Reply, Error = KvsError> + Send> {
+    let mut args = args.into_iter();
+    let (key, value) = (args.next().unwrap(), args.next().unwrap());
+    let mut ttl = None;
+    while let Some(option) = args.next() {
+        let unit = match option.to_ascii_lowercase().as_slice() {
+            b"ex" if ttl.is_none() => Duration::from_secs(1),
+            b"px" if ttl.is_none() => Duration::from_millis(1),
+            _ => return Box::new(future::ok(Reply::Error("ERR syntax error".to_owned()))),
+        };
+        match args.next().as_deref().and_then(parse_integer) {
+            Some(n) if n > 0 && n <= u32::MAX as u64 => ttl = Some(unit * n as u32),
+            _ => {
+                let msg = "ERR invalid expire time in 'set' command".to_owned();
+                return Box::new(future::ok(Reply::Error(msg)));
+            }
+        }
+    }
+    let written = match ttl {
+        Some(ttl) => engine.set_bytes_with_ttl(key, value, ttl),
+        None => engine.set_bytes(key, value),
+    };
+    Box::new(written.map(|_| Reply::Simple("OK")))
+}
+
+/// Runs `SCAN cursor [MATCH pattern] [COUNT count]`.
+fn scan<E: KvsEngine>(
+    engine: &E,
+    args: Vec<Vec<u8>>,
+) -> Box<dyn Future<Item = Reply, Error = KvsError> + Send> {
+    let error = |msg: &str| -> Box<dyn Future<Item = Reply, Error = KvsError> + Send> {
+        Box::new(future::ok(Reply::Error(msg.to_owned())))
+    };
+    let mut args = args.into_iter();
+    let start = match args.next().as_deref() {
+        Some(b"0") => Vec::new(),
+        Some(cursor) => match decode_cursor(cursor) {
+            Some(start) => start,
+            None => return error("ERR invalid cursor"),
+        },
+        None => return error("ERR invalid cursor"),
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    while let Some(option) = args.next() {
+        match (option.to_ascii_lowercase().as_slice(), args.next()) {
+            (b"match", Some(arg)) => pattern = Some(arg),
+            (b"count", Some(arg)) => match parse_integer(&arg) {
+                Some(n) if n > 0 => count = n as usize,
+                Some(_) => return error("ERR syntax error"),
+                None => return error("ERR value is not an integer or out of range"),
+            },
+            _ => return error("ERR syntax error"),
+        }
+    }
+
+    // One more key is read to find where the next page starts. MATCH filters the
+    // keys after they are examined, as in Redis.
+    let page = engine
+        .scan_keys_bytes(start, None, Some(count.saturating_add(1)))
+        .collect()
+        .map(move |mut keys| {
+            let next = if keys.len() > count {
+                encode_cursor(&keys.split_off(count)[0])
+            } else {
+                b"0".to_vec()
+            };
+            let keys = keys
+                .into_iter()
+                .filter(|key| pattern.as_ref().is_none_or(|p| glob_match(p, key)))
+                .map(|key| Reply::Bulk(Some(key)))
+                .collect();
+            Reply::Array(vec![Reply::Bulk(Some(next)), Reply::Array(keys)])
+        });
+    Box::new(page)
+}
+
+/// Encodes the key the next page of a `SCAN` starts from as a cursor, in hex.
+///
+/// The key follows the keys of a page, so it is never empty and its cursor is never
+/// `0`, which starts and ends an iteration.
+fn encode_cursor(key: &[u8]) -> Vec<u8> {
+    key.iter()
+        .flat_map(|byte| format!("{:02x}", byte).into_bytes())
+        .collect()
+}
+
+/// Decodes a cursor made by `encode_cursor`.
+fn decode_cursor(cursor: &[u8]) -> Option<Vec<u8>> {
+    if !cursor.len().is_multiple_of(2) {
+        return None;
+    }
+    cursor
+        .chunks(2)
+        .map(|digits| u8::from_str_radix(str::from_utf8(digits).ok()?, 16).ok())
+        .collect()
+}
+
+fn parse_integer(arg: &[u8]) -> Option<u64> {
+    str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// Returns the `INFO` text of a section, which is empty for unknown sections.
+fn info_reply(info: &Info, section: &str) -> Reply {
+    let text = match section {
+        "server" | "default" | "all" | "everything" => format!(
+            "# Server\r\nkvs_version:{}\r\nprocess_id:{}\r\ntcp_port:{}\r\nuptime_in_seconds:{}\r\n",
+            env!("CARGO_PKG_VERSION"),
+            process::id(),
+            info.port,
+            info.started.elapsed().as_secs()
+        ),
+        _ => String::new(),
+    };
+    Reply::Bulk(Some(text.into_bytes()))
+}
+
+/// Returns whether `s` matches the glob-style `pattern` of Redis, which supports `*`,
+/// `?`, `[...]` classes with ranges and `^` negation, and `\` escapes.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // the pattern after the last `*`, and where in `s` its match starts
+    let mut star = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, i));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], s[i]) {
+            p += len;
+            i += 1;
+            continue;
+        }
+        match star {
+            // let the `*` match one more byte
+            Some((star_p, star_i)) => {
+                p = star_p;
+                i = star_i + 1;
+                star = Some((star_p, star_i + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Matches the first element of a pattern, other than `*`, against a byte, and returns
+/// the length of the element if it matches.
+fn match_one(pattern: &[u8], b: u8) -> Option<usize> {
+    match *pattern.first()? {
+        b'?' => Some(1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == b).then_some(2),
+        b'[' => match_class(pattern, b),
+        c => (c == b).then_some(1),
+    }
+}
+
+/// Matches a `[...]` class at the start of a pattern against a byte, and returns the
+/// length of the class if it matches. An unterminated class ends with the pattern.
+fn match_class(pattern: &[u8], b: u8) -> Option<usize> {
+    let negated = pattern.get(1) == Some(&b'^');
+    let mut i = if negated { 2 } else { 1 };
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == b;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (lo, hi) = (
+                pattern[i].min(pattern[i + 2]),
+                pattern[i].max(pattern[i + 2]),
+            );
+            matched |= (lo..=hi).contains(&b);
+            i += 3;
+        } else {
+            matched |= pattern[i] == b;
+            i += 1;
+        }
+    }
+    let len = (i + 1).min(pattern.len());
+    (matched != negated).then_some(len)
+}
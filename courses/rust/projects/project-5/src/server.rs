@@ -1,64 +1,264 @@
-use crate::common::{Request, Response};
+use crate::common::{Request, Response, Tagged, FEATURES, PROTOCOL_VERSION};
+use crate::{http, resp};
 use crate::{KvsEngine, KvsError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp;
+use std::mem;
 use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
 use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
 use tokio_serde_json::{ReadJson, WriteJson};
 
+/// Maximum number of requests of a connection the server works on at the same time.
+const MAX_IN_FLIGHT: usize = 64;
+
 /// The server of a key value store.
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
+    resp_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    backup_root: Option<PathBuf>,
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+        KvsServer {
+            engine,
+            resp_addr: None,
+            http_addr: None,
+            backup_root: None,
+        }
+    }
+
+    /// Also serve the Redis RESP2 protocol on `addr`, for Redis tools and client
+    /// libraries.
+    ///
+    /// It supports `PING`, `GET`, `SET` with the `EX` and `PX` options, `DEL`, `EXISTS`,
+    /// `SCAN` with the `MATCH` and `COUNT` options, and `INFO`.
+    pub fn with_resp(mut self, addr: SocketAddr) -> Self {
+        self.resp_addr = Some(addr);
+        self
+    }
+
+    /// Also serve an HTTP gateway on `addr`, for consumers that can't use `KvsClient`.
+    ///
+    /// It exposes `GET`, `PUT` and `DELETE` on `/keys/{key}`, a paged
+    /// `GET /keys?prefix={prefix}` and `GET /health`.
+    pub fn with_http(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    /// Write the backups requested by clients to directories under `dir`.
+    ///
+    /// A client names a relative directory, which must not leave `dir`. Backups are
+    /// refused if no root is set, as clients could write anywhere the server can.
+    pub fn with_backup_root(mut self, dir: PathBuf) -> Self {
+        self.backup_root = Some(dir);
+        self
     }
 
     /// Run the server listening on the given address
     pub fn run(self, addr: SocketAddr) -> Result<()> {
         let listener = TcpListener::bind(&addr)?;
+        let mut front_ends: Vec<Box<dyn Future<Item = (), Error = ()> + Send>> = Vec::new();
+        if let Some(resp_addr) = self.resp_addr {
+            let listener = TcpListener::bind(&resp_addr)?;
+            front_ends.push(Box::new(resp::listen(self.engine.clone(), listener)?));
+        }
+        if let Some(http_addr) = self.http_addr {
+            let listener = TcpListener::bind(&http_addr)?;
+            front_ends.push(Box::new(http::listen(self.engine.clone(), listener)));
+        }
         let server = listener
             .incoming()
             .map_err(|e| error!("IO error: {}", e))
             .for_each(move |tcp| {
                 let engine = self.engine.clone();
-                serve(engine, tcp).map_err(|e| error!("Error on serving client: {}", e))
+                let backup_root = self.backup_root.clone();
+                // connections are served concurrently
+                tokio::spawn(
+                    serve(engine, backup_root, tcp)
+                        .map_err(|e| error!("Error on serving client: {}", e)),
+                );
+                Ok(())
             });
-        tokio::run(server);
+        tokio::run(future::lazy(move || {
+            for front_end in front_ends {
+                tokio::spawn(front_end);
+            }
+            server
+        }));
         Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> impl Future<Item = (), Error = KvsError> {
+/// Serves the requests of a connection.
+///
+/// Up to `MAX_IN_FLIGHT` requests run at the same time and are answered as soon as they
+/// finish, so requests in flight together may take effect in any order.
+///
+/// The connection speaks version 1 of the protocol until the client sends a handshake.
+/// A request the server can't decode is answered with `Response::Unsupported`, or with
+/// `Response::Err` in version 1, which doesn't know that response. Requests sent
+/// without an id, by clients that predate ids and wait for every response, are
+/// answered without one.
+fn serve<E: KvsEngine>(
+    engine: E,
+    backup_root: Option<PathBuf>,
+    tcp: TcpStream,
+) -> impl Future<Item = (), Error = KvsError> {
     let (read_half, write_half) = tcp.split();
-    let read_json = ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
+    // requests are decoded by `handle`, so that an unknown one doesn't end the connection
+    let read_json =
+        ReadJson::<_, Value>::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
+    let mut version = 1;
+    let mut first = true;
     let resp_stream = read_json
         .map_err(KvsError::from)
-        .and_then(
-            move |req| -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
-                match req {
-                    Request::Get { key } => Box::new(engine.get(key).map(Response::Get)),
-                    Request::Set { key, value } => {
-                        Box::new(engine.set(key, value).map(|_| Response::Set))
+        .and_then(split_frame)
+        .map(move |(id, body)| {
+            let is_first = mem::replace(&mut first, false);
+            let resp: Box<dyn Future<Item = Response, Error = KvsError> + Send> =
+                match Request::deserialize(&body) {
+                    // handled here, so that the next request sees the new version
+                    Ok(Request::Hello {
+                        version: client_version,
+                        features,
+                    }) if is_first => {
+                        version = cmp::min(client_version, PROTOCOL_VERSION);
+                        let features = features
+                            .into_iter()
+                            .filter(|feature| FEATURES.contains(&feature.as_str()))
+                            .collect();
+                        Box::new(future::ok(Response::Hello { version, features }))
                     }
-                    Request::Remove { key } => {
-                        Box::new(engine.remove(key).map(|_| Response::Remove))
+                    Ok(req) => handle(&engine, backup_root.as_deref(), req),
+                    Err(e) => {
+                        debug!("Unsupported request {}: {}", body, e);
+                        let msg = format!("{}", e);
+                        let resp = if version >= 2 {
+                            Response::Unsupported(msg)
+                        } else {
+                            Response::Err(format!("{}", KvsError::UnsupportedRequest(msg)))
+                        };
+                        Box::new(future::ok(resp))
                     }
-                }
-            },
-        )
-        .then(|resp| -> Result<Response> {
-            match resp {
-                Ok(resp) => Ok(resp),
-                Err(e) => Ok(Response::Err(format!("{}", e))),
-            }
-        });
+                };
+            resp.then(move |resp| -> Result<ResponseFrame> {
+                let body = match resp {
+                    Ok(resp) => resp,
+                    Err(e) => Response::Err(format!("{}", e)),
+                };
+                Ok(match id {
+                    Some(id) => ResponseFrame::Tagged(Tagged { id, body }),
+                    None => ResponseFrame::Untagged(body),
+                })
+            })
+        })
+        .buffer_unordered(MAX_IN_FLIGHT);
     let write_json = WriteJson::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()));
     write_json
         .sink_map_err(KvsError::from)
         .send_all(resp_stream)
         .map(|_| ())
 }
+
+/// A response, sent with the id of its request unless the request had none.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ResponseFrame {
+    Tagged(Tagged<Response>),
+    Untagged(Response),
+}
+
+/// Splits a request frame into the id of the request, if it has one, and the request.
+///
+/// Requests are tagged objects with an `id`, or the request alone in the first
+/// releases of version 1, which is an object with the name of the request as its only
+/// field, or a string.
+fn split_frame(frame: Value) -> Result<(Option<u64>, Value)> {
+    if frame.get("id").is_some() {
+        let Tagged { id, body } = serde_json::from_value(frame)?;
+        Ok((Some(id), body))
+    } else {
+        Ok((None, frame))
+    }
+}
+
+/// Runs a request on the engine.
+fn handle<E: KvsEngine>(
+    engine: &E,
+    backup_root: Option<&Path>,
+    req: Request,
+) -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
+    match req {
+        Request::Get { key } => Box::new(engine.get_bytes(key).map(Response::Get)),
+        Request::Set { key, value } => {
+            Box::new(engine.set_bytes(key, value).map(|_| Response::Set))
+        }
+        Request::SetWithTtl { key, value, ttl } => Box::new(
+            engine
+                .set_bytes_with_ttl(key, value, ttl)
+                .map(|_| Response::Set),
+        ),
+        Request::Remove { key } => Box::new(engine.remove_bytes(key).map(|_| Response::Remove)),
+        Request::Cas { key, expected, new } => Box::new(
+            engine
+                .compare_and_swap_bytes(key, expected, new)
+                .map(|res| match res {
+                    Ok(()) => Response::Cas,
+                    Err(current) => Response::CasMismatch(current),
+                }),
+        ),
+        Request::Scan { start, end, limit } => Box::new(
+            engine
+                .scan_bytes(start, end, limit)
+                .collect()
+                .map(Response::Scan),
+        ),
+        Request::ScanPrefix { prefix } => Box::new(
+            engine
+                .scan_prefix_bytes(prefix)
+                .collect()
+                .map(Response::Scan),
+        ),
+        Request::Batch(batch) => Box::new(engine.write_batch(batch).map(|_| Response::Batch)),
+        Request::Compact => Box::new(engine.compact().map(|_| Response::Compact)),
+        Request::Backup { dir } => match backup_dir(backup_root, &dir) {
+            Ok(dir) => Box::new(engine.backup_to(dir).map(|_| Response::Backup)),
+            Err(e) => Box::new(future::err(e)),
+        },
+        Request::Ping => Box::new(future::ok(Response::Pong)),
+        // a handshake is only valid as the first request
+        Request::Hello { .. } => Box::new(future::err(KvsError::StringError(
+            "Unexpected handshake".to_owned(),
+        ))),
+    }
+}
+
+/// Resolves the directory a client asked to back up to under the backup root.
+///
+/// # Errors
+///
+/// It returns an error if no backup root is set, or if `dir` is absolute or contains
+/// `..`, which could leave the root.
+fn backup_dir(backup_root: Option<&Path>, dir: &Path) -> Result<PathBuf> {
+    let backup_root = backup_root.ok_or_else(|| {
+        KvsError::StringError("Backups are disabled, as no backup directory is set".to_owned())
+    })?;
+    if dir
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(KvsError::StringError(format!(
+            "Backup directory {:?} must be relative and stay in the backup directory",
+            dir
+        )));
+    }
+    Ok(backup_root.join(dir))
+}
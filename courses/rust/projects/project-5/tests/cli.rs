@@ -1,10 +1,15 @@
+// the commands take their arguments as arrays, and the servers are killed, not waited on
+#![allow(clippy::needless_borrows_for_generic_args, clippy::zombie_processes)]
+
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::TcpStream;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
 // `kvs-client` with no args should exit with a non-zero code.
@@ -118,6 +123,31 @@ fn client_cli_invalid_rm() {
         .failure();
 }
 
+#[test]
+fn client_cli_invalid_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key1", "key2", "extra_field"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key1", "--prefix", "key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--limit", "invalid-limit"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
@@ -151,6 +181,19 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+#[test]
+fn server_cli_invalid_sync() {
+    let temp_dir = TempDir::new().unwrap();
+    for policy in &["sometimes", "every:0", "interval:", "every:many"] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--sync", policy, "--addr", "127.0.0.1:4006"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -283,6 +326,30 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1 value2\nkey2 value3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2 value3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "key", "--limit", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1 value2\n");
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["rm", "key1", "--addr", addr])
@@ -291,6 +358,22 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value4", "--ttl", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["compact", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -322,6 +405,13 @@ fn cli_access_server(engine: &str, addr: &str) {
         .assert()
         .success()
         .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -335,3 +425,293 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4008");
+}
+
+// The memory engine should serve requests without writing to the current directory.
+#[test]
+fn cli_access_server_memory_engine() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+}
+
+// `kvs-server --resp-addr` should also serve the data to Redis clients.
+#[test]
+fn cli_access_server_resp() {
+    let addr = "127.0.0.1:4028";
+    let resp_addr = "127.0.0.1:4029";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--engine",
+            "memory",
+            "--addr",
+            addr,
+            "--resp-addr",
+            resp_addr,
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let mut stream = TcpStream::connect(resp_addr).unwrap();
+    stream.write_all(b"GET key1\r\n").unwrap();
+    let mut reply = [0; 12];
+    stream.read_exact(&mut reply).unwrap();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert_eq!(&reply, b"$6\r\nvalue1\r\n");
+}
+
+// `kvs-server --http-addr` should also serve the data over HTTP.
+#[test]
+fn cli_access_server_http() {
+    let addr = "127.0.0.1:4030";
+    let http_addr = "127.0.0.1:4031";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--engine",
+            "memory",
+            "--addr",
+            addr,
+            "--http-addr",
+            http_addr,
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let mut stream = TcpStream::connect(http_addr).unwrap();
+    stream
+        .write_all(b"GET /keys/key1 HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nvalue1"));
+}
+
+// `kvs-server backup` should back up a running server to a directory under its backup
+// directory and `kvs-server restore` should restore the backup to a new directory.
+#[test]
+fn cli_backup_and_restore() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let server_dir = temp_dir.path().join("server");
+    let restore_dir = temp_dir.path().join("restore");
+    fs::create_dir(&server_dir).unwrap();
+    fs::create_dir(&restore_dir).unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--backup-dir"])
+        .arg(temp_dir.path())
+        .current_dir(&server_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["backup", "backup", "--addr", addr])
+        .assert()
+        .success();
+    // clients can't write outside the backup directory
+    let outside = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["backup", "../outside", "--addr", addr])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["backup", "--addr", addr])
+        .arg(outside.path().join("backup"))
+        .assert()
+        .failure();
+    assert!(!temp_dir.path().join("../outside").exists());
+    assert!(!outside.path().join("backup").exists());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value2", "--addr", addr])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["restore", temp_dir.path().join("backup").to_str().unwrap()])
+        .current_dir(&restore_dir)
+        .assert()
+        .success();
+    // the directory already contains log files
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["restore", temp_dir.path().join("backup").to_str().unwrap()])
+        .current_dir(&restore_dir)
+        .assert()
+        .failure();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr])
+        .current_dir(&restore_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-server migrate` should copy the data of a kvs server to a directory of the sled
+// engine, where a server then starts without `--engine`. Keys set with a time-to-live
+// should still expire.
+#[test]
+fn cli_migrate() {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let server_dir = temp_dir.path().join("server");
+    let sled_dir = temp_dir.path().join("sled");
+    fs::create_dir(&server_dir).unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&server_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for (key, value) in &[("key1", "value1"), ("key2", "value2")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, value, "--addr", addr])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value3", "--ttl", "4", "--addr", addr])
+        .assert()
+        .success();
+    let expires_at = Instant::now() + Duration::from_secs(4);
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // the data is already stored by the kvs engine
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["migrate", "--to", "kvs", sled_dir.to_str().unwrap()])
+        .current_dir(&server_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["migrate", "--to", "sled", sled_dir.to_str().unwrap()])
+        .current_dir(&server_dir)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(sled_dir.join("engine")).unwrap(), "sled");
+    // the directory is not empty anymore
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["migrate", "--to", "sled", sled_dir.to_str().unwrap()])
+        .current_dir(&server_dir)
+        .assert()
+        .failure();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr])
+        .current_dir(&sled_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("key1 value1\nkey3 value3\n");
+    thread::sleep(expires_at.saturating_duration_since(Instant::now()));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("key1 value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
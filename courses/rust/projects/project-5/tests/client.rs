@@ -0,0 +1,229 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    KvsClient, KvsClientPool, KvsClientPoolOptions, KvsError, KvsServer, MemoryKvsEngine, Result,
+};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+/// Runs a server with an in-memory engine at `addr` and connects a client to it.
+fn start_server(addr: &str) -> Result<KvsClient> {
+    let addr: SocketAddr = addr.parse().unwrap();
+    thread::spawn(move || KvsServer::new(MemoryKvsEngine::new()).run(addr));
+    for _ in 0..50 {
+        if let Ok(client) = KvsClient::connect(addr).wait() {
+            return Ok(client);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    KvsClient::connect(addr).wait()
+}
+
+// Should route the responses of many requests in flight on one connection
+#[test]
+fn pipeline_requests() -> Result<()> {
+    let client = start_server("127.0.0.1:4011")?;
+
+    let sets: Vec<_> = (0..500)
+        .map(|i| client.set(format!("key{}", i), format!("value{}", i)))
+        .collect();
+    future::join_all(sets).wait()?;
+
+    let gets: Vec<_> = (0..500).map(|i| client.get(format!("key{}", i))).collect();
+    let values = future::join_all(gets).wait()?;
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(value, Some(format!("value{}", i)));
+    }
+
+    // a failing request only fails its own future
+    let removes: Vec<_> = (495..505)
+        .map(|i| client.remove(format!("key{}", i)).then(Ok::<_, KvsError>))
+        .collect();
+    let results = future::join_all(removes).wait()?;
+    assert!(results[..5].iter().all(|res| res.is_ok()));
+    assert!(results[5..].iter().all(|res| res.is_err()));
+    assert_eq!(client.get("key495".to_owned()).wait()?, None);
+    assert_eq!(
+        client.get("key494".to_owned()).wait()?,
+        Some("value494".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should share one connection between clones used from several threads
+#[test]
+fn share_client_between_threads() -> Result<()> {
+    let client = start_server("127.0.0.1:4012")?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let client = client.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    let key = format!("key{}-{}", t, i);
+                    client.set(key.clone(), format!("value{}", i)).wait()?;
+                    assert_eq!(client.get(key).wait()?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let pairs = client.scan_prefix("key7-".to_owned()).wait()?;
+    assert_eq!(pairs.len(), 100);
+
+    Ok(())
+}
+
+// Should fail the requests once the connection is closed
+#[test]
+fn fail_requests_on_closed_connection() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4013".parse().unwrap();
+    let listener = TcpListener::bind(addr)?;
+    let server = thread::spawn(move || {
+        // accept the connection and answer the handshake, then close it
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut hello = vec![0; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut hello).unwrap();
+        let hello = br#"{"id":0,"body":{"Hello":{"version":2,"features":[]}}}"#;
+        stream
+            .write_all(&(hello.len() as u32).to_be_bytes())
+            .unwrap();
+        stream.write_all(hello).unwrap();
+        drop(stream);
+    });
+    let client = KvsClient::connect(addr).wait()?;
+    server.join().unwrap();
+
+    assert!(client.get("key1".to_owned()).wait().is_err());
+    assert!(client.get("key2".to_owned()).wait().is_err());
+
+    Ok(())
+}
+
+// Should serve a new connection while another one is still open
+#[test]
+fn serve_connections_concurrently() -> Result<()> {
+    let client = start_server("127.0.0.1:4017")?;
+    client.set("key1".to_owned(), "value1".to_owned()).wait()?;
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let res = KvsClient::connect("127.0.0.1:4017".parse().unwrap())
+            .and_then(|other| other.get("key1".to_owned()))
+            .wait();
+        tx.send(res).unwrap();
+    });
+    let value = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("the second connection is not served");
+    assert_eq!(value?, Some("value1".to_owned()));
+    // the first connection is still usable
+    assert_eq!(
+        client.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+/// Pool options that notice a restarted server within a few hundred milliseconds.
+fn fast_options() -> KvsClientPoolOptions {
+    KvsClientPoolOptions {
+        size: 2,
+        timeout: Duration::from_secs(5),
+        health_check_interval: Duration::from_millis(100),
+        min_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(200),
+    }
+}
+
+// Should spread concurrent requests over the connections of a pool
+#[test]
+fn pool_requests() -> Result<()> {
+    let client = start_server("127.0.0.1:4014")?;
+    let pool =
+        KvsClientPool::connect_with_options("127.0.0.1:4014".parse().unwrap(), fast_options())?;
+
+    let sets: Vec<_> = (0..200)
+        .map(|i| {
+            let pool = pool.clone();
+            let (key, value) = (format!("key{}", i), format!("value{}", i));
+            pool.execute(move |client| client.set(key, value))
+        })
+        .collect();
+    future::join_all(sets).wait()?;
+    let value = pool
+        .execute(|client| client.get("key199".to_owned()))
+        .wait()?;
+    assert_eq!(value, Some("value199".to_owned()));
+    assert_eq!(client.scan_prefix("key".to_owned()).wait()?.len(), 200);
+
+    Ok(())
+}
+
+// Should fail requests the server doesn't answer in time with `KvsError::Timeout`
+#[test]
+fn pool_timeout() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:4015")?;
+    thread::spawn(move || {
+        // keep the connections open without answering
+        let streams: Vec<_> = listener.incoming().collect();
+        drop(streams);
+    });
+    let options = KvsClientPoolOptions {
+        timeout: Duration::from_millis(200),
+        ..fast_options()
+    };
+    let pool = KvsClientPool::connect_with_options("127.0.0.1:4015".parse().unwrap(), options)?;
+
+    let res = pool.execute(|client| client.get("key1".to_owned())).wait();
+    assert!(matches!(res, Err(KvsError::Timeout)));
+
+    Ok(())
+}
+
+// Should wait for a server that is not up yet and reconnect after it restarts
+#[test]
+fn pool_reconnect() -> Result<()> {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = KvsClientPool::connect_with_options(addr.parse().unwrap(), fast_options())?;
+    let start = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+
+    // the request waits until the server is up
+    let request = pool.execute(|client| client.set("key1".to_owned(), "value1".to_owned()));
+    let mut child = start();
+    let res = request.wait();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    res?;
+
+    // the health checks notice the restart and reconnect
+    let mut child = start();
+    thread::sleep(Duration::from_secs(1));
+    let value = pool.execute(|client| client.get("key1".to_owned())).wait();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert_eq!(value?, Some("value1".to_owned()));
+
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use kvs::{KvsServer, MemoryKvsEngine};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// A response, as read by the tests.
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn connect(addr: SocketAddr) -> Connection {
+        let stream = TcpStream::connect(addr).unwrap();
+        Connection {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    /// Sends a request on the kept-alive connection and reads its response.
+    fn request(&mut self, method: &str, target: &str, body: &[u8]) -> Response {
+        let head = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+            method,
+            target,
+            body.len()
+        );
+        self.writer.write_all(head.as_bytes()).unwrap();
+        self.writer.write_all(body).unwrap();
+        self.read_response()
+    }
+
+    fn read_response(&mut self) -> Response {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.to_owned(), value.trim().to_owned()));
+        }
+        let mut response = Response {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+        if let Some(len) = response.header("Content-Length") {
+            let mut body = vec![0; len.parse().unwrap()];
+            self.reader.read_exact(&mut body).unwrap();
+            response.body = body;
+        }
+        response
+    }
+}
+
+/// Runs a server with an in-memory engine, serving HTTP at `http_addr`.
+fn start_server(addr: &str, http_addr: &str) -> SocketAddr {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let http_addr: SocketAddr = http_addr.parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(MemoryKvsEngine::new())
+            .with_http(http_addr)
+            .run(addr)
+    });
+    for _ in 0..50 {
+        if TcpStream::connect(http_addr).is_ok() {
+            return http_addr;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("server didn't start");
+}
+
+// Should get, put and delete keys with the status codes of REST
+#[test]
+fn key_requests() {
+    let addr = start_server("127.0.0.1:4032", "127.0.0.1:4033");
+    let mut conn = Connection::connect(addr);
+
+    let resp = conn.request("GET", "/keys/key1", b"");
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.json(), json!({ "error": "Key not found" }));
+
+    assert_eq!(conn.request("PUT", "/keys/key1", b"value1").status, 204);
+    let resp = conn.request("GET", "/keys/key1", b"");
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body, b"value1");
+    assert_eq!(
+        resp.header("Content-Type"),
+        Some("application/octet-stream")
+    );
+
+    assert_eq!(conn.request("DELETE", "/keys/key1", b"").status, 204);
+    assert_eq!(conn.request("GET", "/keys/key1", b"").status, 404);
+    assert_eq!(conn.request("DELETE", "/keys/key1", b"").status, 404);
+
+    // keys are percent-decoded and may hold slashes
+    assert_eq!(
+        conn.request("PUT", "/keys/dir/a%20b%2Fc", b"value2").status,
+        204
+    );
+    assert_eq!(
+        conn.request("GET", "/keys/dir/a%20b/c", b"").body,
+        b"value2"
+    );
+    assert_eq!(conn.request("GET", "/keys/bad%2", b"").status, 400);
+}
+
+// Should list the pairs of a prefix as JSON
+#[test]
+fn list_keys() {
+    let addr = start_server("127.0.0.1:4034", "127.0.0.1:4035");
+    let mut conn = Connection::connect(addr);
+    for key in &["user:2", "user:1", "order:1"] {
+        conn.request(
+            "PUT",
+            &format!("/keys/{}", key),
+            format!("{}-value", key).as_bytes(),
+        );
+    }
+
+    let resp = conn.request("GET", "/keys?prefix=user%3A", b"");
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("Content-Type"), Some("application/json"));
+    assert_eq!(
+        resp.json(),
+        json!({
+            "pairs": [
+                { "key": "user:1", "value": "user:1-value" },
+                { "key": "user:2", "value": "user:2-value" },
+            ],
+            "next": null,
+        })
+    );
+    let resp = conn.request("GET", "/keys", b"");
+    assert_eq!(resp.json()["pairs"].as_array().unwrap().len(), 3);
+
+    // values that aren't UTF-8 are listed in base64
+    conn.request("PUT", "/keys/user:3", &[0xff, 0xfe]);
+    let resp = conn.request("GET", "/keys?prefix=user%3A3", b"");
+    assert_eq!(resp.status, 200);
+    assert_eq!(
+        resp.json()["pairs"],
+        json!([{ "key": "user:3", "value": { "base64": "//4=" } }])
+    );
+
+    // pages end at the limit and the next one starts from `next`
+    let resp = conn.request("GET", "/keys?limit=2", b"");
+    assert_eq!(
+        resp.json(),
+        json!({
+            "pairs": [
+                { "key": "order:1", "value": "order:1-value" },
+                { "key": "user:1", "value": "user:1-value" },
+            ],
+            "next": "user:2",
+        })
+    );
+    let resp = conn.request("GET", "/keys?prefix=user%3A&limit=2&start=user%3A2", b"");
+    let page = resp.json();
+    assert_eq!(page["pairs"].as_array().unwrap().len(), 2);
+    assert_eq!(page["pairs"][0]["key"], "user:2");
+    assert_eq!(page["next"], Value::Null);
+    assert_eq!(conn.request("GET", "/keys?limit=0", b"").status, 400);
+}
+
+// Should expire keys put with a ttl
+#[test]
+fn put_with_ttl() {
+    let addr = start_server("127.0.0.1:4036", "127.0.0.1:4037");
+    let mut conn = Connection::connect(addr);
+
+    assert_eq!(
+        conn.request("PUT", "/keys/key1?ttl=1", b"value1").status,
+        204
+    );
+    assert_eq!(conn.request("GET", "/keys/key1", b"").status, 200);
+    assert_eq!(
+        conn.request("PUT", "/keys/key2?ttl=0", b"value2").status,
+        400
+    );
+    assert_eq!(
+        conn.request("PUT", "/keys/key2?ttl=x", b"value2").status,
+        400
+    );
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(conn.request("GET", "/keys/key1", b"").status, 404);
+}
+
+// Should serve the health check and reject invalid requests
+#[test]
+fn health_and_errors() {
+    let addr = start_server("127.0.0.1:4038", "127.0.0.1:4039");
+    let mut conn = Connection::connect(addr);
+
+    let resp = conn.request("GET", "/health", b"");
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.json(), json!({ "status": "ok" }));
+    assert_eq!(conn.request("POST", "/keys/key1", b"").status, 405);
+    assert_eq!(conn.request("DELETE", "/health", b"").status, 405);
+    assert_eq!(conn.request("GET", "/other", b"").status, 404);
+
+    // a transfer coding is refused and the connection closed
+    let mut conn = Connection::connect(addr);
+    conn.writer
+        .write_all(b"PUT /keys/key1 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")
+        .unwrap();
+    let resp = conn.read_response();
+    assert_eq!(resp.status, 501);
+    assert_eq!(resp.header("Connection"), Some("close"));
+
+    // an HTTP/1.0 request is answered and the connection closed
+    let mut conn = Connection::connect(addr);
+    conn.writer
+        .write_all(b"GET /health HTTP/1.0\r\n\r\n")
+        .unwrap();
+    let resp = conn.read_response();
+    assert_eq!(resp.status, 200);
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
@@ -1,5 +1,13 @@
 use kvs::thread_pool::RayonThreadPool;
-use kvs::{KvStore, KvsEngine, KvsError, Result};
+use kvs::{
+    CompactionPolicy, CompactionWindow, Compression, GenUsage, IndexMode, KvStore, KvStoreOptions,
+    KvsEngine, KvsError, Result, SyncPolicy, ThresholdPolicy, WriteBatch,
+};
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
@@ -119,6 +127,11 @@ fn compaction() -> Result<()> {
             .map(|res| {
                 res.and_then(|entry| entry.metadata())
                     .map(|metadata| metadata.len())
+                    // the file is deleted by the background compaction
+                    .or_else(|e| match e.io_error().map(|e| e.kind()) {
+                        Some(io::ErrorKind::NotFound) => Ok(0),
+                        _ => Err(e),
+                    })
             })
             .sum();
         len.expect("fail to get directory size")
@@ -152,6 +165,585 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Overwrite keys while compactions run in the background.
+// Test that no write is lost and the compaction stats are updated.
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert!(!store.compaction_stats().running);
+
+    let mut iter = 0;
+    while store.compaction_stats().finished < 2 {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value).wait()?;
+        }
+        iter += 1;
+    }
+    let stats = store.compaction_stats();
+    assert!(stats.entries_total > 0);
+    assert!(stats.bytes_reclaimed > 0);
+    assert!(stats.last_duration.is_some());
+
+    for key_id in 0..1000 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key).wait()?, Some(format!("{}", iter - 1)));
+    }
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key_id in 0..1000 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key).wait()?, Some(format!("{}", iter - 1)));
+    }
+
+    Ok(())
+}
+
+// `compact` should merge every log file whatever the compaction policy is.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction: Arc::new(ThresholdPolicy {
+            max_stale_bytes: None,
+            ..ThresholdPolicy::default()
+        }),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store
+                .set(format!("key{}", key_id), format!("{}", iter))
+                .wait()?;
+        }
+    }
+    store.remove("key0".to_owned()).wait()?;
+    assert_eq!(store.compaction_stats().finished, 0);
+
+    store.compact().wait()?;
+    assert_eq!(store.compaction_stats().finished, 1);
+    // the compaction file and the new active log file
+    let usage = store.gen_usage();
+    assert_eq!(usage.len(), 2);
+    assert!(usage.iter().all(|u| u.stale == 0));
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key0".to_owned()).wait()?, None);
+    for key_id in 1..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key).wait()?, Some("9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Only the dirtiest log files should be compacted with a garbage ratio threshold.
+// Test that a key removed in a compacted file stays removed although its value
+// is still in an older log file.
+#[test]
+fn compact_dirtiest_gens() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id), "value".to_owned())
+            .wait()?;
+    }
+    drop(store);
+
+    let options = KvStoreOptions {
+        compaction: Arc::new(ThresholdPolicy {
+            max_stale_bytes: Some(0),
+            min_garbage_ratio: Some(0.5),
+            ..ThresholdPolicy::default()
+        }),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options.clone())?;
+    let clean_gen = store.gen_usage()[0].gen;
+    // the log file with the removal is mostly garbage, unlike the first one
+    store.remove("key0".to_owned()).wait()?;
+    store.wait_for_compaction().wait()?;
+    assert_eq!(store.compaction_stats().finished, 1);
+    let usage = store.gen_usage();
+    assert_eq!(usage[0].gen, clean_gen);
+    assert!(usage[0].stale > 0);
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+    assert_eq!(store.get("key0".to_owned()).wait()?, None);
+    for key_id in 1..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key).wait()?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn compaction_bytes_limit() {
+    let usage = |gen, len, stale| GenUsage { gen, len, stale };
+    let usage = [
+        usage(1, 1000, 100),
+        usage(2, 1000, 900),
+        usage(3, 1000, 500),
+        usage(4, 100, 0),
+    ];
+    let policy = |max_compaction_bytes| ThresholdPolicy {
+        max_stale_bytes: Some(0),
+        max_compaction_bytes,
+        ..ThresholdPolicy::default()
+    };
+    assert_eq!(policy(None).pick(&usage), vec![1, 2, 3, 4]);
+    // the dirtiest log files are picked first
+    assert_eq!(policy(Some(600)).pick(&usage), vec![2, 3]);
+    // a log file with more live bytes than the limit is still picked alone
+    assert_eq!(policy(Some(10)).pick(&usage), vec![2]);
+}
+
+#[test]
+fn compaction_window() {
+    let hour = |h: u64| UNIX_EPOCH + Duration::from_secs(h * 3600);
+    let day = CompactionWindow::new(8, 18);
+    assert!(day.contains(hour(8)));
+    assert!(day.contains(hour(24 + 17)));
+    assert!(!day.contains(hour(18)));
+    assert!(!day.contains(hour(3)));
+
+    let night = CompactionWindow::new(22, 6);
+    assert!(night.contains(hour(23)));
+    assert!(night.contains(hour(24 + 5)));
+    assert!(!night.contains(hour(6)));
+    assert!(!night.contains(hour(12)));
+}
+
+// Keys set with a time-to-live should disappear once expired, also after a
+// compaction and a restart.
+#[test]
+fn expire_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let ttl = Duration::from_millis(200);
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store
+        .set_with_ttl("key2".to_owned(), "value2".to_owned(), ttl)
+        .wait()?;
+    store
+        .set_with_ttl("key3".to_owned(), "value3".to_owned(), ttl)
+        .wait()?;
+    // setting a key again without a time-to-live keeps it forever
+    store.set("key3".to_owned(), "value3".to_owned()).wait()?;
+    store
+        .set_with_ttl(
+            "key4".to_owned(),
+            "value4".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .wait()?;
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    let expires_at = store.expiry_bytes(b"key4".to_vec()).wait()?;
+    assert!(expires_at.is_some_and(|at| at > SystemTime::now() + Duration::from_secs(3500)));
+    assert!(store.expiry_bytes(b"key3".to_vec()).wait()?.is_none());
+
+    thread::sleep(ttl);
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert!(matches!(
+        store.expiry_bytes(b"key2".to_vec()).wait(),
+        Err(KvsError::KeyNotFound)
+    ));
+    let keys: Vec<String> = store
+        .scan_prefix("key".to_owned())
+        .map(|(key, _)| key)
+        .collect()
+        .wait()?;
+    assert_eq!(keys, vec!["key1", "key3", "key4"]);
+    assert!(store.remove("key2".to_owned()).wait().is_err());
+
+    store.compact().wait()?;
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key3".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(
+        store.get("key4".to_owned()).wait()?,
+        Some("value4".to_owned())
+    );
+
+    Ok(())
+}
+
+// Expired values should be counted as stale bytes the compaction policy decides on,
+// while the store is running and after a restart.
+#[test]
+fn count_expired_as_stale() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction: Arc::new(ThresholdPolicy {
+            max_stale_bytes: None,
+            ..ThresholdPolicy::default()
+        }),
+        ..KvStoreOptions::default()
+    };
+    let stale = |store: &KvStore<RayonThreadPool>| -> u64 {
+        store.gen_usage().iter().map(|usage| usage.stale).sum()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options.clone())?;
+    let ttl = Duration::from_millis(200);
+    store
+        .set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)
+        .wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    assert_eq!(stale(&store), 0);
+
+    thread::sleep(ttl);
+    // the policy runs after every write
+    store.set("key3".to_owned(), "value3".to_owned()).wait()?;
+    let expired = stale(&store);
+    assert!(expired > 0);
+    store.set("key4".to_owned(), "value4".to_owned()).wait()?;
+    assert_eq!(stale(&store), expired);
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+    assert_eq!(stale(&store), expired);
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+
+    Ok(())
+}
+
+// Should only swap the value of a key if it matches the expected one, and return
+// the current value otherwise.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let value = |v: &str| Some(v.to_owned());
+
+    assert_eq!(
+        store
+            .compare_and_swap("key1".to_owned(), None, value("value1"))
+            .wait()?,
+        Ok(())
+    );
+    assert_eq!(
+        store
+            .compare_and_swap("key1".to_owned(), None, value("value2"))
+            .wait()?,
+        Err(value("value1"))
+    );
+    assert_eq!(
+        store
+            .compare_and_swap("key1".to_owned(), value("value1"), value("value2"))
+            .wait()?,
+        Ok(())
+    );
+    assert_eq!(
+        store
+            .compare_and_swap("key2".to_owned(), value("value1"), None)
+            .wait()?,
+        Err(None)
+    );
+    assert_eq!(store.get("key1".to_owned()).wait()?, value("value2"));
+
+    // an expired key doesn't exist anymore
+    let ttl = Duration::from_millis(100);
+    store
+        .set_with_ttl("key3".to_owned(), "value3".to_owned(), ttl)
+        .wait()?;
+    thread::sleep(ttl);
+    assert_eq!(
+        store
+            .compare_and_swap("key3".to_owned(), value("value3"), None)
+            .wait()?,
+        Err(None)
+    );
+    assert_eq!(
+        store
+            .compare_and_swap("key3".to_owned(), None, value("value4"))
+            .wait()?,
+        Ok(())
+    );
+
+    assert_eq!(
+        store
+            .compare_and_swap("key1".to_owned(), value("value2"), None)
+            .wait()?,
+        Ok(())
+    );
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    assert_eq!(store.get("key3".to_owned()).wait()?, value("value4"));
+
+    Ok(())
+}
+
+// Increments done concurrently through compare-and-swap should never be lost.
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    store.set("counter".to_owned(), "0".to_owned()).wait()?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    let mut current = store.get("counter".to_owned()).wait()?;
+                    loop {
+                        let n: u32 = current.as_ref().unwrap().parse().unwrap();
+                        let new = Some((n + 1).to_string());
+                        match store
+                            .compare_and_swap("counter".to_owned(), current, new)
+                            .wait()?
+                        {
+                            Ok(()) => break,
+                            Err(actual) => current = actual,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(
+        store.get("counter".to_owned()).wait()?,
+        Some("400".to_owned())
+    );
+
+    Ok(())
+}
+
+// A snapshot should not see the writes made after it is taken.
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    let snapshot = store.snapshot();
+
+    store.set("key1".to_owned(), "value3".to_owned()).wait()?;
+    store.remove("key2".to_owned()).wait()?;
+    let mut batch = WriteBatch::new();
+    batch.set("key0".to_owned(), "value4".to_owned());
+    batch.set("key1".to_owned(), "value5".to_owned());
+    store.write_batch(batch).wait()?;
+    let later = store.snapshot();
+    assert!(later.lsn() > snapshot.lsn());
+    store.set("key3".to_owned(), "value6".to_owned()).wait()?;
+
+    assert_eq!(
+        snapshot.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        snapshot.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(snapshot.get("key0".to_owned()).wait()?, None);
+    let pairs: Vec<(String, String)> = snapshot.scan(String::new(), None, None).collect().wait()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    let pairs: Vec<(String, String)> = later.scan_prefix("key".to_owned()).collect().wait()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key0".to_owned(), "value4".to_owned()),
+            ("key1".to_owned(), "value5".to_owned()),
+        ]
+    );
+    let pairs: Vec<(String, String)> = later
+        .scan("key1".to_owned(), None, Some(1))
+        .collect()
+        .wait()?;
+    assert_eq!(pairs, vec![("key1".to_owned(), "value5".to_owned())]);
+
+    drop(snapshot);
+    drop(later);
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value5".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+
+    Ok(())
+}
+
+// The log files a snapshot reads should be kept by a compaction until the snapshot
+// is dropped.
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id), "old".to_owned())
+            .wait()?;
+    }
+    let snapshot = store.snapshot();
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id), "new".to_owned())
+            .wait()?;
+    }
+    store.remove("key0".to_owned()).wait()?;
+    store.compact().wait()?;
+
+    let retired_files = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension() == Some("retired".as_ref())
+            })
+            .count()
+    };
+    assert!(retired_files() > 0);
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(snapshot.get(key).wait()?, Some("old".to_owned()));
+    }
+    drop(snapshot);
+    assert_eq!(retired_files(), 0);
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key0".to_owned()).wait()?, None);
+    for key_id in 1..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key).wait()?, Some("new".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A backup should hold the state of the store when it started and restore to it
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = temp_dir.path().join("backup");
+    let store_dir = temp_dir.path().join("store");
+    let store = KvStore::<RayonThreadPool>::open(&store_dir, 1)?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id), "old".to_owned())
+            .wait()?;
+    }
+    // seal the written log files
+    store.compact().wait()?;
+    for key_id in 0..50 {
+        store
+            .set(format!("key{}", key_id), "new".to_owned())
+            .wait()?;
+    }
+    store.remove("key99".to_owned()).wait()?;
+    store.backup_to(backup_dir.clone()).wait()?;
+    assert!(backup_dir.join("MANIFEST").exists());
+    // the backup directory must be empty
+    assert!(store.backup_to(backup_dir.clone()).wait().is_err());
+
+    store.set("key0".to_owned(), "newer".to_owned()).wait()?;
+    store.compact().wait()?;
+    // the store directory already contains log files
+    assert!(KvStore::<RayonThreadPool>::restore(&backup_dir, &store_dir).is_err());
+
+    let restore_dir = temp_dir.path().join("restore");
+    KvStore::<RayonThreadPool>::restore(&backup_dir, &restore_dir)?;
+    let restored = KvStore::<RayonThreadPool>::open(&restore_dir, 1)?;
+    for key_id in 0..99 {
+        let value = if key_id < 50 { "new" } else { "old" };
+        assert_eq!(
+            restored.get(format!("key{}", key_id)).wait()?,
+            Some(value.to_owned())
+        );
+    }
+    assert_eq!(restored.get("key99".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key0".to_owned()).wait()?,
+        Some("newer".to_owned())
+    );
+
+    Ok(())
+}
+
+// Restoring a damaged or unfinished backup should fail without writing anything
+#[test]
+fn restore_invalid_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = temp_dir.path().join("backup");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path().join("store"), 1)?;
+    for key_id in 0..10 {
+        store
+            .set(format!("key{}", key_id), "value".to_owned())
+            .wait()?;
+    }
+    store.backup_to(backup_dir.clone()).wait()?;
+
+    let log = fs::read_dir(&backup_dir)?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("log".as_ref()))
+        .unwrap();
+    let mut data = fs::read(&log)?;
+    let last = data.len() - 2;
+    data[last] ^= 1;
+    fs::write(&log, data)?;
+    let restore_dir = temp_dir.path().join("restore");
+    match KvStore::<RayonThreadPool>::restore(&backup_dir, &restore_dir) {
+        Err(KvsError::InvalidBackup(_)) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(!restore_dir.exists());
+
+    fs::remove_file(backup_dir.join("MANIFEST"))?;
+    match KvStore::<RayonThreadPool>::restore(&backup_dir, &restore_dir) {
+        Err(KvsError::InvalidBackup(_)) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    Ok(())
+}
+
+// A compaction file left by a crash should be ignored and removed on open.
+#[test]
+fn remove_unfinished_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    drop(store);
+
+    let tmp_path = temp_dir.path().join("2.compacting");
+    fs::write(&tmp_path, b"KVSL\x01\x00\x00\x00garbage")?;
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert!(!tmp_path.exists());
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -237,3 +829,719 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Log files written as json before the binary format should still be readable.
+// Test that the data survives a compaction into the binary format.
+#[test]
+fn read_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key2"}}"#,
+    )?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+
+    // overwrite other keys until a compaction removes the json log
+    let mut iter = 0;
+    while temp_dir.path().join("1.log").exists() {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..1000 {
+            store
+                .set(format!("key{}", key_id + 10), format!("{}", iter))
+                .wait()?;
+        }
+        iter += 1;
+    }
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+
+    Ok(())
+}
+
+// A flipped bit in a record in the middle of the log should be reported as corruption.
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut data = fs::read(&log_path)?;
+    // the last byte of the first record, which starts after the 8-byte file header
+    data[8 + 30] ^= 0x01;
+    fs::write(&log_path, data)?;
+
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::Corrupted { gen: 1, pos: 8 }) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption is not detected"),
+    }
+}
+
+// A record that matches its checksum but holds a value of an unknown codec should be
+// reported as invalid on reads and as corrupted on open.
+#[test]
+fn detect_invalid_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut data = fs::read(&log_path)?;
+    // the only record starts after the 8-byte file header, with the codec in the
+    // high bits of its kind byte
+    let record = &mut data[8..];
+    record[12] |= 0x70;
+    let crc = crc32fast::hash(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    fs::write(&log_path, &data)?;
+
+    match store.get("key1".to_owned()).wait() {
+        Err(KvsError::InvalidRecord(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("invalid record is not detected"),
+    }
+    drop(store);
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::Corrupted { gen: 1, pos: 8 }) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("invalid record is not detected"),
+    }
+}
+
+// A torn record at the end of the newest log should be dropped on open.
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(fs::metadata(&log_path)?.len(), 8 + 31);
+
+    store.set("key2".to_owned(), "value3".to_owned()).wait()?;
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// A record at the end of the log whose length runs past the end of the file should be
+// dropped on open, without allocating its length.
+#[test]
+fn recover_overlong_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut data = fs::read(&log_path)?;
+    // the key and value lengths of the second record
+    let lens = 8 + 31 + 13;
+    data[lens..lens + 8].copy_from_slice(&[0xff; 8]);
+    fs::write(&log_path, data)?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(fs::metadata(&log_path)?.len(), 8 + 31);
+
+    Ok(())
+}
+
+// Should store keys and values that are not valid UTF-8
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0x00, 0x9f, 0x92, 0x96, 0xc3];
+
+    store.set_bytes(key.clone(), value.clone()).wait()?;
+    assert_eq!(store.get_bytes(key.clone()).wait()?, Some(value.clone()));
+    match store.scan(String::new(), None, None).collect().wait() {
+        Err(KvsError::Utf8(_)) => {}
+        _ => panic!("Utf8 error is not returned"),
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get_bytes(key.clone()).wait()?, Some(value.clone()));
+    let pairs = store.scan_prefix_bytes(vec![0xff]).collect().wait()?;
+    assert_eq!(pairs, vec![(key.clone(), value)]);
+    store.remove_bytes(key.clone()).wait()?;
+    assert_eq!(store.get_bytes(key).wait()?, None);
+
+    Ok(())
+}
+
+// Should serve hot values from the cache until they are written
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        cache_capacity: 1 << 20,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    for _ in 0..3 {
+        assert_eq!(
+            store.get("key1".to_owned()).wait()?,
+            Some("value1".to_owned())
+        );
+    }
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (2, 1, 1));
+
+    store.set("key1".to_owned(), "value2".to_owned()).wait()?;
+    assert_eq!(store.cache_stats().entries, 0);
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    // the value moves to the compaction file but stays cached
+    store.compact().wait()?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses), (3, 2));
+
+    store.remove("key1".to_owned()).wait()?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    assert_eq!(store.cache_stats().entries, 0);
+    Ok(())
+}
+
+// Should evict the least recently used values to stay within the byte budget
+#[test]
+fn value_cache_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let capacity = 16 * 1024;
+    let options = KvStoreOptions {
+        cache_capacity: capacity,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+    let value = "v".repeat(100);
+    for key_id in 0..1000 {
+        let key = format!("key{}", key_id);
+        store.set(key.clone(), value.clone()).wait()?;
+        assert_eq!(store.get(key).wait()?, Some(value.clone()));
+    }
+    let stats = store.cache_stats();
+    assert!(stats.bytes <= capacity);
+    assert!(stats.entries > 0 && stats.entries < 1000);
+    // a value larger than the budget is never cached
+    store.set("large".to_owned(), "v".repeat(capacity)).wait()?;
+    store.get("large".to_owned()).wait()?;
+    assert!(store.cache_stats().bytes <= capacity);
+    Ok(())
+}
+
+// Should read sealed log files through memory maps across compactions
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        mmap: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 2, options)?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .wait()?;
+    }
+    store.compact().wait()?;
+    let snapshot = store.snapshot();
+    // overwrite half of the keys in the active log file
+    for key_id in 0..50 {
+        store
+            .set(format!("key{}", key_id), format!("new{}", key_id))
+            .wait()?;
+    }
+    let check = |store: &KvStore<RayonThreadPool>| -> Result<()> {
+        for key_id in 0..100 {
+            let value = if key_id < 50 { "new" } else { "value" };
+            assert_eq!(
+                store.get(format!("key{}", key_id)).wait()?,
+                Some(format!("{}{}", value, key_id))
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // the mapped files are merged and deleted
+    store.compact().wait()?;
+    check(&store)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id)).wait()?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    drop(snapshot);
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    check(&store)?;
+    Ok(())
+}
+
+// Should look up the keys of sealed log files in their index files
+#[test]
+fn disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |index| {
+        let options = KvStoreOptions {
+            index,
+            ..KvStoreOptions::default()
+        };
+        KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 2, options)
+    };
+    let index_files = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("index".as_ref()))
+            .count()
+    };
+    let disk = IndexMode::Disk {
+        max_file_size: 4096,
+    };
+
+    let store = open(disk)?;
+    for key_id in 0..1000 {
+        store
+            .set(format!("key{:04}", key_id), format!("value{}", key_id))
+            .wait()?;
+    }
+    // overwrite and remove keys of the sealed log files
+    for key_id in (0..1000).step_by(3) {
+        store
+            .set(format!("key{:04}", key_id), format!("new{}", key_id))
+            .wait()?;
+    }
+    for key_id in (0..1000).step_by(5) {
+        store.remove(format!("key{:04}", key_id)).wait()?;
+    }
+    assert!(index_files() > 1);
+
+    let expected = |key_id: u32| match key_id {
+        _ if key_id.is_multiple_of(5) => None,
+        _ if key_id.is_multiple_of(3) => Some(format!("new{}", key_id)),
+        _ => Some(format!("value{}", key_id)),
+    };
+    let check = |store: &KvStore<RayonThreadPool>| -> Result<()> {
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{:04}", key_id)).wait()?,
+                expected(key_id)
+            );
+        }
+        let pairs: Vec<(String, String)> =
+            store.scan_prefix("key01".to_owned()).collect().wait()?;
+        let expected_pairs: Vec<(String, String)> = (100..200)
+            .filter_map(|key_id| Some((format!("key{:04}", key_id), expected(key_id)?)))
+            .collect();
+        assert_eq!(pairs, expected_pairs);
+        assert!(store.remove("key0005".to_owned()).wait().is_err());
+        Ok(())
+    };
+    check(&store)?;
+
+    let snapshot = store.snapshot();
+    store
+        .set("key0001".to_owned(), "latest".to_owned())
+        .wait()?;
+    store.compact().wait()?;
+    assert_eq!(snapshot.get("key0001".to_owned()).wait()?, expected(1));
+    drop(snapshot);
+    store
+        .set("key0001".to_owned(), "value1".to_owned())
+        .wait()?;
+    // the index files of the merged log files are replaced
+    store.compact().wait()?;
+    assert_eq!(index_files(), 1);
+    check(&store)?;
+
+    drop(store);
+    let store = open(disk)?;
+    check(&store)?;
+    drop(store);
+    let store = open(IndexMode::Memory)?;
+    check(&store)?;
+    Ok(())
+}
+
+// Should read values written with any codec and recompress them in a compaction
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |compression| {
+        let options = KvStoreOptions {
+            compression,
+            ..KvStoreOptions::default()
+        };
+        KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)
+    };
+    let log_len = |store: &KvStore<RayonThreadPool>| -> u64 {
+        store.gen_usage().iter().map(|usage| usage.len).sum()
+    };
+    let document = |key_id: u32| {
+        format!(
+            "{{\"id\":{},\"tags\":[{}]}}",
+            key_id,
+            "\"tag\",".repeat(100)
+        )
+    };
+
+    let store = open(Compression::Lz4)?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id), document(key_id))
+            .wait()?;
+    }
+    // incompressible values are stored verbatim
+    store.set("short".to_owned(), "v".to_owned()).wait()?;
+    assert!(log_len(&store) < 100 * document(0).len() as u64 / 2);
+    drop(store);
+
+    let store = open(Compression::Zstd)?;
+    for key_id in 100..200 {
+        store
+            .set(format!("key{}", key_id), document(key_id))
+            .wait()?;
+    }
+    let mut batch = WriteBatch::new();
+    batch.set("key200".to_owned(), document(200));
+    store.write_batch(batch).wait()?;
+    drop(store);
+
+    let store = open(Compression::None)?;
+    for key_id in 0..201 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).wait()?,
+            Some(document(key_id))
+        );
+    }
+    // the compaction rewrites every value verbatim
+    store.compact().wait()?;
+    assert!(log_len(&store) > 201 * document(0).len() as u64);
+    drop(store);
+
+    let store = open(Compression::Lz4)?;
+    store.compact().wait()?;
+    assert!(log_len(&store) < 201 * document(0).len() as u64 / 2);
+    for key_id in 0..201 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).wait()?,
+            Some(document(key_id))
+        );
+    }
+    assert_eq!(store.get("short".to_owned()).wait()?, Some("v".to_owned()));
+
+    Ok(())
+}
+
+// Should apply all writes in a batch, or none of them if a removed key is not found
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned())
+        .set("key3".to_owned(), "value3".to_owned())
+        .remove("key3".to_owned());
+    store.write_batch(batch).wait()?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get("key3".to_owned()).wait()?, None);
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key4".to_owned(), "value4".to_owned())
+        .remove("key1".to_owned());
+    match store.write_batch(batch).wait() {
+        Err(KvsError::KeyNotFound) => {}
+        _ => panic!("KeyNotFound error is not returned"),
+    }
+    assert_eq!(store.get("key4".to_owned()).wait()?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get("key3".to_owned()).wait()?, None);
+    assert_eq!(store.get("key4".to_owned()).wait()?, None);
+
+    Ok(())
+}
+
+// Concurrent scans should see every write of a batch or none
+#[test]
+fn write_batch_is_atomic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..200 {
+                let mut batch = WriteBatch::new();
+                for key in 0..50 {
+                    if i % 2 == 0 {
+                        batch.set(format!("key{:02}", key), i.to_string());
+                    } else {
+                        batch.remove(format!("key{:02}", key));
+                    }
+                }
+                store.write_batch(batch).wait()?;
+            }
+            Ok(())
+        })
+    };
+    while !writer.is_finished() {
+        let pairs: Vec<_> = store.scan(String::new(), None, None).collect().wait()?;
+        if !pairs.is_empty() {
+            assert_eq!(pairs.len(), 50, "a batch is seen partially");
+            assert!(pairs.iter().all(|(_, value)| *value == pairs[0].1));
+        }
+    }
+    writer.join().unwrap()
+}
+
+// A batch torn at the end of the log should be dropped as a whole on open.
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch).wait()?;
+    drop(store);
+
+    // only the last command of the batch is torn
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(store.get("key3".to_owned()).wait()?, None);
+    assert_eq!(fs::metadata(&log_path)?.len(), 8 + 31);
+
+    Ok(())
+}
+
+fn concurrent_set_with_sync(sync: SyncPolicy) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 8, options)?;
+    let sets: Vec<_> = (0..1000)
+        .map(|i| store.set(format!("key{}", i), format!("value{}", i)))
+        .collect();
+    // every write is acknowledged once the log is synced
+    future::join_all(sets).wait()?;
+    for i in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", i)).wait()?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", i)).wait()?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn sync_always() -> Result<()> {
+    concurrent_set_with_sync(SyncPolicy::Always)
+}
+
+#[test]
+fn sync_every_n() -> Result<()> {
+    concurrent_set_with_sync(SyncPolicy::EveryN(16))
+}
+
+#[test]
+fn sync_interval() -> Result<()> {
+    concurrent_set_with_sync(SyncPolicy::Interval(Duration::from_millis(5)))
+}
+
+// Writes waiting for a group commit should be acknowledged when the store is closed.
+#[test]
+fn sync_pending_on_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync: SyncPolicy::Interval(Duration::from_secs(3600)),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), 1, options)?;
+    let set = store.set("key1".to_owned(), "value1".to_owned());
+    drop(store);
+    set.wait()?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+// Should scan key ranges and key prefixes in key order
+#[test]
+fn scan_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key in &["user:3", "user:1", "order:1", "user:2", "zone"] {
+        store
+            .set(key.to_string(), format!("{}-value", key))
+            .wait()?;
+    }
+    store.remove("user:2".to_owned()).wait()?;
+    let pair = |key: &str| (key.to_owned(), format!("{}-value", key));
+
+    let pairs = store.scan("order:1".to_owned(), Some("zone".to_owned()), None);
+    assert_eq!(
+        pairs.collect().wait()?,
+        vec![pair("order:1"), pair("user:1"), pair("user:3")]
+    );
+    let pairs = store.scan("p".to_owned(), None, Some(2));
+    assert_eq!(
+        pairs.collect().wait()?,
+        vec![pair("user:1"), pair("user:3")]
+    );
+    let pairs = store.scan_prefix("user:".to_owned());
+    assert_eq!(
+        pairs.collect().wait()?,
+        vec![pair("user:1"), pair("user:3")]
+    );
+    let pairs = store.scan_prefix("none".to_owned());
+    assert_eq!(pairs.collect().wait()?, vec![]);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let pairs = store.scan(String::new(), None, None);
+    assert_eq!(
+        pairs.collect().wait()?,
+        vec![
+            pair("order:1"),
+            pair("user:1"),
+            pair("user:3"),
+            pair("zone")
+        ]
+    );
+
+    Ok(())
+}
+
+// Scans longer than a page should yield every pair in order
+#[test]
+fn scan_many_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    for i in 0..1000 {
+        store
+            .set(format!("key{:04}", i), format!("value{}", i))
+            .wait()?;
+    }
+    for i in (0..1000).step_by(3) {
+        store.remove(format!("key{:04}", i)).wait()?;
+    }
+    let pair = |i: usize| (format!("key{:04}", i), format!("value{}", i));
+    let live: Vec<_> = (0..1000).filter(|i| i % 3 != 0).map(pair).collect();
+
+    let pairs = store.scan(String::new(), None, None);
+    assert_eq!(pairs.collect().wait()?, live);
+    let pairs = store.scan("key0100".to_owned(), None, Some(500));
+    assert_eq!(pairs.collect().wait()?, live[66..566].to_vec());
+    let pairs = store.scan_prefix("key09".to_owned());
+    assert_eq!(pairs.collect().wait()?, live[600..].to_vec());
+
+    let snapshot = store.snapshot();
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), "new".to_owned()).wait()?;
+    }
+    let pairs = snapshot.scan(String::new(), None, None);
+    assert_eq!(pairs.collect().wait()?, live);
+    let pairs = snapshot.scan_prefix("key09".to_owned());
+    assert_eq!(pairs.collect().wait()?, live[600..].to_vec());
+
+    Ok(())
+}
@@ -0,0 +1,323 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvsEngine, KvsError, LsmKvsEngine, LsmOptions, Result, WriteBatch};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+/// Options that flush and compact after a few kilobytes of writes.
+fn small_options() -> LsmOptions {
+    LsmOptions {
+        memtable_size: 4096,
+        level0_tables: 2,
+        level1_size: 16 * 1024,
+        table_size: 4096,
+        sync: false,
+    }
+}
+
+fn open(path: &Path) -> Result<LsmKvsEngine<RayonThreadPool>> {
+    LsmKvsEngine::open_with_options(path, 2, small_options())
+}
+
+/// Returns the number of files with the given extension in `dir`.
+fn count_files(dir: &Path, extension: &str) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| {
+            let path = entry.as_ref().unwrap().path();
+            path.extension().is_some_and(|ext| ext == extension)
+        })
+        .count()
+}
+
+// Should get previously stored values, from the write-ahead log after reopening
+#[test]
+fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    engine.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    engine.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    engine.set("key1".to_owned(), "value3".to_owned()).wait()?;
+    assert_eq!(
+        engine.get("key1".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(engine.get("key3".to_owned()).wait()?, None);
+
+    // Open from disk again and check persistent data
+    drop(engine);
+    let engine = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        engine.get("key1".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(
+        engine.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should remove keys and fail to remove missing ones
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    engine.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    engine.remove("key1".to_owned()).wait()?;
+    assert_eq!(engine.get("key1".to_owned()).wait()?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()).wait(),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    drop(engine);
+    let engine = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(engine.get("key1".to_owned()).wait()?, None);
+
+    Ok(())
+}
+
+// Should keep the newest value of every key through flushes and leveled compactions
+#[test]
+fn flush_and_compact_levels() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    let value = |key: u32, round: u32| format!("value{}-{}-{}", key, round, "x".repeat(64));
+
+    for round in 0..3 {
+        for key in 0..1000 {
+            if round == 2 && key % 2 == 0 {
+                continue;
+            }
+            engine
+                .set(format!("key{:04}", key), value(key, round))
+                .wait()?;
+        }
+    }
+    for key in (0..1000).step_by(3) {
+        engine.remove(format!("key{:04}", key)).wait()?;
+    }
+
+    let check = |engine: &LsmKvsEngine<RayonThreadPool>| -> Result<()> {
+        for key in 0..1000 {
+            let expected = match key {
+                key if key % 3 == 0 => None,
+                key if key % 2 == 0 => Some(value(key, 1)),
+                key => Some(value(key, 2)),
+            };
+            assert_eq!(engine.get(format!("key{:04}", key)).wait()?, expected);
+        }
+        Ok(())
+    };
+    check(&engine)?;
+    assert!(count_files(temp_dir.path(), "sst") > 0);
+
+    // Open from disk again and check persistent data
+    drop(engine);
+    let engine = open(temp_dir.path())?;
+    check(&engine)?;
+
+    // a full compaction leaves one sorted run without the stale entries
+    engine.compact().wait()?;
+    check(&engine)?;
+    assert_eq!(count_files(temp_dir.path(), "wal"), 1);
+    assert_eq!(count_files(temp_dir.path(), "building"), 0);
+    let live = 1000 - 334;
+    let max_tables = live * 100 / 4096 + 1;
+    assert!(count_files(temp_dir.path(), "sst") <= max_tables);
+
+    drop(engine);
+    let engine = open(temp_dir.path())?;
+    check(&engine)?;
+
+    Ok(())
+}
+
+// Should scan the newest live pairs merged from the memtable and the tables
+#[test]
+fn scan_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    for key in &["user:3", "user:1", "order:1", "user:2", "zone"] {
+        engine
+            .set(key.to_string(), format!("{}-value", key))
+            .wait()?;
+    }
+    // move the pairs to a table, then shadow some of them in the memtable
+    engine.compact().wait()?;
+    engine.remove("user:2".to_owned()).wait()?;
+    engine
+        .set("user:1".to_owned(), "user:1-new".to_owned())
+        .wait()?;
+    let pair = |key: &str| (key.to_owned(), format!("{}-value", key));
+    let new_pair = ("user:1".to_owned(), "user:1-new".to_owned());
+
+    let pairs = engine.scan("order:1".to_owned(), Some("zone".to_owned()), None);
+    assert_eq!(
+        pairs.collect().wait()?,
+        vec![pair("order:1"), new_pair.clone(), pair("user:3")]
+    );
+    let pairs = engine.scan("p".to_owned(), None, Some(2));
+    assert_eq!(
+        pairs.collect().wait()?,
+        vec![new_pair.clone(), pair("user:3")]
+    );
+    let pairs = engine.scan_prefix("user:".to_owned());
+    assert_eq!(
+        pairs.collect().wait()?,
+        vec![new_pair.clone(), pair("user:3")]
+    );
+
+    drop(engine);
+    let engine = open(temp_dir.path())?;
+    let pairs = engine.scan(String::new(), None, None);
+    assert_eq!(
+        pairs.collect().wait()?,
+        vec![pair("order:1"), new_pair, pair("user:3"), pair("zone")]
+    );
+
+    Ok(())
+}
+
+// Should scan ranges longer than a page, skipping removed keys across page ends
+#[test]
+fn scan_many_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    for i in 0..1000 {
+        engine
+            .set(format!("key{:04}", i), format!("value{}", i))
+            .wait()?;
+    }
+    for i in (0..1000).step_by(3) {
+        engine.remove(format!("key{:04}", i)).wait()?;
+    }
+    let live: Vec<_> = (0..1000)
+        .filter(|i| i % 3 != 0)
+        .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+        .collect();
+
+    let pairs = engine.scan(String::new(), None, None);
+    assert_eq!(pairs.collect().wait()?, live);
+    let pairs = engine.scan_prefix("key".to_owned());
+    assert_eq!(pairs.collect().wait()?, live);
+    let pairs = engine.scan("key0100".to_owned(), None, Some(500));
+    assert_eq!(pairs.collect().wait()?, &live[66..566]);
+
+    Ok(())
+}
+
+// Should expire values set with a time-to-live, in the memtable and in the tables
+#[test]
+fn expire_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    let ttl = Duration::from_millis(200);
+    engine
+        .set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)
+        .wait()?;
+    engine.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    engine.compact().wait()?;
+    engine
+        .set_with_ttl("key3".to_owned(), "value3".to_owned(), ttl)
+        .wait()?;
+    assert_eq!(
+        engine.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert!(engine.expiry_bytes(b"key1".to_vec()).wait()?.is_some());
+    assert!(engine.expiry_bytes(b"key2".to_vec()).wait()?.is_none());
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("key1".to_owned()).wait()?, None);
+    assert_eq!(engine.get("key3".to_owned()).wait()?, None);
+    assert!(matches!(
+        engine.remove("key3".to_owned()).wait(),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        engine.expiry_bytes(b"key3".to_vec()).wait(),
+        Err(KvsError::KeyNotFound)
+    ));
+    let pairs = engine.scan(String::new(), None, None);
+    assert_eq!(
+        pairs.collect().wait()?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+
+    Ok(())
+}
+
+// Should swap values atomically and apply batches as a whole
+#[test]
+fn compare_and_swap_and_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+
+    let swapped = engine
+        .compare_and_swap("key1".to_owned(), None, Some("value1".to_owned()))
+        .wait()?;
+    assert_eq!(swapped, Ok(()));
+    let swapped = engine
+        .compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))
+        .wait()?;
+    assert_eq!(swapped, Err(Some("value1".to_owned())));
+    let swapped = engine
+        .compare_and_swap("key1".to_owned(), Some("value1".to_owned()), None)
+        .wait()?;
+    assert_eq!(swapped, Ok(()));
+    assert_eq!(engine.get("key1".to_owned()).wait()?, None);
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").set("key3", "value3");
+    engine.write_batch(batch).wait()?;
+    let mut batch = WriteBatch::new();
+    batch.set("key4", "value4").remove("key1");
+    assert!(matches!(
+        engine.write_batch(batch).wait(),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.get("key4".to_owned()).wait()?, None);
+
+    drop(engine);
+    let engine = open(temp_dir.path())?;
+    assert_eq!(
+        engine.get("key3".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should drop a torn record at the end of the write-ahead log
+#[test]
+fn recover_torn_wal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    engine.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    engine.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    drop(engine);
+
+    let wal_path = temp_dir.path().join("1.wal");
+    let len = fs::metadata(&wal_path)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&wal_path)?
+        .set_len(len - 3)?;
+
+    let engine = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        engine.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(engine.get("key2".to_owned()).wait()?, None);
+    assert_eq!(fs::metadata(&wal_path)?.len(), 8 + 27);
+
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use kvs::{KvsEngine, KvsError, MemoryKvsEngine, Result, WriteBatch};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::prelude::*;
+
+// Should get and overwrite values, through every clone of the engine
+#[test]
+fn get_stored_value() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    let clone = engine.clone();
+
+    engine.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    assert_eq!(
+        clone.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    clone.set("key1".to_owned(), "value2".to_owned()).wait()?;
+    assert_eq!(
+        engine.get("key1".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(engine.get("key2".to_owned()).wait()?, None);
+
+    Ok(())
+}
+
+// Should remove keys and fail to remove missing ones
+#[test]
+fn remove_key() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    assert!(matches!(
+        engine.remove("key1".to_owned()).wait(),
+        Err(KvsError::KeyNotFound)
+    ));
+    engine.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    engine.remove("key1".to_owned()).wait()?;
+    assert_eq!(engine.get("key1".to_owned()).wait()?, None);
+
+    Ok(())
+}
+
+// Should expire values set with a time-to-live
+#[test]
+fn expire_keys() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    engine
+        .set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_millis(100),
+        )
+        .wait()?;
+    engine.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    assert_eq!(
+        engine.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(engine.get("key1".to_owned()).wait()?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()).wait(),
+        Err(KvsError::KeyNotFound)
+    ));
+    engine.compact().wait()?;
+    let pairs = engine.scan(String::new(), None, None);
+    assert_eq!(
+        pairs.collect().wait()?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+
+    Ok(())
+}
+
+// Should swap values atomically and apply batches as a whole
+#[test]
+fn compare_and_swap_and_batch() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    let swapped = engine
+        .compare_and_swap("key1".to_owned(), None, Some("value1".to_owned()))
+        .wait()?;
+    assert_eq!(swapped, Ok(()));
+    let swapped = engine
+        .compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))
+        .wait()?;
+    assert_eq!(swapped, Err(Some("value1".to_owned())));
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").remove("key3");
+    assert!(matches!(
+        engine.write_batch(batch).wait(),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.get("key2".to_owned()).wait()?, None);
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").remove("key1");
+    engine.write_batch(batch).wait()?;
+    assert_eq!(engine.get("key1".to_owned()).wait()?, None);
+    assert_eq!(
+        engine.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should never let a concurrent reader see part of a batch
+#[test]
+fn batch_visible_at_once() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    engine.set("key1".to_owned(), "0".to_owned()).wait()?;
+    engine.set("key2".to_owned(), "0".to_owned()).wait()?;
+    let done = Arc::new(AtomicBool::new(false));
+
+    // Every batch sets key1 before key2, so a reader seeing a batch half applied would
+    // read key2 older than key1.
+    let writer = {
+        let engine = engine.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            for i in 1..=10_000 {
+                let mut batch = WriteBatch::new();
+                batch.set("key1", i.to_string()).set("key2", i.to_string());
+                engine.write_batch(batch).wait().unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+    let read = |key: &str| -> Result<u64> {
+        let value = engine.get(key.to_owned()).wait()?.unwrap();
+        Ok(value.parse().unwrap())
+    };
+    while !done.load(Ordering::SeqCst) {
+        let first = read("key1")?;
+        let second = read("key2")?;
+        assert!(
+            second >= first,
+            "read key1 = {} then key2 = {}",
+            first,
+            second
+        );
+    }
+    writer.join().unwrap();
+
+    Ok(())
+}
+
+// Should scan the pairs in ascending key order
+#[test]
+fn scan_keys() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    for key in &["user:3", "user:1", "order:1", "user:2", "zone"] {
+        engine
+            .set(key.to_string(), format!("{}-value", key))
+            .wait()?;
+    }
+    engine.remove("user:2".to_owned()).wait()?;
+    let pair = |key: &str| (key.to_owned(), format!("{}-value", key));
+
+    let pairs = engine.scan("order:1".to_owned(), Some("zone".to_owned()), None);
+    assert_eq!(
+        pairs.collect().wait()?,
+        vec![pair("order:1"), pair("user:1"), pair("user:3")]
+    );
+    let pairs = engine.scan("p".to_owned(), None, Some(1));
+    assert_eq!(pairs.collect().wait()?, vec![pair("user:1")]);
+    let pairs = engine.scan_prefix("user:".to_owned());
+    assert_eq!(
+        pairs.collect().wait()?,
+        vec![pair("user:1"), pair("user:3")]
+    );
+
+    Ok(())
+}
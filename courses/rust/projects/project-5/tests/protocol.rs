@@ -0,0 +1,264 @@
+use kvs::{KvsClient, KvsError, KvsServer, MemoryKvsEngine, Result, PROTOCOL_VERSION};
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tokio::prelude::*;
+
+/// A connection sending raw frames, standing in for clients and servers of other
+/// versions of the crate.
+struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    fn send(&mut self, frame: Value) {
+        let data = serde_json::to_vec(&frame).unwrap();
+        self.stream
+            .write_all(&(data.len() as u32).to_be_bytes())
+            .unwrap();
+        self.stream.write_all(&data).unwrap();
+    }
+
+    fn receive(&mut self) -> Value {
+        let mut len = [0; 4];
+        self.stream.read_exact(&mut len).unwrap();
+        let mut data = vec![0; u32::from_be_bytes(len) as usize];
+        self.stream.read_exact(&mut data).unwrap();
+        serde_json::from_slice(&data).unwrap()
+    }
+
+    /// Sends a request and returns the body of its response.
+    fn request(&mut self, id: u64, body: Value) -> Value {
+        self.send(json!({ "id": id, "body": body }));
+        let resp = self.receive();
+        assert_eq!(resp["id"], id);
+        resp["body"].clone()
+    }
+}
+
+/// Runs a server with an in-memory engine at `addr`.
+fn start_server(addr: &str) -> SocketAddr {
+    let addr: SocketAddr = addr.parse().unwrap();
+    thread::spawn(move || KvsServer::new(MemoryKvsEngine::new()).run(addr));
+    for _ in 0..50 {
+        if TcpStream::connect(addr).is_ok() {
+            return addr;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("server didn't start");
+}
+
+fn connect(addr: SocketAddr) -> Connection {
+    Connection {
+        stream: TcpStream::connect(addr).unwrap(),
+    }
+}
+
+// Should serve clients that don't send a handshake with version 1
+#[test]
+fn client_without_handshake() {
+    let addr = start_server("127.0.0.1:4040");
+    let mut conn = connect(addr);
+
+    let set = json!({ "Set": { "key": b"key1", "value": b"value1" } });
+    assert_eq!(conn.request(1, set), json!("Set"));
+    assert_eq!(
+        conn.request(2, json!({ "Get": { "key": b"key1" } })),
+        json!({ "Get": "value1" })
+    );
+
+    // an unknown request is answered with an error version 1 knows
+    let resp = conn.request(3, json!({ "Frobnicate": { "key": b"key1" } }));
+    let msg = resp["Err"].as_str().unwrap();
+    assert!(msg.starts_with("Unsupported request: "));
+    assert!(msg.contains("Frobnicate"));
+    assert_eq!(conn.request(4, json!("Ping")), json!("Pong"));
+}
+
+// Should serve clients that send requests without ids, as the first releases did
+#[test]
+fn client_without_ids() {
+    let addr = start_server("127.0.0.1:4045");
+    let mut conn = connect(addr);
+    let mut request = |req: Value| {
+        conn.send(req);
+        conn.receive()
+    };
+
+    let set = json!({ "Set": { "key": "key1", "value": "value1" } });
+    assert_eq!(request(set), json!("Set"));
+    assert_eq!(
+        request(json!({ "Get": { "key": "key1" } })),
+        json!({ "Get": "value1" })
+    );
+    assert_eq!(
+        request(json!({ "Remove": { "key": "key1" } })),
+        json!("Remove")
+    );
+    assert_eq!(
+        request(json!({ "Get": { "key": "key1" } })),
+        json!({ "Get": null })
+    );
+    assert_eq!(
+        request(json!({ "Remove": { "key": "key1" } })),
+        json!({ "Err": "Key not found" })
+    );
+    let resp = request(json!({ "Frobnicate": { "key": "key1" } }));
+    assert!(resp["Err"]
+        .as_str()
+        .unwrap()
+        .starts_with("Unsupported request: "));
+}
+
+// Should fail to connect with a clear error to a server from before requests had ids
+#[test]
+fn client_against_server_without_ids() {
+    let addr: SocketAddr = "127.0.0.1:4046".parse().unwrap();
+    let listener = TcpListener::bind(addr).unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut conn = Connection { stream };
+        // such a server only knows `Get`, `Set` and `Remove`, and closes the connection
+        // on any other frame
+        let req = conn.receive();
+        assert!(req.get("id").is_some());
+    });
+
+    let err = KvsClient::connect(addr).wait().err().unwrap();
+    assert!(format!("{}", err).contains("predate request ids"));
+    server.join().unwrap();
+}
+
+// Should agree on the lower version and the common features in the handshake
+// Should send keys and values as strings, or in base64 if they aren't valid UTF-8
+#[test]
+fn byte_encoding() {
+    let addr = start_server("127.0.0.1:4044");
+    let mut conn = connect(addr);
+
+    // strings, as version 1 sent them
+    let set = json!({ "Set": { "key": "key1", "value": "value1" } });
+    assert_eq!(conn.request(1, set), json!("Set"));
+    assert_eq!(
+        conn.request(2, json!({ "Get": { "key": "key1" } })),
+        json!({ "Get": "value1" })
+    );
+
+    // base64 for bytes that aren't valid UTF-8
+    let set = json!({ "Set": { "key": "key2", "value": { "base64": "/wA=" } } });
+    assert_eq!(conn.request(3, set), json!("Set"));
+    assert_eq!(
+        conn.request(4, json!({ "Get": { "key": { "base64": "a2V5Mg==" } } })),
+        json!({ "Get": { "base64": "/wA=" } })
+    );
+    let scan = json!({ "Scan": { "start": "", "end": null, "limit": null } });
+    assert_eq!(
+        conn.request(5, scan),
+        json!({ "Scan": [["key1", "value1"], ["key2", { "base64": "/wA=" }]] })
+    );
+
+    let resp = conn.request(6, json!({ "Get": { "key": { "base64": "!" } } }));
+    assert!(resp["Err"].is_string());
+}
+
+#[test]
+fn negotiate_handshake() {
+    let addr = start_server("127.0.0.1:4041");
+
+    // a newer client with a feature the server doesn't know
+    let mut conn = connect(addr);
+    let hello = json!({ "Hello": { "version": 9, "features": ["scan", "streams"] } });
+    assert_eq!(
+        conn.request(0, hello),
+        json!({ "Hello": { "version": PROTOCOL_VERSION, "features": ["scan"] } })
+    );
+    let resp = conn.request(1, json!({ "Frobnicate": null }));
+    assert!(resp["Unsupported"].as_str().unwrap().contains("Frobnicate"));
+    assert_eq!(conn.request(2, json!("Ping")), json!("Pong"));
+
+    // a handshake is only accepted as the first request
+    let hello = json!({ "Hello": { "version": 2, "features": [] } });
+    assert!(conn.request(3, hello)["Err"].is_string());
+
+    // an older client speaks its own version
+    let mut conn = connect(addr);
+    let hello = json!({ "Hello": { "version": 1, "features": [] } });
+    assert_eq!(
+        conn.request(0, hello),
+        json!({ "Hello": { "version": 1, "features": [] } })
+    );
+    assert!(conn.request(1, json!("Frobnicate"))["Err"].is_string());
+}
+
+// Should negotiate the protocol with the server when connecting
+#[test]
+fn client_handshake() -> Result<()> {
+    let addr = start_server("127.0.0.1:4042");
+    let client = KvsClient::connect(addr).wait()?;
+
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    for feature in &["ttl", "cas", "scan", "batch", "compact", "backup"] {
+        assert!(client.supports(feature));
+    }
+    assert!(!client.supports("streams"));
+    client.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    assert_eq!(
+        client.scan_prefix("key".to_owned()).wait()?,
+        vec![("key1".to_owned(), "value1".to_owned())]
+    );
+
+    Ok(())
+}
+
+// Should refuse the requests of features the server lacks, and only fail the request of
+// a response the client can't decode
+#[test]
+fn client_against_other_server() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4043".parse().unwrap();
+    let listener = TcpListener::bind(addr)?;
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut conn = Connection { stream };
+        let hello = conn.receive();
+        assert_eq!(hello["id"], 0);
+        assert_eq!(hello["body"]["Hello"]["version"], PROTOCOL_VERSION);
+        let hello = json!({ "Hello": { "version": PROTOCOL_VERSION, "features": ["scan"] } });
+        conn.send(json!({ "id": 0, "body": hello }));
+
+        // answers with a response the client doesn't know, then an unsupported request
+        let req = conn.receive();
+        conn.send(json!({ "id": req["id"], "body": { "Frobnicated": null } }));
+        let req = conn.receive();
+        conn.send(json!({ "id": req["id"], "body": { "Unsupported": "unknown variant" } }));
+        let req = conn.receive();
+        conn.send(json!({ "id": req["id"], "body": "Pong" }));
+    });
+    let client = KvsClient::connect(addr).wait()?;
+
+    assert!(client.supports("scan"));
+    assert!(!client.supports("ttl"));
+    let res = client
+        .set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_secs(1),
+        )
+        .wait();
+    assert!(matches!(res, Err(KvsError::UnsupportedRequest(_))));
+
+    assert!(matches!(
+        client.get("key1".to_owned()).wait(),
+        Err(KvsError::Serde(_))
+    ));
+    assert!(matches!(
+        client.get("key1".to_owned()).wait(),
+        Err(KvsError::UnsupportedRequest(_))
+    ));
+    client.ping().wait()?;
+    server.join().unwrap();
+
+    Ok(())
+}
@@ -0,0 +1,257 @@
+use kvs::{KvsServer, MemoryKvsEngine};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// A reply of the RESP2 protocol, as read by the tests.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(Some(s.to_owned()))
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    /// Sends raw bytes, which may hold several commands.
+    fn send_raw(&mut self, data: &[u8]) {
+        self.writer.write_all(data).unwrap();
+    }
+
+    /// Sends a command as an array of bulk strings and reads its reply.
+    fn command(&mut self, args: &[&str]) -> Reply {
+        let mut data = format!("*{}\r\n", args.len());
+        for arg in args {
+            data.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.send_raw(data.as_bytes());
+        self.read_reply()
+    }
+
+    fn read_reply(&mut self) -> Reply {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let line = line.trim_end_matches("\r\n");
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Reply::Simple(rest.to_owned()),
+            "-" => Reply::Error(rest.to_owned()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "$" => match rest.parse::<i64>().unwrap() {
+                -1 => Reply::Bulk(None),
+                len => {
+                    let mut data = vec![0; len as usize + 2];
+                    self.reader.read_exact(&mut data).unwrap();
+                    data.truncate(len as usize);
+                    Reply::Bulk(Some(String::from_utf8(data).unwrap()))
+                }
+            },
+            "*" => {
+                let len = rest.parse().unwrap();
+                Reply::Array((0..len).map(|_| self.read_reply()).collect())
+            }
+            _ => panic!("invalid reply: {}", line),
+        }
+    }
+}
+
+/// Runs a server with an in-memory engine, serving RESP2 at `resp_addr`, and connects
+/// to it.
+fn start_server(addr: &str, resp_addr: &str) -> Connection {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let resp_addr: SocketAddr = resp_addr.parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(MemoryKvsEngine::new())
+            .with_resp(resp_addr)
+            .run(addr)
+    });
+    for _ in 0..50 {
+        if let Ok(stream) = TcpStream::connect(resp_addr) {
+            return Connection {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+            };
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("server didn't start");
+}
+
+// Should map the Redis string commands onto the engine
+#[test]
+fn string_commands() {
+    let mut conn = start_server("127.0.0.1:4020", "127.0.0.1:4021");
+
+    assert_eq!(conn.command(&["PING"]), Reply::Simple("PONG".to_owned()));
+    assert_eq!(conn.command(&["ping", "hello"]), bulk("hello"));
+    assert_eq!(conn.command(&["GET", "key1"]), Reply::Bulk(None));
+    assert_eq!(
+        conn.command(&["SET", "key1", "value1"]),
+        Reply::Simple("OK".to_owned())
+    );
+    assert_eq!(conn.command(&["GET", "key1"]), bulk("value1"));
+    conn.command(&["SET", "key2", "value2"]);
+    assert_eq!(
+        conn.command(&["EXISTS", "key1", "key2", "key3", "key1"]),
+        Reply::Integer(3)
+    );
+    assert_eq!(
+        conn.command(&["DEL", "key1", "key3", "key2"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(conn.command(&["EXISTS", "key1"]), Reply::Integer(0));
+
+    // values with line breaks and spaces are binary-safe bulk strings
+    conn.command(&["SET", "key 3", "line1\r\nline2"]);
+    assert_eq!(conn.command(&["GET", "key 3"]), bulk("line1\r\nline2"));
+}
+
+// Should expire keys set with EX or PX
+#[test]
+fn set_with_expiry() {
+    let mut conn = start_server("127.0.0.1:4022", "127.0.0.1:4023");
+
+    conn.command(&["SET", "key1", "value1", "PX", "100"]);
+    conn.command(&["SET", "key2", "value2", "ex", "100"]);
+    assert_eq!(conn.command(&["GET", "key1"]), bulk("value1"));
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(conn.command(&["GET", "key1"]), Reply::Bulk(None));
+    assert_eq!(conn.command(&["GET", "key2"]), bulk("value2"));
+
+    assert!(matches!(
+        conn.command(&["SET", "key1", "value1", "EX", "0"]),
+        Reply::Error(_)
+    ));
+    assert!(matches!(
+        conn.command(&["SET", "key1", "value1", "EX", "10", "PX", "10"]),
+        Reply::Error(_)
+    ));
+    assert!(matches!(
+        conn.command(&["SET", "key1", "value1", "KEEPTTL"]),
+        Reply::Error(_)
+    ));
+    assert_eq!(conn.command(&["GET", "key1"]), Reply::Bulk(None));
+}
+
+// Should iterate the keys with SCAN, filtered by MATCH
+#[test]
+fn scan_keys() {
+    let mut conn = start_server("127.0.0.1:4024", "127.0.0.1:4025");
+    for i in 0..25 {
+        conn.command(&["SET", &format!("user:{:02}", i), "value"]);
+        conn.command(&["SET", &format!("order:{:02}", i), "value"]);
+    }
+
+    // returns the next cursor and the keys of a page
+    let page = |conn: &mut Connection, cursor: &str, args: &[&str]| -> (String, Vec<String>) {
+        let mut command = vec!["SCAN", cursor];
+        command.extend_from_slice(args);
+        let mut reply = match conn.command(&command) {
+            Reply::Array(reply) => reply,
+            reply => panic!("invalid reply: {:?}", reply),
+        };
+        let mut keys = Vec::new();
+        if let Reply::Array(page) = reply.pop().unwrap() {
+            for key in page {
+                if let Reply::Bulk(Some(key)) = key {
+                    keys.push(key);
+                }
+            }
+        }
+        match reply.pop().unwrap() {
+            Reply::Bulk(Some(cursor)) => (cursor, keys),
+            reply => panic!("invalid cursor: {:?}", reply),
+        }
+    };
+    let scan = |conn: &mut Connection, args: &[&str]| -> Vec<String> {
+        let mut cursor = "0".to_owned();
+        let mut keys = Vec::new();
+        loop {
+            let (next, page_keys) = page(conn, &cursor, args);
+            keys.extend(page_keys);
+            cursor = next;
+            if cursor == "0" {
+                return keys;
+            }
+        }
+    };
+
+    assert_eq!(scan(&mut conn, &[]).len(), 50);
+    let keys = scan(&mut conn, &["MATCH", "user:*", "COUNT", "7"]);
+    let expected: Vec<_> = (0..25).map(|i| format!("user:{:02}", i)).collect();
+    assert_eq!(keys, expected);
+    let keys = scan(&mut conn, &["MATCH", "*:1[0-2]"]);
+    assert_eq!(
+        keys,
+        vec!["order:10", "order:11", "order:12", "user:10", "user:11", "user:12"]
+    );
+    let keys = scan(&mut conn, &["MATCH", "user:?[^0-8]"]);
+    assert_eq!(keys, vec!["user:09", "user:19"]);
+
+    assert!(matches!(
+        conn.command(&["SCAN", "0", "COUNT", "0"]),
+        Reply::Error(_)
+    ));
+    assert!(matches!(conn.command(&["SCAN", "abc"]), Reply::Error(_)));
+
+    // removing the keys already returned doesn't make the iteration skip keys
+    let (cursor, first) = page(&mut conn, "0", &["COUNT", "10"]);
+    assert_eq!(first.len(), 10);
+    for key in &first {
+        conn.command(&["DEL", key]);
+    }
+    let mut rest = scan(&mut conn, &[]);
+    let mut cursor = cursor;
+    let mut keys = Vec::new();
+    while cursor != "0" {
+        let (next, page_keys) = page(&mut conn, &cursor, &["COUNT", "10"]);
+        keys.extend(page_keys);
+        cursor = next;
+    }
+    assert_eq!(keys.len(), 40);
+    rest.sort();
+    assert_eq!(keys, rest);
+}
+
+// Should answer pipelined and inline commands in order, and report errors
+#[test]
+fn pipeline_and_errors() {
+    let mut conn = start_server("127.0.0.1:4026", "127.0.0.1:4027");
+
+    conn.send_raw(
+        b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+    );
+    conn.send_raw(b"DEL key1\r\n\r\nGET key1\r\n");
+    assert_eq!(conn.read_reply(), Reply::Simple("OK".to_owned()));
+    assert_eq!(conn.read_reply(), bulk("value1"));
+    assert_eq!(conn.read_reply(), Reply::Integer(1));
+    assert_eq!(conn.read_reply(), Reply::Bulk(None));
+
+    assert_eq!(
+        conn.command(&["GET"]),
+        Reply::Error("ERR wrong number of arguments for 'get' command".to_owned())
+    );
+    assert_eq!(
+        conn.command(&["FLUSHALL"]),
+        Reply::Error("ERR unknown command 'flushall'".to_owned())
+    );
+    match conn.command(&["INFO"]) {
+        Reply::Bulk(Some(info)) => {
+            assert!(info.starts_with("# Server\r\n"));
+            assert!(info.contains("tcp_port:4027\r\n"));
+        }
+        reply => panic!("invalid reply: {:?}", reply),
+    }
+    assert_eq!(conn.command(&["INFO", "keyspace"]), bulk(""));
+}
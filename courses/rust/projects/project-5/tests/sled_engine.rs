@@ -0,0 +1,66 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvsEngine, KvsError, Result, SledKvsEngine, WriteBatch};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+fn open(path: &Path) -> Result<SledKvsEngine<RayonThreadPool>> {
+    SledKvsEngine::new(sled::open(path)?, 1)
+}
+
+// Should apply batches as a whole, and not at all if they remove a missing key
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned()).wait()?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").remove("key3");
+    assert!(matches!(
+        engine.write_batch(batch).wait(),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.get("key2".to_owned()).wait()?, None);
+
+    // a removed key is checked in the batch, so a set earlier in it counts
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").remove("key1").remove("key2");
+    engine.write_batch(batch).wait()?;
+    assert_eq!(engine.get("key1".to_owned()).wait()?, None);
+    assert_eq!(engine.get("key2".to_owned()).wait()?, None);
+
+    // an expired key can't be removed, and a written key no longer expires
+    engine
+        .set_with_ttl(
+            "key3".to_owned(),
+            "value3".to_owned(),
+            Duration::from_millis(100),
+        )
+        .wait()?;
+    engine
+        .set_with_ttl(
+            "key4".to_owned(),
+            "value4".to_owned(),
+            Duration::from_millis(100),
+        )
+        .wait()?;
+    let mut batch = WriteBatch::new();
+    batch.set("key4", "value5");
+    engine.write_batch(batch).wait()?;
+    thread::sleep(Duration::from_millis(200));
+    let mut batch = WriteBatch::new();
+    batch.remove("key3");
+    assert!(matches!(
+        engine.write_batch(batch).wait(),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(
+        engine.get("key4".to_owned()).wait()?,
+        Some("value5".to_owned())
+    );
+
+    Ok(())
+}